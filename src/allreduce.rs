@@ -0,0 +1,415 @@
+// allreduce.rs: Ring all-reduce between Ki nodes: a reduce-scatter then an all-gather around a ring formed from the node registry.
+
+use crate::messages::{Envelope, RingChunk};
+use crate::messaging::{Transport, TransportError};
+use crate::node_registry::NodeRegistry;
+use crate::routing;
+use crate::tensor::{Tensor, TensorError};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::error;
+use uuid::Uuid;
+
+// How long a member waits for its predecessor's next chunk before giving up on the collective
+pub const DEFAULT_COLLECTIVE_TIMEOUT: Duration = Duration::from_secs(10);
+// How many discarded collectives a mailbox remembers, to drop chunks that arrive after the discard
+const DISCARDED_COLLECTIVES: usize = 256;
+
+// The members of an all-reduce, each sending to the next and the last sending to the first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ring {
+    members: Vec<Uuid>,
+}
+
+impl Ring {
+    // Members are ordered by id, so every node that forms the ring from the same ids agrees on it.
+    pub fn new(mut members: Vec<Uuid>) -> Self {
+        members.sort();
+        members.dedup();
+        Ring { members }
+    }
+
+    // The Ki nodes among `eligible` that the registry has heard from within `max_age`.
+    pub fn form(registry: &NodeRegistry, max_age: Duration, eligible: &[Uuid]) -> Self {
+        Ring::new(
+            registry
+                .active_nodes_with_role("ki", max_age)
+                .into_iter()
+                .filter(|member| eligible.contains(member))
+                .collect(),
+        )
+    }
+
+    // The ring re-formed without nodes that left.
+    pub fn without(&self, departed: &[Uuid]) -> Self {
+        Ring::new(
+            self.members
+                .iter()
+                .filter(|member| !departed.contains(member))
+                .copied()
+                .collect(),
+        )
+    }
+
+    pub fn members(&self) -> &[Uuid] {
+        &self.members
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn position(&self, node_id: Uuid) -> Option<usize> {
+        self.members.iter().position(|member| *member == node_id)
+    }
+}
+
+#[derive(Debug)]
+pub enum CollectiveError {
+    NotAMember(Uuid),
+    // The predecessor's chunk for `step` did not arrive in time, e.g. because it left the ring
+    Timeout { collective_id: String, step: u32 },
+    Send(TransportError),
+    Malformed(String),
+}
+
+impl fmt::Display for CollectiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollectiveError::NotAMember(node_id) => write!(f, "node {} is not a member of the ring", node_id),
+            CollectiveError::Timeout { collective_id, step } => {
+                write!(f, "timed out waiting for step {} of collective {}", step, collective_id)
+            }
+            CollectiveError::Send(e) => write!(f, "failed to send chunk: {}", e),
+            CollectiveError::Malformed(e) => write!(f, "malformed chunk: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CollectiveError {}
+
+impl From<TensorError> for CollectiveError {
+    fn from(e: TensorError) -> Self {
+        CollectiveError::Malformed(e.to_string())
+    }
+}
+
+// Chunks that reached one node, held until the step that needs them takes them.
+#[derive(Clone, Default)]
+pub struct Mailbox {
+    chunks: Arc<Mutex<HashMap<(String, u32), Tensor>>>,
+    // Locked before `chunks` where both are needed
+    discarded: Arc<Mutex<VecDeque<String>>>,
+    arrived: Arc<Notify>,
+}
+
+impl Mailbox {
+    pub fn new() -> Self {
+        Mailbox::default()
+    }
+
+    pub fn deliver(&self, chunk: RingChunk) {
+        let discarded = self.discarded.lock().unwrap();
+        if discarded.contains(&chunk.collective_id) {
+            return;
+        }
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert((chunk.collective_id, chunk.step), chunk.values);
+        drop(discarded);
+        self.arrived.notify_waiters();
+    }
+
+    // Waits up to `timeout` for the chunk of `step`.
+    pub async fn take(&self, collective_id: &str, step: u32, timeout: Duration) -> Option<Tensor> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let key = (collective_id.to_string(), step);
+        loop {
+            // Register for the wake-up before looking, so a chunk delivered in between is not missed
+            let arrived = self.arrived.notified();
+            tokio::pin!(arrived);
+            arrived.as_mut().enable();
+            if let Some(chunk) = self.chunks.lock().unwrap().remove(&key) {
+                return Some(chunk);
+            }
+            if tokio::time::timeout_at(deadline, arrived).await.is_err() {
+                return self.chunks.lock().unwrap().remove(&key);
+            }
+        }
+    }
+
+    // Drops what is left of a collective that failed part-way, and any of its chunks still on the way.
+    pub fn discard(&self, collective_id: &str) {
+        let mut discarded = self.discarded.lock().unwrap();
+        self.chunks.lock().unwrap().retain(|(id, _), _| id != collective_id);
+        discarded.push_back(collective_id.to_string());
+        if discarded.len() > DISCARDED_COLLECTIVES {
+            discarded.pop_front();
+        }
+    }
+}
+
+// Delivers the ring chunks addressed to `node_id` into `mailbox`.
+pub async fn spawn_listener(node_id: Uuid, transport: Arc<dyn Transport>, mailbox: Mailbox) -> Result<(), TransportError> {
+    let queue_name = routing::node_collective_queue(node_id);
+    let dead_letters =
+        routing::declare_node_queue(transport.as_ref(), &queue_name, &[routing::node_collective_key(node_id)]).await?;
+    let mut consumer = transport
+        .consume(&queue_name, &format!("collective_listener_{}", node_id))
+        .await?;
+
+    tokio::spawn(async move {
+        while let Some(delivery) = consumer.next().await {
+            match Envelope::<RingChunk>::from_delivery(&delivery) {
+                Ok(envelope) => {
+                    mailbox.deliver(envelope.payload);
+                    if let Err(e) = delivery.ack().await {
+                        error!("Failed to acknowledge message: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("Rejecting ring chunk: {}", e);
+                    if let Err(e) = dead_letters.dead_letter(transport.as_ref(), &delivery, &e.to_string()).await {
+                        error!("Failed to dead-letter ring chunk: {:?}", e);
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Sends a chunk to another member's collective queue, or straight to the member over a persistent peer
+// connection when the transport has a route to it.
+pub async fn send_chunk(transport: &dyn Transport, sender: Uuid, to: Uuid, chunk: RingChunk) -> Result<(), TransportError> {
+    Envelope::new(sender, chunk)
+        .route(transport, &routing::node_collective_key(to))
+        .await
+}
+
+// Splits `len` values into `parts` nearly equal contiguous chunks.
+pub fn chunk_ranges(len: usize, parts: usize) -> Vec<Range<usize>> {
+    let parts = parts.max(1);
+    let mut start = 0;
+    (0..parts)
+        .map(|part| {
+            let count = len / parts + usize::from(part < len % parts);
+            start += count;
+            start - count..start
+        })
+        .collect()
+}
+
+// Sums `values` with those of every other member of `ring`, each calling this with the same collective
+// id and the same number of values, and returns the sum every member ends up with. The values travel as
+// one chunk per member: n - 1 reduce-scatter steps leave each member with the full sum of one chunk,
+// then n - 1 all-gather steps pass the summed chunks around. `send(to, chunk)` hands a chunk to the next
+// member; chunks from the previous member arrive in `mailbox`.
+pub async fn ring_allreduce<S, Fut>(
+    ring: &Ring,
+    node_id: Uuid,
+    collective_id: &str,
+    mut values: Vec<f32>,
+    mailbox: &Mailbox,
+    timeout: Duration,
+    send: S,
+) -> Result<Vec<f32>, CollectiveError>
+where
+    S: Fn(Uuid, RingChunk) -> Fut,
+    Fut: Future<Output = Result<(), TransportError>>,
+{
+    let position = ring.position(node_id).ok_or(CollectiveError::NotAMember(node_id))?;
+    let n = ring.len();
+    if n == 1 {
+        return Ok(values);
+    }
+    let next = ring.members[(position + 1) % n];
+    let ranges = chunk_ranges(values.len(), n);
+
+    for step in 0..2 * (n - 1) {
+        let reducing = step < n - 1;
+        let (send_index, receive_index) = if reducing {
+            ((position + n - step) % n, (position + 2 * n - step - 1) % n)
+        } else {
+            let step = step - (n - 1);
+            ((position + 1 + n - step) % n, (position + n - step) % n)
+        };
+
+        let outgoing = values[ranges[send_index].clone()].to_vec();
+        let chunk = RingChunk {
+            collective_id: collective_id.to_string(),
+            step: step as u32,
+            values: Tensor::new(vec![outgoing.len()], outgoing)?,
+        };
+        send(next, chunk).await.map_err(CollectiveError::Send)?;
+
+        let received = mailbox
+            .take(collective_id, step as u32, timeout)
+            .await
+            .ok_or_else(|| CollectiveError::Timeout {
+                collective_id: collective_id.to_string(),
+                step: step as u32,
+            })?;
+        let received = received.to_vec::<f32>()?;
+        let target = &mut values[ranges[receive_index].clone()];
+        if received.len() != target.len() {
+            return Err(CollectiveError::Malformed(format!(
+                "step {} carried {} values, expected {}",
+                step,
+                received.len(),
+                target.len()
+            )));
+        }
+        for (value, incoming) in target.iter_mut().zip(received) {
+            if reducing {
+                *value += incoming;
+            } else {
+                *value = incoming;
+            }
+        }
+    }
+    Ok(values)
+}
+
+// All values of `tensors`, one after another.
+pub fn flatten(tensors: &[Tensor]) -> Result<Vec<f32>, TensorError> {
+    let mut values = Vec::with_capacity(tensors.iter().map(Tensor::len).sum());
+    for tensor in tensors {
+        values.extend_from_slice(&tensor.values::<f32>()?);
+    }
+    Ok(values)
+}
+
+// Cuts `values` back into tensors shaped like `like`; the inverse of `flatten`.
+pub fn unflatten(values: &[f32], like: &[Tensor]) -> Result<Vec<Tensor>, TensorError> {
+    let mut start = 0;
+    let mut tensors = Vec::with_capacity(like.len());
+    for tensor in like {
+        let end = start + tensor.len();
+        let slice = values
+            .get(start..end)
+            .ok_or_else(|| TensorError::Malformed(format!("{} values are too few to unflatten", values.len())))?;
+        tensors.push(Tensor::new(tensor.shape().to_vec(), slice.to_vec())?);
+        start = end;
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join_all;
+
+    // Runs one all-reduce among `members`, with chunks handed straight to the receiving mailbox.
+    // Members in `silent` never take part.
+    async fn run(members: usize, len: usize, silent: &[usize]) -> Vec<Result<Vec<f32>, CollectiveError>> {
+        let ring = Ring::new((0..members).map(|_| Uuid::new_v4()).collect());
+        let mailboxes: HashMap<Uuid, Mailbox> = ring.members().iter().map(|member| (*member, Mailbox::new())).collect();
+        let participants = ring
+            .members()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !silent.contains(index))
+            .map(|(index, &member)| {
+                let values: Vec<f32> = (0..len).map(|i| (index * 100 + i) as f32).collect();
+                let (ring, mailboxes) = (&ring, &mailboxes);
+                async move {
+                    ring_allreduce(
+                        ring,
+                        member,
+                        "collective-1",
+                        values,
+                        &mailboxes[&member],
+                        Duration::from_millis(200),
+                        |to, chunk| async move {
+                            mailboxes[&to].deliver(chunk);
+                            Ok(())
+                        },
+                    )
+                    .await
+                }
+            });
+        join_all(participants).await
+    }
+
+    #[tokio::test]
+    async fn test_every_member_ends_with_the_sum() {
+        for members in 1..=5 {
+            // Fewer values than members leaves some chunks empty
+            for len in [3, 7, 64] {
+                let expected: Vec<f32> = (0..len)
+                    .map(|i| (0..members).map(|index| (index * 100 + i) as f32).sum())
+                    .collect();
+                for result in run(members, len, &[]).await {
+                    assert_eq!(result.unwrap(), expected, "{} members, {} values", members, len);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_departed_member_times_out_the_ring() {
+        let results = run(3, 10, &[1]).await;
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(CollectiveError::Timeout { .. }))));
+
+        let ring = Ring::new(vec![Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()]);
+        let departed = ring.members()[1];
+        let reformed = ring.without(&[departed]);
+        assert_eq!(reformed.len(), 2);
+        assert_eq!(reformed.members(), &[ring.members()[0], ring.members()[2]][..]);
+        assert_eq!(reformed.position(departed), None);
+    }
+
+    #[test]
+    fn test_ring_forms_from_active_eligible_ki_nodes() {
+        let registry = NodeRegistry::new();
+        let (ki, other_ki, an) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        registry.register_node(ki, "ki".to_string());
+        registry.register_node(other_ki, "ki".to_string());
+        registry.register_node(an, "an".to_string());
+        let ring = Ring::form(&registry, Duration::from_secs(60), &[ki, an]);
+        assert_eq!(ring.members(), &[ki][..]);
+    }
+
+    #[tokio::test]
+    async fn test_discarded_collective_drops_late_chunks() {
+        let mailbox = Mailbox::new();
+        let chunk = |collective_id: &str, step| RingChunk {
+            collective_id: collective_id.to_string(),
+            step,
+            values: Tensor::scalar(1.0f32),
+        };
+        mailbox.deliver(chunk("failed", 0));
+        mailbox.discard("failed");
+        mailbox.deliver(chunk("failed", 1));
+        mailbox.deliver(chunk("next", 0));
+        assert!(mailbox.chunks.lock().unwrap().keys().all(|(id, _)| id == "next"));
+        assert!(mailbox.take("next", 0, Duration::from_millis(10)).await.is_some());
+    }
+
+    #[test]
+    fn test_flatten_round_trips() {
+        let tensors = vec![
+            Tensor::new(vec![2, 2], vec![1.0f32, 2.0, 3.0, 4.0]).unwrap(),
+            Tensor::scalar(5.0f32),
+        ];
+        let values = flatten(&tensors).unwrap();
+        assert_eq!(values, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(unflatten(&values, &tensors).unwrap(), tensors);
+        assert!(unflatten(&values[..3], &tensors).is_err());
+        assert_eq!(chunk_ranges(5, 3), vec![0..2, 2..4, 4..5]);
+    }
+}
@@ -1,99 +1,1406 @@
 // an_node.rs: Contains the logic for An nodes, including task distribution to Ki nodes and local database handling.
 
-use lapin::{options::*, types::FieldTable, BasicProperties, Connection, ConnectionProperties};
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use crate::allreduce::Ring;
+use crate::checkpoint::{self, CheckpointRequests, CheckpointStore, TrainingState};
+use crate::dead_letter::DeadLetterPolicy;
+use crate::dedup::{Claim, DedupStore, DEFAULT_DEDUP_CAPACITY};
+use crate::kernels;
+use crate::messages::{
+    CheckpointAck, CheckpointRequest, Envelope, EnvelopeError, EnvelopeHeader, MessageKind, ModelAck, ModelUpdate,
+    NodeRegistration, ResultMessage, RoleAck, RoleAssignment, TaskMessage,
+};
+use crate::load_balancer::{self, LoadBalancer};
+use crate::logging_metrics;
+use crate::messaging::{Consumer, Delivery, Transport, TransportError};
+use crate::mlp::Mlp;
+use crate::model::{Model, ModelSpec};
+use crate::node_registry::NodeRegistry;
+use crate::pipeline;
+use crate::routing;
+use crate::rpc::{self, RpcClient, DEFAULT_RPC_TIMEOUT};
+use crate::tensor::Tensor;
+use crate::tensor_parallel::{ShardAxis, ShardedMatmul};
+use crate::training::{self, BarrierTimeout, GradientPush, GradientSync, ParameterServer, TrainingConfig, TrainingData};
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
 use tracing::{error, info};
-use futures_util::stream::StreamExt;
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TaskMessage {
-    task_id: String,
-    data: String,
+// Ki nodes not heard from for this long are left out of all-reduce rings (they announce every 10 seconds)
+const RING_MAX_AGE: Duration = Duration::from_secs(30);
+// How many rings a training step is tried on before Ki nodes leaving fails the task
+const MAX_RING_ATTEMPTS: usize = 3;
+
+// State shared by the task handlers of one An node.
+#[derive(Clone)]
+struct AnNode {
+    node_id: Uuid,
+    transport: Arc<dyn Transport>,
+    rpc_client: RpcClient,
+    load_balancer: LoadBalancer,
+    // When each Ki node last announced itself; all-reduce rings are formed from it
+    registry: NodeRegistry,
+    // Results already recorded, keyed by the task's idempotency key
+    results: DedupStore<ResultMessage>,
+    // Models shipped by the principal, by name
+    models: Arc<RwLock<HashMap<String, Model>>>,
+    // Where training jobs are checkpointed, when AN_CHECKPOINT_DIR is set
+    checkpoints: Option<CheckpointStore>,
+    checkpoint_requests: CheckpointRequests,
 }
 
-pub async fn run() -> Result<(), Box<dyn Error>> {
-    // Establish connection to RabbitMQ
-    let amqp_addr = std::env::var("AMQP_ADDR").map_err(|e| {
-        error!("Failed to read AMQP_ADDR environment variable: {:?}", e);
-        e
-    })?;
-    let connection = Connection::connect(&amqp_addr, ConnectionProperties::default()).await.map_err(|e| {
-        error!("Failed to connect to RabbitMQ: {:?}", e);
-        e
-    })?;
-    let channel = connection.create_channel().await.map_err(|e| {
-        error!("Failed to create channel: {:?}", e);
-        e
-    })?;
+pub async fn run(transport: Arc<dyn Transport>) -> Result<(), TransportError> {
+    let node_id = Uuid::new_v4();
+    let rpc_client = RpcClient::new(node_id, transport.clone()).await?;
 
-    // Declare the queue for receiving tasks from the principal
-    let queue_name = "an_task_queue";
-    channel
-        .queue_declare(
-            queue_name,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await.map_err(|e| {
+    // Track the Ki nodes that announce themselves, weighted by their advertised capacity
+    let load_balancer = LoadBalancer::new();
+    let registry = NodeRegistry::new();
+    load_balancer::spawn_registration_listener(
+        transport.clone(),
+        load_balancer.clone(),
+        registry.clone(),
+        node_id,
+        "ki",
+    )
+    .await?;
+
+    // Results are recorded once per idempotency key; AN_DEDUP_PATH keeps them across restarts
+    let dedup_capacity = std::env::var("AN_DEDUP_CAPACITY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_DEDUP_CAPACITY);
+    let dedup_path = std::env::var_os("AN_DEDUP_PATH").map(PathBuf::from);
+    let results = DedupStore::open(dedup_capacity, dedup_path.as_deref())?;
+    let checkpoints = match std::env::var_os("AN_CHECKPOINT_DIR") {
+        Some(dir) => Some(CheckpointStore::open(PathBuf::from(dir))?),
+        None => None,
+    };
+
+    let node = AnNode {
+        node_id,
+        transport: transport.clone(),
+        rpc_client,
+        load_balancer,
+        registry,
+        results,
+        models: Arc::new(RwLock::new(HashMap::new())),
+        checkpoints,
+        checkpoint_requests: CheckpointRequests::default(),
+    };
+
+    // Listen for control messages addressed to this node, and for models broadcast by the principal
+    let control_queue = routing::node_control_queue(node_id);
+    let control_dead_letters = routing::declare_node_queue(
+        transport.as_ref(),
+        &control_queue,
+        &[routing::node_control_key(node_id), routing::model_update_key()],
+    )
+    .await?;
+    let control_consumer = transport.consume(&control_queue, "an_control_consumer").await?;
+    tokio::spawn(handle_control(node.clone(), control_consumer, control_dead_letters));
+
+    // Announce this node, so that the principal ships it the current model
+    let registration = NodeRegistration {
+        node_id: node_id.to_string(),
+        role: "an".to_string(),
+        capabilities: Vec::new(),
+        capacity: 1,
+        ops: Vec::new(),
+        address: transport.peer_address().map(|address| address.to_string()),
+    };
+    tokio::spawn(load_balancer::announce(transport.clone(), node_id, registration));
+
+    // Results that Ki nodes could not return to a waiting caller
+    let result_queue = routing::an_result_queue();
+    let result_dead_letters =
+        routing::declare_routed_queue(transport.as_ref(), &result_queue, &[routing::an_result_key()]).await?;
+    let result_consumer = transport.consume(&result_queue, "an_result_consumer").await?;
+    tokio::spawn(handle_results(node.clone(), result_consumer, result_dead_letters));
+
+    // Tasks addressed to this node specifically, e.g. by the scheduler
+    let node_queue = routing::node_task_queue(node_id);
+    let node_dead_letters =
+        routing::declare_node_queue(transport.as_ref(), &node_queue, &[routing::node_task_key(node_id)]).await?;
+    let node_consumer = transport.consume(&node_queue, "an_node_consumer").await?;
+    tokio::spawn(handle_tasks(node.clone(), node_consumer, node_dead_letters));
+
+    // Tasks from the principal, shared with every other An node
+    let queue_name = routing::an_task_queue();
+    let dead_letters = routing::declare_routed_queue(transport.as_ref(), &queue_name, &[routing::an_task_key()])
+        .await
+        .map_err(|e| {
             error!("Failed to declare queue: {:?}", e);
             e
         })?;
 
     // Start consuming tasks from the queue
-    let mut consumer = channel
-        .basic_consume(
-            queue_name,
-            "an_consumer",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await.map_err(|e| {
-            error!("Failed to start consuming: {:?}", e);
-            e
-        })?;
+    let consumer = transport.consume(&queue_name, "an_consumer").await.map_err(|e| {
+        error!("Failed to start consuming: {:?}", e);
+        e
+    })?;
 
-    info!("An node is running and waiting for tasks...");
+    info!("An node {} is running and waiting for tasks...", node_id);
+    handle_tasks(node, consumer, dead_letters).await;
 
-    while let Some(result) = consumer.next().await {
-        match result {
-            Ok((_, delivery)) => {
-                match serde_json::from_slice::<TaskMessage>(&delivery.data) {
-                    Ok(task_message) => {
-                        info!("Received task: {:?}", task_message);
+    Ok(())
+}
 
-                        // Process the task (distribute to Ki nodes or handle locally)
-                        if let Err(e) = process_task(task_message).await {
-                            error!("Failed to process task: {:?}", e);
-                        }
+async fn handle_tasks(node: AnNode, mut consumer: Consumer, dead_letters: DeadLetterPolicy) {
+    while let Some(delivery) = consumer.next().await {
+        match Envelope::<TaskMessage>::from_delivery(&delivery) {
+            Ok(envelope) => {
+                info!("Received task from {}: {:?}", envelope.sender, envelope.payload);
 
-                        // Acknowledge the message
-                        if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                            error!("Failed to acknowledge message: {:?}", e);
-                        }
+                // A training job runs for many steps, so it gets a task of its own and the tasks behind it
+                // are not held up
+                if envelope.payload.op == "model_train" {
+                    let (node, dead_letters) = (node.clone(), dead_letters.clone());
+                    tokio::spawn(async move { handle_task(&node, &dead_letters, delivery, envelope).await });
+                } else {
+                    handle_task(&node, &dead_letters, delivery, envelope).await;
+                }
+            }
+            Err(e) => {
+                error!("Rejecting task message: {}", e);
+                if let Err(e) = dead_letters.dead_letter(node.transport.as_ref(), &delivery, &e.to_string()).await {
+                    error!("Failed to dead-letter task message: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+async fn handle_task(node: &AnNode, dead_letters: &DeadLetterPolicy, delivery: Delivery, envelope: Envelope<TaskMessage>) {
+    let transport = node.transport.as_ref();
+
+    // Process the task (distribute to Ki nodes or handle locally)
+    let result = match process_task(node, envelope.payload.clone()).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Failed to process task: {:?}", e);
+            if let Err(e) = dead_letters.retry_or_dead_letter(transport, &delivery, &e.to_string()).await {
+                error!("Failed to requeue task message: {:?}", e);
+            }
+            return;
+        }
+    };
+
+    // Answer the sender if it is waiting for the result
+    if let Err(e) = rpc::respond(transport, node.node_id, &envelope, result).await {
+        error!("Failed to send result: {:?}", e);
+    }
+
+    // Acknowledge the message
+    if let Err(e) = delivery.ack().await {
+        error!("Failed to acknowledge message: {:?}", e);
+    }
+}
+
+async fn handle_results(node: AnNode, mut consumer: Consumer, dead_letters: DeadLetterPolicy) {
+    while let Some(delivery) = consumer.next().await {
+        match Envelope::<ResultMessage>::from_delivery(&delivery) {
+            Ok(envelope) => {
+                record_result(&node.results, envelope.sender, envelope.payload);
+                if let Err(e) = delivery.ack().await {
+                    error!("Failed to acknowledge message: {:?}", e);
+                }
+            }
+            Err(e) => {
+                error!("Rejecting result message: {}", e);
+                if let Err(e) = dead_letters.dead_letter(node.transport.as_ref(), &delivery, &e.to_string()).await {
+                    error!("Failed to dead-letter result message: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+// Records a result unless one for the same idempotency key was already recorded. Returns whether it was new.
+fn record_result(results: &DedupStore<ResultMessage>, sender: Uuid, result: ResultMessage) -> bool {
+    let key = if result.idempotency_key.is_empty() {
+        result.task_id.clone()
+    } else {
+        result.idempotency_key.clone()
+    };
+    match results.claim(&key) {
+        Claim::Claimed => {
+            info!("Ki node {} completed task {}: {:?}", sender, result.task_id, result.outputs);
+            results.complete(&key, result);
+            true
+        }
+        _ => {
+            info!("Dropping duplicate result for task {} from {}", result.task_id, sender);
+            false
+        }
+    }
+}
+
+// Handles role assignments and model updates sent to this node.
+async fn handle_control(node: AnNode, mut consumer: Consumer, dead_letters: DeadLetterPolicy) {
+    let transport = node.transport.clone();
+    while let Some(delivery) = consumer.next().await {
+        let handled = match EnvelopeHeader::peek(&delivery.data, &delivery.headers).map(|header| header.kind) {
+            Ok(MessageKind::RoleAssignment) => match Envelope::<RoleAssignment>::from_delivery(&delivery) {
+                Ok(envelope) => {
+                    info!("Received role assignment from {}: {:?}", envelope.sender, envelope.payload);
+                    let ack = RoleAck {
+                        node_id: envelope.payload.node_id.clone(),
+                        role: envelope.payload.role.clone(),
+                        accepted: envelope.payload.node_id == node.node_id.to_string(),
+                    };
+                    if let Err(e) = rpc::respond(transport.as_ref(), node.node_id, &envelope, ack).await {
+                        error!("Failed to acknowledge role assignment: {:?}", e);
                     }
-                    Err(e) => {
-                        error!("Failed to deserialize task message: {:?}", e);
-                        if let Err(e) = delivery.nack(BasicNackOptions::default()).await {
-                            error!("Failed to negatively acknowledge message: {:?}", e);
-                        }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Ok(MessageKind::ModelUpdate) => match Envelope::<ModelUpdate>::from_delivery(&delivery) {
+                Ok(envelope) => {
+                    info!(
+                        "Received model {} v{} from {}",
+                        envelope.payload.name, envelope.payload.version, envelope.sender
+                    );
+                    let ack = install_model(&node, envelope.payload.clone());
+                    if let Err(e) = rpc::respond(transport.as_ref(), node.node_id, &envelope, ack).await {
+                        error!("Failed to acknowledge model update: {:?}", e);
                     }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Ok(MessageKind::CheckpointRequest) => match Envelope::<CheckpointRequest>::from_delivery(&delivery) {
+                Ok(envelope) => {
+                    info!("Received checkpoint request for model {} from {}", envelope.payload.model, envelope.sender);
+                    handle_checkpoint_request(&node, envelope);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Ok(kind) => Err(EnvelopeError::Malformed(format!("unexpected {:?} message on the control queue", kind))),
+            Err(e) => Err(e),
+        };
+
+        match handled {
+            Ok(()) => {
+                if let Err(e) = delivery.ack().await {
+                    error!("Failed to acknowledge message: {:?}", e);
                 }
             }
             Err(e) => {
-                error!("Error in consumer: {:?}", e);
+                error!("Rejecting control message: {}", e);
+                if let Err(e) = dead_letters.dead_letter(transport.as_ref(), &delivery, &e.to_string()).await {
+                    error!("Failed to dead-letter control message: {:?}", e);
+                }
             }
         }
     }
+}
 
-    Ok(())
+// Answers an on-demand checkpoint request: a model being trained is checkpointed at the end of its current
+// step, and an installed model that is not being trained right away, without optimizer state.
+fn handle_checkpoint_request(node: &AnNode, envelope: Envelope<CheckpointRequest>) {
+    let model = envelope.payload.model.clone();
+    let pending = node.checkpoint_requests.request(&model);
+    // Waiting for a training step or for the disk must not hold up other control messages
+    let node = node.clone();
+    tokio::spawn(async move {
+        let ack = match (&node.checkpoints, pending) {
+            (None, _) => CheckpointAck::failed(&model, "checkpoints are not kept (set AN_CHECKPOINT_DIR)"),
+            (Some(_), Some(pending)) => pending
+                .await
+                .unwrap_or_else(|_| CheckpointAck::failed(&model, "training stopped before the checkpoint was taken")),
+            (Some(store), None) => {
+                let installed = node.models.read().unwrap().get(&model).cloned();
+                match installed {
+                    Some(installed) => {
+                        let hash = checkpoint::config_hash(&installed.spec, &kernels::Attrs::new());
+                        let state = TrainingState {
+                            parameters: installed.weights(),
+                            ..TrainingState::default()
+                        };
+                        save_in_background(store, installed.spec, hash, state).await
+                    }
+                    None => CheckpointAck::failed(&model, format!("model {} is not installed", model)),
+                }
+            }
+        };
+        if let Err(e) = rpc::respond(node.transport.as_ref(), node.node_id, &envelope, ack).await {
+            error!("Failed to answer checkpoint request: {:?}", e);
+        }
+    });
+}
+
+// Validates a shipped model and makes it available to `model_forward` tasks, unless a newer version
+// of it is already installed.
+fn install_model(node: &AnNode, update: ModelUpdate) -> ModelAck {
+    let (name, version) = (update.name.clone(), update.version);
+    let rejected = |error: String| {
+        error!("Rejecting model {} v{}: {}", name, version, error);
+        ModelAck {
+            name: name.clone(),
+            version,
+            accepted: false,
+            error: Some(error),
+        }
+    };
+
+    let model = match Model::from_update(update) {
+        Ok(model) => model,
+        Err(e) => return rejected(e.to_string()),
+    };
+    let mut models = node.models.write().unwrap();
+    if let Some(installed) = models.get(&name) {
+        if installed.spec.version > version {
+            return rejected(format!("version {} is already installed", installed.spec.version));
+        }
+    }
+    models.insert(name.clone(), model);
+    info!("Installed model {} v{}", name, version);
+    ModelAck {
+        name,
+        version,
+        accepted: true,
+        error: None,
+    }
+}
+
+// The installed model a `model_forward` task names (attribute `model`, and `version` if given), and
+// the batch to run it on.
+fn model_for(node: &AnNode, task: &TaskMessage) -> Result<(Model, Tensor), String> {
+    let name = kernels::str_attr(&task.attrs, "model")
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("{} tasks need a model attribute", task.op))?;
+    let models = node.models.read().unwrap();
+    let model = models.get(name).ok_or_else(|| format!("model {} is not installed", name))?;
+    // Tasks may pin a version, as the inference server does
+    if let Some(version) = kernels::int_attr(&task.attrs, "version").map_err(|e| e.to_string())? {
+        if model.spec.version != version as u64 {
+            return Err(format!("model {} v{} is not installed (v{} is)", name, version, model.spec.version));
+        }
+    }
+    let batch = task
+        .inputs
+        .first()
+        .cloned()
+        .ok_or_else(|| format!("{} tasks need an input batch", task.op))?;
+    Ok((model.clone(), batch))
+}
+
+// Runs `model_forward` tasks with the model they name, with the batch split across Ki nodes by rows or,
+// when the attribute `parallelism` is "pipeline", the model split across them by layers, and when it is
+// "tensor", every weight matrix split across them. `model_train` tasks train the model they name. Every
+// other task goes straight to `dispatch`.
+async fn execute(node: &AnNode, task: &TaskMessage) -> Result<ResultMessage, TransportError> {
+    match task.op.as_str() {
+        "model_forward" => {}
+        "model_train" => return train(node, task).await,
+        _ => return dispatch(node, task).await,
+    }
+    let (model, batch) = match model_for(node, task) {
+        Ok(found) => found,
+        Err(e) => return Ok(ResultMessage::failed(task, e)),
+    };
+    match kernels::str_attr(&task.attrs, "parallelism") {
+        Ok(Some("pipeline" | "tensor")) if !model.features.is_empty() => Ok(ResultMessage::failed(
+            task,
+            format!("model {} has layers other than dense ones; only its batch can be split", model.spec.name),
+        )),
+        Ok(None) | Ok(Some("data")) => {
+            let mut expanded = model.task(task.task_id.clone(), batch);
+            expanded.idempotency_key = task.idempotency_key.clone();
+            dispatch(node, &expanded).await
+        }
+        Ok(Some("pipeline")) => run_pipeline(node, task, &model.graph, batch).await,
+        Ok(Some("tensor")) => run_tensor_parallel(node, task, &model.graph, batch).await,
+        Ok(Some(other)) => Ok(ResultMessage::failed(task, format!("unknown parallelism {:?}", other))),
+        Err(e) => Ok(ResultMessage::failed(task, e.to_string())),
+    }
+}
+
+// Why work spread over Ki nodes failed: transport errors are retried, kernel errors are reported.
+enum RemoteError {
+    Transport(TransportError),
+    Kernel(String),
+}
+
+impl From<kernels::KernelError> for RemoteError {
+    fn from(e: kernels::KernelError) -> Self {
+        RemoteError::Kernel(e.to_string())
+    }
+}
+
+impl From<BarrierTimeout> for RemoteError {
+    fn from(e: BarrierTimeout) -> Self {
+        RemoteError::Transport(Box::new(e))
+    }
 }
 
-async fn process_task(task: TaskMessage) -> Result<(), Box<dyn Error>> {
-    // Placeholder for task processing logic
-    // This is where you would distribute tasks to Ki nodes or handle them locally
+// Sends a single-output task to one Ki node and waits for its output.
+async fn call_node(node: &AnNode, node_id: Uuid, task: TaskMessage) -> Result<Tensor, RemoteError> {
+    let task_id = task.task_id.clone();
+    call_node_outputs(node, node_id, task)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| RemoteError::Kernel(format!("{} returned no outputs", task_id)))
+}
+
+// Sends a task to one Ki node and waits for its outputs.
+async fn call_node_outputs(node: &AnNode, node_id: Uuid, task: TaskMessage) -> Result<Vec<Tensor>, RemoteError> {
+    let task_id = task.task_id.clone();
+    let result: Envelope<ResultMessage> = node
+        .rpc_client
+        .call(&routing::node_task_key(node_id), task, DEFAULT_RPC_TIMEOUT)
+        .await
+        .map_err(|e| RemoteError::Transport(e.into()))?;
+    match result.payload.error {
+        Some(e) => Err(RemoteError::Kernel(format!("{}: {}", task_id, e))),
+        None => Ok(result.payload.outputs),
+    }
+}
+
+// Runs a network as a pipeline over the Ki nodes that run `mlp_forward`: each gets a contiguous range
+// of layers sized by its capacity, and the batch flows through them in micro-batches (attribute
+// `micro_batches`). Activations pass through this node between stages.
+async fn run_pipeline(node: &AnNode, task: &TaskMessage, mlp: &Mlp, batch: Tensor) -> Result<ResultMessage, TransportError> {
+    let stages = pipeline::partition(
+        &pipeline::layer_costs(mlp),
+        &node.load_balancer.capacities_for("mlp_forward"),
+    );
+    if stages.is_empty() {
+        return Err("No Ki nodes run mlp_forward".into());
+    }
+    let micro_batches = match kernels::int_attr(&task.attrs, "micro_batches") {
+        Ok(count) => count.map_or(pipeline::DEFAULT_MICRO_BATCHES, |count| count.max(1) as usize),
+        Err(e) => return Ok(ResultMessage::failed(task, e.to_string())),
+    };
+    let micro_batches = match batch.split_rows(micro_batches) {
+        Ok(micro_batches) => micro_batches,
+        Err(e) => return Ok(ResultMessage::failed(task, e.to_string())),
+    };
+    info!("Running task {} as a pipeline of {} stages: {:?}", task.task_id, stages.len(), stages);
+
+    let networks: Vec<Mlp> = stages.iter().map(|stage| pipeline::stage_network(mlp, stage)).collect();
+    let outcome = pipeline::run(&stages, micro_batches, |stage, index, activations| {
+        // Keys derived from the task's, so a redelivered task recomputes nothing the Ki nodes remember
+        let mut stage_task = networks[stage].task(format!("{}#stage{}#{}", task.task_id, stage, index), activations);
+        stage_task.idempotency_key = format!("{}#stage{}#{}", task.dedup_key(), stage, index);
+        call_node(node, stages[stage].node_id, stage_task)
+    })
+    .await;
+
+    match outcome {
+        Ok((outputs, report)) => {
+            report.log(&task.task_id);
+            Ok(ResultMessage::for_task(task, vec![Tensor::concat_rows(&outputs)?]))
+        }
+        Err(RemoteError::Kernel(e)) => Ok(ResultMessage::failed(task, e)),
+        Err(RemoteError::Transport(e)) => Err(e),
+    }
+}
+
+// Runs a network with every layer's weight matrix sharded across the Ki nodes that run `matmul`, by
+// columns or by rows (attribute `shard_axis`, columns by default). Shard `i` of every layer always goes
+// to the same node; this node applies the bias and activation to the combined product.
+async fn run_tensor_parallel(
+    node: &AnNode,
+    task: &TaskMessage,
+    mlp: &Mlp,
+    batch: Tensor,
+) -> Result<ResultMessage, TransportError> {
+    let mut nodes: Vec<Uuid> = node
+        .load_balancer
+        .capacities_for("matmul")
+        .into_iter()
+        .map(|(node_id, _)| node_id)
+        .collect();
+    if nodes.is_empty() {
+        return Err("No Ki nodes run matmul".into());
+    }
+    nodes.sort();
+    let axis = match kernels::str_attr(&task.attrs, "shard_axis") {
+        Ok(axis) => match axis.map_or(Ok(ShardAxis::Columns), ShardAxis::parse) {
+            Ok(axis) => axis,
+            Err(e) => return Ok(ResultMessage::failed(task, e.to_string())),
+        },
+        Err(e) => return Ok(ResultMessage::failed(task, e.to_string())),
+    };
+    info!(
+        "Running task {} with weights sharded by {} across {} Ki nodes",
+        task.task_id,
+        axis.name(),
+        nodes.len()
+    );
+
+    let mut activations = batch;
+    for (index, layer) in mlp.layers.iter().enumerate() {
+        let outcome = async {
+            let sharded = ShardedMatmul::new(&layer.weights, axis, nodes.len())?;
+            let product = sharded
+                .run(&activations, |shard, inputs| {
+                    let mut shard_task =
+                        TaskMessage::new(format!("{}#layer{}#shard{}", task.task_id, index, shard), "matmul", inputs);
+                    shard_task.idempotency_key = format!("{}#layer{}#shard{}", task.dedup_key(), index, shard);
+                    call_node(node, nodes[shard], shard_task)
+                })
+                .await?;
+            Ok(layer.activation.apply(&kernels::add_bias(&product, &layer.bias)?)?)
+        };
+        activations = match outcome.await {
+            Ok(activations) => activations,
+            Err(RemoteError::Kernel(e)) => return Ok(ResultMessage::failed(task, format!("layer {}: {}", index, e))),
+            Err(RemoteError::Transport(e)) => return Err(e),
+        };
+    }
+    Ok(ResultMessage::for_task(task, vec![activations]))
+}
+
+// Trains the model a `model_train` task names on its inputs (first input) and targets (second input),
+// data-parallel. Each step splits a mini-batch across the Ki nodes that run `mlp_gradients` and sends
+// each its shard with the current weights; once the barrier closes, the parameter server averages the
+// gradients they pushed back and updates the weights. The job is checkpointed every `checkpoint_every`
+// steps, on request and when it finishes. The trained model is installed as the next version, and the
+// result holds the loss of every step.
+async fn train(node: &AnNode, task: &TaskMessage) -> Result<ResultMessage, TransportError> {
+    let (model, state) = match starting_point(node, task) {
+        Ok(found) => found,
+        Err(e) => return Ok(ResultMessage::failed(task, e)),
+    };
+    if !model.features.is_empty() {
+        return Ok(ResultMessage::failed(
+            task,
+            format!("model {} has layers other than dense ones; only MLPs are trained", model.spec.name),
+        ));
+    }
+    let parsed = TrainingConfig::from_attrs(&task.attrs)
+        .and_then(|config| Ok((config, TrainingData::from_task(task)?)));
+    let (config, data) = match parsed {
+        Ok(found) => found,
+        Err(e) => return Ok(ResultMessage::failed(task, e.to_string())),
+    };
+    let workers = training_workers(node, data.op());
+    if workers.is_empty() {
+        return Err(format!("No Ki nodes run {}", data.op()).into());
+    }
+    info!(
+        "Training model {} for {} steps across {} Ki nodes ({}, {})",
+        model.spec.name,
+        config.steps,
+        workers.len(),
+        config.optimizer.name(),
+        config.sync.name()
+    );
+
+    let names: Vec<String> = model.spec.parameter_shapes().into_iter().map(|(name, _)| name).collect();
+    let mut server =
+        ParameterServer::new(state.parameters, config.optimizer, config.schedule).with_state(state.optimizer);
+    let mut graph = model.graph.clone();
+    let mut losses = state.losses;
+    let job = node.checkpoint_requests.track(&model.spec.name);
+    let hash = checkpoint::config_hash(&model.spec, &task.attrs);
+    let mut workers_before = workers;
+    for step in losses.len()..config.steps {
+        let outcome = async {
+            let pushes = match config.sync {
+                GradientSync::ParameterServer => {
+                    let workers = training_workers(node, data.op());
+                    if workers.is_empty() {
+                        return Err(RemoteError::Transport(format!("No Ki nodes left that run {}", data.op()).into()));
+                    }
+                    if workers != workers_before {
+                        info!("Re-balancing the shards of model {} across {} Ki nodes", model.spec.name, workers.len());
+                        workers_before = workers.clone();
+                    }
+                    let shard_tasks = data.shard_tasks(&config, step, &graph, workers.len(), |shard| {
+                        format!("{}#step{}#{}", task.task_id, step, shard)
+                    })?;
+                    training::round(&config.barrier, shard_tasks.len(), |shard| {
+                        let (mut shard_task, rows) = shard_tasks[shard].clone();
+                        shard_task.idempotency_key = format!("{}#step{}#{}", task.dedup_key(), step, shard);
+                        let (names, worker) = (&names, workers.members()[shard]);
+                        async move {
+                            let outputs = call_node_outputs(node, worker, shard_task).await?;
+                            Ok::<_, RemoteError>(GradientPush::from_outputs(rows, names, outputs)?)
+                        }
+                    })
+                    .await?
+                }
+                GradientSync::AllReduce => {
+                    vec![allreduce_gradients(node, task, step, &graph, &config, &names, &data).await?]
+                }
+            };
+            let loss = server.apply(&pushes)?;
+            let updated = Model::load(model.spec.clone(), Some(server.parameters().clone()))
+                .map_err(|e| RemoteError::Kernel(e.to_string()))?;
+            Ok::<_, RemoteError>((loss, updated.graph))
+        };
+        match outcome.await {
+            Ok((loss, updated)) => {
+                logging_metrics::log_training_step(&model.spec.name, server.step(), loss);
+                losses.push(loss);
+                graph = updated;
+
+                let requests = job.take();
+                let due = config.checkpoint_every.is_some_and(|every| losses.len() % every == 0);
+                if due || !requests.is_empty() || losses.len() == config.steps {
+                    save_checkpoint(node, &model.spec, &hash, &server, &losses, requests).await;
+                }
+            }
+            Err(RemoteError::Kernel(e)) => return Ok(ResultMessage::failed(task, format!("step {}: {}", step, e))),
+            Err(RemoteError::Transport(e)) => return Err(e),
+        }
+    }
+
+    let mut spec = model.spec.clone();
+    spec.version += 1;
+    let trained = match Model::load(spec, Some(server.parameters().clone())) {
+        Ok(trained) => trained,
+        Err(e) => return Ok(ResultMessage::failed(task, e.to_string())),
+    };
+    let ack = install_model(node, trained.to_update());
+    if let Some(e) = ack.error {
+        return Ok(ResultMessage::failed(task, e));
+    }
+    Ok(ResultMessage::for_task(task, vec![Tensor::new(vec![losses.len()], losses)?]))
+}
+
+// Where a training job starts: from the newest valid checkpoint of the same job when this node keeps
+// checkpoints, so that a job sent again after a restart carries on even before its model is shipped
+// again; otherwise from the installed model.
+fn starting_point(node: &AnNode, task: &TaskMessage) -> Result<(Model, TrainingState), String> {
+    let name = kernels::str_attr(&task.attrs, "model")
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("{} tasks need a model attribute", task.op))?;
+    let installed = node.models.read().unwrap().get(name).cloned();
+    if let Some(store) = &node.checkpoints {
+        let resumed = store
+            .latest(name, |metadata| {
+                installed.as_ref().is_none_or(|model| model.spec == metadata.spec)
+                    && metadata.config_hash == checkpoint::config_hash(&metadata.spec, &task.attrs)
+            })
+            .map_err(|e| e.to_string())?;
+        if let Some(resumed) = resumed {
+            info!(
+                "Resuming training of model {} from checkpoint {} at step {}",
+                name, resumed.metadata.version, resumed.metadata.step
+            );
+            let model = Model::load(resumed.metadata.spec, Some(resumed.state.parameters.clone()))
+                .map_err(|e| e.to_string())?;
+            return Ok((model, resumed.state));
+        }
+    }
+    let model = installed.ok_or_else(|| format!("model {} is not installed", name))?;
+    let state = TrainingState {
+        parameters: model.weights(),
+        ..TrainingState::default()
+    };
+    Ok((model, state))
+}
+
+// Saves a training job's state, when this node keeps checkpoints, and answers the requests waiting for it.
+// A failed save is logged and training carries on.
+async fn save_checkpoint(
+    node: &AnNode,
+    spec: &ModelSpec,
+    hash: &str,
+    server: &ParameterServer,
+    losses: &[f32],
+    requests: Vec<oneshot::Sender<CheckpointAck>>,
+) {
+    let Some(store) = &node.checkpoints else {
+        return;
+    };
+    let state = TrainingState {
+        parameters: server.parameters().clone(),
+        optimizer: server.state().clone(),
+        losses: losses.to_vec(),
+    };
+    let ack = save_in_background(store, spec.clone(), hash.to_string(), state).await;
+    for reply in requests {
+        let _ = reply.send(ack.clone());
+    }
+}
+
+// Saves a checkpoint on the blocking pool, since writing and syncing its files can stall for a while.
+async fn save_in_background(store: &CheckpointStore, spec: ModelSpec, hash: String, state: TrainingState) -> CheckpointAck {
+    let store = store.clone();
+    let name = spec.name.clone();
+    let saved = tokio::task::spawn_blocking(move || store.save(&spec, &hash, &state)).await;
+    match saved {
+        Ok(Ok(metadata)) => CheckpointAck::from(&metadata),
+        Ok(Err(e)) => {
+            error!("Failed to checkpoint model {}: {}", name, e);
+            CheckpointAck::failed(&name, e.to_string())
+        }
+        Err(e) => {
+            error!("Checkpointing model {} panicked: {:?}", name, e);
+            CheckpointAck::failed(&name, format!("checkpoint panicked: {}", e))
+        }
+    }
+}
+
+// The Ki nodes a training step is shared among: those that run `op` and that the registry still lists.
+// A Ki node that stops announcing itself drops out, and the data is re-sharded across the others.
+fn training_workers(node: &AnNode, op: &str) -> Ring {
+    let eligible: Vec<Uuid> = node.load_balancer.capacities_for(op).into_iter().map(|(node_id, _)| node_id).collect();
+    Ring::form(&node.registry, RING_MAX_AGE, &eligible)
+}
+
+// Computes a training step's gradients on a ring of the Ki nodes that run the data's gradient op and have
+// announced themselves recently. Each member gets a shard of the mini-batch and the members sum their
+// gradients, scaled by their share of the rows, among themselves; only the first reports the sum. Members
+// that do not answer in time are taken to have left: they are dropped from the registry and the load
+// balancer, and the step is rerun on the ring re-formed without them.
+async fn allreduce_gradients(
+    node: &AnNode,
+    task: &TaskMessage,
+    step: usize,
+    graph: &Mlp,
+    config: &TrainingConfig,
+    names: &[String],
+    data: &TrainingData,
+) -> Result<GradientPush, RemoteError> {
+    let mut ring = training_workers(node, data.op());
+
+    for attempt in 0..MAX_RING_ATTEMPTS {
+        if ring.is_empty() {
+            return Err(RemoteError::Transport("No Ki nodes left to form an all-reduce ring".into()));
+        }
+        let shard_tasks = data.shard_tasks(config, step, graph, ring.len(), |shard| {
+            format!("{}#step{}#ring{}#{}", task.task_id, step, attempt, shard)
+        })?;
+        let total: usize = shard_tasks.iter().map(|(_, rows)| rows).sum();
+        // A batch with fewer rows than the ring has members only needs some of them
+        let active = Ring::new(ring.members()[..shard_tasks.len()].to_vec());
+        let collective = format!("{}#step{}#ring{}", task.dedup_key(), step, attempt);
+        let members: Vec<String> = active.members().iter().map(Uuid::to_string).collect();
+        // Lets members that take peer connections send their chunks to each other directly
+        let addresses: Vec<String> = active
+            .members()
+            .iter()
+            .map(|member| node.registry.get_node_info(member).and_then(|info| info.address))
+            .map(|address| address.map(|address| address.to_string()).unwrap_or_default())
+            .collect();
+        // Members give up on a missing chunk before this node gives up on them, so that the survivors
+        // report the failure rather than time out alongside the member that left
+        let collective_timeout = (config.barrier.timeout.as_millis() / 2).max(1) as i64;
+
+        let calls = active.members().iter().zip(shard_tasks).enumerate().map(|(shard, (&member, (shard_task, rows)))| {
+            let mut shard_task = shard_task
+                .with_attr("ring", members.clone())
+                .with_attr("collective", collective.as_str())
+                .with_attr("allreduce_scale", rows as f64 / total as f64)
+                .with_attr("collective_timeout_ms", collective_timeout);
+            if addresses.iter().any(|address| !address.is_empty()) {
+                shard_task = shard_task.with_attr("ring_addresses", addresses.clone());
+            }
+            shard_task.idempotency_key = format!("{}#{}", collective, shard);
+            async move {
+                let reply = tokio::time::timeout(config.barrier.timeout, call_node_outputs(node, member, shard_task)).await;
+                (member, reply)
+            }
+        });
+
+        let mut departed = Vec::new();
+        let mut failure = None;
+        let mut sums = None;
+        for (member, reply) in join_all(calls).await {
+            match reply {
+                Ok(Ok(outputs)) if active.position(member) == Some(0) => sums = Some(outputs),
+                Ok(Ok(_)) => {}
+                Ok(Err(RemoteError::Kernel(e))) => failure = failure.or(Some(e)),
+                Ok(Err(RemoteError::Transport(e))) => {
+                    error!("Ki node {} dropped out of all-reduce {}: {}", member, collective, e);
+                    departed.push(member);
+                }
+                Err(_) => {
+                    error!("Ki node {} timed out in all-reduce {}", member, collective);
+                    departed.push(member);
+                }
+            }
+        }
+
+        if departed.is_empty() {
+            return match (failure, sums) {
+                (Some(e), _) => Err(RemoteError::Kernel(e)),
+                (None, Some(outputs)) => Ok(GradientPush::from_outputs(total, names, outputs)?),
+                (None, None) => Err(RemoteError::Kernel(format!("all-reduce {} returned no gradients", collective))),
+            };
+        }
+        for member in &departed {
+            node.registry.remove_node(member);
+            node.load_balancer.remove_node(member);
+        }
+        ring = ring.without(&departed);
+        info!("Re-formed the all-reduce ring for step {} with {} Ki nodes", step, ring.len());
+    }
+    Err(RemoteError::Transport(
+        format!("Ki nodes kept leaving the all-reduce ring for step {}", step).into(),
+    ))
+}
+
+// Runs a task on the Ki nodes and records its result, or returns the result recorded for an earlier delivery.
+async fn process_task(node: &AnNode, task: TaskMessage) -> Result<ResultMessage, TransportError> {
     info!("Processing task with ID: {}", task.task_id);
 
-    // For now, we just log that the task is processed
-    Ok(())
+    // A redelivered task whose result was already recorded must not be counted again
+    let key = task.dedup_key().to_string();
+    loop {
+        match node.results.claim(&key) {
+            Claim::Claimed => break,
+            Claim::Completed(Some(result)) => {
+                info!("Task {} already has a result, skipping", task.task_id);
+                return Ok(result);
+            }
+            Claim::Completed(None) => {
+                info!("Task {} was completed before a restart, skipping", task.task_id);
+                return Ok(ResultMessage::failed(&task, "completed before a restart; its result was not kept"));
+            }
+            Claim::Running(mut finished) => {
+                let _ = finished.changed().await;
+            }
+        }
+    }
+
+    let result = match execute(node, &task).await {
+        Ok(result) => result,
+        Err(e) => {
+            node.results.release(&key);
+            return Err(e);
+        }
+    };
+    match &result.error {
+        Some(e) => error!("Task {} failed: {}", task.task_id, e),
+        None => info!("Task {} completed: {:?}", task.task_id, result.outputs),
+    }
+    node.results.complete(&key, result.clone());
+    Ok(result)
+}
+
+// Splits a batch across every Ki node that runs the task's op and concatenates their outputs in
+// batch order. Tasks whose op does not work row by row go to a single Ki node.
+async fn dispatch(node: &AnNode, task: &TaskMessage) -> Result<ResultMessage, TransportError> {
+    let shards = shard_task(task, node.load_balancer.nodes_for(&task.op));
+    if shards.len() == 1 {
+        return call_ki(node, task.clone()).await;
+    }
+
+    info!("Splitting task {} into {} shards", task.task_id, shards.len());
+    let results = join_all(shards.into_iter().map(|shard| call_ki(node, shard))).await;
+    let results = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    if let Some((index, error)) = results
+        .iter()
+        .enumerate()
+        .find_map(|(index, result)| result.error.as_ref().map(|error| (index, error)))
+    {
+        return Ok(ResultMessage::failed(task, format!("shard {}: {}", index, error)));
+    }
+
+    let output_count = results[0].outputs.len();
+    let mut outputs = Vec::with_capacity(output_count);
+    for output in 0..output_count {
+        let parts: Vec<Tensor> = results
+            .iter()
+            .map(|result| result.outputs.get(output).cloned())
+            .collect::<Option<_>>()
+            .ok_or("Ki nodes returned different numbers of outputs")?;
+        outputs.push(Tensor::concat_rows(&parts)?);
+    }
+    Ok(ResultMessage::for_task(task, outputs))
+}
+
+// One task per part of the first input's batch, each with an idempotency key derived from the
+// task's, so redelivering the task redelivers the same shards.
+fn shard_task(task: &TaskMessage, parts: usize) -> Vec<TaskMessage> {
+    let splittable = kernels::splits_along_batch(&task.op)
+        && task.inputs.first().is_some_and(|batch| batch.rank() >= 2);
+    if parts < 2 || !splittable {
+        return vec![task.clone()];
+    }
+    let Ok(batches) = task.inputs[0].split_rows(parts) else {
+        return vec![task.clone()];
+    };
+    batches
+        .into_iter()
+        .enumerate()
+        .map(|(index, batch)| {
+            let mut shard = task.clone();
+            shard.task_id = format!("{}#{}", task.task_id, index);
+            shard.idempotency_key = format!("{}#{}", task.dedup_key(), index);
+            shard.inputs[0] = batch;
+            shard
+        })
+        .collect()
+}
+
+// Sends a task to the least loaded known Ki node that runs its op, or to any Ki node offering the
+// capability while none have announced that op yet, and waits for its result.
+async fn call_ki(node: &AnNode, task: TaskMessage) -> Result<ResultMessage, TransportError> {
+    let assigned = node.load_balancer.assign_task_for(&task.op);
+    let routing_key = match assigned {
+        Some(ki_node) => routing::node_task_key(ki_node),
+        None => routing::ki_task_key(routing::DEFAULT_CAPABILITY),
+    };
+
+    let result: Result<Envelope<ResultMessage>, _> = node.rpc_client.call(&routing_key, task, DEFAULT_RPC_TIMEOUT).await;
+    if let Some(ki_node) = assigned {
+        node.load_balancer.complete_task(&ki_node);
+    }
+    let result = result?;
+    match &result.payload.error {
+        Some(e) => error!("Ki node {} failed task {}: {}", result.sender, result.payload.task_id, e),
+        None => info!(
+            "Ki node {} completed task {}: {:?}",
+            result.sender, result.payload.task_id, result.payload.outputs
+        ),
+    }
+    Ok(result.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::{Activation, KernelRegistry};
+    use crate::ki_node::{self, KiConfig};
+    use crate::messaging::InMemoryBroker;
+    use crate::mlp::DenseLayer;
+    use crate::model::ModelSpec;
+    use crate::principal;
+    use crate::tensor::DType;
+    use crate::test_support::tensor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn an_node(broker: &Arc<dyn Transport>, (load_balancer, registry): (LoadBalancer, NodeRegistry)) -> AnNode {
+        AnNode {
+            node_id: Uuid::new_v4(),
+            transport: broker.clone(),
+            rpc_client: RpcClient::new(Uuid::new_v4(), broker.clone()).await.unwrap(),
+            load_balancer,
+            registry,
+            results: DedupStore::new(DEFAULT_DEDUP_CAPACITY),
+            models: Arc::new(RwLock::new(HashMap::new())),
+            checkpoints: None,
+            checkpoint_requests: CheckpointRequests::default(),
+        }
+    }
+
+    // Starts Ki nodes with the given capacities and waits until the returned load balancer knows them all.
+    async fn spawn_ki_nodes(broker: &Arc<dyn Transport>, capacities: &[usize]) -> (LoadBalancer, NodeRegistry) {
+        let load_balancer = LoadBalancer::new();
+        let registry = NodeRegistry::new();
+        load_balancer::spawn_registration_listener(
+            broker.clone(),
+            load_balancer.clone(),
+            registry.clone(),
+            Uuid::new_v4(),
+            "ki",
+        )
+        .await
+        .unwrap();
+        for &capacity in capacities {
+            let config = KiConfig {
+                capabilities: Vec::new(),
+                concurrency: capacity,
+                prefetch: capacity as u16,
+                dedup_capacity: DEFAULT_DEDUP_CAPACITY,
+                dedup_path: None,
+                kernels: KernelRegistry::builtin(),
+            };
+            tokio::spawn(ki_node::run_node(Uuid::new_v4(), config, broker.clone()));
+        }
+        for _ in 0..50 {
+            if load_balancer.nodes_for("mlp_forward") == capacities.len() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(load_balancer.nodes_for("mlp_forward"), capacities.len());
+        (load_balancer, registry)
+    }
+
+    // Deterministic, varied weights, so that rows or layers coming back out of order would show
+    fn dense(inputs: usize, outputs: usize, activation: Activation, seed: f32) -> DenseLayer {
+        DenseLayer::new(tensor(vec![inputs, outputs], seed), tensor(vec![outputs], seed + 0.5), activation).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_task_is_dispatched_once() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let capability_queue = routing::capability_queue(routing::DEFAULT_CAPABILITY);
+        routing::declare_routed_queue(
+            broker.as_ref(),
+            &capability_queue,
+            &[routing::ki_task_key(routing::DEFAULT_CAPABILITY)],
+        )
+        .await
+        .unwrap();
+
+        // A Ki stand-in that counts how often it is asked to compute
+        let computed = Arc::new(AtomicUsize::new(0));
+        let mut consumer = broker.consume(&capability_queue, "counting_ki").await.unwrap();
+        let (ki_transport, ki_computed) = (broker.clone(), computed.clone());
+        tokio::spawn(async move {
+            while let Some(delivery) = consumer.next().await {
+                ki_computed.fetch_add(1, Ordering::SeqCst);
+                let request = Envelope::<TaskMessage>::from_delivery(&delivery).unwrap();
+                let result = ResultMessage::for_task(&request.payload, vec![Tensor::scalar(0.5f32)]);
+                rpc::respond(ki_transport.as_ref(), Uuid::new_v4(), &request, result).await.unwrap();
+                delivery.ack().await.unwrap();
+            }
+        });
+
+        let node = an_node(&broker, (LoadBalancer::new(), NodeRegistry::new())).await;
+        let task = TaskMessage::new("step-1", "dense_forward", vec![Tensor::zeros(DType::F32, vec![4, 8])]);
+        process_task(&node, task.clone()).await.unwrap();
+        process_task(&node, task.clone()).await.unwrap();
+
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+        let duplicate = ResultMessage::for_task(&task, vec![Tensor::scalar(0.5f32)]);
+        assert!(!record_result(&node.results, Uuid::new_v4(), duplicate));
+    }
+
+    #[tokio::test]
+    async fn test_mlp_batch_is_split_across_ki_nodes() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, spawn_ki_nodes(&broker, &[1, 1, 1]).await).await;
+
+        let mlp = Mlp::new(vec![
+            dense(4, 8, Activation::Relu, 1.0),
+            dense(8, 6, Activation::Sigmoid, 3.0),
+            dense(6, 3, Activation::Softmax, 5.0),
+        ])
+        .unwrap();
+        let batch = tensor(vec![10, 4], 7.0);
+
+        let result = process_task(&node, mlp.task("infer-1", batch.clone())).await.unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.outputs, vec![mlp.forward(&batch).unwrap()]);
+        assert_eq!(result.outputs[0].shape(), &[10, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_principal_ships_model_to_an_node() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, (LoadBalancer::new(), NodeRegistry::new())).await;
+        let control_queue = routing::node_control_queue(node.node_id);
+        let dead_letters = routing::declare_routed_queue(
+            broker.as_ref(),
+            &control_queue,
+            &[routing::node_control_key(node.node_id), routing::model_update_key()],
+        )
+        .await
+        .unwrap();
+        let consumer = broker.consume(&control_queue, "an_control_consumer").await.unwrap();
+        tokio::spawn(handle_control(node.clone(), consumer, dead_letters));
+
+        let spec = ModelSpec::from_json(
+            r#"{"name": "xor", "version": 2, "inputs": 2, "layers": [
+                {"type": "dense", "name": "hidden", "units": 4, "activation": "tanh"},
+                {"type": "dense", "name": "output", "units": 1, "activation": "sigmoid"}]}"#,
+        )
+        .unwrap();
+        let model = Model::load(spec.clone(), None).unwrap();
+        let principal = RpcClient::new(Uuid::new_v4(), broker.clone()).await.unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let ack = principal::ship_model(&principal, node.node_id, &model, timeout).await.unwrap();
+        assert!(ack.accepted, "{:?}", ack.error);
+
+        let batch = Tensor::new(vec![2, 2], vec![0.0f32, 1.0, 1.0, 1.0]).unwrap();
+        let task = TaskMessage::new("infer-1", "model_forward", vec![batch.clone()]).with_attr("model", "xor");
+        let (installed, input) = model_for(&node, &task).unwrap();
+        assert_eq!(installed.weights(), model.weights());
+        assert_eq!(input, batch);
+        let pinned = task.clone().with_attr("version", spec.version as i64 + 1);
+        assert!(model_for(&node, &pinned).is_err());
+
+        // Older versions and updates whose weights do not fit the spec are refused
+        let mut older = spec;
+        older.version = 1;
+        let ack = principal::ship_model(&principal, node.node_id, &Model::load(older, None).unwrap(), timeout)
+            .await
+            .unwrap();
+        assert!(!ack.accepted);
+        let mut broken = model.to_update();
+        broken.weights.remove("output.bias");
+        assert!(!install_model(&node, broken).accepted);
+
+        let unknown = process_task(&node, task.with_attr("model", "mnist")).await.unwrap();
+        assert!(unknown.error.unwrap().contains("not installed"));
+    }
+
+    #[tokio::test]
+    async fn test_principal_ships_model_to_an_node_when_it_announces_itself() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, (LoadBalancer::new(), NodeRegistry::new())).await;
+        let control_queue = routing::node_control_queue(node.node_id);
+        let dead_letters =
+            routing::declare_node_queue(broker.as_ref(), &control_queue, &[routing::node_control_key(node.node_id)])
+                .await
+                .unwrap();
+        let consumer = broker.consume(&control_queue, "an_control_consumer").await.unwrap();
+        tokio::spawn(handle_control(node.clone(), consumer, dead_letters));
+
+        let spec = ModelSpec::from_json(
+            r#"{"name": "late", "version": 1, "inputs": 2, "layers": [
+                {"type": "dense", "name": "output", "units": 1, "activation": "sigmoid"}]}"#,
+        )
+        .unwrap();
+        let model = Model::load(spec, None).unwrap();
+        let principal = RpcClient::new(Uuid::new_v4(), broker.clone()).await.unwrap();
+        principal::spawn_model_shipper(broker.clone(), principal, Arc::new(model.clone()), Uuid::new_v4())
+            .await
+            .unwrap();
+
+        // The node started after the model was broadcast, so it only gets it by announcing itself
+        let registration = NodeRegistration {
+            node_id: node.node_id.to_string(),
+            role: "an".to_string(),
+            capabilities: Vec::new(),
+            capacity: 1,
+            ops: Vec::new(),
+            address: None,
+        };
+        tokio::spawn(load_balancer::announce(broker.clone(), node.node_id, registration));
+        for _ in 0..100 {
+            if node.models.read().unwrap().contains_key("late") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(node.models.read().unwrap()["late"].weights(), model.weights());
+    }
+
+    #[tokio::test]
+    async fn test_model_runs_as_pipeline_across_ki_nodes() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, spawn_ki_nodes(&broker, &[2, 1, 1]).await).await;
+
+        let spec = ModelSpec::from_json(
+            r#"{"name": "deep", "inputs": 6, "seed": 3, "layers": [
+                {"type": "dense", "name": "l0", "units": 8, "activation": "relu"},
+                {"type": "dense", "name": "l1", "units": 8, "activation": "tanh"},
+                {"type": "dense", "name": "l2", "units": 8, "activation": "relu"},
+                {"type": "dense", "name": "l3", "units": 8, "activation": "sigmoid"},
+                {"type": "dense", "name": "l4", "units": 4, "activation": "softmax"}]}"#,
+        )
+        .unwrap();
+        let model = Model::load(spec, None).unwrap();
+        install_model(&node, model.to_update());
+
+        let batch = tensor(vec![12, 6], 2.0);
+        let task = TaskMessage::new("infer-1", "model_forward", vec![batch.clone()])
+            .with_attr("model", "deep")
+            .with_attr("parallelism", "pipeline")
+            .with_attr("micro_batches", 4i64);
+        let result = process_task(&node, task).await.unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.outputs, vec![model.forward(&batch).unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_model_trains_data_parallel_across_ki_nodes() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, spawn_ki_nodes(&broker, &[1, 1]).await).await;
+        let spec = ModelSpec::from_json(
+            r#"{"name": "linear", "inputs": 3, "seed": 5, "layers": [
+                {"type": "dense", "name": "hidden", "units": 4, "activation": "tanh"},
+                {"type": "dense", "name": "out", "units": 1}]}"#,
+        )
+        .unwrap();
+        install_model(&node, Model::load(spec, None).unwrap().to_update());
+
+        // Targets a linear function of the inputs
+        let x = tensor(vec![16, 3], 1.0);
+        let targets: Vec<f32> = x
+            .to_vec::<f32>()
+            .unwrap()
+            .chunks(3)
+            .map(|row| 0.5 * row[0] - 0.3 * row[1] + 0.2 * row[2])
+            .collect();
+        let y = Tensor::new(vec![16, 1], targets).unwrap();
+        let task = TaskMessage::new("train-1", "model_train", vec![x, y])
+            .with_attr("model", "linear")
+            .with_attr("steps", 40i64)
+            .with_attr("batch_size", 8i64)
+            .with_attr("optimizer", "adam")
+            .with_attr("learning_rate", 0.05)
+            .with_attr("schedule", "cosine")
+            .with_attr("warmup_steps", 4i64);
+        let result = process_task(&node, task).await.unwrap();
+        assert_eq!(result.error, None);
+
+        let losses = result.outputs[0].to_vec::<f32>().unwrap();
+        assert_eq!(losses.len(), 40);
+        assert!(losses[38] < losses[0] / 2.0, "{:?}", losses);
+        assert_eq!(node.models.read().unwrap()["linear"].spec.version, 1);
+    }
+
+    #[tokio::test]
+    async fn test_training_resumes_from_the_latest_checkpoint_after_a_restart() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let ki_nodes = spawn_ki_nodes(&broker, &[1, 1]).await;
+        let dir = std::env::temp_dir().join(format!("an_checkpoint_test_{}", Uuid::new_v4()));
+        let mut node = an_node(&broker, ki_nodes.clone()).await;
+        node.checkpoints = Some(CheckpointStore::open(&dir).unwrap());
+        let spec = ModelSpec::from_json(
+            r#"{"name": "resumed", "inputs": 3, "seed": 4, "layers": [
+                {"type": "dense", "name": "out", "units": 2, "activation": "tanh"}]}"#,
+        )
+        .unwrap();
+        install_model(&node, Model::load(spec, None).unwrap().to_update());
+        let x = tensor(vec![8, 3], 6.0);
+        let y = tensor(vec![8, 2], 7.0);
+        let task = TaskMessage::new("train-resumed", "model_train", vec![x, y])
+            .with_attr("model", "resumed")
+            .with_attr("steps", 6i64)
+            .with_attr("batch_size", 4i64)
+            .with_attr("optimizer", "momentum")
+            .with_attr("learning_rate", 0.1)
+            .with_attr("checkpoint_every", 2i64);
+        let uninterrupted = process_task(&node, task.clone()).await.unwrap();
+        assert_eq!(uninterrupted.error, None);
+        let store = node.checkpoints.clone().unwrap();
+        assert_eq!(store.versions("resumed").unwrap(), vec![1, 2, 3]);
+        assert_eq!(store.metadata("resumed", 3).unwrap().step, 6);
+
+        // As if every node went down after step 4, and the last checkpoint never made it to disk
+        std::fs::remove_file(dir.join("resumed").join("00000003.json")).unwrap();
+        let mut restarted = an_node(&broker, ki_nodes).await;
+        restarted.checkpoints = Some(store.clone());
+        let resumed = process_task(&restarted, task).await.unwrap();
+        assert_eq!(resumed.error, None);
+        assert_eq!(resumed.outputs, uninterrupted.outputs);
+        assert_eq!(
+            restarted.models.read().unwrap()["resumed"].weights(),
+            node.models.read().unwrap()["resumed"].weights()
+        );
+        assert_eq!(store.metadata("resumed", 3).unwrap().step, 6);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_allreduce_training_matches_parameter_server_and_survives_a_ki_node_leaving() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, spawn_ki_nodes(&broker, &[1, 1, 1]).await).await;
+        let x = tensor(vec![12, 3], 2.0);
+        let y = tensor(vec![12, 2], 5.0).to_vec::<f32>().unwrap().iter().map(|v| v.abs()).collect();
+        let y = Tensor::new(vec![12, 2], y).unwrap();
+
+        let mut runs = Vec::new();
+        for sync in ["parameter_server", "allreduce"] {
+            if sync == "allreduce" {
+                // A Ki node that announced itself and then went away: the first ring waits for it in vain
+                let departed = Uuid::new_v4();
+                node.registry.heartbeat(departed, "ki");
+                node.load_balancer.register_node(departed, 1, vec!["mlp_gradients".to_string()]);
+            }
+            let spec = ModelSpec::from_json(&format!(
+                r#"{{"name": "{}", "inputs": 3, "seed": 9, "layers": [
+                    {{"type": "dense", "name": "out", "units": 2, "activation": "sigmoid"}}]}}"#,
+                sync
+            ))
+            .unwrap();
+            install_model(&node, Model::load(spec, None).unwrap().to_update());
+            let task = TaskMessage::new(format!("train-{}", sync), "model_train", vec![x.clone(), y.clone()])
+                .with_attr("model", sync)
+                .with_attr("steps", 6i64)
+                .with_attr("batch_size", 12i64)
+                .with_attr("learning_rate", 0.5)
+                .with_attr("sync", sync)
+                .with_attr("barrier_timeout_ms", 1000i64);
+            let result = process_task(&node, task).await.unwrap();
+            assert_eq!(result.error, None, "{}", sync);
+            runs.push(result.outputs[0].to_vec::<f32>().unwrap());
+        }
+
+        // The ring was re-formed from the three Ki nodes still running
+        assert_eq!(node.registry.active_nodes_with_role("ki", RING_MAX_AGE).len(), 3);
+        assert_eq!(node.load_balancer.nodes_for("mlp_gradients"), 3);
+        for (parameter_server, allreduce) in runs[0].iter().zip(&runs[1]) {
+            assert!((parameter_server - allreduce).abs() < 1e-5, "{:?}", runs);
+        }
+        assert!(runs[1][5] < runs[1][0], "{:?}", runs);
+    }
+
+    #[tokio::test]
+    async fn test_model_trains_on_dataset_shards_read_by_each_ki_node() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, spawn_ki_nodes(&broker, &[1, 1, 1]).await).await;
+        // A Ki node that left the registry but not yet the load balancer gets no shard; waiting for it
+        // would time the step out
+        let departed = Uuid::new_v4();
+        node.load_balancer.register_node(departed, 1, vec!["shard_gradients".to_string()]);
+
+        let dir = std::env::temp_dir().join(format!("an_dataset_test_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("linear.csv");
+        let mut csv = "x0,x1,y\n".to_string();
+        for row in tensor(vec![30, 2], 1.0).to_vec::<f32>().unwrap().chunks(2) {
+            csv += &format!("{},{},{}\n", row[0], row[1], 0.6 * row[0] - 0.4 * row[1]);
+        }
+        std::fs::write(&path, csv).unwrap();
+        let dataset = format!(
+            r#"{{"name": "linear", "source": {{"format": "csv", "path": {:?}, "header": true}}, "seed": 11}}"#,
+            path
+        );
+
+        let spec = ModelSpec::from_json(
+            r#"{"name": "from_disk", "inputs": 2, "seed": 2, "layers": [{"type": "dense", "name": "out", "units": 1}]}"#,
+        )
+        .unwrap();
+        install_model(&node, Model::load(spec, None).unwrap().to_update());
+        let task = TaskMessage::new("train-dataset", "model_train", Vec::new())
+            .with_attr("model", "from_disk")
+            .with_attr("dataset", dataset)
+            .with_attr("steps", 30i64)
+            .with_attr("batch_size", 4i64)
+            .with_attr("learning_rate", 0.3)
+            .with_attr("barrier_timeout_ms", 2000i64);
+        let result = process_task(&node, task).await.unwrap();
+        assert_eq!(result.error, None);
+
+        let losses = result.outputs[0].to_vec::<f32>().unwrap();
+        assert_eq!(losses.len(), 30);
+        assert!(losses[29] < losses[0] / 4.0, "{:?}", losses);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_model_runs_with_weights_sharded_across_ki_nodes() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node = an_node(&broker, spawn_ki_nodes(&broker, &[1, 1, 1]).await).await;
+        let graph = Mlp::new(vec![dense(6, 10, Activation::Relu, 1.0), dense(10, 4, Activation::Softmax, 2.0)]).unwrap();
+        let model = Model {
+            spec: ModelSpec::from_json(
+                r#"{"name": "wide", "inputs": 6, "layers": [
+                    {"type": "dense", "name": "l0", "units": 10, "activation": "relu"},
+                    {"type": "dense", "name": "l1", "units": 4, "activation": "softmax"}]}"#,
+            )
+            .unwrap(),
+            features: Vec::new(),
+            graph,
+        };
+        install_model(&node, model.to_update());
+
+        let batch = tensor(vec![5, 6], 3.0);
+        let expected = model.forward(&batch).unwrap().to_vec::<f32>().unwrap();
+        for axis in ["columns", "rows"] {
+            let task = TaskMessage::new(format!("infer-{}", axis), "model_forward", vec![batch.clone()])
+                .with_attr("model", "wide")
+                .with_attr("parallelism", "tensor")
+                .with_attr("shard_axis", axis);
+            let result = process_task(&node, task).await.unwrap();
+            assert_eq!(result.error, None);
+            let found = result.outputs[0].to_vec::<f32>().unwrap();
+            for (found, expected) in found.iter().zip(&expected) {
+                assert!((found - expected).abs() < 1e-5, "sharded by {}", axis);
+            }
+        }
+    }
 }
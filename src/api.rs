@@ -1,33 +1,46 @@
-// api.rs: Implements REST API endpoints for interacting with the task recovery system.
+// api.rs: Implements REST API endpoints for interacting with the task recovery system and for online inference.
 
 use warp::Filter;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use crate::task_recovery_module::{TaskRecoveryManager, Task};
+use crate::serving::{InferenceError, InferenceServer};
+use crate::task_recovery::{TaskRecoveryManager, Task};
+use crate::tensor::Tensor;
 use uuid::Uuid;
 use warp::http::StatusCode;
+use warp::Reply;
 
 #[derive(Clone)]
 pub struct Api {
     pub task_manager: Arc<TaskRecoveryManager>,
+    // Answers the inference route, when this node serves models
+    pub inference: Option<InferenceServer>,
 }
 
 impl Api {
     pub fn new(task_manager: Arc<TaskRecoveryManager>) -> Self {
-        Api { task_manager }
+        Api {
+            task_manager,
+            inference: None,
+        }
+    }
+
+    pub fn with_inference(mut self, inference: InferenceServer) -> Self {
+        self.inference = Some(inference);
+        self
     }
 
     pub fn filters(self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let api = warp::path("tasks").and(warp::path::end());
 
         let get_task = warp::get()
-            .and(api.clone())
+            .and(api)
             .and(with_task_manager(self.task_manager.clone()))
             .and(warp::query::<GetTaskParams>())
             .and_then(get_task_handler);
 
         let add_task = warp::post()
-            .and(api.clone())
+            .and(api)
             .and(with_task_manager(self.task_manager.clone()))
             .and(warp::body::json())
             .and_then(add_task_handler);
@@ -38,10 +51,30 @@ impl Api {
             .and(warp::query::<DeleteTaskParams>())
             .and_then(delete_task_handler);
 
-        get_task.or(add_task).or(delete_task)
+        // POST /models/<name>/versions/<version>/infer
+        let inference = self.inference.clone();
+        let infer = warp::post()
+            .and(warp::path!("models" / String / "versions" / u64 / "infer"))
+            .and(warp::any().map(move || inference.clone()))
+            .and(warp::body::json())
+            .and_then(infer_handler);
+
+        get_task.or(add_task).or(delete_task).or(infer)
     }
 }
 
+// Serves the API on `port` of every interface.
+pub async fn serve(api: Api, port: u16) {
+    warp::serve(api.filters()).run(([0, 0, 0, 0], port)).await;
+}
+
+// A batch of rows, as the inference route takes and returns it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct InferenceBody {
+    pub shape: Vec<usize>,
+    pub values: Vec<f32>,
+}
+
 #[derive(Deserialize)]
 struct GetTaskParams {
     task_id: String,
@@ -61,17 +94,17 @@ fn with_task_manager(
 async fn get_task_handler(
     task_manager: Arc<TaskRecoveryManager>,
     params: GetTaskParams,
-) -> Result<impl warp::Reply, warp::Rejection> {
+) -> Result<warp::reply::Response, warp::Rejection> {
     let task_id = match Uuid::parse_str(&params.task_id) {
         Ok(uuid) => uuid,
-        Err(_) => return Ok(warp::reply::with_status("Invalid UUID", StatusCode::BAD_REQUEST)),
+        Err(_) => return Ok(warp::reply::with_status("Invalid UUID", StatusCode::BAD_REQUEST).into_response()),
     };
 
     let tasks = task_manager.tasks.read().unwrap();
     if let Some(task) = tasks.get(&task_id) {
-        Ok(warp::reply::json(task))
+        Ok(warp::reply::json(task).into_response())
     } else {
-        Ok(warp::reply::with_status("Task not found", StatusCode::NOT_FOUND))
+        Ok(warp::reply::with_status("Task not found", StatusCode::NOT_FOUND).into_response())
     }
 }
 
@@ -96,15 +129,55 @@ async fn delete_task_handler(
     Ok(warp::reply::with_status("Task deleted", StatusCode::OK))
 }
 
+async fn infer_handler(
+    model: String,
+    version: u64,
+    inference: Option<InferenceServer>,
+    body: InferenceBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let Some(inference) = inference else {
+        return Ok(warp::reply::with_status("Inference is not served here", StatusCode::NOT_FOUND).into_response());
+    };
+    let outputs = match Tensor::new(body.shape, body.values) {
+        Ok(inputs) => inference.infer(&model, version, inputs).await,
+        Err(e) => Err(e.into()),
+    };
+    let reply = outputs.and_then(|outputs| {
+        let values = outputs.to_vec::<f32>().map_err(|e| InferenceError::Failed(e.to_string()))?;
+        Ok(InferenceBody {
+            shape: outputs.shape().to_vec(),
+            values,
+        })
+    });
+    match reply {
+        Ok(body) => Ok(warp::reply::json(&body).into_response()),
+        Err(e) => {
+            let status = match e {
+                InferenceError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+                InferenceError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+                InferenceError::Failed(_) => StatusCode::BAD_GATEWAY,
+            };
+            Ok(warp::reply::with_status(e.to_string(), status).into_response())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::serving::BatchingConfig;
+    use std::path::PathBuf;
     use warp::test::request;
 
+    // A task manager that keeps its tasks in a file of its own under the temp dir
+    fn task_manager() -> (Arc<TaskRecoveryManager>, PathBuf) {
+        let storage_file = std::env::temp_dir().join(format!("api_tasks_{}.json", Uuid::new_v4()));
+        (Arc::new(TaskRecoveryManager::new(storage_file.to_str().unwrap())), storage_file)
+    }
+
     #[tokio::test]
     async fn test_add_task() {
-        let storage_file = "test_tasks_api.json";
-        let task_manager = Arc::new(TaskRecoveryManager::new(storage_file));
+        let (task_manager, storage_file) = task_manager();
         let api = Api::new(task_manager.clone());
 
         let new_task = Task {
@@ -119,12 +192,12 @@ mod tests {
             .reply(&api.filters())
             .await;
         assert_eq!(res.status(), StatusCode::CREATED);
+        let _ = std::fs::remove_file(storage_file);
     }
 
     #[tokio::test]
     async fn test_get_task() {
-        let storage_file = "test_tasks_api.json";
-        let task_manager = Arc::new(TaskRecoveryManager::new(storage_file));
+        let (task_manager, storage_file) = task_manager();
         let api = Api::new(task_manager.clone());
 
         let task = Task {
@@ -139,12 +212,12 @@ mod tests {
             .reply(&api.filters())
             .await;
         assert_eq!(res.status(), StatusCode::OK);
+        let _ = std::fs::remove_file(storage_file);
     }
 
     #[tokio::test]
     async fn test_delete_task() {
-        let storage_file = "test_tasks_api.json";
-        let task_manager = Arc::new(TaskRecoveryManager::new(storage_file));
+        let (task_manager, storage_file) = task_manager();
         let api = Api::new(task_manager.clone());
 
         let task = Task {
@@ -159,5 +232,36 @@ mod tests {
             .reply(&api.filters())
             .await;
         assert_eq!(res.status(), StatusCode::OK);
+        let _ = std::fs::remove_file(storage_file);
+    }
+
+    #[tokio::test]
+    async fn test_infer() {
+        let (task_manager, storage_file) = task_manager();
+        let inference = InferenceServer::new(BatchingConfig::default(), |model: String, version, inputs: Tensor| async move {
+            assert_eq!((model.as_str(), version), ("negate", 3));
+            let negated = inputs.to_vec::<f32>().unwrap().iter().map(|value| -value).collect();
+            Tensor::new(inputs.shape().to_vec(), negated).map_err(|e| e.to_string())
+        });
+        let filters = Api::new(task_manager).with_inference(inference).filters();
+
+        let res = request()
+            .method("POST")
+            .path("/models/negate/versions/3/infer")
+            .json(&InferenceBody { shape: vec![2, 2], values: vec![1.0, -2.0, 3.0, 0.5] })
+            .reply(&filters)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: InferenceBody = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body, InferenceBody { shape: vec![2, 2], values: vec![-1.0, 2.0, -3.0, -0.5] });
+
+        let res = request()
+            .method("POST")
+            .path("/models/negate/versions/3/infer")
+            .json(&InferenceBody { shape: vec![2, 2], values: vec![1.0] })
+            .reply(&filters)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let _ = std::fs::remove_file(storage_file);
     }
 }
@@ -0,0 +1,661 @@
+// attention.rs: Transformer kernels, forward and backward: masked multi-head attention, layer-norm, GELU and embeddings.
+
+use crate::conv;
+use crate::kernels::{self, Attrs, KernelError};
+use crate::tensor::{DType, Tensor};
+
+// sqrt(2 / pi), for the tanh approximation of GELU
+const GELU_SCALE: f32 = 0.797_884_6;
+const GELU_CUBIC: f32 = 0.044_715;
+
+// GELU by its tanh approximation: 0.5 x (1 + tanh(sqrt(2 / pi) (x + 0.044715 x^3)))
+pub fn gelu(x: &Tensor) -> Result<Tensor, KernelError> {
+    let values = x
+        .values::<f32>()?
+        .iter()
+        .map(|&x| 0.5 * x * (1.0 + (GELU_SCALE * (x + GELU_CUBIC * x * x * x)).tanh()))
+        .collect();
+    Ok(Tensor::new(x.shape().to_vec(), values)?)
+}
+
+// The gradient of GELU with respect to x, given the gradient `grad` with respect to its output. Unlike the
+// activations, GELU's derivative needs its input rather than its output.
+pub fn gelu_backward(x: &Tensor, grad: &Tensor) -> Result<Tensor, KernelError> {
+    grad.expect_shape(x.shape())?;
+    let values = x
+        .values::<f32>()?
+        .iter()
+        .zip(grad.values::<f32>()?.iter())
+        .map(|(&x, &grad)| {
+            let t = (GELU_SCALE * (x + GELU_CUBIC * x * x * x)).tanh();
+            grad * (0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * GELU_SCALE * (1.0 + 3.0 * GELU_CUBIC * x * x))
+        })
+        .collect();
+    Ok(Tensor::new(x.shape().to_vec(), values)?)
+}
+
+// Width of the last axis, which layer normalization and attention work along
+fn last_axis(op: &str, x: &Tensor) -> Result<usize, KernelError> {
+    match x.shape().last() {
+        Some(&width) if width > 0 => Ok(width),
+        _ => Err(KernelError::InvalidInput(format!("{} needs a last axis, got shape {:?}", op, x.shape()))),
+    }
+}
+
+// Mean and 1 / standard deviation of each row of `width` values
+fn row_statistics(values: &[f32], width: usize, epsilon: f32) -> Vec<(f32, f32)> {
+    values
+        .chunks_exact(width)
+        .map(|row| {
+            let mean = row.iter().sum::<f32>() / width as f32;
+            let variance = row.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / width as f32;
+            (mean, 1.0 / (variance + epsilon).sqrt())
+        })
+        .collect()
+}
+
+// Normalizes x over its last axis, then scales by gamma and shifts by beta, both the width of that axis.
+pub fn layer_norm(x: &Tensor, gamma: &Tensor, beta: &Tensor, epsilon: f32) -> Result<Tensor, KernelError> {
+    let width = last_axis("layer_norm", x)?;
+    gamma.expect_shape(&[width])?;
+    beta.expect_shape(&[width])?;
+    let (values, gamma, beta) = (x.values::<f32>()?, gamma.values::<f32>()?, beta.values::<f32>()?);
+    let mut y = Vec::with_capacity(values.len());
+    for (row, (mean, inverse_std)) in values.chunks_exact(width).zip(row_statistics(&values, width, epsilon)) {
+        y.extend(row.iter().enumerate().map(|(i, value)| gamma[i] * (value - mean) * inverse_std + beta[i]));
+    }
+    Ok(Tensor::new(x.shape().to_vec(), y)?)
+}
+
+// The gradients of layer_norm with respect to x, gamma and beta.
+pub fn layer_norm_backward(
+    x: &Tensor,
+    gamma: &Tensor,
+    grad: &Tensor,
+    epsilon: f32,
+) -> Result<(Tensor, Tensor, Tensor), KernelError> {
+    let width = last_axis("layer_norm_backward", x)?;
+    gamma.expect_shape(&[width])?;
+    grad.expect_shape(x.shape())?;
+    let (values, gamma, g) = (x.values::<f32>()?, gamma.values::<f32>()?, grad.values::<f32>()?);
+    let (mut dx, mut dgamma, mut dbeta) = (Vec::with_capacity(values.len()), vec![0.0; width], vec![0.0; width]);
+    for ((row, g), (mean, inverse_std)) in values
+        .chunks_exact(width)
+        .zip(g.chunks_exact(width))
+        .zip(row_statistics(&values, width, epsilon))
+    {
+        let normalized: Vec<f32> = row.iter().map(|value| (value - mean) * inverse_std).collect();
+        let dnormalized: Vec<f32> = g.iter().zip(gamma.iter()).map(|(g, gamma)| g * gamma).collect();
+        let mean_d = dnormalized.iter().sum::<f32>() / width as f32;
+        let mean_dn = dnormalized.iter().zip(&normalized).map(|(d, n)| d * n).sum::<f32>() / width as f32;
+        for i in 0..width {
+            dgamma[i] += g[i] * normalized[i];
+            dbeta[i] += g[i];
+            dx.push(inverse_std * (dnormalized[i] - mean_d - normalized[i] * mean_dn));
+        }
+    }
+    Ok((
+        Tensor::new(x.shape().to_vec(), dx)?,
+        Tensor::new(vec![width], dgamma)?,
+        Tensor::new(vec![width], dbeta)?,
+    ))
+}
+
+// Token ids as indices into a table of `vocab` rows. Ids may be integers or whole floats.
+fn token_ids(ids: &Tensor, vocab: usize) -> Result<Vec<usize>, KernelError> {
+    let ids: Vec<f64> = match ids.dtype() {
+        DType::I32 => ids.values::<i32>()?.iter().map(|&id| id as f64).collect(),
+        DType::U8 => ids.values::<u8>()?.iter().map(|&id| id as f64).collect(),
+        DType::F32 => ids.values::<f32>()?.iter().map(|&id| id as f64).collect(),
+        DType::F64 => ids.to_vec::<f64>()?,
+    };
+    ids.into_iter()
+        .map(|id| match id {
+            id if id.fract() == 0.0 && id >= 0.0 && (id as usize) < vocab => Ok(id as usize),
+            id => Err(KernelError::InvalidInput(format!("token id {} is not below the vocabulary size {}", id, vocab))),
+        })
+        .collect()
+}
+
+// The rows of `table` [vocab, dim] for each id in `ids`: ids of shape [..] give [.., dim].
+pub fn embedding(ids: &Tensor, table: &Tensor) -> Result<Tensor, KernelError> {
+    let (vocab, dim) = kernels::matrix_shape("embedding", table)?;
+    let table = table.values::<f32>()?;
+    let mut values = Vec::with_capacity(ids.len() * dim);
+    for id in token_ids(ids, vocab)? {
+        values.extend_from_slice(&table[id * dim..(id + 1) * dim]);
+    }
+    Ok(Tensor::new([ids.shape(), &[dim]].concat(), values)?)
+}
+
+// The gradient with respect to the table: each id's row of `grad` added to the row it looked up.
+pub fn embedding_backward(ids: &Tensor, table: &Tensor, grad: &Tensor) -> Result<Tensor, KernelError> {
+    let (vocab, dim) = kernels::matrix_shape("embedding_backward", table)?;
+    grad.expect_shape(&[ids.shape(), &[dim]].concat())?;
+    let mut dtable = vec![0.0; vocab * dim];
+    for (id, row) in token_ids(ids, vocab)?.into_iter().zip(grad.values::<f32>()?.chunks_exact(dim)) {
+        for (total, value) in dtable[id * dim..(id + 1) * dim].iter_mut().zip(row) {
+            *total += value;
+        }
+    }
+    Ok(Tensor::new(vec![vocab, dim], dtable)?)
+}
+
+// The projections of multi-head attention, each [dim, dim] with a [dim] bias: query, key, value and output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttentionWeights {
+    pub query: Tensor,
+    pub query_bias: Tensor,
+    pub key: Tensor,
+    pub key_bias: Tensor,
+    pub value: Tensor,
+    pub value_bias: Tensor,
+    pub output: Tensor,
+    pub output_bias: Tensor,
+}
+
+impl AttentionWeights {
+    // From eight tensors in `to_vec` order
+    pub fn from_slice(tensors: &[Tensor]) -> Result<Self, KernelError> {
+        let [query, query_bias, key, key_bias, value, value_bias, output, output_bias] = tensors else {
+            return Err(KernelError::InvalidInput(format!("attention needs 8 weight tensors, got {}", tensors.len())));
+        };
+        Ok(AttentionWeights {
+            query: query.clone(),
+            query_bias: query_bias.clone(),
+            key: key.clone(),
+            key_bias: key_bias.clone(),
+            value: value.clone(),
+            value_bias: value_bias.clone(),
+            output: output.clone(),
+            output_bias: output_bias.clone(),
+        })
+    }
+
+    pub fn tensors(&self) -> [&Tensor; 8] {
+        [
+            &self.query,
+            &self.query_bias,
+            &self.key,
+            &self.key_bias,
+            &self.value,
+            &self.value_bias,
+            &self.output,
+            &self.output_bias,
+        ]
+    }
+
+    pub fn to_vec(&self) -> Vec<Tensor> {
+        self.tensors().into_iter().cloned().collect()
+    }
+
+    fn dim(&self) -> Result<usize, KernelError> {
+        let (dim, _) = kernels::matrix_shape("attention", &self.query)?;
+        for matrix in [&self.query, &self.key, &self.value, &self.output] {
+            matrix.expect_shape(&[dim, dim])?;
+        }
+        for bias in [&self.query_bias, &self.key_bias, &self.value_bias, &self.output_bias] {
+            bias.expect_shape(&[dim])?;
+        }
+        Ok(dim)
+    }
+}
+
+// Scaled dot-product attention split across `heads` heads of dim / heads columns each. With `causal`,
+// each position attends only to itself and the positions before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attention {
+    pub heads: usize,
+    pub causal: bool,
+}
+
+// One sequence's values from a forward pass, which the backward pass starts from
+struct Pass {
+    q: Vec<f32>,
+    k: Vec<f32>,
+    v: Vec<f32>,
+    // Attention probabilities [heads, seq, seq]
+    p: Vec<f32>,
+    // The heads' outputs side by side, before the output projection
+    o: Vec<f32>,
+    y: Vec<f32>,
+}
+
+// x [rows, dim] W [dim, dim] + b
+fn project(x: &[f32], w: &[f32], b: &[f32], rows: usize, dim: usize) -> Vec<f32> {
+    let mut y = kernels::matmul_values(x, w, rows, dim, dim);
+    for row in y.chunks_exact_mut(dim) {
+        row.iter_mut().zip(b).for_each(|(value, bias)| *value += bias);
+    }
+    y
+}
+
+fn add_into(total: &mut [f32], values: &[f32]) {
+    total.iter_mut().zip(values).for_each(|(total, value)| *total += value);
+}
+
+fn column_sums(values: &[f32], width: usize) -> Vec<f32> {
+    let mut sums = vec![0.0; width];
+    for row in values.chunks_exact(width) {
+        add_into(&mut sums, row);
+    }
+    sums
+}
+
+impl Attention {
+    pub fn from_attrs(attrs: &Attrs) -> Result<Self, KernelError> {
+        let heads = match kernels::int_attr(attrs, "heads")? {
+            None => 1,
+            Some(heads) if heads > 0 => heads as usize,
+            Some(heads) => return Err(KernelError::InvalidInput(format!("heads must be positive, got {}", heads))),
+        };
+        Ok(Attention {
+            heads,
+            causal: kernels::int_attr(attrs, "causal")?.unwrap_or(0) != 0,
+        })
+    }
+
+    // x [batch, seq, dim], and a mask [seq, seq] or [batch, seq, seq] whose zeros keep a query position
+    // (row) from attending to a key position (column)
+    fn shape(&self, x: &Tensor, weights: &AttentionWeights, mask: Option<&Tensor>) -> Result<[usize; 3], KernelError> {
+        let &[batch, seq, dim] = x.shape() else {
+            return Err(KernelError::InvalidInput(format!("attention expects [batch, seq, dim], got shape {:?}", x.shape())));
+        };
+        if weights.dim()? != dim {
+            return Err(KernelError::InvalidInput(format!("attention weights are not {}x{}", dim, dim)));
+        }
+        if dim % self.heads != 0 {
+            return Err(KernelError::InvalidInput(format!("{} heads do not divide dim {}", self.heads, dim)));
+        }
+        if let Some(mask) = mask {
+            if mask.shape() != [seq, seq] && mask.shape() != [batch, seq, seq] {
+                return Err(KernelError::InvalidInput(format!("mask shape {:?} does not fit {:?}", mask.shape(), x.shape())));
+            }
+        }
+        Ok([batch, seq, dim])
+    }
+
+    fn allowed(&self, mask: Option<&[f32]>, seq: usize, i: usize, j: usize) -> bool {
+        !(self.causal && j > i) && mask.is_none_or(|mask| mask[i * seq + j] != 0.0)
+    }
+
+    fn forward_sequence(&self, x: &[f32], w: &[Vec<f32>], mask: Option<&[f32]>, seq: usize, dim: usize) -> Pass {
+        let head_dim = dim / self.heads;
+        let scale = 1.0 / (head_dim as f32).sqrt();
+        let q = project(x, &w[0], &w[1], seq, dim);
+        let k = project(x, &w[2], &w[3], seq, dim);
+        let v = project(x, &w[4], &w[5], seq, dim);
+        let mut p = vec![0.0; self.heads * seq * seq];
+        let mut o = vec![0.0; seq * dim];
+        for head in 0..self.heads {
+            let columns = head * head_dim..(head + 1) * head_dim;
+            for i in 0..seq {
+                let row = &mut p[(head * seq + i) * seq..(head * seq + i + 1) * seq];
+                let mut max = f32::NEG_INFINITY;
+                for (j, score) in row.iter_mut().enumerate() {
+                    if self.allowed(mask, seq, i, j) {
+                        *score = columns.clone().map(|c| q[i * dim + c] * k[j * dim + c]).sum::<f32>() * scale;
+                        max = max.max(*score);
+                    }
+                }
+                // A position that may attend to nothing gets no probabilities and outputs zeros
+                let mut sum = 0.0;
+                for (j, score) in row.iter_mut().enumerate() {
+                    *score = if self.allowed(mask, seq, i, j) { (*score - max).exp() } else { 0.0 };
+                    sum += *score;
+                }
+                for (j, probability) in row.iter_mut().enumerate() {
+                    if sum > 0.0 {
+                        *probability /= sum;
+                    }
+                    for c in columns.clone() {
+                        o[i * dim + c] += *probability * v[j * dim + c];
+                    }
+                }
+            }
+        }
+        let y = project(&o, &w[6], &w[7], seq, dim);
+        Pass { q, k, v, p, o, y }
+    }
+
+    // x [batch, seq, dim] to [batch, seq, dim]
+    pub fn forward(&self, x: &Tensor, weights: &AttentionWeights, mask: Option<&Tensor>) -> Result<Tensor, KernelError> {
+        let [_, seq, dim] = self.shape(x, weights, mask)?;
+        let w = weight_values(weights)?;
+        let masks = mask_values(mask, seq)?;
+        let mut y = Vec::with_capacity(x.len());
+        for (index, sequence) in x.values::<f32>()?.chunks_exact(seq * dim).enumerate() {
+            y.extend(self.forward_sequence(sequence, &w, masks.get(index), seq, dim).y);
+        }
+        Ok(Tensor::new(x.shape().to_vec(), y)?)
+    }
+
+    // The gradients with respect to x and each weight, given the gradient `grad` with respect to the output.
+    pub fn backward(
+        &self,
+        x: &Tensor,
+        weights: &AttentionWeights,
+        mask: Option<&Tensor>,
+        grad: &Tensor,
+    ) -> Result<(Tensor, AttentionWeights), KernelError> {
+        let [_, seq, dim] = self.shape(x, weights, mask)?;
+        grad.expect_shape(x.shape())?;
+        let head_dim = dim / self.heads;
+        let scale = 1.0 / (head_dim as f32).sqrt();
+        let w = weight_values(weights)?;
+        let masks = mask_values(mask, seq)?;
+        let transposed: Vec<Vec<f32>> = [0, 2, 4, 6].iter().map(|&i| kernels::transpose(&w[i], dim, dim)).collect();
+
+        let mut dx = Vec::with_capacity(x.len());
+        let mut dw: Vec<Vec<f32>> = w.iter().map(|values| vec![0.0; values.len()]).collect();
+        let g_values = grad.values::<f32>()?;
+        for (index, (sequence, dy)) in x
+            .values::<f32>()?
+            .chunks_exact(seq * dim)
+            .zip(g_values.chunks_exact(seq * dim))
+            .enumerate()
+        {
+            let Pass { q, k, v, p, o, .. } = self.forward_sequence(sequence, &w, masks.get(index), seq, dim);
+            add_into(&mut dw[6], &kernels::matmul_values(&kernels::transpose(&o, seq, dim), dy, dim, seq, dim));
+            add_into(&mut dw[7], &column_sums(dy, dim));
+            let d_o = kernels::matmul_values(dy, &transposed[3], seq, dim, dim);
+
+            let (mut dq, mut dk, mut dv) = (vec![0.0; seq * dim], vec![0.0; seq * dim], vec![0.0; seq * dim]);
+            for head in 0..self.heads {
+                let columns = head * head_dim..(head + 1) * head_dim;
+                for i in 0..seq {
+                    let probabilities = &p[(head * seq + i) * seq..(head * seq + i + 1) * seq];
+                    let dp: Vec<f32> = (0..seq)
+                        .map(|j| columns.clone().map(|c| d_o[i * dim + c] * v[j * dim + c]).sum())
+                        .collect();
+                    let weighted: f32 = probabilities.iter().zip(&dp).map(|(p, dp)| p * dp).sum();
+                    for j in 0..seq {
+                        let ds = probabilities[j] * (dp[j] - weighted) * scale;
+                        for c in columns.clone() {
+                            dv[j * dim + c] += probabilities[j] * d_o[i * dim + c];
+                            dq[i * dim + c] += ds * k[j * dim + c];
+                            dk[j * dim + c] += ds * q[i * dim + c];
+                        }
+                    }
+                }
+            }
+
+            let xt = kernels::transpose(sequence, seq, dim);
+            let mut dsequence = vec![0.0; seq * dim];
+            for (projection, d) in [dq, dk, dv].iter().enumerate() {
+                add_into(&mut dw[2 * projection], &kernels::matmul_values(&xt, d, dim, seq, dim));
+                add_into(&mut dw[2 * projection + 1], &column_sums(d, dim));
+                add_into(&mut dsequence, &kernels::matmul_values(d, &transposed[projection], seq, dim, dim));
+            }
+            dx.extend(dsequence);
+        }
+
+        let gradients: Vec<Tensor> = weights
+            .to_vec()
+            .iter()
+            .zip(dw)
+            .map(|(weight, values)| Tensor::new(weight.shape().to_vec(), values))
+            .collect::<Result<_, _>>()?;
+        Ok((Tensor::new(x.shape().to_vec(), dx)?, AttentionWeights::from_slice(&gradients)?))
+    }
+}
+
+fn weight_values(weights: &AttentionWeights) -> Result<Vec<Vec<f32>>, KernelError> {
+    weights
+        .to_vec()
+        .iter()
+        .map(|weight| Ok(weight.to_vec::<f32>()?))
+        .collect()
+}
+
+// The mask of each sequence, or none. A [seq, seq] mask is shared by every sequence.
+fn mask_values(mask: Option<&Tensor>, seq: usize) -> Result<Masks, KernelError> {
+    Ok(match mask {
+        None => Masks::None,
+        Some(mask) if mask.rank() == 2 => Masks::Shared(mask.to_vec::<f32>()?),
+        Some(mask) => Masks::PerSequence(mask.to_vec::<f32>()?, seq * seq),
+    })
+}
+
+enum Masks {
+    None,
+    Shared(Vec<f32>),
+    PerSequence(Vec<f32>, usize),
+}
+
+impl Masks {
+    fn get(&self, sequence: usize) -> Option<&[f32]> {
+        match self {
+            Masks::None => None,
+            Masks::Shared(mask) => Some(mask),
+            Masks::PerSequence(masks, len) => Some(&masks[sequence * len..(sequence + 1) * len]),
+        }
+    }
+}
+
+// inputs: x; outputs: GELU of x
+pub fn gelu_kernel(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    Ok(vec![gelu(kernels::input("gelu", inputs, 0)?)?])
+}
+
+// inputs: x and the gradient with respect to GELU's output; outputs: the gradient with respect to x
+pub fn gelu_backward_kernel(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("gelu_backward", inputs, 0)?;
+    Ok(vec![gelu_backward(x, kernels::input("gelu_backward", inputs, 1)?)?])
+}
+
+// inputs: x [.., width], gamma [width], beta [width]; attrs: epsilon; outputs: x normalized over its last axis
+pub fn layer_norm_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("layer_norm", inputs, 0)?;
+    let gamma = kernels::input("layer_norm", inputs, 1)?;
+    let beta = kernels::input("layer_norm", inputs, 2)?;
+    Ok(vec![layer_norm(x, gamma, beta, conv::epsilon_attr(attrs)?)?])
+}
+
+// inputs: x, gamma and the gradient with respect to layer_norm's output; attrs: epsilon; outputs: the
+// gradients with respect to x, gamma and beta
+pub fn layer_norm_backward_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("layer_norm_backward", inputs, 0)?;
+    let gamma = kernels::input("layer_norm_backward", inputs, 1)?;
+    let grad = kernels::input("layer_norm_backward", inputs, 2)?;
+    let (dx, dgamma, dbeta) = layer_norm_backward(x, gamma, grad, conv::epsilon_attr(attrs)?)?;
+    Ok(vec![dx, dgamma, dbeta])
+}
+
+// inputs: token ids [..] and the table [vocab, dim]; outputs: [.., dim]
+pub fn embedding_kernel(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let ids = kernels::input("embedding", inputs, 0)?;
+    Ok(vec![embedding(ids, kernels::input("embedding", inputs, 1)?)?])
+}
+
+// inputs: token ids, the table and the gradient with respect to embedding's output; outputs: the
+// gradient with respect to the table
+pub fn embedding_backward_kernel(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let ids = kernels::input("embedding_backward", inputs, 0)?;
+    let table = kernels::input("embedding_backward", inputs, 1)?;
+    Ok(vec![embedding_backward(ids, table, kernels::input("embedding_backward", inputs, 2)?)?])
+}
+
+// inputs: x [batch, seq, dim], the query, key, value and output projections as in `AttentionWeights::to_vec`,
+// then optionally a mask [seq, seq] or [batch, seq, seq] of ones and zeros; attrs: heads (1 by default)
+// and causal (0 or 1); outputs: [batch, seq, dim]
+pub fn attention_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("multi_head_attention", inputs, 0)?;
+    kernels::input("multi_head_attention", inputs, 8)?;
+    let weights = AttentionWeights::from_slice(&inputs[1..9])?;
+    Ok(vec![Attention::from_attrs(attrs)?.forward(x, &weights, inputs.get(9))?])
+}
+
+// inputs: those of multi_head_attention, then the gradient with respect to its output; attrs: as for
+// multi_head_attention; outputs: the gradients with respect to x and each of the eight weights
+pub fn attention_backward_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("multi_head_attention_backward", inputs, 0)?;
+    kernels::input("multi_head_attention_backward", inputs, 9)?;
+    let weights = AttentionWeights::from_slice(&inputs[1..9])?;
+    let (mask, grad) = match &inputs[9..] {
+        [grad] => (None, grad),
+        [mask, grad] => (Some(mask), grad),
+        _ => {
+            return Err(KernelError::InvalidInput(format!(
+                "multi_head_attention_backward takes 10 or 11 inputs, got {}",
+                inputs.len()
+            )))
+        }
+    };
+    let (dx, dweights) = Attention::from_attrs(attrs)?.backward(x, &weights, mask, grad)?;
+    let mut outputs = vec![dx];
+    outputs.extend(dweights.to_vec());
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::{Attr, KernelRegistry};
+    use crate::test_support::{assert_close, numeric_gradient, tensor};
+
+    fn close(found: &Tensor, expected: &[f32]) {
+        assert_close(found, expected, 2e-3, 2e-3);
+    }
+
+    fn weights(dim: usize) -> AttentionWeights {
+        let tensors: Vec<Tensor> = (0..8)
+            .map(|i| if i % 2 == 0 { tensor(vec![dim, dim], i as f32) } else { tensor(vec![dim], i as f32) })
+            .collect();
+        AttentionWeights::from_slice(&tensors).unwrap()
+    }
+
+    // One output element at a time, straight from the definition
+    fn naive_attention(x: &Tensor, w: &AttentionWeights, heads: usize, causal: bool, mask: &Tensor) -> Vec<f32> {
+        let &[b, s, d] = x.shape() else { unreachable!() };
+        let (x, mask) = (x.to_vec::<f32>().unwrap(), mask.to_vec::<f32>().unwrap());
+        let w: Vec<Vec<f32>> = w.to_vec().iter().map(|t| t.to_vec::<f32>().unwrap()).collect();
+        let linear = |input: &dyn Fn(usize, usize) -> f32, m: usize, row: usize, column: usize| -> f32 {
+            w[m + 1][column] + (0..d).map(|inner| input(row, inner) * w[m][inner * d + column]).sum::<f32>()
+        };
+        let hd = d / heads;
+        let mut y = vec![0.0; b * s * d];
+        for n in 0..b {
+            let xs = |row: usize, column: usize| x[(n * s + row) * d + column];
+            let mut concat = vec![0.0; s * d];
+            for h in 0..heads {
+                for i in 0..s {
+                    let allowed: Vec<usize> =
+                        (0..s).filter(|&j| !(causal && j > i) && mask[(n * s + i) * s + j] != 0.0).collect();
+                    let scores: Vec<f32> = allowed
+                        .iter()
+                        .map(|&j| {
+                            (0..hd).map(|c| linear(&xs, 0, i, h * hd + c) * linear(&xs, 2, j, h * hd + c)).sum::<f32>()
+                                / (hd as f32).sqrt()
+                        })
+                        .collect();
+                    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let total: f32 = scores.iter().map(|score| (score - max).exp()).sum();
+                    for (&j, score) in allowed.iter().zip(&scores) {
+                        for c in 0..hd {
+                            concat[i * d + h * hd + c] += (score - max).exp() / total * linear(&xs, 4, j, h * hd + c);
+                        }
+                    }
+                }
+            }
+            for i in 0..s {
+                for column in 0..d {
+                    y[(n * s + i) * d + column] = linear(&|row, inner| concat[row * d + inner], 6, i, column);
+                }
+            }
+        }
+        y
+    }
+
+    #[test]
+    fn test_attention_matches_naive_reference_and_finite_differences() {
+        let (b, s, d, heads) = (2, 4, 6, 2);
+        let x = tensor(vec![b, s, d], 0.3);
+        let w = weights(d);
+        // The second sequence may not attend to its last position
+        let mut mask = vec![1.0f32; b * s * s];
+        for i in 0..s {
+            mask[(s + i) * s + s - 1] = 0.0;
+        }
+        let mask = Tensor::new(vec![b, s, s], mask).unwrap();
+        for causal in [false, true] {
+            let attention = Attention { heads, causal };
+            let y = attention.forward(&x, &w, Some(&mask)).unwrap();
+            close(&y, &naive_attention(&x, &w, heads, causal, &mask));
+        }
+
+        let attention = Attention { heads, causal: true };
+        let probe = tensor(vec![b, s, d], 5.0);
+        let (dx, dw) = attention.backward(&x, &w, Some(&mask), &probe).unwrap();
+        close(&dx, &numeric_gradient(&x, &probe, |x| attention.forward(x, &w, Some(&mask)).unwrap()));
+        for (index, gradient) in dw.to_vec().iter().enumerate() {
+            let numeric = numeric_gradient(&w.to_vec()[index], &probe, |weight| {
+                let mut tensors = w.to_vec();
+                tensors[index] = weight.clone();
+                attention.forward(&x, &AttentionWeights::from_slice(&tensors).unwrap(), Some(&mask)).unwrap()
+            });
+            close(gradient, &numeric);
+        }
+
+        // Through the registry, with the mask and then the gradient after the weights
+        let mut attrs = Attrs::new();
+        attrs.insert("heads".to_string(), Attr::Int(heads as i64));
+        attrs.insert("causal".to_string(), Attr::Int(1));
+        let inputs: Vec<Tensor> = [vec![x.clone()], w.to_vec(), vec![mask.clone()]].concat();
+        let registry = KernelRegistry::builtin();
+        let y = registry.run("multi_head_attention", &inputs, &attrs).unwrap();
+        assert_eq!(y[0], attention.forward(&x, &w, Some(&mask)).unwrap());
+        let gradients = registry.run("multi_head_attention_backward", &[inputs, vec![probe]].concat(), &attrs).unwrap();
+        assert_eq!(gradients.len(), 9);
+        assert_eq!(gradients[0], dx);
+    }
+
+    #[test]
+    fn test_layer_norm_and_gelu_match_naive_reference() {
+        let (x, gamma, beta) = (tensor(vec![3, 5], 1.0), tensor(vec![5], 2.0), tensor(vec![5], 3.0));
+        let (xv, gv, bv) = (x.to_vec::<f32>().unwrap(), gamma.to_vec::<f32>().unwrap(), beta.to_vec::<f32>().unwrap());
+        let mut expected = Vec::new();
+        for row in xv.chunks(5) {
+            let mean = row.iter().sum::<f32>() / 5.0;
+            let variance = row.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / 5.0;
+            for i in 0..5 {
+                expected.push(gv[i] * (row[i] - mean) / (variance + 1e-5).sqrt() + bv[i]);
+            }
+        }
+        close(&layer_norm(&x, &gamma, &beta, 1e-5).unwrap(), &expected);
+
+        let probe = tensor(vec![3, 5], 4.0);
+        let (dx, dgamma, dbeta) = layer_norm_backward(&x, &gamma, &probe, 1e-5).unwrap();
+        close(&dx, &numeric_gradient(&x, &probe, |x| layer_norm(x, &gamma, &beta, 1e-5).unwrap()));
+        close(&dgamma, &numeric_gradient(&gamma, &probe, |gamma| layer_norm(&x, gamma, &beta, 1e-5).unwrap()));
+        close(&dbeta, &numeric_gradient(&beta, &probe, |beta| layer_norm(&x, &gamma, beta, 1e-5).unwrap()));
+
+        let expected: Vec<f32> = xv
+            .iter()
+            .map(|&v| 0.5 * v * (1.0 + ((2.0 / std::f32::consts::PI).sqrt() * (v + 0.044715 * v.powi(3))).tanh()))
+            .collect();
+        close(&gelu(&x).unwrap(), &expected);
+        close(&gelu_backward(&x, &probe).unwrap(), &numeric_gradient(&x, &probe, |x| gelu(x).unwrap()));
+    }
+
+    #[test]
+    fn test_embedding_looks_up_and_scatters_rows() {
+        let table = tensor(vec![5, 3], 1.0);
+        let ids = Tensor::new(vec![2, 2], vec![4i32, 0, 4, 2]).unwrap();
+        let rows = table.to_vec::<f32>().unwrap();
+        let y = embedding(&ids, &table).unwrap();
+        assert_eq!(y.shape(), &[2, 2, 3]);
+        assert_eq!(y.to_vec::<f32>().unwrap(), [&rows[12..15], &rows[0..3], &rows[12..15], &rows[6..9]].concat());
+
+        let grad = Tensor::new(vec![2, 2, 3], (0..12).map(|i| i as f32).collect::<Vec<_>>()).unwrap();
+        let registry = KernelRegistry::builtin();
+        let dtable = registry.run("embedding_backward", &[ids.clone(), table.clone(), grad], &Attrs::new()).unwrap();
+        assert_eq!(
+            dtable[0].to_vec::<f32>().unwrap(),
+            vec![3.0, 4.0, 5.0, 0.0, 0.0, 0.0, 9.0, 10.0, 11.0, 0.0, 0.0, 0.0, 6.0, 8.0, 10.0]
+        );
+
+        let out_of_range = Tensor::new(vec![1], vec![5.0f32]).unwrap();
+        assert!(registry.run("embedding", &[out_of_range, table], &Attrs::new()).is_err());
+    }
+}
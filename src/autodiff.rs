@@ -0,0 +1,306 @@
+// autodiff.rs: Tape-based reverse-mode differentiation over tensors, for the layers and losses models are trained with.
+
+use crate::kernels::{self, Activation, KernelError};
+use crate::tensor::Tensor;
+
+// Probabilities are clamped to this before taking their logarithm
+const PROBABILITY_FLOOR: f32 = 1e-7;
+
+// A value recorded on a tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Var(usize);
+
+#[derive(Debug, Clone)]
+enum Op {
+    Leaf,
+    // [m, k] x [k, n]
+    Matmul(Var, Var),
+    // x [.., n] + bias [n]
+    AddBias(Var, Var),
+    Activation(Activation, Var),
+    // Mean over every element of (x - targets)^2
+    Mse(Var, Tensor),
+    // Mean over rows of -sum(targets * ln(x)), for rows of probabilities x
+    CrossEntropy(Var, Tensor),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Tensor,
+    op: Op,
+}
+
+// Records operations as they are computed, so that `backward` can replay them in reverse. Tapes are
+// used once: record a forward pass, take its gradients, drop the tape.
+#[derive(Debug, Clone, Default)]
+pub struct Tape {
+    entries: Vec<Entry>,
+}
+
+// The gradient of one output with respect to each variable it depends on.
+#[derive(Debug, Clone)]
+pub struct Gradients {
+    grads: Vec<Option<Tensor>>,
+}
+
+impl Gradients {
+    // None for variables the output does not depend on.
+    pub fn get(&self, var: Var) -> Option<&Tensor> {
+        self.grads.get(var.0).and_then(Option::as_ref)
+    }
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Tape::default()
+    }
+
+    pub fn value(&self, var: Var) -> &Tensor {
+        &self.entries[var.0].value
+    }
+
+    // An input or parameter.
+    pub fn leaf(&mut self, value: Tensor) -> Var {
+        self.push(value, Op::Leaf)
+    }
+
+    pub fn matmul(&mut self, a: Var, b: Var) -> Result<Var, KernelError> {
+        let (m, k) = kernels::matrix_shape("matmul", self.value(a))?;
+        let (k2, n) = kernels::matrix_shape("matmul", self.value(b))?;
+        if k != k2 {
+            return Err(KernelError::InvalidInput(format!("cannot multiply [{}, {}] by [{}, {}]", m, k, k2, n)));
+        }
+        let values = kernels::matmul_values(&self.value(a).values::<f32>()?, &self.value(b).values::<f32>()?, m, k, n);
+        Ok(self.push(Tensor::new(vec![m, n], values)?, Op::Matmul(a, b)))
+    }
+
+    pub fn add_bias(&mut self, x: Var, bias: Var) -> Result<Var, KernelError> {
+        let value = kernels::add_bias(self.value(x), self.value(bias))?;
+        Ok(self.push(value, Op::AddBias(x, bias)))
+    }
+
+    pub fn activation(&mut self, activation: Activation, x: Var) -> Result<Var, KernelError> {
+        let value = activation.apply(self.value(x))?;
+        Ok(self.push(value, Op::Activation(activation, x)))
+    }
+
+    // activation(x·weights + bias)
+    pub fn dense(&mut self, x: Var, weights: Var, bias: Var, activation: Activation) -> Result<Var, KernelError> {
+        let product = self.matmul(x, weights)?;
+        let biased = self.add_bias(product, bias)?;
+        self.activation(activation, biased)
+    }
+
+    pub fn mse(&mut self, predictions: Var, targets: &Tensor) -> Result<Var, KernelError> {
+        let values = self.value(predictions).values::<f32>()?;
+        targets.expect_shape(self.value(predictions).shape())?;
+        let count = values.len().max(1) as f32;
+        let loss: f32 = values
+            .iter()
+            .zip(targets.values::<f32>()?.iter())
+            .map(|(prediction, target)| (prediction - target) * (prediction - target))
+            .sum::<f32>()
+            / count;
+        Ok(self.push(Tensor::scalar(loss), Op::Mse(predictions, targets.clone())))
+    }
+
+    // `probabilities` are rows summing to one, such as a softmax output; `targets` are rows of the same
+    // shape, usually one-hot.
+    pub fn cross_entropy(&mut self, probabilities: Var, targets: &Tensor) -> Result<Var, KernelError> {
+        let (rows, _) = kernels::matrix_shape("cross_entropy", self.value(probabilities))?;
+        targets.expect_shape(self.value(probabilities).shape())?;
+        let values = self.value(probabilities).values::<f32>()?;
+        let loss: f32 = values
+            .iter()
+            .zip(targets.values::<f32>()?.iter())
+            .map(|(probability, target)| -target * probability.max(PROBABILITY_FLOOR).ln())
+            .sum::<f32>()
+            / rows.max(1) as f32;
+        Ok(self.push(Tensor::scalar(loss), Op::CrossEntropy(probabilities, targets.clone())))
+    }
+
+    // Differentiates the scalar `output` with respect to every variable recorded before it.
+    pub fn backward(&self, output: Var) -> Result<Gradients, KernelError> {
+        if self.value(output).len() != 1 {
+            return Err(KernelError::InvalidInput(format!(
+                "can only differentiate a scalar, got shape {:?}",
+                self.value(output).shape()
+            )));
+        }
+        let mut grads: Vec<Option<Tensor>> = vec![None; self.entries.len()];
+        grads[output.0] = Some(Tensor::new(self.value(output).shape().to_vec(), vec![1.0f32])?);
+
+        for index in (0..=output.0).rev() {
+            let Some(grad) = grads[index].take() else {
+                continue;
+            };
+            let entry = &self.entries[index];
+            match &entry.op {
+                Op::Leaf => {}
+                Op::Matmul(a, b) => {
+                    let (m, k) = kernels::matrix_shape("matmul", self.value(*a))?;
+                    let n = entry.value.shape()[1];
+                    let g = grad.values::<f32>()?;
+                    let a_values = self.value(*a).values::<f32>()?;
+                    let b_values = self.value(*b).values::<f32>()?;
+                    let da = kernels::matmul_values(&g, &kernels::transpose(&b_values, k, n), m, n, k);
+                    let db = kernels::matmul_values(&kernels::transpose(&a_values, m, k), &g, k, m, n);
+                    accumulate(&mut grads, *a, Tensor::new(vec![m, k], da)?)?;
+                    accumulate(&mut grads, *b, Tensor::new(vec![k, n], db)?)?;
+                }
+                Op::AddBias(x, bias) => {
+                    let width = self.value(*bias).len().max(1);
+                    let mut db = vec![0.0f32; self.value(*bias).len()];
+                    for row in grad.values::<f32>()?.chunks_exact(width) {
+                        for (total, value) in db.iter_mut().zip(row) {
+                            *total += value;
+                        }
+                    }
+                    accumulate(&mut grads, *bias, Tensor::new(self.value(*bias).shape().to_vec(), db)?)?;
+                    accumulate(&mut grads, *x, grad.clone())?;
+                }
+                Op::Activation(activation, x) => {
+                    let dx = activation.backward(&entry.value, &grad)?;
+                    accumulate(&mut grads, *x, dx)?;
+                }
+                Op::Mse(predictions, targets) => {
+                    let scale = grad.values::<f32>()?[0];
+                    let values = self.value(*predictions).values::<f32>()?;
+                    let count = values.len().max(1) as f32;
+                    let dx = values
+                        .iter()
+                        .zip(targets.values::<f32>()?.iter())
+                        .map(|(prediction, target)| scale * 2.0 * (prediction - target) / count)
+                        .collect();
+                    accumulate(&mut grads, *predictions, Tensor::new(targets.shape().to_vec(), dx)?)?;
+                }
+                Op::CrossEntropy(probabilities, targets) => {
+                    let scale = grad.values::<f32>()?[0];
+                    let rows = targets.shape()[0].max(1) as f32;
+                    let values = self.value(*probabilities).values::<f32>()?;
+                    let dx = values
+                        .iter()
+                        .zip(targets.values::<f32>()?.iter())
+                        .map(|(probability, target)| -scale * target / (probability.max(PROBABILITY_FLOOR) * rows))
+                        .collect();
+                    accumulate(&mut grads, *probabilities, Tensor::new(targets.shape().to_vec(), dx)?)?;
+                }
+            }
+            grads[index] = Some(grad);
+        }
+        Ok(Gradients { grads })
+    }
+
+    fn push(&mut self, value: Tensor, op: Op) -> Var {
+        self.entries.push(Entry { value, op });
+        Var(self.entries.len() - 1)
+    }
+}
+
+// Adds `grad` to the gradient collected so far for `var`, which may be used by several operations.
+fn accumulate(grads: &mut [Option<Tensor>], var: Var, grad: Tensor) -> Result<(), KernelError> {
+    grads[var.0] = Some(match grads[var.0].take() {
+        None => grad,
+        Some(total) => {
+            total.expect_shape(grad.shape())?;
+            let values = total
+                .values::<f32>()?
+                .iter()
+                .zip(grad.values::<f32>()?.iter())
+                .map(|(a, b)| a + b)
+                .collect();
+            Tensor::new(total.shape().to_vec(), values)?
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{numeric_gradient, tensor};
+
+    // Records a two-layer network and its loss on a fresh tape; returns the tape, the loss and the leaves
+    // [x, w0, b0, w1, b1].
+    fn record(leaves: &[Tensor], hidden: Activation, output: Activation, cross_entropy: bool, y: &Tensor) -> (Tape, Var, Vec<Var>) {
+        let mut tape = Tape::new();
+        let vars: Vec<Var> = leaves.iter().map(|leaf| tape.leaf(leaf.clone())).collect();
+        let h = tape.dense(vars[0], vars[1], vars[2], hidden).unwrap();
+        let out = tape.dense(h, vars[3], vars[4], output).unwrap();
+        let loss = if cross_entropy {
+            tape.cross_entropy(out, y).unwrap()
+        } else {
+            tape.mse(out, y).unwrap()
+        };
+        (tape, loss, vars)
+    }
+
+    #[test]
+    fn test_gradients_match_finite_differences() {
+        let one_hot = Tensor::new(vec![3, 2], vec![1.0f32, 0.0, 0.0, 1.0, 1.0, 0.0]).unwrap();
+        let cases = [
+            (Activation::Tanh, Activation::Identity, false),
+            (Activation::Sigmoid, Activation::Sigmoid, false),
+            (Activation::Relu, Activation::Identity, false),
+            (Activation::Tanh, Activation::Softmax, true),
+            (Activation::Sigmoid, Activation::Softmax, false),
+        ];
+        for (hidden, output, cross_entropy) in cases {
+            let leaves = vec![
+                tensor(vec![3, 4], 0.0),
+                tensor(vec![4, 5], 1.0),
+                tensor(vec![5], 2.0),
+                tensor(vec![5, 2], 3.0),
+                tensor(vec![2], 4.0),
+            ];
+            let y = if cross_entropy { one_hot.clone() } else { tensor(vec![3, 2], 5.0) };
+            let (tape, loss, vars) = record(&leaves, hidden, output, cross_entropy, &y);
+            let gradients = tape.backward(loss).unwrap();
+
+            // Central differences in every coordinate of every leaf
+            let probe = Tensor::new(tape.value(loss).shape().to_vec(), vec![1.0f32]).unwrap();
+            for (index, leaf) in leaves.iter().enumerate() {
+                let analytic = gradients.get(vars[index]).unwrap().to_vec::<f32>().unwrap();
+                let numeric = numeric_gradient(leaf, &probe, |perturbed_leaf| {
+                    let mut perturbed = leaves.clone();
+                    perturbed[index] = perturbed_leaf.clone();
+                    let (tape, loss, _) = record(&perturbed, hidden, output, cross_entropy, &y);
+                    tape.value(loss).clone()
+                });
+                assert_eq!(analytic.len(), numeric.len());
+                for (coordinate, (found, numeric)) in analytic.iter().zip(&numeric).enumerate() {
+                    assert!(
+                        (found - numeric).abs() <= 1e-3 + 2e-2 * numeric.abs(),
+                        "{:?}/{:?}: leaf {} coordinate {}: {} vs {}",
+                        hidden,
+                        output,
+                        index,
+                        coordinate,
+                        found,
+                        numeric
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_variables_accumulate() {
+        // loss = (x·w·w + b)^2 uses w twice
+        let mut tape = Tape::new();
+        let x = tape.leaf(Tensor::new(vec![1, 1], vec![2.0f32]).unwrap());
+        let w = tape.leaf(Tensor::new(vec![1, 1], vec![3.0f32]).unwrap());
+        let b = tape.leaf(Tensor::new(vec![1], vec![0.0f32]).unwrap());
+        let once = tape.matmul(x, w).unwrap();
+        let twice = tape.matmul(once, w).unwrap();
+        let biased = tape.add_bias(twice, b).unwrap();
+        let loss = tape.mse(biased, &Tensor::new(vec![1, 1], vec![0.0f32]).unwrap()).unwrap();
+        let gradients = tape.backward(loss).unwrap();
+        // d/dw (x w^2)^2 = 4 x^2 w^3
+        assert_eq!(gradients.get(w).unwrap().to_vec::<f32>().unwrap(), vec![432.0]);
+        assert_eq!(gradients.get(b).unwrap().to_vec::<f32>().unwrap(), vec![36.0]);
+        assert!(tape.backward(once).is_ok());
+        let unused = tape.leaf(Tensor::scalar(1.0f32));
+        assert!(tape.backward(loss).unwrap().get(unused).is_none());
+    }
+}
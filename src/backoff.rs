@@ -0,0 +1,109 @@
+// backoff.rs: Implements retries with jittered exponential backoff for operations against remote services.
+
+use rand::Rng;
+use std::future::Future;
+use tokio::time::{sleep, Duration};
+use tracing::warn;
+
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // None retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Backoff {
+    pub fn new(base_delay: Duration, max_delay: Duration, max_attempts: Option<u32>) -> Self {
+        Backoff {
+            base_delay,
+            max_delay,
+            max_attempts,
+        }
+    }
+
+    // Full jitter: a random delay between zero and the capped exponential step for this attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.min(31)))
+            .min(self.max_delay);
+        let millis = exponential.as_millis() as u64;
+        if millis == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    pub fn exhausted(&self, attempt: u32) -> bool {
+        matches!(self.max_attempts, Some(max) if attempt + 1 >= max)
+    }
+
+    pub async fn retry<T, E, F, Fut>(&self, description: &str, mut operation: F) -> Result<T, E>
+    where
+        E: std::fmt::Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) if self.exhausted(attempt) => return Err(e),
+                Err(e) => {
+                    let delay = self.delay(attempt);
+                    warn!("{} failed (attempt {}): {:?}. Retrying in {:?}", description, attempt + 1, e, delay);
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(10), Some(5))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_is_capped() {
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(50), None);
+        for attempt in 0..40 {
+            assert!(backoff.delay(attempt) <= Duration::from_millis(50));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_succeeds_after_failures() {
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5), Some(5));
+        let mut calls = 0;
+        let result: Result<u32, &str> = backoff
+            .retry("flaky operation", || {
+                calls += 1;
+                let outcome = if calls < 3 { Err("not yet") } else { Ok(calls) };
+                async move { outcome }
+            })
+            .await;
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up_after_max_attempts() {
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5), Some(3));
+        let mut calls = 0;
+        let result: Result<(), &str> = backoff
+            .retry("failing operation", || {
+                calls += 1;
+                async { Err("always") }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+}
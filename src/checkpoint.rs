@@ -0,0 +1,402 @@
+// checkpoint.rs: Versioned, checksummed checkpoints of training jobs (weights, optimizer state and metadata), kept in a directory per model.
+
+use crate::kernels::Attrs;
+use crate::messages::CheckpointAck;
+use crate::model::{self, Model, ModelError, ModelSpec, Weights};
+use crate::optimizer::OptimizerState;
+use crate::tensor::{Tensor, TensorError};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+
+// Attributes that say how a job is checkpointed rather than what it trains; the config hash leaves them out
+const CONTROL_ATTRS: &[&str] = &["checkpoint_every"];
+// Names of the non-parameter tensors in an artifact
+const OPTIMIZER_PREFIX: &str = "optimizer/";
+const LOSSES_KEY: &str = "training/losses";
+
+// Describes a checkpoint; stored next to its artifact as `<version>.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckpointMetadata {
+    pub model: String,
+    // Numbers the model's checkpoints in the order they were written, from 1
+    pub version: u64,
+    // Training steps completed
+    pub step: u64,
+    // Loss of the last completed step
+    pub loss: Option<f32>,
+    // Identifies the training job, see config_hash
+    pub config_hash: String,
+    // SHA-256 of the artifact, in hex
+    pub checksum: String,
+    pub created_at: DateTime<Utc>,
+    // The model as the job started training it
+    pub spec: ModelSpec,
+}
+
+// Everything a training job needs to carry on from where it stopped.
+#[derive(Debug, Clone, Default)]
+pub struct TrainingState {
+    pub parameters: Weights,
+    pub optimizer: OptimizerState,
+    // Loss of every completed step
+    pub losses: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub metadata: CheckpointMetadata,
+    pub state: TrainingState,
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    InvalidModelName(String),
+    // The checkpoint is incomplete, damaged or does not match its metadata
+    Corrupt { path: PathBuf, reason: String },
+    Model(ModelError),
+    Io(io::Error),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::InvalidModelName(name) => write!(f, "cannot checkpoint a model named {:?}", name),
+            CheckpointError::Corrupt { path, reason } => write!(f, "checkpoint {} is corrupt: {}", path.display(), reason),
+            CheckpointError::Model(e) => write!(f, "{}", e),
+            CheckpointError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+impl From<ModelError> for CheckpointError {
+    fn from(e: ModelError) -> Self {
+        CheckpointError::Model(e)
+    }
+}
+
+impl From<TensorError> for CheckpointError {
+    fn from(e: TensorError) -> Self {
+        CheckpointError::Model(e.into())
+    }
+}
+
+impl From<&CheckpointMetadata> for CheckpointAck {
+    fn from(metadata: &CheckpointMetadata) -> Self {
+        CheckpointAck {
+            model: metadata.model.clone(),
+            version: metadata.version,
+            step: metadata.step,
+            error: None,
+        }
+    }
+}
+
+// Identifies a training job by the model it starts from and the attributes it was sent with, so that a
+// job sent again after a restart finds its own checkpoints and no other job's.
+pub fn config_hash(spec: &ModelSpec, attrs: &Attrs) -> String {
+    let mut attrs = attrs.clone();
+    attrs.retain(|name, _| !CONTROL_ATTRS.contains(&name.as_str()));
+    let mut hasher = Sha256::new();
+    hasher.update(spec.to_json().as_bytes());
+    hasher.update(serde_json::to_vec(&attrs).expect("attributes serialize to JSON"));
+    hex(&hasher.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Checkpoints under `dir`, as `<dir>/<model>/<version>.ckpt` (weights, optimizer state and losses in
+// the weights file format) and `<version>.json` (metadata). The metadata is written last, so a
+// checkpoint interrupted part-way is never listed.
+#[derive(Clone, Debug)]
+pub struct CheckpointStore {
+    dir: PathBuf,
+    // Saves on this node take versions one at a time
+    saving: Arc<Mutex<()>>,
+}
+
+impl CheckpointStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, CheckpointError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(CheckpointStore {
+            dir,
+            saving: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn model_dir(&self, model: &str) -> Result<PathBuf, CheckpointError> {
+        let valid = !model.is_empty()
+            && !model.starts_with('.')
+            && model.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !valid {
+            return Err(CheckpointError::InvalidModelName(model.to_string()));
+        }
+        Ok(self.dir.join(model))
+    }
+
+    // The model's checkpoint versions, oldest first.
+    pub fn versions(&self, model: &str) -> Result<Vec<u64>, CheckpointError> {
+        let entries = match fs::read_dir(self.model_dir(model)?) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut versions = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                if let Some(version) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                    versions.push(version);
+                }
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    // Writes the next version of `spec.name`'s checkpoints.
+    pub fn save(&self, spec: &ModelSpec, config_hash: &str, state: &TrainingState) -> Result<CheckpointMetadata, CheckpointError> {
+        let dir = self.model_dir(&spec.name)?;
+        let mut artifact = state.parameters.clone();
+        for (name, slot) in state.optimizer.to_weights() {
+            artifact.insert(format!("{}{}", OPTIMIZER_PREFIX, name), slot);
+        }
+        artifact.insert(LOSSES_KEY.to_string(), Tensor::new(vec![state.losses.len()], state.losses.clone())?);
+        let bytes = model::encode_weights(&artifact);
+
+        let _saving = self.saving.lock().unwrap();
+        fs::create_dir_all(&dir)?;
+        let version = self.versions(&spec.name)?.last().map_or(1, |latest| latest + 1);
+        let metadata = CheckpointMetadata {
+            model: spec.name.clone(),
+            version,
+            step: state.losses.len() as u64,
+            loss: state.losses.last().copied(),
+            config_hash: config_hash.to_string(),
+            checksum: hex(&Sha256::digest(&bytes)),
+            created_at: Utc::now(),
+            spec: spec.clone(),
+        };
+        write_atomically(&dir.join(format!("{:08}.ckpt", version)), &bytes)?;
+        let json = serde_json::to_vec_pretty(&metadata).expect("checkpoint metadata serializes to JSON");
+        write_atomically(&dir.join(format!("{:08}.json", version)), &json)?;
+        info!("Saved checkpoint {} of model {} at step {}", version, spec.name, metadata.step);
+        Ok(metadata)
+    }
+
+    pub fn metadata(&self, model: &str, version: u64) -> Result<CheckpointMetadata, CheckpointError> {
+        let path = self.model_dir(model)?.join(format!("{:08}.json", version));
+        let metadata: CheckpointMetadata = serde_json::from_slice(&fs::read(&path)?)
+            .map_err(|e| CheckpointError::Corrupt { path: path.clone(), reason: e.to_string() })?;
+        if metadata.model != model || metadata.version != version {
+            return Err(CheckpointError::Corrupt {
+                path,
+                reason: format!("describes {} checkpoint {}", metadata.model, metadata.version),
+            });
+        }
+        Ok(metadata)
+    }
+
+    // Reads a checkpoint, checking its artifact against the checksum and its weights against the spec.
+    pub fn load(&self, model: &str, version: u64) -> Result<Checkpoint, CheckpointError> {
+        let metadata = self.metadata(model, version)?;
+        let path = self.model_dir(model)?.join(format!("{:08}.ckpt", version));
+        let bytes = fs::read(&path)?;
+        let corrupt = |reason: String| CheckpointError::Corrupt { path: path.clone(), reason };
+        if hex(&Sha256::digest(&bytes)) != metadata.checksum {
+            return Err(corrupt("checksum mismatch".to_string()));
+        }
+
+        let mut state = TrainingState::default();
+        let mut optimizer = Weights::new();
+        for (name, tensor) in model::decode_weights(Bytes::from(bytes))? {
+            if name == LOSSES_KEY {
+                state.losses = tensor.to_vec::<f32>()?;
+            } else if let Some(slot) = name.strip_prefix(OPTIMIZER_PREFIX) {
+                optimizer.insert(slot.to_string(), tensor);
+            } else {
+                state.parameters.insert(name, tensor);
+            }
+        }
+        state.optimizer = OptimizerState::from_weights(optimizer).map_err(|e| corrupt(e.to_string()))?;
+        if state.losses.len() as u64 != metadata.step {
+            return Err(corrupt(format!("{} losses for {} steps", state.losses.len(), metadata.step)));
+        }
+        Model::load(metadata.spec.clone(), Some(state.parameters.clone()))?;
+        Ok(Checkpoint { metadata, state })
+    }
+
+    // The newest checkpoint of `model` that loads cleanly and that `accept` takes; damaged checkpoints
+    // are logged and passed over.
+    pub fn latest(&self, model: &str, accept: impl Fn(&CheckpointMetadata) -> bool) -> Result<Option<Checkpoint>, CheckpointError> {
+        for version in self.versions(model)?.into_iter().rev() {
+            let loaded = self.metadata(model, version).and_then(|metadata| match accept(&metadata) {
+                true => self.load(model, version).map(Some),
+                false => Ok(None),
+            });
+            match loaded {
+                Ok(Some(checkpoint)) => return Ok(Some(checkpoint)),
+                Ok(None) => {}
+                Err(e) => error!("Skipping checkpoint {} of model {}: {}", version, model, e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Writes through a temporary file and renames it into place, so readers never see part of a file.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    let mut file = File::create(&partial)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&partial, path)
+}
+
+// On-demand checkpoint requests for the training jobs running on this node, each answered at the end of
+// its job's next step.
+#[derive(Clone, Default)]
+pub struct CheckpointRequests {
+    jobs: Arc<Mutex<HashMap<String, Vec<oneshot::Sender<CheckpointAck>>>>>,
+}
+
+impl CheckpointRequests {
+    // Marks a job training `model` as running until the returned guard is dropped.
+    pub fn track(&self, model: &str) -> TrackedJob {
+        self.jobs.lock().unwrap().entry(model.to_string()).or_default();
+        TrackedJob {
+            requests: self.clone(),
+            model: model.to_string(),
+        }
+    }
+
+    // Queues a request with the job training `model`, or returns None when no such job is running.
+    pub fn request(&self, model: &str) -> Option<oneshot::Receiver<CheckpointAck>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (reply, pending) = oneshot::channel();
+        jobs.get_mut(model)?.push(reply);
+        Some(pending)
+    }
+}
+
+pub struct TrackedJob {
+    requests: CheckpointRequests,
+    model: String,
+}
+
+impl TrackedJob {
+    // The requests made since the last call.
+    pub fn take(&self) -> Vec<oneshot::Sender<CheckpointAck>> {
+        let mut jobs = self.requests.jobs.lock().unwrap();
+        jobs.get_mut(&self.model).map(std::mem::take).unwrap_or_default()
+    }
+}
+
+// Requests still waiting when the job stops see their reply dropped.
+impl Drop for TrackedJob {
+    fn drop(&mut self) {
+        self.requests.jobs.lock().unwrap().remove(&self.model);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> ModelSpec {
+        ModelSpec::from_json(
+            r#"{"name": "tiny", "inputs": 2, "seed": 3, "layers": [{"type": "dense", "name": "out", "units": 1}]}"#,
+        )
+        .unwrap()
+    }
+
+    fn state(steps: usize) -> TrainingState {
+        let mut slots = Weights::new();
+        slots.insert("out.weights:velocity".to_string(), Tensor::new(vec![2, 1], vec![0.5f32, -0.5]).unwrap());
+        let optimizer = OptimizerState { step: steps as u64, slots };
+        TrainingState {
+            parameters: Model::load(spec(), None).unwrap().weights(),
+            optimizer,
+            losses: (0..steps).map(|step| 1.0 / (step as f32 + 1.0)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_latest_valid_checkpoint_survives_corruption() {
+        let dir = std::env::temp_dir().join(format!("checkpoint_test_{}", uuid::Uuid::new_v4()));
+        let store = CheckpointStore::open(&dir).unwrap();
+        assert!(store.latest("tiny", |_| true).unwrap().is_none());
+
+        let hash = config_hash(&spec(), &Attrs::new());
+        for steps in [2, 4, 6] {
+            store.save(&spec(), &hash, &state(steps)).unwrap();
+        }
+        assert_eq!(store.versions("tiny").unwrap(), vec![1, 2, 3]);
+        let latest = store.latest("tiny", |_| true).unwrap().unwrap();
+        assert_eq!((latest.metadata.version, latest.metadata.step, latest.metadata.loss), (3, 6, Some(1.0 / 6.0)));
+        assert_eq!(latest.state.optimizer, state(6).optimizer);
+        assert_eq!(latest.state.parameters, state(6).parameters);
+
+        // A flipped byte in the newest artifact falls back to the one before
+        let artifact = dir.join("tiny").join("00000003.ckpt");
+        let mut bytes = fs::read(&artifact).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&artifact, bytes).unwrap();
+        assert!(matches!(store.load("tiny", 3), Err(CheckpointError::Corrupt { .. })));
+        assert_eq!(store.latest("tiny", |_| true).unwrap().unwrap().metadata.step, 4);
+        // Checkpoints of other jobs are passed over
+        assert_eq!(store.latest("tiny", |metadata| metadata.step < 4).unwrap().unwrap().metadata.version, 1);
+
+        assert!(store.save(&ModelSpec { name: "../escape".to_string(), ..spec() }, &hash, &state(1)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_hash_ignores_checkpoint_attrs() {
+        let mut attrs = Attrs::new();
+        attrs.insert("steps".to_string(), 10i64.into());
+        let hash = config_hash(&spec(), &attrs);
+        attrs.insert("checkpoint_every".to_string(), 2i64.into());
+        assert_eq!(config_hash(&spec(), &attrs), hash);
+        attrs.insert("steps".to_string(), 20i64.into());
+        assert_ne!(config_hash(&spec(), &attrs), hash);
+    }
+
+    #[tokio::test]
+    async fn test_requests_wait_for_a_running_job() {
+        let requests = CheckpointRequests::default();
+        assert!(requests.request("tiny").is_none());
+
+        let job = requests.track("tiny");
+        let pending = requests.request("tiny").unwrap();
+        for reply in job.take() {
+            reply.send(CheckpointAck { model: "tiny".to_string(), version: 1, step: 2, error: None }).unwrap();
+        }
+        assert_eq!(pending.await.unwrap().version, 1);
+
+        let abandoned = requests.request("tiny").unwrap();
+        drop(job);
+        assert!(abandoned.await.is_err());
+        assert!(requests.request("tiny").is_none());
+    }
+}
@@ -0,0 +1,412 @@
+// codec.rs: Implements pluggable payload codecs and compression, selected per message through transport headers.
+
+use crate::messaging::{Headers, TransportError};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+pub const CONTENT_ENCODING_HEADER: &str = "content-encoding";
+
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 64 * 1024;
+// Compressed payloads that would inflate beyond this are rejected rather than decompressed
+pub const MAX_DECOMPRESSED_SIZE: usize = 512 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Json,
+    MessagePack,
+    Cbor,
+    Bincode,
+}
+
+impl Codec {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::MessagePack => "application/msgpack",
+            Codec::Cbor => "application/cbor",
+            Codec::Bincode => "application/x-bincode",
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Codec> {
+        match content_type {
+            "application/json" => Some(Codec::Json),
+            "application/msgpack" => Some(Codec::MessagePack),
+            "application/cbor" => Some(Codec::Cbor),
+            "application/x-bincode" => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "json" => Some(Codec::Json),
+            "msgpack" | "messagepack" => Some(Codec::MessagePack),
+            "cbor" => Some(Codec::Cbor),
+            "bincode" => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+
+    // Codecs compiled into this build. JSON is always available.
+    pub fn available() -> Vec<Codec> {
+        let mut codecs = vec![Codec::Json];
+        if cfg!(feature = "msgpack") {
+            codecs.push(Codec::MessagePack);
+        }
+        if cfg!(feature = "cbor") {
+            codecs.push(Codec::Cbor);
+        }
+        if cfg!(feature = "bincode") {
+            codecs.push(Codec::Bincode);
+        }
+        codecs
+    }
+
+    // Whether readers can skip fields they do not know and default fields that are missing. Bincode
+    // lays out fields by position alone, so only nodes on the same schema version can read each other.
+    pub fn is_self_describing(&self) -> bool {
+        !matches!(self, Codec::Bincode)
+    }
+
+    // Picks `preferred` if the peer can read it, falling back to JSON. A codec that is not self-describing
+    // is only picked for a peer on the same schema version.
+    pub fn negotiate(preferred: Codec, accepted_by_peer: &[Codec], same_version: bool) -> Codec {
+        if accepted_by_peer.contains(&preferred) && (same_version || preferred.is_self_describing()) {
+            preferred
+        } else {
+            Codec::Json
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TransportError> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            Codec::MessagePack => {
+                #[cfg(feature = "msgpack")]
+                {
+                    // Named fields keep the format self-describing, so older readers can skip new fields.
+                    Ok(rmp_serde::to_vec_named(value)?)
+                }
+                #[cfg(not(feature = "msgpack"))]
+                Err(unsupported(*self))
+            }
+            Codec::Cbor => {
+                #[cfg(feature = "cbor")]
+                {
+                    let mut buffer = Vec::new();
+                    ciborium::ser::into_writer(value, &mut buffer)?;
+                    Ok(buffer)
+                }
+                #[cfg(not(feature = "cbor"))]
+                Err(unsupported(*self))
+            }
+            Codec::Bincode => {
+                #[cfg(feature = "bincode")]
+                {
+                    Ok(bincode::serialize(value)?)
+                }
+                #[cfg(not(feature = "bincode"))]
+                Err(unsupported(*self))
+            }
+        }
+    }
+
+    // Decoding ignores trailing data, so a prefix of a struct can be read on its own.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TransportError> {
+        match self {
+            Codec::Json => Ok(serde_json::Deserializer::from_slice(bytes)
+                .into_iter()
+                .next()
+                .ok_or("empty JSON payload")??),
+            Codec::MessagePack => {
+                #[cfg(feature = "msgpack")]
+                {
+                    Ok(rmp_serde::from_slice(bytes)?)
+                }
+                #[cfg(not(feature = "msgpack"))]
+                Err(unsupported(*self))
+            }
+            Codec::Cbor => {
+                #[cfg(feature = "cbor")]
+                {
+                    Ok(ciborium::de::from_reader(bytes)?)
+                }
+                #[cfg(not(feature = "cbor"))]
+                Err(unsupported(*self))
+            }
+            Codec::Bincode => {
+                #[cfg(feature = "bincode")]
+                {
+                    Ok(bincode::deserialize(bytes)?)
+                }
+                #[cfg(not(feature = "bincode"))]
+                Err(unsupported(*self))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zstd"),
+            Compression::Lz4 => Some("lz4"),
+        }
+    }
+
+    pub fn from_content_encoding(encoding: &str) -> Option<Compression> {
+        match encoding {
+            "identity" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Zstd => {
+                #[cfg(feature = "zstd")]
+                {
+                    Ok(zstd::bulk::compress(&bytes, 3)?)
+                }
+                #[cfg(not(feature = "zstd"))]
+                Err("zstd compression is not enabled in this build".into())
+            }
+            Compression::Lz4 => {
+                #[cfg(feature = "lz4")]
+                {
+                    Ok(lz4_flex::compress_prepend_size(&bytes))
+                }
+                #[cfg(not(feature = "lz4"))]
+                Err("lz4 compression is not enabled in this build".into())
+            }
+        }
+    }
+
+    // Fails once the output would exceed `max_size` bytes, without allocating more than that.
+    fn decompress(&self, bytes: &[u8], max_size: usize) -> Result<Vec<u8>, TransportError> {
+        let too_large = || format!("{:?} payload decompresses to more than {} bytes", self, max_size).into();
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Zstd => {
+                #[cfg(feature = "zstd")]
+                {
+                    use std::io::Read;
+                    let mut decompressed = Vec::new();
+                    zstd::stream::read::Decoder::new(bytes)?
+                        .take(max_size as u64 + 1)
+                        .read_to_end(&mut decompressed)?;
+                    if decompressed.len() > max_size {
+                        return Err(too_large());
+                    }
+                    Ok(decompressed)
+                }
+                #[cfg(not(feature = "zstd"))]
+                Err("zstd compression is not enabled in this build".into())
+            }
+            Compression::Lz4 => {
+                #[cfg(feature = "lz4")]
+                {
+                    let (size, block) = lz4_flex::block::uncompressed_size(bytes)?;
+                    if size > max_size {
+                        return Err(too_large());
+                    }
+                    Ok(lz4_flex::block::decompress(block, size)?)
+                }
+                #[cfg(not(feature = "lz4"))]
+                Err("lz4 compression is not enabled in this build".into())
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn unsupported(codec: Codec) -> TransportError {
+    format!("{:?} codec is not enabled in this build", codec).into()
+}
+
+#[derive(Clone, Debug)]
+pub struct CodecConfig {
+    pub codec: Codec,
+    pub compression: Compression,
+    // Payloads smaller than this are sent uncompressed
+    pub compression_threshold: usize,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig {
+            codec: Codec::Json,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl CodecConfig {
+    // Reads MESSAGE_CODEC, MESSAGE_COMPRESSION and COMPRESSION_THRESHOLD, keeping defaults for anything unset.
+    pub fn from_env() -> Self {
+        let mut config = CodecConfig::default();
+        if let Some(codec) = std::env::var("MESSAGE_CODEC").ok().and_then(|name| Codec::from_name(&name)) {
+            config.codec = codec;
+        }
+        if let Some(compression) = std::env::var("MESSAGE_COMPRESSION")
+            .ok()
+            .and_then(|name| Compression::from_content_encoding(&name))
+        {
+            config.compression = compression;
+        }
+        if let Some(threshold) = std::env::var("COMPRESSION_THRESHOLD").ok().and_then(|t| t.parse().ok()) {
+            config.compression_threshold = threshold;
+        }
+        config
+    }
+
+    // Process-wide configuration used by publishers that are not given one explicitly.
+    pub fn global() -> &'static CodecConfig {
+        static CONFIG: OnceLock<CodecConfig> = OnceLock::new();
+        CONFIG.get_or_init(CodecConfig::from_env)
+    }
+
+    pub fn with_codec(&self, codec: Codec) -> Self {
+        CodecConfig {
+            codec,
+            ..self.clone()
+        }
+    }
+
+    // Serializes `value` and returns the bytes together with the headers a receiver needs to decode them.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<(Vec<u8>, Headers), TransportError> {
+        let mut headers = Headers::new();
+        headers.insert(CONTENT_TYPE_HEADER.to_string(), self.codec.content_type().to_string());
+
+        let mut bytes = self.codec.encode(value)?;
+        if bytes.len() >= self.compression_threshold {
+            if let Some(encoding) = self.compression.content_encoding() {
+                bytes = self.compression.compress(bytes)?;
+                headers.insert(CONTENT_ENCODING_HEADER.to_string(), encoding.to_string());
+            }
+        }
+        Ok((bytes, headers))
+    }
+}
+
+// Decodes a payload using the codec and compression named in its headers. Messages without a
+// content-type header are treated as JSON, which is what nodes sent before codecs existed.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8], headers: &Headers) -> Result<T, TransportError> {
+    let codec = match headers.get(CONTENT_TYPE_HEADER) {
+        Some(content_type) => Codec::from_content_type(content_type)
+            .ok_or_else(|| format!("Unknown content type: {}", content_type))?,
+        None => Codec::Json,
+    };
+    match headers.get(CONTENT_ENCODING_HEADER) {
+        Some(encoding) => {
+            let compression = Compression::from_content_encoding(encoding)
+                .ok_or_else(|| format!("Unknown content encoding: {}", encoding))?;
+            codec.decode(&compression.decompress(bytes, MAX_DECOMPRESSED_SIZE)?)
+        }
+        None => codec.decode(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Sample {
+        name: String,
+        values: Vec<f32>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct SamplePrefix {
+        name: String,
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            name: "weights".to_string(),
+            values: (0..1024).map(|i| i as f32 * 0.5).collect(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_with_every_available_codec() {
+        for codec in Codec::available() {
+            let config = CodecConfig::default().with_codec(codec);
+            let (bytes, headers) = config.encode(&sample()).unwrap();
+            assert_eq!(headers[CONTENT_TYPE_HEADER], codec.content_type());
+            assert_eq!(decode::<Sample>(&bytes, &headers).unwrap(), sample());
+
+            // Readers can pick the leading fields out of a message without knowing the rest
+            assert_eq!(decode::<SamplePrefix>(&bytes, &headers).unwrap().name, "weights");
+        }
+    }
+
+    #[test]
+    fn test_compression_above_threshold() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            if compression.compress(Vec::new()).is_err() {
+                continue;
+            }
+            let config = CodecConfig {
+                codec: Codec::Json,
+                compression,
+                compression_threshold: 128,
+            };
+            let (bytes, headers) = config.encode(&sample()).unwrap();
+            assert_eq!(headers.get(CONTENT_ENCODING_HEADER).map(String::as_str), compression.content_encoding());
+            assert_eq!(decode::<Sample>(&bytes, &headers).unwrap(), sample());
+
+            let small = Sample {
+                name: "tiny".to_string(),
+                values: vec![],
+            };
+            let (_, headers) = config.encode(&small).unwrap();
+            assert!(!headers.contains_key(CONTENT_ENCODING_HEADER));
+        }
+    }
+
+    #[test]
+    fn test_decompression_is_capped() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let Ok(bomb) = compression.compress(vec![0u8; 1 << 20]) else {
+                continue;
+            };
+            assert!(bomb.len() < 1 << 14);
+            assert_eq!(compression.decompress(&bomb, 1 << 20).unwrap().len(), 1 << 20);
+            assert!(compression.decompress(&bomb, (1 << 20) - 1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_missing_content_type_defaults_to_json() {
+        let bytes = serde_json::to_vec(&sample()).unwrap();
+        assert_eq!(decode::<Sample>(&bytes, &Headers::new()).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_negotiate_falls_back_to_json() {
+        assert_eq!(Codec::negotiate(Codec::Bincode, &[Codec::Json, Codec::Bincode], true), Codec::Bincode);
+        assert_eq!(Codec::negotiate(Codec::Bincode, &[Codec::Json], true), Codec::Json);
+        // Bincode cannot carry fields across minor versions
+        assert_eq!(Codec::negotiate(Codec::Bincode, &[Codec::Json, Codec::Bincode], false), Codec::Json);
+        assert_eq!(Codec::negotiate(Codec::Cbor, &[Codec::Json, Codec::Cbor], false), Codec::Cbor);
+    }
+}
@@ -0,0 +1,629 @@
+// conv.rs: Convolution, pooling, batch-norm and flatten kernels for CNN workloads, forward and backward, over NCHW tensors.
+
+use crate::kernels::{self, Activation, Attr, Attrs, KernelError};
+use crate::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_EPSILON: f64 = 1e-5;
+
+// How a kernel window slides over the height and width of an image. Each pair is (height, width).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window2d {
+    pub kernel: [usize; 2],
+    pub stride: [usize; 2],
+    pub padding: [usize; 2],
+    pub dilation: [usize; 2],
+}
+
+impl Window2d {
+    // From the attributes kernel_size, stride, padding and dilation, each an integer or a pair. `kernel`
+    // is the kernel size when it comes from a weight tensor; the stride defaults to `default_stride`.
+    pub fn from_attrs(attrs: &Attrs, kernel: Option<[usize; 2]>, default_stride: Option<[usize; 2]>) -> Result<Self, KernelError> {
+        let kernel = match kernel {
+            Some(kernel) => kernel,
+            None => pair_attr(attrs, "kernel_size", 1)?
+                .ok_or_else(|| KernelError::InvalidInput("pooling needs a kernel_size attribute".to_string()))?,
+        };
+        let window = Window2d {
+            kernel,
+            stride: pair_attr(attrs, "stride", 1)?.or(default_stride).unwrap_or([1, 1]),
+            padding: pair_attr(attrs, "padding", 0)?.unwrap_or([0, 0]),
+            dilation: pair_attr(attrs, "dilation", 1)?.unwrap_or([1, 1]),
+        };
+        window.validate()?;
+        Ok(window)
+    }
+
+    pub fn validate(&self) -> Result<(), KernelError> {
+        if self.kernel.contains(&0) || self.stride.contains(&0) || self.dilation.contains(&0) {
+            return Err(KernelError::InvalidInput(format!("kernel size, stride and dilation must be positive, got {:?}", self)));
+        }
+        Ok(())
+    }
+
+    // The height and width of the output for an input of `[height, width]`
+    pub fn output_size(&self, [height, width]: [usize; 2]) -> Result<[usize; 2], KernelError> {
+        let mut size = [0; 2];
+        for (axis, input) in [height, width].into_iter().enumerate() {
+            let extent = self.dilation[axis] * (self.kernel[axis] - 1) + 1;
+            let padded = input + 2 * self.padding[axis];
+            if padded < extent {
+                return Err(KernelError::InvalidInput(format!(
+                    "a {:?} window does not fit a {}x{} input",
+                    self.kernel, height, width
+                )));
+            }
+            size[axis] = (padded - extent) / self.stride[axis] + 1;
+        }
+        Ok(size)
+    }
+
+    // The input position under kernel offset `offset` of output position `output` along `axis`, or None
+    // where the window hangs over the padding.
+    fn input_position(&self, axis: usize, output: usize, offset: usize, input: usize) -> Option<usize> {
+        (output * self.stride[axis] + offset * self.dilation[axis])
+            .checked_sub(self.padding[axis])
+            .filter(|&position| position < input)
+    }
+}
+
+fn pair_attr(attrs: &Attrs, name: &str, min: i64) -> Result<Option<[usize; 2]>, KernelError> {
+    let pair = match attrs.get(name) {
+        None => return Ok(None),
+        Some(Attr::Int(value)) => [*value, *value],
+        Some(Attr::Ints(values)) if values.len() == 2 => [values[0], values[1]],
+        Some(other) => {
+            return Err(KernelError::InvalidInput(format!(
+                "attribute {} should be an integer or a pair of them, got {:?}",
+                name, other
+            )))
+        }
+    };
+    if pair.iter().any(|&value| value < min) {
+        return Err(KernelError::InvalidInput(format!("attribute {} must be at least {}, got {:?}", name, min, pair)));
+    }
+    Ok(Some([pair[0] as usize, pair[1] as usize]))
+}
+
+// The shape of an NCHW tensor as (batch, channels, height, width).
+fn image_shape(op: &str, tensor: &Tensor) -> Result<[usize; 4], KernelError> {
+    match tensor.shape() {
+        &[n, c, h, w] => Ok([n, c, h, w]),
+        shape => Err(KernelError::InvalidInput(format!("{} expects an NCHW tensor, got shape {:?}", op, shape))),
+    }
+}
+
+// Unfolds one image [C, H, W] into columns [C * KH * KW, OH * OW], so that the convolution becomes a
+// matrix product with the weights.
+fn im2col(image: &[f32], [c, h, w]: [usize; 3], window: &Window2d, [oh, ow]: [usize; 2]) -> Vec<f32> {
+    let [kh, kw] = window.kernel;
+    let mut columns = vec![0.0; c * kh * kw * oh * ow];
+    for channel in 0..c {
+        for i in 0..kh {
+            for j in 0..kw {
+                let row = ((channel * kh + i) * kw + j) * oh * ow;
+                for oy in 0..oh {
+                    let Some(y) = window.input_position(0, oy, i, h) else {
+                        continue;
+                    };
+                    for ox in 0..ow {
+                        if let Some(x) = window.input_position(1, ox, j, w) {
+                            columns[row + oy * ow + ox] = image[(channel * h + y) * w + x];
+                        }
+                    }
+                }
+            }
+        }
+    }
+    columns
+}
+
+// The inverse of `im2col`: adds each column entry back onto the image position it came from.
+fn col2im(columns: &[f32], [c, h, w]: [usize; 3], window: &Window2d, [oh, ow]: [usize; 2], image: &mut [f32]) {
+    let [kh, kw] = window.kernel;
+    for channel in 0..c {
+        for i in 0..kh {
+            for j in 0..kw {
+                let row = ((channel * kh + i) * kw + j) * oh * ow;
+                for oy in 0..oh {
+                    let Some(y) = window.input_position(0, oy, i, h) else {
+                        continue;
+                    };
+                    for ox in 0..ow {
+                        if let Some(x) = window.input_position(1, ox, j, w) {
+                            image[(channel * h + y) * w + x] += columns[row + oy * ow + ox];
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn weight_shape(weights: &Tensor, channels: usize, window: &Window2d) -> Result<[usize; 4], KernelError> {
+    let [f, c, kh, kw] = image_shape("conv2d weights", weights)?;
+    if c != channels {
+        return Err(KernelError::InvalidInput(format!("conv2d weights expect {} channels, the input has {}", c, channels)));
+    }
+    if window.kernel != [kh, kw] {
+        return Err(KernelError::InvalidInput(format!("{:?} weights for a {:?} window", weights.shape(), window.kernel)));
+    }
+    Ok([f, c, kh, kw])
+}
+
+// x [N, C, H, W], weights [F, C, KH, KW] and bias [F] to [N, F, OH, OW]; the window's kernel is the
+// weights' spatial size.
+pub fn conv2d(x: &Tensor, weights: &Tensor, bias: &Tensor, window: &Window2d) -> Result<Tensor, KernelError> {
+    let [n, c, h, w] = image_shape("conv2d", x)?;
+    let [f, _, kh, kw] = weight_shape(weights, c, window)?;
+    bias.expect_shape(&[f])?;
+    let out = window.output_size([h, w])?;
+    let (patch, positions) = (c * kh * kw, out[0] * out[1]);
+    let (x_values, w_values, b_values) = (x.values::<f32>()?, weights.values::<f32>()?, bias.values::<f32>()?);
+
+    let mut y = Vec::with_capacity(n * f * positions);
+    for image in x_values.chunks_exact(c * h * w) {
+        let columns = im2col(image, [c, h, w], window, out);
+        let mut product = kernels::matmul_values(&w_values, &columns, f, patch, positions);
+        for (filter, row) in product.chunks_exact_mut(positions).enumerate() {
+            row.iter_mut().for_each(|value| *value += b_values[filter]);
+        }
+        y.extend(product);
+    }
+    Ok(Tensor::new(vec![n, f, out[0], out[1]], y)?)
+}
+
+// The gradients of conv2d with respect to x, the weights and the bias, given the gradient `grad` [N, F, OH, OW]
+// with respect to its output.
+pub fn conv2d_backward(x: &Tensor, weights: &Tensor, grad: &Tensor, window: &Window2d) -> Result<(Tensor, Tensor, Tensor), KernelError> {
+    let [n, c, h, w] = image_shape("conv2d_backward", x)?;
+    let [f, _, kh, kw] = weight_shape(weights, c, window)?;
+    let out = window.output_size([h, w])?;
+    grad.expect_shape(&[n, f, out[0], out[1]])?;
+    let (patch, positions) = (c * kh * kw, out[0] * out[1]);
+    let (x_values, w_values, g_values) = (x.values::<f32>()?, weights.values::<f32>()?, grad.values::<f32>()?);
+    let w_transposed = kernels::transpose(&w_values, f, patch);
+
+    let mut dx = vec![0.0; x.len()];
+    let mut dw = vec![0.0; weights.len()];
+    let mut db = vec![0.0; f];
+    for ((image, g), dimage) in x_values
+        .chunks_exact(c * h * w)
+        .zip(g_values.chunks_exact(f * positions))
+        .zip(dx.chunks_exact_mut(c * h * w))
+    {
+        let columns = im2col(image, [c, h, w], window, out);
+        let product = kernels::matmul_values(g, &kernels::transpose(&columns, patch, positions), f, positions, patch);
+        dw.iter_mut().zip(product).for_each(|(total, value)| *total += value);
+        for (total, row) in db.iter_mut().zip(g.chunks_exact(positions)) {
+            *total += row.iter().sum::<f32>();
+        }
+        let dcolumns = kernels::matmul_values(&w_transposed, g, patch, f, positions);
+        col2im(&dcolumns, [c, h, w], window, out, dimage);
+    }
+    Ok((
+        Tensor::new(x.shape().to_vec(), dx)?,
+        Tensor::new(weights.shape().to_vec(), dw)?,
+        Tensor::new(vec![f], db)?,
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pool {
+    Max,
+    // Over the positions of each window inside the image; padding does not count
+    Avg,
+}
+
+impl Pool {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pool::Max => "max_pool2d",
+            Pool::Avg => "avg_pool2d",
+        }
+    }
+
+    // Calls `visit` with each output index and the input indices of its window, per image and channel.
+    fn windows(self, shape: [usize; 4], window: &Window2d, mut visit: impl FnMut(usize, &[usize])) -> Result<[usize; 2], KernelError> {
+        let [n, c, h, w] = shape;
+        let [oh, ow] = window.output_size([h, w])?;
+        let mut inputs = Vec::with_capacity(window.kernel[0] * window.kernel[1]);
+        for plane in 0..n * c {
+            for oy in 0..oh {
+                for ox in 0..ow {
+                    inputs.clear();
+                    for i in 0..window.kernel[0] {
+                        let Some(y) = window.input_position(0, oy, i, h) else {
+                            continue;
+                        };
+                        for j in 0..window.kernel[1] {
+                            if let Some(x) = window.input_position(1, ox, j, w) {
+                                inputs.push((plane * h + y) * w + x);
+                            }
+                        }
+                    }
+                    visit((plane * oh + oy) * ow + ox, &inputs);
+                }
+            }
+        }
+        Ok([oh, ow])
+    }
+
+    // x [N, C, H, W] to [N, C, OH, OW]
+    pub fn forward(self, x: &Tensor, window: &Window2d) -> Result<Tensor, KernelError> {
+        let [n, c, h, w] = image_shape(self.name(), x)?;
+        let values = x.values::<f32>()?;
+        let mut y = Vec::new();
+        let [oh, ow] = self.windows([n, c, h, w], window, |_, inputs| {
+            y.push(match self {
+                Pool::Max => inputs.iter().map(|&i| values[i]).fold(f32::NEG_INFINITY, f32::max),
+                Pool::Avg => inputs.iter().map(|&i| values[i]).sum::<f32>() / inputs.len().max(1) as f32,
+            });
+        })?;
+        Ok(Tensor::new(vec![n, c, oh, ow], y)?)
+    }
+
+    // The gradient with respect to x, given the gradient `grad` with respect to the output. Max pooling
+    // passes each gradient to the first maximum of its window.
+    pub fn backward(self, x: &Tensor, grad: &Tensor, window: &Window2d) -> Result<Tensor, KernelError> {
+        let [n, c, h, w] = image_shape(self.name(), x)?;
+        let [oh, ow] = window.output_size([h, w])?;
+        grad.expect_shape(&[n, c, oh, ow])?;
+        let (values, g) = (x.values::<f32>()?, grad.values::<f32>()?);
+        let mut dx = vec![0.0; x.len()];
+        self.windows([n, c, h, w], window, |output, inputs| match self {
+            Pool::Max => {
+                let first_max = inputs.iter().copied().reduce(|best, i| if values[i] > values[best] { i } else { best });
+                if let Some(i) = first_max {
+                    dx[i] += g[output];
+                }
+            }
+            Pool::Avg => {
+                for &i in inputs {
+                    dx[i] += g[output] / inputs.len() as f32;
+                }
+            }
+        })?;
+        Ok(Tensor::new(x.shape().to_vec(), dx)?)
+    }
+}
+
+// Batch normalization statistics are per channel, the second axis, over the batch and any spatial axes.
+// Returns (channels, elements per channel per example).
+fn channels(op: &str, x: &Tensor) -> Result<(usize, usize), KernelError> {
+    match x.shape() {
+        [_, c, spatial @ ..] => Ok((*c, spatial.iter().product())),
+        shape => Err(KernelError::InvalidInput(format!("{} expects [N, C, ...], got shape {:?}", op, shape))),
+    }
+}
+
+// Calls `visit` with the channel of every element of x, in order
+fn per_channel(values: &[f32], c: usize, spatial: usize, mut visit: impl FnMut(usize, usize, f32)) {
+    for (i, value) in values.iter().enumerate() {
+        visit(i, i / spatial.max(1) % c, *value);
+    }
+}
+
+// Normalizes x with the batch's own statistics, then scales by gamma [C] and shifts by beta [C]. Returns
+// the output and the batch mean and (biased) variance of each channel.
+pub fn batch_norm(x: &Tensor, gamma: &Tensor, beta: &Tensor, epsilon: f32) -> Result<(Tensor, Tensor, Tensor), KernelError> {
+    let (c, spatial) = channels("batch_norm", x)?;
+    let values = x.values::<f32>()?;
+    let count = (x.len() / c.max(1)).max(1) as f32;
+    let mut mean = vec![0.0; c];
+    per_channel(&values, c, spatial, |_, channel, value| mean[channel] += value / count);
+    let mut variance = vec![0.0; c];
+    per_channel(&values, c, spatial, |_, channel, value| variance[channel] += (value - mean[channel]).powi(2) / count);
+    let mean = Tensor::new(vec![c], mean)?;
+    let variance = Tensor::new(vec![c], variance)?;
+    let y = batch_norm_inference(x, gamma, beta, &mean, &variance, epsilon)?;
+    Ok((y, mean, variance))
+}
+
+// Normalizes x with given statistics, such as running averages kept from training.
+pub fn batch_norm_inference(
+    x: &Tensor,
+    gamma: &Tensor,
+    beta: &Tensor,
+    mean: &Tensor,
+    variance: &Tensor,
+    epsilon: f32,
+) -> Result<Tensor, KernelError> {
+    let (c, spatial) = channels("batch_norm", x)?;
+    for parameter in [gamma, beta, mean, variance] {
+        parameter.expect_shape(&[c])?;
+    }
+    let (gamma, beta) = (gamma.values::<f32>()?, beta.values::<f32>()?);
+    let (mean, variance) = (mean.values::<f32>()?, variance.values::<f32>()?);
+    let mut y = x.to_vec::<f32>()?;
+    let values = y.clone();
+    per_channel(&values, c, spatial, |i, channel, value| {
+        y[i] = gamma[channel] * (value - mean[channel]) / (variance[channel] + epsilon).sqrt() + beta[channel];
+    });
+    Ok(Tensor::new(x.shape().to_vec(), y)?)
+}
+
+// The gradients of batch_norm, with batch statistics, with respect to x, gamma and beta.
+pub fn batch_norm_backward(x: &Tensor, gamma: &Tensor, grad: &Tensor, epsilon: f32) -> Result<(Tensor, Tensor, Tensor), KernelError> {
+    let (c, spatial) = channels("batch_norm_backward", x)?;
+    gamma.expect_shape(&[c])?;
+    grad.expect_shape(x.shape())?;
+    let zeros = Tensor::new(vec![c], vec![0.0f32; c])?;
+    let (_, mean, variance) = batch_norm(x, gamma, &zeros, epsilon)?;
+    let (mean, variance) = (mean.values::<f32>()?, variance.values::<f32>()?);
+    let (values, g, gamma) = (x.values::<f32>()?, grad.values::<f32>()?, gamma.values::<f32>()?);
+    let count = (x.len() / c.max(1)).max(1) as f32;
+    let inverse_std: Vec<f32> = variance.iter().map(|variance| 1.0 / (variance + epsilon).sqrt()).collect();
+    let normalized = |i: usize, channel: usize| (values[i] - mean[channel]) * inverse_std[channel];
+
+    let (mut dgamma, mut dbeta) = (vec![0.0; c], vec![0.0; c]);
+    per_channel(&values, c, spatial, |i, channel, _| {
+        dbeta[channel] += g[i];
+        dgamma[channel] += g[i] * normalized(i, channel);
+    });
+    let mut dx = vec![0.0; x.len()];
+    per_channel(&values, c, spatial, |i, channel, _| {
+        dx[i] = gamma[channel] * inverse_std[channel]
+            * (g[i] - dbeta[channel] / count - normalized(i, channel) * dgamma[channel] / count);
+    });
+    Ok((
+        Tensor::new(x.shape().to_vec(), dx)?,
+        Tensor::new(vec![c], dgamma)?,
+        Tensor::new(vec![c], dbeta)?,
+    ))
+}
+
+// [N, ...] to [N, product of the rest]
+pub fn flatten(x: &Tensor) -> Result<Tensor, KernelError> {
+    let rows = *x
+        .shape()
+        .first()
+        .ok_or_else(|| KernelError::InvalidInput("cannot flatten a scalar".to_string()))?;
+    Ok(x.reshape(vec![rows, x.shape()[1..].iter().product()])?)
+}
+
+// The `epsilon` attribute of a normalization, DEFAULT_EPSILON when absent.
+pub fn epsilon_attr(attrs: &Attrs) -> Result<f32, KernelError> {
+    let epsilon = kernels::float_attr(attrs, "epsilon")?.unwrap_or(DEFAULT_EPSILON);
+    if epsilon <= 0.0 {
+        return Err(KernelError::InvalidInput(format!("epsilon must be positive, got {}", epsilon)));
+    }
+    Ok(epsilon as f32)
+}
+
+fn conv_window(attrs: &Attrs, weights: &Tensor) -> Result<Window2d, KernelError> {
+    let [_, _, kh, kw] = image_shape("conv2d weights", weights)?;
+    Window2d::from_attrs(attrs, Some([kh, kw]), None)
+}
+
+// inputs: x [N, C, H, W], weights [F, C, KH, KW], bias [F]; attrs: stride, padding and dilation (an
+// integer or a pair each), activation (identity by default); outputs: [N, F, OH, OW]
+pub fn conv2d_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let weights = kernels::input("conv2d", inputs, 1)?;
+    let window = conv_window(attrs, weights)?;
+    let y = conv2d(kernels::input("conv2d", inputs, 0)?, weights, kernels::input("conv2d", inputs, 2)?, &window)?;
+    let activation = Activation::parse(kernels::str_attr(attrs, "activation")?.unwrap_or("identity"))?;
+    Ok(vec![activation.apply(&y)?])
+}
+
+// inputs: x, weights and the gradient with respect to conv2d's output (before any activation); attrs:
+// as for conv2d; outputs: the gradients with respect to x, the weights and the bias
+pub fn conv2d_backward_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let weights = kernels::input("conv2d_backward", inputs, 1)?;
+    let window = conv_window(attrs, weights)?;
+    let x = kernels::input("conv2d_backward", inputs, 0)?;
+    let (dx, dw, db) = conv2d_backward(x, weights, kernels::input("conv2d_backward", inputs, 2)?, &window)?;
+    Ok(vec![dx, dw, db])
+}
+
+// inputs: x [N, C, H, W]; attrs: kernel_size, stride (the kernel size by default), padding and dilation;
+// outputs: [N, C, OH, OW]
+pub fn pool_kernel(pool: Pool) -> impl Fn(&[Tensor], &Attrs) -> Result<Vec<Tensor>, KernelError> + Send + Sync {
+    move |inputs, attrs| {
+        let window = pool_window(attrs)?;
+        Ok(vec![pool.forward(kernels::input(pool.name(), inputs, 0)?, &window)?])
+    }
+}
+
+// inputs: x and the gradient with respect to the pool's output; attrs: as for the pool; outputs: the
+// gradient with respect to x
+pub fn pool_backward_kernel(pool: Pool) -> impl Fn(&[Tensor], &Attrs) -> Result<Vec<Tensor>, KernelError> + Send + Sync {
+    move |inputs, attrs| {
+        let window = pool_window(attrs)?;
+        let x = kernels::input(pool.name(), inputs, 0)?;
+        Ok(vec![pool.backward(x, kernels::input(pool.name(), inputs, 1)?, &window)?])
+    }
+}
+
+fn pool_window(attrs: &Attrs) -> Result<Window2d, KernelError> {
+    let kernel = pair_attr(attrs, "kernel_size", 1)?;
+    Window2d::from_attrs(attrs, kernel, kernel)
+}
+
+// inputs: x [N, C, ...], gamma [C], beta [C], then optionally the mean [C] and variance [C] to normalize
+// with; attrs: epsilon; outputs: the normalized x and, without given statistics, the batch's mean and variance
+pub fn batch_norm_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("batch_norm", inputs, 0)?;
+    let gamma = kernels::input("batch_norm", inputs, 1)?;
+    let beta = kernels::input("batch_norm", inputs, 2)?;
+    let epsilon = epsilon_attr(attrs)?;
+    match (inputs.get(3), inputs.get(4)) {
+        (Some(mean), Some(variance)) => Ok(vec![batch_norm_inference(x, gamma, beta, mean, variance, epsilon)?]),
+        _ => {
+            let (y, mean, variance) = batch_norm(x, gamma, beta, epsilon)?;
+            Ok(vec![y, mean, variance])
+        }
+    }
+}
+
+// inputs: x, gamma and the gradient with respect to batch_norm's output; attrs: epsilon; outputs: the
+// gradients with respect to x, gamma and beta
+pub fn batch_norm_backward_kernel(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("batch_norm_backward", inputs, 0)?;
+    let gamma = kernels::input("batch_norm_backward", inputs, 1)?;
+    let grad = kernels::input("batch_norm_backward", inputs, 2)?;
+    let (dx, dgamma, dbeta) = batch_norm_backward(x, gamma, grad, epsilon_attr(attrs)?)?;
+    Ok(vec![dx, dgamma, dbeta])
+}
+
+// inputs: x [N, ...]; outputs: [N, product of the rest]
+pub fn flatten_kernel(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    Ok(vec![flatten(kernels::input("flatten", inputs, 0)?)?])
+}
+
+// inputs: x and the gradient with respect to flatten's output; outputs: the gradient in x's shape
+pub fn flatten_backward_kernel(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("flatten_backward", inputs, 0)?;
+    Ok(vec![kernels::input("flatten_backward", inputs, 1)?.reshape(x.shape().to_vec())?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::KernelRegistry;
+    use crate::test_support::{assert_close, numeric_gradient, tensor};
+
+    fn close(found: &Tensor, expected: &[f32]) {
+        assert_close(found, expected, 1e-3, 0.0);
+    }
+
+    #[test]
+    fn test_conv2d_matches_naive_loops() {
+        let window = Window2d {
+            kernel: [3, 2],
+            stride: [2, 1],
+            padding: [1, 2],
+            dilation: [1, 2],
+        };
+        let (x, w, b) = (tensor(vec![2, 3, 6, 5], 1.0), tensor(vec![4, 3, 3, 2], 2.0), tensor(vec![4], 3.0));
+        let y = conv2d(&x, &w, &b, &window).unwrap();
+        let [oh, ow] = window.output_size([6, 5]).unwrap();
+        assert_eq!(y.shape(), &[2, 4, oh, ow]);
+
+        let (xv, wv, bv) = (x.to_vec::<f32>().unwrap(), w.to_vec::<f32>().unwrap(), b.to_vec::<f32>().unwrap());
+        let g = tensor(vec![2, 4, oh, ow], 4.0);
+        let gv = g.to_vec::<f32>().unwrap();
+        let mut expected = vec![0.0; y.len()];
+        let (mut dx, mut dw, mut db) = (vec![0.0; x.len()], vec![0.0; w.len()], vec![0.0; 4]);
+        for n in 0..2 {
+            for f in 0..4 {
+                for oy in 0..oh {
+                    for ox in 0..ow {
+                        let out = ((n * 4 + f) * oh + oy) * ow + ox;
+                        expected[out] = bv[f];
+                        db[f] += gv[out];
+                        for c in 0..3 {
+                            for i in 0..3 {
+                                for j in 0..2 {
+                                    let yy = (oy * 2 + i) as isize - 1;
+                                    let xx = (ox + j * 2) as isize - 2;
+                                    if !(0..6).contains(&yy) || !(0..5).contains(&xx) {
+                                        continue;
+                                    }
+                                    let input = ((n * 3 + c) * 6 + yy as usize) * 5 + xx as usize;
+                                    let weight = ((f * 3 + c) * 3 + i) * 2 + j;
+                                    expected[out] += xv[input] * wv[weight];
+                                    dx[input] += gv[out] * wv[weight];
+                                    dw[weight] += gv[out] * xv[input];
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        close(&y, &expected);
+        let (gx, gw, gb) = conv2d_backward(&x, &w, &g, &window).unwrap();
+        close(&gx, &dx);
+        close(&gw, &dw);
+        close(&gb, &db);
+
+        // Through the registry, with the window from attributes
+        let mut attrs = Attrs::new();
+        attrs.insert("stride".to_string(), Attr::Ints(vec![2, 1]));
+        attrs.insert("padding".to_string(), Attr::Ints(vec![1, 2]));
+        attrs.insert("dilation".to_string(), Attr::Ints(vec![1, 2]));
+        let registry = KernelRegistry::builtin();
+        let outputs = registry.run("conv2d", &[x.clone(), w.clone(), b], &attrs).unwrap();
+        close(&outputs[0], &expected);
+        let outputs = registry.run("conv2d_backward", &[x, w, g], &attrs).unwrap();
+        close(&outputs[1], &dw);
+    }
+
+    #[test]
+    fn test_pooling_matches_naive_loops_and_finite_differences() {
+        let window = Window2d {
+            kernel: [2, 3],
+            stride: [2, 2],
+            padding: [1, 1],
+            dilation: [1, 1],
+        };
+        let x = tensor(vec![2, 2, 5, 6], 0.5);
+        let xv = x.to_vec::<f32>().unwrap();
+        let [oh, ow] = window.output_size([5, 6]).unwrap();
+        let (mut max, mut avg) = (Vec::new(), Vec::new());
+        for plane in 0..4 {
+            for oy in 0..oh {
+                for ox in 0..ow {
+                    let mut inside = Vec::new();
+                    for i in 0..2 {
+                        for j in 0..3 {
+                            let (yy, xx) = ((oy * 2 + i) as isize - 1, (ox * 2 + j) as isize - 1);
+                            if (0..5).contains(&yy) && (0..6).contains(&xx) {
+                                inside.push(xv[(plane * 5 + yy as usize) * 6 + xx as usize]);
+                            }
+                        }
+                    }
+                    max.push(inside.iter().copied().fold(f32::NEG_INFINITY, f32::max));
+                    avg.push(inside.iter().sum::<f32>() / inside.len() as f32);
+                }
+            }
+        }
+        close(&Pool::Max.forward(&x, &window).unwrap(), &max);
+        close(&Pool::Avg.forward(&x, &window).unwrap(), &avg);
+
+        let probe = tensor(vec![2, 2, oh, ow], 9.0);
+        for pool in [Pool::Max, Pool::Avg] {
+            let expected = numeric_gradient(&x, &probe, |x| pool.forward(x, &window).unwrap());
+            close(&pool.backward(&x, &probe, &window).unwrap(), &expected);
+        }
+
+        // Pools default to a stride of their kernel size
+        let mut attrs = Attrs::new();
+        attrs.insert("kernel_size".to_string(), Attr::Int(2));
+        let outputs = KernelRegistry::builtin().run("max_pool2d", &[x], &attrs).unwrap();
+        assert_eq!(outputs[0].shape(), &[2, 2, 2, 3]);
+    }
+
+    #[test]
+    fn test_batch_norm_and_flatten() {
+        let (x, gamma, beta) = (tensor(vec![3, 2, 2, 2], 1.0), tensor(vec![2], 5.0), tensor(vec![2], 6.0));
+        let (y, mean, variance) = batch_norm(&x, &gamma, &beta, 1e-5).unwrap();
+        let (xv, gv, bv) = (x.to_vec::<f32>().unwrap(), gamma.to_vec::<f32>().unwrap(), beta.to_vec::<f32>().unwrap());
+        let mut expected = vec![0.0; x.len()];
+        for c in 0..2 {
+            let indices: Vec<usize> = (0..3).flat_map(|n| (0..4).map(move |s| (n * 2 + c) * 4 + s)).collect();
+            let m = indices.iter().map(|&i| xv[i]).sum::<f32>() / 12.0;
+            let v = indices.iter().map(|&i| (xv[i] - m).powi(2)).sum::<f32>() / 12.0;
+            assert!((mean.to_vec::<f32>().unwrap()[c] - m).abs() < 1e-5);
+            assert!((variance.to_vec::<f32>().unwrap()[c] - v).abs() < 1e-5);
+            for i in indices {
+                expected[i] = gv[c] * (xv[i] - m) / (v + 1e-5).sqrt() + bv[c];
+            }
+        }
+        close(&y, &expected);
+
+        let probe = tensor(vec![3, 2, 2, 2], 7.0);
+        let (dx, dgamma, dbeta) = batch_norm_backward(&x, &gamma, &probe, 1e-5).unwrap();
+        close(&dx, &numeric_gradient(&x, &probe, |x| batch_norm(x, &gamma, &beta, 1e-5).unwrap().0));
+        close(&dgamma, &numeric_gradient(&gamma, &probe, |gamma| batch_norm(&x, gamma, &beta, 1e-5).unwrap().0));
+        close(&dbeta, &numeric_gradient(&beta, &probe, |beta| batch_norm(&x, &gamma, beta, 1e-5).unwrap().0));
+
+        let registry = KernelRegistry::builtin();
+        let flat = registry.run("flatten", std::slice::from_ref(&x), &Attrs::new()).unwrap();
+        assert_eq!(flat[0].shape(), &[3, 8]);
+        assert_eq!(flat[0].to_vec::<f32>().unwrap(), xv);
+        let back = registry.run("flatten_backward", &[x.clone(), flat[0].clone()], &Attrs::new()).unwrap();
+        assert_eq!(back[0], x);
+    }
+}
@@ -0,0 +1,521 @@
+// dataset.rs: Dataset descriptors for training: where a dataset's examples live on the Ki nodes' disks, how the An node splits them into shards, and how each Ki node reads the mini-batches of its shard.
+
+use crate::allreduce;
+use crate::kernels::KernelError;
+use crate::tensor::{Tensor, TensorError};
+use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// A dataset as written in a descriptor (JSON). Every Ki node that trains on it must find its files
+// at the same paths.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DatasetSpec {
+    pub name: String,
+    pub source: DataSource,
+    // Examples in the dataset; counted from the files when not given
+    #[serde(default)]
+    pub records: Option<usize>,
+    // Seeds the order each shard is visited in, epoch by epoch
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_shuffle")]
+    pub shuffle: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum DataSource {
+    // One example per line: its features, then `targets` target columns
+    Csv {
+        path: PathBuf,
+        #[serde(default)]
+        header: bool,
+        #[serde(default = "default_targets")]
+        targets: usize,
+        // One-hot encodes a single target column holding class indices
+        #[serde(default)]
+        classes: Option<usize>,
+    },
+    // Back-to-back records of `features` then `targets` little-endian f32 values
+    Raw { path: PathBuf, features: usize, targets: usize },
+    // A file of examples and one of labels in the IDX format MNIST is distributed in
+    Idx {
+        inputs: PathBuf,
+        targets: PathBuf,
+        // Multiplies every input value, e.g. 1/255 for pixels
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default)]
+        classes: Option<usize>,
+    },
+}
+
+fn default_shuffle() -> bool {
+    true
+}
+
+fn default_targets() -> usize {
+    1
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+#[derive(Debug)]
+pub enum DatasetError {
+    InvalidSpec(String),
+    Malformed { path: PathBuf, reason: String },
+    Io(io::Error),
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatasetError::InvalidSpec(reason) => write!(f, "invalid dataset descriptor: {}", reason),
+            DatasetError::Malformed { path, reason } => write!(f, "malformed dataset file {}: {}", path.display(), reason),
+            DatasetError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+impl From<io::Error> for DatasetError {
+    fn from(e: io::Error) -> Self {
+        DatasetError::Io(e)
+    }
+}
+
+impl From<TensorError> for DatasetError {
+    fn from(e: TensorError) -> Self {
+        DatasetError::InvalidSpec(e.to_string())
+    }
+}
+
+impl From<DatasetError> for KernelError {
+    fn from(e: DatasetError) -> Self {
+        KernelError::InvalidInput(e.to_string())
+    }
+}
+
+fn malformed(path: &Path, reason: impl Into<String>) -> DatasetError {
+    DatasetError::Malformed {
+        path: path.to_path_buf(),
+        reason: reason.into(),
+    }
+}
+
+impl DatasetSpec {
+    pub fn from_json(json: &str) -> Result<Self, DatasetError> {
+        let spec: DatasetSpec = serde_json::from_str(json).map_err(|e| DatasetError::InvalidSpec(e.to_string()))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn validate(&self) -> Result<(), DatasetError> {
+        let invalid = |reason: &str| Err(DatasetError::InvalidSpec(format!("dataset {}: {}", self.name, reason)));
+        match &self.source {
+            DataSource::Csv { targets: 0, .. } | DataSource::Raw { targets: 0, .. } => invalid("it has no target columns"),
+            DataSource::Csv { targets, classes: Some(_), .. } if *targets != 1 => {
+                invalid("classes can only encode a single target column")
+            }
+            DataSource::Raw { features: 0, .. } => invalid("its records have no features"),
+            DataSource::Csv { classes: Some(0), .. } | DataSource::Idx { classes: Some(0), .. } => invalid("classes must be positive"),
+            _ if self.records == Some(0) => invalid("it has no records"),
+            _ => Ok(()),
+        }
+    }
+
+    // Examples in the dataset
+    pub fn records(&self) -> Result<usize, DatasetError> {
+        if let Some(records) = self.records {
+            return Ok(records);
+        }
+        match &self.source {
+            DataSource::Csv { path, header, .. } => Ok(csv_offsets(path, *header)?.len()),
+            DataSource::Raw { path, features, targets } => {
+                let record = 4 * (features + targets) as u64;
+                let len = fs::metadata(path)?.len();
+                if len % record != 0 {
+                    return Err(malformed(path, format!("{} bytes is not a whole number of {}-byte records", len, record)));
+                }
+                Ok((len / record) as usize)
+            }
+            DataSource::Idx { inputs, targets, .. } => {
+                let (examples, labels) = (IdxHeader::read(inputs)?, IdxHeader::read(targets)?);
+                if examples.dims[0] != labels.dims[0] {
+                    return Err(malformed(
+                        targets,
+                        format!("{} labels for {} examples", labels.dims[0], examples.dims[0]),
+                    ));
+                }
+                Ok(examples.dims[0])
+            }
+        }
+    }
+
+    // The inputs [n, features] and targets [n, targets] of the examples at `indices`, read from disk
+    // without loading the rest of the dataset.
+    pub fn read(&self, indices: &[usize]) -> Result<(Tensor, Tensor), DatasetError> {
+        match &self.source {
+            DataSource::Csv { path, header, targets, classes } => {
+                let offsets = csv_offsets(path, *header)?;
+                let mut reader = BufReader::new(File::open(path)?);
+                let (mut x, mut y, mut columns) = (Vec::new(), Vec::new(), None);
+                for &index in indices {
+                    let offset = *offsets
+                        .get(index)
+                        .ok_or_else(|| malformed(path, format!("no record {}, it has {}", index, offsets.len())))?;
+                    reader.seek(SeekFrom::Start(offset))?;
+                    let mut line = String::new();
+                    reader.read_line(&mut line)?;
+                    let values = line
+                        .trim()
+                        .split(',')
+                        .map(|field| field.trim().parse::<f32>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| malformed(path, format!("record {}: {}", index, e)))?;
+                    if values.len() <= *targets || columns.is_some_and(|columns| columns != values.len()) {
+                        return Err(malformed(path, format!("record {} has {} columns", index, values.len())));
+                    }
+                    columns = Some(values.len());
+                    let (features, labels) = values.split_at(values.len() - targets);
+                    x.extend_from_slice(features);
+                    y.extend(encode(path, labels, *classes)?);
+                }
+                let features = columns.unwrap_or(*targets) - targets;
+                batch(indices.len(), features, x, y)
+            }
+            DataSource::Raw { path, features, targets } => {
+                let record = features + targets;
+                let mut file = File::open(path)?;
+                let (mut x, mut y) = (Vec::new(), Vec::new());
+                let mut bytes = vec![0u8; 4 * record];
+                for &index in indices {
+                    file.seek(SeekFrom::Start((4 * record * index) as u64))?;
+                    file.read_exact(&mut bytes)
+                        .map_err(|e| malformed(path, format!("record {}: {}", index, e)))?;
+                    let values = bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+                    for (column, value) in values.enumerate() {
+                        if column < *features {
+                            x.push(value);
+                        } else {
+                            y.push(value);
+                        }
+                    }
+                }
+                batch(indices.len(), *features, x, y)
+            }
+            DataSource::Idx { inputs, targets, scale, classes } => {
+                let x: Vec<f32> = IdxHeader::read(inputs)?
+                    .records(inputs, indices)?
+                    .into_iter()
+                    .map(|value| value * scale)
+                    .collect();
+                let labels = IdxHeader::read(targets)?.records(targets, indices)?;
+                let label_width = labels.len() / indices.len().max(1);
+                let mut y = Vec::new();
+                for label in labels.chunks(label_width.max(1)) {
+                    y.extend(encode(targets, label, *classes)?);
+                }
+                batch(indices.len(), x.len() / indices.len().max(1), x, y)
+            }
+        }
+    }
+
+    // The examples `step` reads from `shard`, by index into the dataset. A shard is visited one
+    // epoch after another, each in its own order; the last batch of an epoch runs on into the next.
+    pub fn batch_indices(&self, shard: &Range<usize>, step: usize, batch_size: usize) -> Vec<usize> {
+        let rows = batch_rows(shard, batch_size);
+        let mut orders: HashMap<usize, Vec<usize>> = HashMap::new();
+        (step * rows..(step + 1) * rows)
+            .map(|position| {
+                let epoch = position / shard.len();
+                let order = orders.entry(epoch).or_insert_with(|| self.epoch_order(shard, epoch));
+                order[position % shard.len()]
+            })
+            .collect()
+    }
+
+    // Every index of `shard`, shuffled with a generator seeded by the dataset's seed, the epoch and
+    // where the shard starts.
+    fn epoch_order(&self, shard: &Range<usize>, epoch: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = shard.clone().collect();
+        if self.shuffle {
+            let seed = self.seed ^ (epoch as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (shard.start as u64).rotate_left(32);
+            order.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+        order
+    }
+}
+
+// Contiguous ranges of a dataset's records, one for each of `workers`. There are fewer when there are
+// fewer records than workers, so that no shard is empty.
+pub fn shards(records: usize, workers: usize) -> Vec<Range<usize>> {
+    allreduce::chunk_ranges(records, workers)
+        .into_iter()
+        .filter(|shard| !shard.is_empty())
+        .collect()
+}
+
+// Rows in each mini-batch read from `shard`
+pub fn batch_rows(shard: &Range<usize>, batch_size: usize) -> usize {
+    batch_size.min(shard.len())
+}
+
+fn batch(rows: usize, features: usize, x: Vec<f32>, y: Vec<f32>) -> Result<(Tensor, Tensor), DatasetError> {
+    let targets = y.len() / rows.max(1);
+    Ok((Tensor::new(vec![rows, features], x)?, Tensor::new(vec![rows, targets], y)?))
+}
+
+// The target columns of one example, with a class index one-hot encoded when `classes` is given
+fn encode(path: &Path, labels: &[f32], classes: Option<usize>) -> Result<Vec<f32>, DatasetError> {
+    let Some(classes) = classes else {
+        return Ok(labels.to_vec());
+    };
+    match labels {
+        [label] if label.fract() == 0.0 && *label >= 0.0 && (*label as usize) < classes => {
+            let mut encoded = vec![0.0; classes];
+            encoded[*label as usize] = 1.0;
+            Ok(encoded)
+        }
+        _ => Err(malformed(path, format!("{:?} is not a class index below {}", labels, classes))),
+    }
+}
+
+// Where each record of a CSV file starts, with the file's length and modification time when it was read
+type CsvIndex = (u64, SystemTime, Arc<Vec<u64>>);
+
+lazy_static! {
+    // Kept while each file is unchanged, so that every step does not scan the whole file again
+    static ref CSV_OFFSETS: Mutex<HashMap<(PathBuf, bool), CsvIndex>> = Mutex::new(HashMap::new());
+}
+
+fn csv_offsets(path: &Path, header: bool) -> Result<Arc<Vec<u64>>, DatasetError> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    let key = (path.to_path_buf(), header);
+    if let Some((len, at, offsets)) = CSV_OFFSETS.lock().unwrap().get(&key) {
+        if *len == metadata.len() && *at == modified {
+            return Ok(offsets.clone());
+        }
+    }
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut offsets = Vec::new();
+    let (mut offset, mut skip_header) = (0u64, header);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            if skip_header {
+                skip_header = false;
+            } else {
+                offsets.push(offset);
+            }
+        }
+        offset += read as u64;
+    }
+    let offsets = Arc::new(offsets);
+    CSV_OFFSETS
+        .lock()
+        .unwrap()
+        .insert(key, (metadata.len(), modified, offsets.clone()));
+    Ok(offsets)
+}
+
+// The header of an IDX file: two zero bytes, the element type, the number of dimensions, then each
+// dimension as a big-endian u32. The first dimension counts the records.
+struct IdxHeader {
+    dtype: u8,
+    dims: Vec<usize>,
+}
+
+impl IdxHeader {
+    fn read(path: &Path) -> Result<Self, DatasetError> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)
+            .map_err(|_| malformed(path, "the file is too short for an IDX header"))?;
+        let [0, 0, dtype, ndims] = magic else {
+            return Err(malformed(path, "not an IDX file"));
+        };
+        if ndims == 0 {
+            return Err(malformed(path, "the file has no dimensions"));
+        }
+        let mut dims = Vec::with_capacity(ndims as usize);
+        for _ in 0..ndims {
+            let mut dim = [0u8; 4];
+            file.read_exact(&mut dim)
+                .map_err(|_| malformed(path, "the file is too short for its dimensions"))?;
+            dims.push(u32::from_be_bytes(dim) as usize);
+        }
+        let header = IdxHeader { dtype, dims };
+        header.element_size(path)?;
+        Ok(header)
+    }
+
+    fn element_size(&self, path: &Path) -> Result<usize, DatasetError> {
+        match self.dtype {
+            0x08 | 0x09 => Ok(1),
+            0x0B => Ok(2),
+            0x0C | 0x0D => Ok(4),
+            0x0E => Ok(8),
+            other => Err(malformed(path, format!("unknown element type {:#04x}", other))),
+        }
+    }
+
+    // The values of the records at `indices`, one after another
+    fn records(&self, path: &Path, indices: &[usize]) -> Result<Vec<f32>, DatasetError> {
+        let size = self.element_size(path)?;
+        let values: usize = self.dims[1..].iter().product();
+        let start = 4 + 4 * self.dims.len();
+        let mut file = File::open(path)?;
+        let mut bytes = vec![0u8; values * size];
+        let mut decoded = Vec::with_capacity(indices.len() * values);
+        for &index in indices {
+            if index >= self.dims[0] {
+                return Err(malformed(path, format!("no record {}, it has {}", index, self.dims[0])));
+            }
+            file.seek(SeekFrom::Start((start + index * values * size) as u64))?;
+            file.read_exact(&mut bytes)
+                .map_err(|e| malformed(path, format!("record {}: {}", index, e)))?;
+            decoded.extend(bytes.chunks_exact(size).map(|b| match self.dtype {
+                0x08 => b[0] as f32,
+                0x09 => b[0] as i8 as f32,
+                0x0B => i16::from_be_bytes([b[0], b[1]]) as f32,
+                0x0C => i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f32,
+                0x0D => f32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                _ => f64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+            }));
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("an_ki_dataset_{}_{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn spec(source: DataSource) -> DatasetSpec {
+        DatasetSpec {
+            name: "test".to_string(),
+            source,
+            records: None,
+            seed: 7,
+            shuffle: true,
+        }
+    }
+
+    #[test]
+    fn test_each_format_reads_the_requested_records() {
+        let dir = scratch_dir("formats");
+
+        let csv = dir.join("data.csv");
+        fs::write(&csv, "a,b,label\n1,2,0\n\n3,4,2\n5,6,1\n").unwrap();
+        let from_csv = spec(DataSource::Csv {
+            path: csv,
+            header: true,
+            targets: 1,
+            classes: Some(3),
+        });
+        assert_eq!(from_csv.records().unwrap(), 3);
+        let (x, y) = from_csv.read(&[2, 0]).unwrap();
+        assert_eq!(x.shape(), &[2, 2]);
+        assert_eq!(x.to_vec::<f32>().unwrap(), vec![5.0, 6.0, 1.0, 2.0]);
+        assert_eq!(y.to_vec::<f32>().unwrap(), vec![0.0, 1.0, 0.0, 1.0, 0.0, 0.0]);
+
+        let raw = dir.join("data.bin");
+        let values: Vec<u8> = (0..12).flat_map(|i| (i as f32).to_le_bytes()).collect();
+        fs::write(&raw, values).unwrap();
+        let from_raw = spec(DataSource::Raw {
+            path: raw,
+            features: 2,
+            targets: 1,
+        });
+        assert_eq!(from_raw.records().unwrap(), 4);
+        let (x, y) = from_raw.read(&[1, 3]).unwrap();
+        assert_eq!(x.to_vec::<f32>().unwrap(), vec![3.0, 4.0, 9.0, 10.0]);
+        assert_eq!(y.to_vec::<f32>().unwrap(), vec![5.0, 11.0]);
+
+        // Three 2x2 unsigned byte "images" and their labels
+        let images = dir.join("images-idx3-ubyte");
+        let mut bytes = vec![0, 0, 0x08, 3, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 2];
+        bytes.extend(0..12u8);
+        fs::write(&images, bytes).unwrap();
+        let labels = dir.join("labels-idx1-ubyte");
+        fs::write(&labels, [0, 0, 0x08, 1, 0, 0, 0, 3, 1, 0, 1]).unwrap();
+        let from_idx = spec(DataSource::Idx {
+            inputs: images,
+            targets: labels,
+            scale: 0.5,
+            classes: Some(2),
+        });
+        assert_eq!(from_idx.records().unwrap(), 3);
+        let (x, y) = from_idx.read(&[1]).unwrap();
+        assert_eq!(x.shape(), &[1, 4]);
+        assert_eq!(x.to_vec::<f32>().unwrap(), vec![2.0, 2.5, 3.0, 3.5]);
+        assert_eq!(y.to_vec::<f32>().unwrap(), vec![1.0, 0.0]);
+
+        assert!(matches!(from_idx.read(&[3]), Err(DatasetError::Malformed { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_shards_cover_the_dataset_and_shuffle_per_epoch() {
+        let dataset = spec(DataSource::Raw {
+            path: PathBuf::from("unused"),
+            features: 1,
+            targets: 1,
+        });
+        // Re-balancing from three workers to two still covers every record exactly once
+        for workers in [3, 2] {
+            let shards = shards(10, workers);
+            assert_eq!(shards.len(), workers);
+            let covered: Vec<usize> = shards.iter().flat_map(|shard| shard.clone()).collect();
+            assert_eq!(covered, (0..10).collect::<Vec<_>>());
+        }
+        assert_eq!(shards(2, 5).len(), 2);
+
+        // An epoch of a five-record shard in batches of two: every record once, then a new order
+        let shard = 5..10;
+        let epochs: Vec<usize> = (0..5).flat_map(|step| dataset.batch_indices(&shard, step, 2)).collect();
+        let first: HashSet<usize> = epochs[..5].iter().copied().collect();
+        let second: HashSet<usize> = epochs[5..].iter().copied().collect();
+        assert_eq!(first, shard.clone().collect());
+        assert_eq!(second, shard.clone().collect());
+        assert_ne!(epochs[..5], epochs[5..]);
+        // The same seed visits the shard in the same order
+        assert_eq!(dataset.batch_indices(&shard, 3, 2), epochs[6..8]);
+
+        let ordered = DatasetSpec { shuffle: false, ..dataset };
+        assert_eq!(ordered.batch_indices(&shard, 2, 2), vec![9, 5]);
+    }
+}
@@ -0,0 +1,198 @@
+// dead_letter.rs: Implements dead-letter queues, bounded redelivery and replay for poison messages.
+
+use crate::messaging::{Delivery, Headers, Transport, TransportError};
+use tracing::{error, info, warn};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+pub const REDELIVERY_COUNT_HEADER: &str = "x-redelivery-count";
+pub const DEATH_REASON_HEADER: &str = "x-death-reason";
+pub const ORIGINAL_QUEUE_HEADER: &str = "x-original-queue";
+
+pub fn dead_letter_queue_name(queue_name: &str) -> String {
+    format!("{}.dlq", queue_name)
+}
+
+pub fn redelivery_count(delivery: &Delivery) -> u32 {
+    delivery
+        .headers
+        .get(REDELIVERY_COUNT_HEADER)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    pub data: Vec<u8>,
+    pub headers: Headers,
+}
+
+impl DeadLetter {
+    pub fn reason(&self) -> Option<&str> {
+        self.headers.get(DEATH_REASON_HEADER).map(String::as_str)
+    }
+}
+
+// Retry and dead-letter rules for one consumed queue.
+#[derive(Clone, Debug)]
+pub struct DeadLetterPolicy {
+    pub queue_name: String,
+    pub dead_letter_queue: String,
+    pub max_attempts: u32,
+}
+
+impl DeadLetterPolicy {
+    pub fn new(queue_name: &str, max_attempts: u32) -> Self {
+        DeadLetterPolicy {
+            queue_name: queue_name.to_string(),
+            dead_letter_queue: dead_letter_queue_name(queue_name),
+            max_attempts,
+        }
+    }
+
+    pub async fn declare(&self, transport: &dyn Transport) -> Result<(), TransportError> {
+        transport
+            .declare_queue_with_dead_letter(&self.queue_name, &self.dead_letter_queue)
+            .await
+    }
+
+    // For a queue only this process consumes, which the broker deletes when the process's connection closes.
+    pub async fn declare_exclusive(&self, transport: &dyn Transport) -> Result<(), TransportError> {
+        transport
+            .declare_exclusive_queue_with_dead_letter(&self.queue_name, &self.dead_letter_queue)
+            .await
+    }
+
+    // Sends a message that can never be processed (e.g. undeserializable) straight to the DLQ.
+    pub async fn dead_letter(&self, transport: &dyn Transport, delivery: &Delivery, reason: &str) -> Result<(), TransportError> {
+        let mut headers = delivery.headers.clone();
+        headers.insert(DEATH_REASON_HEADER.to_string(), reason.to_string());
+        headers.insert(ORIGINAL_QUEUE_HEADER.to_string(), self.queue_name.clone());
+        transport
+            .publish_with_headers(&self.dead_letter_queue, &delivery.data, &headers)
+            .await?;
+        delivery.ack().await?;
+        warn!("Dead-lettered message from {}: {}", self.queue_name, reason);
+        Ok(())
+    }
+
+    // Requeues a failed message with an incremented redelivery count, dead-lettering it once the
+    // count reaches `max_attempts`.
+    pub async fn retry_or_dead_letter(&self, transport: &dyn Transport, delivery: &Delivery, reason: &str) -> Result<(), TransportError> {
+        let attempts = redelivery_count(delivery) + 1;
+        if attempts >= self.max_attempts {
+            return self
+                .dead_letter(transport, delivery, &format!("{} (after {} attempts)", reason, attempts))
+                .await;
+        }
+
+        let mut headers = delivery.headers.clone();
+        headers.insert(REDELIVERY_COUNT_HEADER.to_string(), attempts.to_string());
+        transport
+            .publish_with_headers(&self.queue_name, &delivery.data, &headers)
+            .await?;
+        delivery.ack().await?;
+        info!(
+            "Requeued message on {} (attempt {}/{}): {}",
+            self.queue_name, attempts, self.max_attempts, reason
+        );
+        Ok(())
+    }
+
+    // Returns up to `limit` dead letters without removing them from the DLQ.
+    pub async fn inspect(&self, transport: &dyn Transport, limit: usize) -> Result<Vec<DeadLetter>, TransportError> {
+        let mut held = Vec::new();
+        while held.len() < limit {
+            match transport.get(&self.dead_letter_queue).await? {
+                Some(delivery) => held.push(delivery),
+                None => break,
+            }
+        }
+
+        let letters = held
+            .iter()
+            .map(|delivery| DeadLetter {
+                data: delivery.data.clone(),
+                headers: delivery.headers.clone(),
+            })
+            .collect();
+        for delivery in held {
+            if let Err(e) = delivery.nack(true).await {
+                error!("Failed to return dead letter to {}: {:?}", self.dead_letter_queue, e);
+            }
+        }
+        Ok(letters)
+    }
+
+    // Moves up to `limit` dead letters back onto the original queue with a fresh redelivery count.
+    pub async fn replay(&self, transport: &dyn Transport, limit: usize) -> Result<usize, TransportError> {
+        let mut replayed = 0;
+        while replayed < limit {
+            let Some(delivery) = transport.get(&self.dead_letter_queue).await? else {
+                break;
+            };
+            let mut headers = delivery.headers.clone();
+            headers.remove(REDELIVERY_COUNT_HEADER);
+            headers.remove(DEATH_REASON_HEADER);
+            headers.remove(ORIGINAL_QUEUE_HEADER);
+            transport
+                .publish_with_headers(&self.queue_name, &delivery.data, &headers)
+                .await?;
+            delivery.ack().await?;
+            replayed += 1;
+        }
+        info!("Replayed {} dead letters onto {}", replayed, self.queue_name);
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::InMemoryBroker;
+
+    #[tokio::test]
+    async fn test_retry_until_dead_lettered() {
+        let broker = InMemoryBroker::new();
+        let policy = DeadLetterPolicy::new("retry_queue", 3);
+        policy.declare(&broker).await.unwrap();
+        broker.publish_with_headers("retry_queue", b"flaky", &Headers::new()).await.unwrap();
+
+        for attempt in 1..3 {
+            let delivery = broker.get("retry_queue").await.unwrap().unwrap();
+            assert_eq!(redelivery_count(&delivery), attempt - 1);
+            policy.retry_or_dead_letter(&broker, &delivery, "boom").await.unwrap();
+        }
+        let delivery = broker.get("retry_queue").await.unwrap().unwrap();
+        assert_eq!(redelivery_count(&delivery), 2);
+        policy.retry_or_dead_letter(&broker, &delivery, "boom").await.unwrap();
+
+        assert!(broker.get("retry_queue").await.unwrap().is_none());
+        let letters = policy.inspect(&broker, 10).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].data, b"flaky");
+        assert_eq!(letters[0].headers[ORIGINAL_QUEUE_HEADER], "retry_queue");
+        assert!(letters[0].reason().unwrap().starts_with("boom"));
+    }
+
+    #[tokio::test]
+    async fn test_inspect_keeps_and_replay_moves_dead_letters() {
+        let broker = InMemoryBroker::new();
+        let policy = DeadLetterPolicy::new("replay_queue", DEFAULT_MAX_ATTEMPTS);
+        policy.declare(&broker).await.unwrap();
+        broker.publish_with_headers("replay_queue", b"not json", &Headers::new()).await.unwrap();
+
+        let delivery = broker.get("replay_queue").await.unwrap().unwrap();
+        policy.dead_letter(&broker, &delivery, "undeserializable").await.unwrap();
+
+        assert_eq!(policy.inspect(&broker, 10).await.unwrap().len(), 1);
+        assert_eq!(policy.inspect(&broker, 10).await.unwrap().len(), 1);
+
+        assert_eq!(policy.replay(&broker, 10).await.unwrap(), 1);
+        assert!(policy.inspect(&broker, 10).await.unwrap().is_empty());
+        let replayed = broker.get("replay_queue").await.unwrap().unwrap();
+        assert_eq!(replayed.data, b"not json");
+        assert_eq!(redelivery_count(&replayed), 0);
+        assert!(!replayed.headers.contains_key(DEATH_REASON_HEADER));
+    }
+}
@@ -0,0 +1,292 @@
+// dedup.rs: Implements a bounded idempotency store so redelivered work is recognised and not repeated.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::sync::watch;
+use tracing::{error, info};
+
+pub const DEFAULT_DEDUP_CAPACITY: usize = 10_000;
+
+// Outcome of claiming an idempotency key.
+pub enum Claim<V> {
+    // The caller owns the key and must `complete` or `release` it
+    Claimed,
+    // The work already finished with this value. Only keys are journaled, so the value is None when the work
+    // finished before a restart.
+    Completed(Option<V>),
+    // Someone else is working on the key; the receiver closes when they finish
+    Running(watch::Receiver<()>),
+}
+
+enum Entry<V> {
+    Running(watch::Sender<()>),
+    Done(Option<V>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+}
+
+struct Inner<V> {
+    entries: HashMap<String, Entry<V>>,
+    // Completed keys, oldest first
+    order: VecDeque<String>,
+    journal: Option<JournalWriter>,
+}
+
+// Append-only file of completed keys, rewritten once it grows past twice the capacity. It is owned by a
+// writer thread so that completing a key never waits for the disk.
+struct Journal {
+    path: PathBuf,
+    file: File,
+    records: usize,
+    capacity: usize,
+    // The keys a rewrite keeps, oldest first
+    keys: VecDeque<String>,
+}
+
+// Hands completed keys to the journal's writer thread, and waits for it to finish when dropped.
+struct JournalWriter {
+    sender: Option<mpsc::Sender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone)]
+pub struct DedupStore<V> {
+    capacity: usize,
+    inner: Arc<Mutex<Inner<V>>>,
+}
+
+impl<V: Clone> DedupStore<V> {
+    // Remembers the last `capacity` completed keys in memory only.
+    pub fn new(capacity: usize) -> Self {
+        DedupStore {
+            capacity: capacity.max(1),
+            inner: Arc::new(Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                journal: None,
+            })),
+        }
+    }
+
+    // Persistent when a path is given, in-memory otherwise.
+    pub fn open(capacity: usize, path: Option<&Path>) -> io::Result<Self> {
+        match path {
+            Some(path) => Self::with_persistence(capacity, path),
+            None => Ok(Self::new(capacity)),
+        }
+    }
+
+    // Like `new`, but completed keys are also journaled to `path` and reloaded from it on startup. Their
+    // values are kept in memory only.
+    pub fn with_persistence(capacity: usize, path: &Path) -> io::Result<Self> {
+        let store = Self::new(capacity);
+        let mut records = 0;
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) => {
+                        store.remember(&mut store.inner.lock().unwrap(), record.key, None);
+                        records += 1;
+                    }
+                    // A torn final line after a crash is expected; skip it
+                    Err(e) => error!("Skipping unreadable dedup record in {}: {}", path.display(), e),
+                }
+            }
+        } else if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut inner = store.inner.lock().unwrap();
+        info!("Loaded {} completed keys from {}", inner.order.len(), path.display());
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file,
+            records,
+            capacity: store.capacity,
+            keys: inner.order.clone(),
+        };
+        inner.journal = Some(JournalWriter::spawn(journal)?);
+        drop(inner);
+        Ok(store)
+    }
+
+    pub fn claim(&self, key: &str) -> Claim<V> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.get(key) {
+            Some(Entry::Done(value)) => Claim::Completed(value.clone()),
+            Some(Entry::Running(sender)) => Claim::Running(sender.subscribe()),
+            None => {
+                let (sender, _) = watch::channel(());
+                inner.entries.insert(key.to_string(), Entry::Running(sender));
+                Claim::Claimed
+            }
+        }
+    }
+
+    // Records the result of a claimed key and wakes anyone waiting on it.
+    pub fn complete(&self, key: &str, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(journal) = &inner.journal {
+            journal.append(key);
+        }
+        self.remember(&mut inner, key.to_string(), Some(value));
+    }
+
+    // Gives up a claimed key after a failure so that a later delivery can retry it.
+    pub fn release(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if matches!(inner.entries.get(key), Some(Entry::Running(_))) {
+            inner.entries.remove(key);
+        }
+    }
+
+    fn remember(&self, inner: &mut Inner<V>, key: String, value: Option<V>) {
+        if !matches!(inner.entries.get(&key), Some(Entry::Done(_))) {
+            inner.order.push_back(key.clone());
+        }
+        inner.entries.insert(key, Entry::Done(value));
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl JournalWriter {
+    fn spawn(mut journal: Journal) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<String>();
+        let thread = std::thread::Builder::new()
+            .name("dedup-journal".to_string())
+            .spawn(move || {
+                for key in receiver {
+                    if let Err(e) = journal.append(key) {
+                        error!("Failed to journal completed key: {:?}", e);
+                    }
+                }
+            })?;
+        Ok(JournalWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    fn append(&self, key: &str) {
+        if let Some(sender) = &self.sender {
+            if sender.send(key.to_string()).is_err() {
+                error!("Dedup journal writer has stopped; key {} is not journaled", key);
+            }
+        }
+    }
+}
+
+impl Drop for JournalWriter {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer drain what is queued and exit
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Journal {
+    fn append(&mut self, key: String) -> io::Result<()> {
+        writeln!(self.file, "{}", encode_record(&key)?)?;
+        self.file.flush()?;
+        self.records += 1;
+        self.keys.push_back(key);
+        while self.keys.len() > self.capacity {
+            self.keys.pop_front();
+        }
+        if self.records > 2 * self.capacity {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let temporary_path = self.path.with_extension("compact");
+        let mut file = File::create(&temporary_path)?;
+        for key in &self.keys {
+            writeln!(file, "{}", encode_record(key)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = self.keys.len();
+        Ok(())
+    }
+}
+
+fn encode_record(key: &str) -> io::Result<String> {
+    serde_json::to_string(&Record { key: key.to_string() }).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_complete_and_evict() {
+        let store: DedupStore<String> = DedupStore::new(2);
+        assert!(matches!(store.claim("a"), Claim::Claimed));
+        assert!(matches!(store.claim("a"), Claim::Running(_)));
+        store.complete("a", "result a".to_string());
+        assert!(matches!(store.claim("a"), Claim::Completed(Some(ref value)) if value == "result a"));
+
+        for key in ["b", "c"] {
+            assert!(matches!(store.claim(key), Claim::Claimed));
+            store.complete(key, key.to_string());
+        }
+        assert!(matches!(store.claim("c"), Claim::Completed(_)));
+        // Evicted, so it can be claimed again
+        assert!(matches!(store.claim("a"), Claim::Claimed));
+    }
+
+    #[tokio::test]
+    async fn test_waiter_wakes_when_released() {
+        let store: DedupStore<String> = DedupStore::new(10);
+        assert!(matches!(store.claim("key"), Claim::Claimed));
+        let Claim::Running(mut waiter) = store.claim("key") else {
+            panic!("expected the key to be running");
+        };
+        store.release("key");
+        assert!(waiter.changed().await.is_err());
+        assert!(matches!(store.claim("key"), Claim::Claimed));
+    }
+
+    #[test]
+    fn test_persistence_survives_restart_and_compacts() {
+        let dir = std::env::temp_dir().join(format!("dedup_test_{}", uuid::Uuid::new_v4()));
+        let path = dir.join("ki.dedup");
+        {
+            let store: DedupStore<u32> = DedupStore::with_persistence(2, &path).unwrap();
+            for (i, key) in ["a", "b", "c", "d", "e", "f"].iter().enumerate() {
+                assert!(matches!(store.claim(key), Claim::Claimed));
+                store.complete(key, i as u32);
+            }
+        }
+        assert!(fs::read_to_string(&path).unwrap().lines().count() <= 5);
+
+        let store: DedupStore<u32> = DedupStore::with_persistence(2, &path).unwrap();
+        assert!(matches!(store.claim("d"), Claim::Claimed));
+        // Only the key survives a restart
+        assert!(matches!(store.claim("f"), Claim::Completed(None)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+// kernels.rs: Defines the Kernel trait and the registry Ki nodes use to dispatch tasks to operations by name.
+
+use crate::attention;
+use crate::conv::{self, Pool};
+use crate::tensor::{Tensor, TensorError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+// Operation parameters that are not tensors, such as a stride or an activation name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Attr {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Ints(Vec<i64>),
+    Strs(Vec<String>),
+}
+
+pub type Attrs = BTreeMap<String, Attr>;
+
+impl From<i64> for Attr {
+    fn from(value: i64) -> Self {
+        Attr::Int(value)
+    }
+}
+
+impl From<f64> for Attr {
+    fn from(value: f64) -> Self {
+        Attr::Float(value)
+    }
+}
+
+impl From<&str> for Attr {
+    fn from(value: &str) -> Self {
+        Attr::Str(value.to_string())
+    }
+}
+
+impl From<String> for Attr {
+    fn from(value: String) -> Self {
+        Attr::Str(value)
+    }
+}
+
+impl From<Vec<i64>> for Attr {
+    fn from(value: Vec<i64>) -> Self {
+        Attr::Ints(value)
+    }
+}
+
+impl From<Vec<String>> for Attr {
+    fn from(value: Vec<String>) -> Self {
+        Attr::Strs(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KernelError {
+    UnknownOp(String),
+    InvalidInput(String),
+    Tensor(TensorError),
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelError::UnknownOp(op) => write!(f, "no kernel registered for op {:?}", op),
+            KernelError::InvalidInput(reason) => write!(f, "invalid kernel input: {}", reason),
+            KernelError::Tensor(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for KernelError {}
+
+impl From<TensorError> for KernelError {
+    fn from(e: TensorError) -> Self {
+        KernelError::Tensor(e)
+    }
+}
+
+// A computation a Ki node can run on the tensors of a task.
+pub trait Kernel: Send + Sync {
+    fn compute(&self, inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError>;
+}
+
+impl<F> Kernel for F
+where
+    F: Fn(&[Tensor], &Attrs) -> Result<Vec<Tensor>, KernelError> + Send + Sync,
+{
+    fn compute(&self, inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+        self(inputs, attrs)
+    }
+}
+
+// Kernels keyed by op name. Clones share the same kernels.
+#[derive(Clone, Default)]
+pub struct KernelRegistry {
+    kernels: HashMap<String, Arc<dyn Kernel>>,
+}
+
+impl KernelRegistry {
+    pub fn new() -> Self {
+        KernelRegistry {
+            kernels: HashMap::new(),
+        }
+    }
+
+    // Every kernel that ships with the crate.
+    pub fn builtin() -> Self {
+        let mut registry = KernelRegistry::new();
+        registry.register("matmul", matmul);
+        registry.register("dense_forward", dense_forward);
+        registry.register("bias_add", bias_add);
+        registry.register("mlp_forward", crate::mlp::mlp_forward);
+        registry.register("mlp_gradients", crate::training::mlp_gradients);
+        registry.register("shard_gradients", crate::training::shard_gradients);
+        registry.register("conv2d", conv::conv2d_kernel);
+        registry.register("conv2d_backward", conv::conv2d_backward_kernel);
+        for pool in [Pool::Max, Pool::Avg] {
+            registry.register(pool.name(), conv::pool_kernel(pool));
+            registry.register(&format!("{}_backward", pool.name()), conv::pool_backward_kernel(pool));
+        }
+        registry.register("batch_norm", conv::batch_norm_kernel);
+        registry.register("batch_norm_backward", conv::batch_norm_backward_kernel);
+        registry.register("flatten", conv::flatten_kernel);
+        registry.register("flatten_backward", conv::flatten_backward_kernel);
+        registry.register("gelu", attention::gelu_kernel);
+        registry.register("gelu_backward", attention::gelu_backward_kernel);
+        registry.register("layer_norm", attention::layer_norm_kernel);
+        registry.register("layer_norm_backward", attention::layer_norm_backward_kernel);
+        registry.register("embedding", attention::embedding_kernel);
+        registry.register("embedding_backward", attention::embedding_backward_kernel);
+        registry.register("multi_head_attention", attention::attention_kernel);
+        registry.register("multi_head_attention_backward", attention::attention_backward_kernel);
+        registry.register("model_forward", crate::model::model_forward);
+        for activation in Activation::ALL {
+            if activation != Activation::Identity {
+                registry.register(activation.name(), move |inputs: &[Tensor], _attrs: &Attrs| {
+                    Ok(vec![activation.apply(input(activation.name(), inputs, 0)?)?])
+                });
+            }
+        }
+        registry
+    }
+
+    // Registers a kernel under `op`, replacing any kernel already registered under that name.
+    pub fn register(&mut self, op: &str, kernel: impl Kernel + 'static) {
+        self.kernels.insert(op.to_string(), Arc::new(kernel));
+    }
+
+    pub fn get(&self, op: &str) -> Option<Arc<dyn Kernel>> {
+        self.kernels.get(op).cloned()
+    }
+
+    // Registered op names, sorted
+    pub fn ops(&self) -> Vec<String> {
+        let mut ops: Vec<String> = self.kernels.keys().cloned().collect();
+        ops.sort();
+        ops
+    }
+
+    pub fn run(&self, op: &str, inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+        let kernel = self.get(op).ok_or_else(|| KernelError::UnknownOp(op.to_string()))?;
+        kernel.compute(inputs, attrs)
+    }
+}
+
+impl fmt::Debug for KernelRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KernelRegistry").field(&self.ops()).finish()
+    }
+}
+
+// Whether an op treats the rows of its first input independently, so that An nodes may split a batch
+// across Ki nodes and concatenate the outputs.
+pub fn splits_along_batch(op: &str) -> bool {
+    matches!(
+        op,
+        "dense_forward"
+            | "bias_add"
+            | "mlp_forward"
+            | "conv2d"
+            | "max_pool2d"
+            | "avg_pool2d"
+            | "flatten"
+            | "gelu"
+            | "layer_norm"
+            | "embedding"
+            | "model_forward"
+    ) || Activation::parse(op).is_ok()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Activation {
+    Identity,
+    Relu,
+    Sigmoid,
+    Tanh,
+    // Over the last axis
+    Softmax,
+}
+
+impl Activation {
+    pub const ALL: [Activation; 5] = [
+        Activation::Identity,
+        Activation::Relu,
+        Activation::Sigmoid,
+        Activation::Tanh,
+        Activation::Softmax,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Activation::Identity => "identity",
+            Activation::Relu => "relu",
+            Activation::Sigmoid => "sigmoid",
+            Activation::Tanh => "tanh",
+            Activation::Softmax => "softmax",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, KernelError> {
+        Activation::ALL
+            .into_iter()
+            .find(|activation| activation.name() == name)
+            .ok_or_else(|| KernelError::InvalidInput(format!("unknown activation {:?}", name)))
+    }
+
+    pub fn apply(self, x: &Tensor) -> Result<Tensor, KernelError> {
+        let mut values = x.to_vec::<f32>()?;
+        match self {
+            Activation::Identity => {}
+            Activation::Relu => values.iter_mut().for_each(|value| *value = value.max(0.0)),
+            Activation::Sigmoid => values.iter_mut().for_each(|value| *value = 1.0 / (1.0 + (-*value).exp())),
+            Activation::Tanh => values.iter_mut().for_each(|value| *value = value.tanh()),
+            Activation::Softmax => {
+                let width = x.shape().last().copied().unwrap_or(1).max(1);
+                for row in values.chunks_exact_mut(width) {
+                    // Shift by the maximum so large logits do not overflow
+                    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let mut sum = 0.0;
+                    for value in row.iter_mut() {
+                        *value = (*value - max).exp();
+                        sum += *value;
+                    }
+                    row.iter_mut().for_each(|value| *value /= sum);
+                }
+            }
+        }
+        Ok(Tensor::new(x.shape().to_vec(), values)?)
+    }
+
+    // The gradient with respect to the activation's input, given its output and the gradient with
+    // respect to that output.
+    pub fn backward(self, output: &Tensor, grad: &Tensor) -> Result<Tensor, KernelError> {
+        grad.expect_shape(output.shape())?;
+        let output_values = output.values::<f32>()?;
+        let mut values = grad.to_vec::<f32>()?;
+        match self {
+            Activation::Identity => {}
+            Activation::Relu => {
+                for (value, output) in values.iter_mut().zip(output_values.iter()) {
+                    if *output <= 0.0 {
+                        *value = 0.0;
+                    }
+                }
+            }
+            Activation::Sigmoid => {
+                for (value, output) in values.iter_mut().zip(output_values.iter()) {
+                    *value *= output * (1.0 - output);
+                }
+            }
+            Activation::Tanh => {
+                for (value, output) in values.iter_mut().zip(output_values.iter()) {
+                    *value *= 1.0 - output * output;
+                }
+            }
+            Activation::Softmax => {
+                // Jacobian-vector product: s_i * (g_i - sum_j s_j g_j) per row
+                let width = output.shape().last().copied().unwrap_or(1).max(1);
+                for (row, outputs) in values.chunks_exact_mut(width).zip(output_values.chunks_exact(width)) {
+                    let dot: f32 = row.iter().zip(outputs).map(|(grad, output)| grad * output).sum();
+                    for (value, output) in row.iter_mut().zip(outputs) {
+                        *value = output * (*value - dot);
+                    }
+                }
+            }
+        }
+        Ok(Tensor::new(output.shape().to_vec(), values)?)
+    }
+}
+
+pub fn str_attr<'a>(attrs: &'a Attrs, name: &str) -> Result<Option<&'a str>, KernelError> {
+    match attrs.get(name) {
+        None => Ok(None),
+        Some(Attr::Str(value)) => Ok(Some(value)),
+        Some(other) => Err(KernelError::InvalidInput(format!("attribute {} should be a string, got {:?}", name, other))),
+    }
+}
+
+pub fn int_attr(attrs: &Attrs, name: &str) -> Result<Option<i64>, KernelError> {
+    match attrs.get(name) {
+        None => Ok(None),
+        Some(Attr::Int(value)) => Ok(Some(*value)),
+        Some(other) => Err(KernelError::InvalidInput(format!("attribute {} should be an integer, got {:?}", name, other))),
+    }
+}
+
+pub fn float_attr(attrs: &Attrs, name: &str) -> Result<Option<f64>, KernelError> {
+    match attrs.get(name) {
+        None => Ok(None),
+        Some(Attr::Float(value)) => Ok(Some(*value)),
+        Some(Attr::Int(value)) => Ok(Some(*value as f64)),
+        Some(other) => Err(KernelError::InvalidInput(format!("attribute {} should be a number, got {:?}", name, other))),
+    }
+}
+
+// The `index`th input, or an error naming the op that needed it.
+pub fn input<'a>(op: &str, inputs: &'a [Tensor], index: usize) -> Result<&'a Tensor, KernelError> {
+    inputs
+        .get(index)
+        .ok_or_else(|| KernelError::InvalidInput(format!("{} expects at least {} inputs, got {}", op, index + 1, inputs.len())))
+}
+
+// Shape of a rank-2 tensor as (rows, columns).
+pub fn matrix_shape(op: &str, tensor: &Tensor) -> Result<(usize, usize), KernelError> {
+    match tensor.shape() {
+        &[rows, columns] => Ok((rows, columns)),
+        shape => Err(KernelError::InvalidInput(format!("{} expects a matrix, got shape {:?}", op, shape))),
+    }
+}
+
+// Row-major [m, k] x [k, n] product.
+pub fn matmul_values(a: &[f32], b: &[f32], m: usize, k: usize, n: usize) -> Vec<f32> {
+    let mut out = vec![0.0; m * n];
+    for i in 0..m {
+        let row = &mut out[i * n..(i + 1) * n];
+        for p in 0..k {
+            let scale = a[i * k + p];
+            for (value, &b_value) in row.iter_mut().zip(&b[p * n..(p + 1) * n]) {
+                *value += scale * b_value;
+            }
+        }
+    }
+    out
+}
+
+// Row-major [rows, columns] values rearranged as [columns, rows].
+pub fn transpose(values: &[f32], rows: usize, columns: usize) -> Vec<f32> {
+    let mut transposed = vec![0.0; values.len()];
+    for row in 0..rows {
+        for column in 0..columns {
+            transposed[column * rows + row] = values[row * columns + column];
+        }
+    }
+    transposed
+}
+
+// inputs: a [m, k], b [k, n]; outputs: a·b [m, n]
+fn matmul(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let (a, b) = (input("matmul", inputs, 0)?, input("matmul", inputs, 1)?);
+    let ((m, k), (k_b, n)) = (matrix_shape("matmul", a)?, matrix_shape("matmul", b)?);
+    if k != k_b {
+        return Err(KernelError::InvalidInput(format!(
+            "matmul cannot multiply {:?} by {:?}",
+            a.shape(),
+            b.shape()
+        )));
+    }
+    let out = matmul_values(&a.values::<f32>()?, &b.values::<f32>()?, m, k, n);
+    Ok(vec![Tensor::new(vec![m, n], out)?])
+}
+
+// inputs: x [batch, in], weights [in, out], optional bias [out]; attrs: optional activation;
+// outputs: activation(x·weights + bias) [batch, out]
+fn dense_forward(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let activation = str_attr(attrs, "activation")?.map_or(Ok(Activation::Identity), Activation::parse)?;
+    let mut output = matmul(&inputs[..inputs.len().min(2)], attrs)?.remove(0);
+    if let Some(bias) = inputs.get(2) {
+        output = add_bias(&output, bias)?;
+    }
+    Ok(vec![activation.apply(&output)?])
+}
+
+// inputs: x [.., n], bias [n]; outputs: x + bias, broadcast over the leading axes
+fn bias_add(inputs: &[Tensor], _attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    Ok(vec![add_bias(input("bias_add", inputs, 0)?, input("bias_add", inputs, 1)?)?])
+}
+
+// x + bias, broadcast over the leading axes of x
+pub fn add_bias(x: &Tensor, bias: &Tensor) -> Result<Tensor, KernelError> {
+    let width = x.shape().last().copied().unwrap_or(1);
+    bias.expect_shape(&[width])?;
+    let bias = bias.values::<f32>()?;
+    let mut values = x.to_vec::<f32>()?;
+    for row in values.chunks_exact_mut(width.max(1)) {
+        for (value, bias) in row.iter_mut().zip(bias.iter()) {
+            *value += bias;
+        }
+    }
+    Ok(Tensor::new(x.shape().to_vec(), values)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_dispatches_by_op() {
+        let mut registry = KernelRegistry::builtin();
+        registry.register("double", |inputs: &[Tensor], _attrs: &Attrs| {
+            let x = input("double", inputs, 0)?;
+            let values = x.values::<f32>()?.iter().map(|value| value * 2.0).collect();
+            Ok(vec![Tensor::new(x.shape().to_vec(), values)?])
+        });
+        assert!(registry.get("double").is_some() && registry.get("softmax").is_some());
+
+        let x = Tensor::new(vec![3], vec![-1.0f32, 0.5, 2.0]).unwrap();
+        let doubled = registry.run("double", std::slice::from_ref(&x), &Attrs::new()).unwrap();
+        assert_eq!(doubled[0].to_vec::<f32>().unwrap(), vec![-2.0, 1.0, 4.0]);
+        let rectified = registry.run("relu", &[x], &Attrs::new()).unwrap();
+        assert_eq!(rectified[0].to_vec::<f32>().unwrap(), vec![0.0, 0.5, 2.0]);
+        assert_eq!(
+            registry.run("conv9d", &[], &Attrs::new()).unwrap_err(),
+            KernelError::UnknownOp("conv9d".to_string())
+        );
+    }
+
+    #[test]
+    fn test_matmul_and_dense_forward() {
+        let registry = KernelRegistry::builtin();
+        let x = Tensor::new(vec![2, 3], vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+        let w = Tensor::new(vec![3, 2], vec![1.0f32, 0.0, 0.0, 1.0, 1.0, 1.0]).unwrap();
+        let bias = Tensor::new(vec![2], vec![0.5f32, -0.5]).unwrap();
+
+        let product = registry.run("matmul", &[x.clone(), w.clone()], &Attrs::new()).unwrap();
+        assert_eq!(product[0].shape(), &[2, 2]);
+        assert_eq!(product[0].to_vec::<f32>().unwrap(), vec![4.0, 5.0, 10.0, 11.0]);
+
+        let dense = registry.run("dense_forward", &[x.clone(), w.clone(), bias], &Attrs::new()).unwrap();
+        assert_eq!(dense[0].to_vec::<f32>().unwrap(), vec![4.5, 4.5, 10.5, 10.5]);
+
+        assert!(matches!(
+            registry.run("matmul", &[x.clone(), x.clone()], &Attrs::new()).unwrap_err(),
+            KernelError::InvalidInput(_)
+        ));
+        assert!(registry.run("matmul", &[x], &Attrs::new()).is_err());
+    }
+
+    #[test]
+    fn test_activations() {
+        let registry = KernelRegistry::builtin();
+        let x = Tensor::new(vec![2, 3], vec![0.0f32, 1.0, -1.0, 1000.0, 1000.0, 0.0]).unwrap();
+
+        let sigmoid = registry.run("sigmoid", std::slice::from_ref(&x), &Attrs::new()).unwrap();
+        assert_eq!(sigmoid[0].to_vec::<f32>().unwrap()[0], 0.5);
+        let tanh = registry.run("tanh", std::slice::from_ref(&x), &Attrs::new()).unwrap();
+        assert!((tanh[0].to_vec::<f32>().unwrap()[1] - 1.0f32.tanh()).abs() < 1e-6);
+
+        let softmax = registry.run("softmax", &[x], &Attrs::new()).unwrap()[0].to_vec::<f32>().unwrap();
+        for row in softmax.chunks(3) {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        }
+        assert!((softmax[3] - 0.5).abs() < 1e-6 && softmax[5] < 1e-6);
+
+        let mut attrs = Attrs::new();
+        attrs.insert("activation".to_string(), Attr::from("relu"));
+        let dense = registry
+            .run(
+                "dense_forward",
+                &[
+                    Tensor::new(vec![1, 2], vec![1.0f32, 2.0]).unwrap(),
+                    Tensor::new(vec![2, 2], vec![1.0f32, -1.0, 1.0, -1.0]).unwrap(),
+                    Tensor::new(vec![2], vec![0.5f32, 0.5]).unwrap(),
+                ],
+                &attrs,
+            )
+            .unwrap();
+        assert_eq!(dense[0].to_vec::<f32>().unwrap(), vec![3.5, 0.0]);
+    }
+}
@@ -1,100 +1,566 @@
 // ki_node.rs: Manages the Ki node behavior, including fetching inputs, running computations, and sending outputs.
 
-use lapin::{options::*, types::FieldTable, BasicProperties, Connection, ConnectionProperties};
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use crate::allreduce::{self, Mailbox, Ring};
+use crate::dead_letter::DeadLetterPolicy;
+use crate::dedup::{Claim, DedupStore, DEFAULT_DEDUP_CAPACITY};
+use crate::kernels::{self, KernelRegistry};
+use crate::load_balancer;
+use crate::messages::{Envelope, NodeRegistration, ResultMessage, TaskMessage};
+use crate::messaging::{Consumer, Delivery, Transport, TransportError};
+use crate::routing;
+use crate::rpc;
+use futures_util::future::join_all;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Duration;
 use tracing::{error, info};
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TaskMessage {
-    task_id: String,
-    data: String,
+#[derive(Clone, Debug)]
+pub struct KiConfig {
+    pub capabilities: Vec<String>,
+    // Tasks computed at once; advertised to load balancers as the node's capacity
+    pub concurrency: usize,
+    // Unacknowledged deliveries each consumer may hold
+    pub prefetch: u16,
+    // Completed tasks remembered for deduplication, optionally journaled to `dedup_path`
+    pub dedup_capacity: usize,
+    pub dedup_path: Option<PathBuf>,
+    // Ops this node can run, advertised in its registration
+    pub kernels: KernelRegistry,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ResultMessage {
-    task_id: String,
-    result: String,
+// State shared by the task handlers of one Ki node.
+#[derive(Clone)]
+struct KiNode {
+    node_id: Uuid,
+    transport: Arc<dyn Transport>,
+    // Results of completed tasks, so redelivered tasks are answered without being recomputed
+    completed: DedupStore<ResultMessage>,
+    kernels: KernelRegistry,
+    // All-reduce chunks sent to this node by its ring predecessor
+    mailbox: Mailbox,
 }
 
-pub async fn run() -> Result<(), Box<dyn Error>> {
-    // Establish connection to RabbitMQ
-    let amqp_addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-    let connection = Connection::connect(&amqp_addr, ConnectionProperties::default()).await?;
-    let channel = connection.create_channel().await?;
-
-    // Declare the queue for receiving tasks from the An node
-    let queue_name = "ki_task_queue";
-    channel
-        .queue_declare(
-            queue_name,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-
-    // Start consuming tasks from the queue
-    let mut consumer = channel
-        .basic_consume(
-            queue_name,
-            "ki_consumer",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+impl KiConfig {
+    // Reads KI_CAPABILITIES, KI_CONCURRENCY (default: available cores), KI_PREFETCH (default: concurrency),
+    // KI_DEDUP_CAPACITY and KI_DEDUP_PATH.
+    pub fn from_env() -> Self {
+        let concurrency = std::env::var("KI_CONCURRENCY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&concurrency| concurrency > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+        let prefetch = std::env::var("KI_PREFETCH")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| concurrency.min(u16::MAX as usize) as u16);
+        let dedup_capacity = std::env::var("KI_DEDUP_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_DEDUP_CAPACITY);
+        KiConfig {
+            capabilities: routing::capabilities_from_env(),
+            concurrency,
+            prefetch,
+            dedup_capacity,
+            dedup_path: std::env::var_os("KI_DEDUP_PATH").map(PathBuf::from),
+            kernels: KernelRegistry::builtin(),
+        }
+    }
+}
+
+pub async fn run(transport: Arc<dyn Transport>) -> Result<(), TransportError> {
+    run_node(Uuid::new_v4(), KiConfig::from_env(), transport).await
+}
+
+// Serves tasks for each capability on its shared queue, plus tasks addressed to this node.
+pub async fn run_node(node_id: Uuid, config: KiConfig, transport: Arc<dyn Transport>) -> Result<(), TransportError> {
+    // The node's own queue goes away with it; the capability queues outlive any one node
+    let mut queues = vec![(routing::node_task_queue(node_id), routing::node_task_key(node_id), true)];
+    for capability in &config.capabilities {
+        queues.push((routing::capability_queue(capability), routing::ki_task_key(capability), false));
+    }
+
+    let node = KiNode {
+        node_id,
+        transport: transport.clone(),
+        completed: DedupStore::open(config.dedup_capacity, config.dedup_path.as_deref())?,
+        kernels: config.kernels.clone(),
+        mailbox: Mailbox::new(),
+    };
+    allreduce::spawn_listener(node_id, transport.clone(), node.mailbox.clone()).await?;
+
+    // One worker pool for all queues, so the node never computes more than `concurrency` tasks at once
+    let workers = Arc::new(Semaphore::new(config.concurrency));
+    let mut handlers = Vec::new();
+    for (queue_name, routing_key, per_node) in queues {
+        // Declare the queue for receiving tasks from An nodes
+        let dead_letters = match per_node {
+            true => routing::declare_node_queue(transport.as_ref(), &queue_name, &[routing_key]).await?,
+            false => routing::declare_routed_queue(transport.as_ref(), &queue_name, &[routing_key]).await?,
+        };
+
+        // Start consuming tasks from the queue
+        let consumer = transport
+            .consume_with_prefetch(&queue_name, &format!("ki_consumer_{}", node_id), config.prefetch)
+            .await?;
+        handlers.push(tokio::spawn(handle_tasks(node.clone(), consumer, dead_letters, workers.clone())));
+    }
+
+    tokio::spawn(announce(node_id, config.clone(), transport.clone()));
+
+    info!(
+        "Ki node {} is running and waiting for tasks ({:?}, ops {:?}, {} workers, prefetch {})...",
+        node_id,
+        config.capabilities,
+        config.kernels.ops(),
+        config.concurrency,
+        config.prefetch
+    );
+
+    for handler in join_all(handlers).await {
+        if let Err(e) = handler {
+            error!("Task handler stopped unexpectedly: {:?}", e);
+        }
+    }
+
+    Ok(())
+}
 
-    info!("Ki node is running and waiting for tasks...");
+// Periodically advertises this node's capabilities, ops and capacity to load balancers.
+async fn announce(node_id: Uuid, config: KiConfig, transport: Arc<dyn Transport>) {
+    let registration = NodeRegistration {
+        node_id: node_id.to_string(),
+        role: "ki".to_string(),
+        capabilities: config.capabilities,
+        capacity: config.concurrency as u32,
+        ops: config.kernels.ops(),
+        address: transport.peer_address().map(|address| address.to_string()),
+    };
+    load_balancer::announce(transport, node_id, registration).await
+}
 
+async fn handle_tasks(node: KiNode, mut consumer: Consumer, dead_letters: DeadLetterPolicy, workers: Arc<Semaphore>) {
+    let mut in_flight = JoinSet::new();
     while let Some(delivery) = consumer.next().await {
-        let (_, delivery) = delivery.expect("error in consumer");
-        let task_message: TaskMessage = serde_json::from_slice(&delivery.data)?;
+        // Wait for a free worker; deliveries waiting here are bounded by the consumer's prefetch
+        let Ok(worker) = workers.clone().acquire_owned().await else {
+            break;
+        };
+        let node = node.clone();
+        let dead_letters = dead_letters.clone();
+        in_flight.spawn(async move {
+            handle_delivery(&node, &dead_letters, delivery).await;
+            drop(worker);
+        });
 
-        info!("Received task: {:?}", task_message);
+        while let Some(finished) = in_flight.try_join_next() {
+            if let Err(e) = finished {
+                error!("Task worker failed: {:?}", e);
+            }
+        }
+    }
 
-        // Perform computation and generate result
-        let result = perform_computation(task_message).await;
+    // Let running tasks send their results and settle their deliveries before stopping
+    while let Some(finished) = in_flight.join_next().await {
+        if let Err(e) = finished {
+            error!("Task worker failed: {:?}", e);
+        }
+    }
+}
 
-        // Send the result back to the An node
-        if let Err(e) = send_result(result, &channel).await {
-            error!("Failed to send result: {:?}", e);
+// Each delivery is acknowledged individually and only after its result has been published, so
+// tasks finishing out of order never acknowledge work that is still running.
+async fn handle_delivery(node: &KiNode, dead_letters: &DeadLetterPolicy, delivery: Delivery) {
+    let transport = node.transport.as_ref();
+    let envelope = match Envelope::<TaskMessage>::from_delivery(&delivery) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            error!("Rejecting task message: {}", e);
+            if let Err(e) = dead_letters.dead_letter(transport, &delivery, &e.to_string()).await {
+                error!("Failed to dead-letter task message: {:?}", e);
+            }
+            return;
         }
+    };
 
-        // Acknowledge the message
-        delivery.ack(BasicAckOptions::default()).await?;
+    info!("Received task from {}: {:?}", envelope.sender, envelope.payload);
+
+    // Reuse the result of an earlier delivery of the same task, waiting if it is still being computed
+    let key = envelope.payload.dedup_key().to_string();
+    let result = loop {
+        match node.completed.claim(&key) {
+            Claim::Claimed => {
+                // Perform computation and generate result
+                let mut result = perform_computation(&node.kernels, envelope.payload.clone()).await;
+                if envelope.payload.attrs.contains_key("ring") {
+                    result = reduce_across_ring(node, &envelope.payload, result).await;
+                }
+                node.completed.complete(&key, result.clone());
+                break result;
+            }
+            Claim::Completed(Some(result)) => {
+                info!("Task {} was already computed, resending its result", key);
+                break result;
+            }
+            // Computing it again could repeat a collective the ring has moved past
+            Claim::Completed(None) => {
+                info!("Task {} was computed before a restart, its result was not kept", key);
+                break ResultMessage::failed(&envelope.payload, "computed before a restart; its result was not kept");
+            }
+            Claim::Running(mut finished) => {
+                let _ = finished.changed().await;
+            }
+        }
+    };
+
+    // Send the result back to the caller, or to the An result queue if nobody is waiting for it
+    if let Err(e) = send_result(node.node_id, &envelope, result, transport).await {
+        error!("Failed to send result: {:?}", e);
+        if let Err(e) = dead_letters
+            .retry_or_dead_letter(transport, &delivery, &e.to_string())
+            .await
+        {
+            error!("Failed to requeue task message: {:?}", e);
+        }
+        return;
     }
 
-    Ok(())
+    // Acknowledge the message
+    if let Err(e) = delivery.ack().await {
+        error!("Failed to acknowledge message: {:?}", e);
+    }
 }
 
-async fn perform_computation(task: TaskMessage) -> ResultMessage {
-    // Placeholder for the computation logic
-    // Simulate some processing based on the input data
-    info!("Performing computation for task ID: {}", task.task_id);
-    let computed_result = format!("Processed data: {}", task.data);
+// Runs the task's kernel on the blocking thread pool. Failures are returned to the caller as a
+// result rather than retried, since running the same kernel on the same inputs fails again.
+async fn perform_computation(kernels: &KernelRegistry, task: TaskMessage) -> ResultMessage {
+    info!("Performing {} for task ID: {} ({:?})", task.op, task.task_id, task.inputs);
+    let kernels = kernels.clone();
+    // Tensor clones share their buffers
+    let (op, inputs, attrs) = (task.op.clone(), task.inputs.clone(), task.attrs.clone());
+    let computed = tokio::task::spawn_blocking(move || kernels.run(&op, &inputs, &attrs)).await;
 
-    ResultMessage {
-        task_id: task.task_id,
-        result: computed_result,
+    match computed {
+        Ok(Ok(outputs)) => ResultMessage::for_task(&task, outputs),
+        Ok(Err(e)) => {
+            error!("Task {} failed: {}", task.task_id, e);
+            ResultMessage::failed(&task, e.to_string())
+        }
+        Err(e) => {
+            error!("Kernel for task {} panicked: {:?}", task.task_id, e);
+            ResultMessage::failed(&task, format!("kernel panicked: {}", e))
+        }
     }
 }
 
-async fn send_result(result: ResultMessage, channel: &lapin::Channel) -> Result<(), Box<dyn Error>> {
-    let result_queue = "an_result_queue";
+// Sums a task's outputs with those of the other Ki nodes in the ring the task names (attribute `ring`,
+// the members' ids), each first scaled by the attribute `allreduce_scale`. The chunks are tagged with
+// the attribute `collective`, and `collective_timeout_ms` bounds the wait for each one. Only the first
+// member of the ring replies with the sums; the others reply with no outputs.
+async fn reduce_across_ring(node: &KiNode, task: &TaskMessage, result: ResultMessage) -> ResultMessage {
+    let collective = match kernels::str_attr(&task.attrs, "collective") {
+        Ok(collective) => collective.unwrap_or(task.dedup_key()).to_string(),
+        Err(e) => return ResultMessage::failed(task, e.to_string()),
+    };
+    let result = reduce_shard(node, task, result, &collective).await;
+    // The other members' chunks arrive however this node failed, and nothing else would take them
+    if result.error.is_some() {
+        node.mailbox.discard(&collective);
+    }
+    result
+}
 
-    // Serialize the result message
-    let payload = serde_json::to_vec(&result)?;
+async fn reduce_shard(node: &KiNode, task: &TaskMessage, result: ResultMessage, collective: &str) -> ResultMessage {
+    if result.error.is_some() {
+        return result;
+    }
+    let settings = (|| {
+        let members = match task.attrs.get("ring") {
+            Some(kernels::Attr::Strs(members)) => members
+                .iter()
+                .map(|member| Uuid::parse_str(member).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, _>>()?,
+            other => return Err(format!("attribute ring should be a list of node ids, got {:?}", other)),
+        };
+        let scale = kernels::float_attr(&task.attrs, "allreduce_scale").map_err(|e| e.to_string())?;
+        let timeout = kernels::int_attr(&task.attrs, "collective_timeout_ms").map_err(|e| e.to_string())?;
+        let timeout = timeout.map_or(allreduce::DEFAULT_COLLECTIVE_TIMEOUT, |ms| Duration::from_millis(ms.max(1) as u64));
+        // Addresses of the members, in the same order, where they take peer connections
+        let routes = match task.attrs.get("ring_addresses") {
+            Some(kernels::Attr::Strs(addresses)) => members
+                .iter()
+                .zip(addresses)
+                .filter(|(_, address)| !address.is_empty())
+                .map(|(&member, address)| Ok((member, address.parse::<SocketAddr>().map_err(|e| e.to_string())?)))
+                .collect::<Result<Vec<_>, String>>()?,
+            None => Vec::new(),
+            other => return Err(format!("attribute ring_addresses should be a list of addresses, got {:?}", other)),
+        };
+        Ok((Ring::new(members), routes, scale.unwrap_or(1.0) as f32, timeout))
+    })();
+    let (ring, routes, scale, timeout) = match settings {
+        Ok(settings) => settings,
+        Err(e) => return ResultMessage::failed(task, e),
+    };
+    // Chunks then travel over one persistent connection per member instead of through the broker
+    for (member, address) in routes {
+        node.transport.add_peer_route(&routing::node_collective_key(member), address);
+    }
 
-    // Publish the result to the An node
-    channel
-        .basic_publish(
-            "",
-            result_queue,
-            BasicPublishOptions::default(),
-            &payload,
-            BasicProperties::default(),
-        )
-        .await?;
+    let values = match allreduce::flatten(&result.outputs) {
+        Ok(values) => values.into_iter().map(|value| value * scale).collect(),
+        Err(e) => return ResultMessage::failed(task, e.to_string()),
+    };
+    let transport = node.transport.as_ref();
+    let reduced = allreduce::ring_allreduce(&ring, node.node_id, collective, values, &node.mailbox, timeout, |to, chunk| {
+        allreduce::send_chunk(transport, node.node_id, to, chunk)
+    })
+    .await;
+    match reduced {
+        Ok(values) if ring.position(node.node_id) == Some(0) => match allreduce::unflatten(&values, &result.outputs) {
+            Ok(outputs) => ResultMessage::for_task(task, outputs),
+            Err(e) => ResultMessage::failed(task, e.to_string()),
+        },
+        Ok(_) => ResultMessage::for_task(task, Vec::new()),
+        Err(e) => {
+            error!("All-reduce for task {} failed: {}", task.task_id, e);
+            ResultMessage::failed(task, format!("all-reduce failed: {}", e))
+        }
+    }
+}
+
+async fn send_result(
+    node_id: Uuid,
+    task: &Envelope<TaskMessage>,
+    result: ResultMessage,
+    transport: &dyn Transport,
+) -> Result<(), TransportError> {
+    let task_id = result.task_id.clone();
+
+    if !rpc::respond(transport, node_id, task, result.clone()).await? {
+        let (payload, headers) = task.reply(node_id, result).encode(&task.reply_codec())?;
+        transport
+            .publish_routed(routing::EXCHANGE, &routing::an_result_key(), &payload, &headers)
+            .await?;
+    }
 
-    info!("Sent result for task ID: {}", result.task_id);
+    info!("Sent result for task ID: {}", task_id);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecConfig;
+    use crate::messaging::{Headers, InMemoryBroker};
+    use crate::load_balancer::{self, LoadBalancer};
+    use crate::node_registry::NodeRegistry;
+    use crate::rpc::RpcClient;
+    use crate::tensor::Tensor;
+
+    fn input() -> Tensor {
+        Tensor::new(vec![2, 2], vec![1.0f32, 2.0, 3.0, 4.0]).unwrap()
+    }
+
+    fn config(capabilities: &[&str]) -> KiConfig {
+        KiConfig {
+            capabilities: capabilities.iter().map(|capability| capability.to_string()).collect(),
+            concurrency: 4,
+            prefetch: 4,
+            dedup_capacity: DEFAULT_DEDUP_CAPACITY,
+            dedup_path: None,
+            kernels: KernelRegistry::builtin(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ki_node_replies_to_rpc_caller() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let capability_queue = routing::capability_queue(routing::DEFAULT_CAPABILITY);
+        let routing_key = routing::ki_task_key(routing::DEFAULT_CAPABILITY);
+        routing::declare_routed_queue(broker.as_ref(), &capability_queue, std::slice::from_ref(&routing_key))
+            .await
+            .unwrap();
+        tokio::spawn(run_node(Uuid::new_v4(), config(&[routing::DEFAULT_CAPABILITY]), broker.clone()));
+
+        let client = RpcClient::new(Uuid::new_v4(), broker).await.unwrap();
+        let reply: Envelope<ResultMessage> = client
+            .call(
+                &routing_key,
+                TaskMessage::new("task-1", "relu", vec![input()]),
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.payload.task_id, "task-1");
+        assert_eq!(reply.payload.outputs, vec![input()]);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_task_is_dead_lettered() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let capability_queue = routing::capability_queue("matmul");
+        let dead_letters = routing::declare_routed_queue(broker.as_ref(), &capability_queue, &[routing::ki_task_key("matmul")])
+            .await
+            .unwrap();
+        tokio::spawn(run_node(Uuid::new_v4(), config(&["matmul"]), broker.clone()));
+
+        broker.publish_with_headers(&capability_queue, b"not an envelope", &Headers::new()).await.unwrap();
+
+        let mut letters = Vec::new();
+        for _ in 0..50 {
+            letters = dead_letters.inspect(broker.as_ref(), 10).await.unwrap();
+            if !letters.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].data, b"not an envelope");
+    }
+
+    #[tokio::test]
+    async fn test_task_reaches_addressed_node_only() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let (chosen, other) = (Uuid::new_v4(), Uuid::new_v4());
+        for node_id in [chosen, other] {
+            routing::declare_routed_queue(broker.as_ref(), &routing::node_task_queue(node_id), &[routing::node_task_key(node_id)])
+                .await
+                .unwrap();
+            tokio::spawn(run_node(node_id, config(&[]), broker.clone()));
+        }
+
+        let client = RpcClient::new(Uuid::new_v4(), broker).await.unwrap();
+        for i in 0..4 {
+            let reply: Envelope<ResultMessage> = client
+                .call(
+                    &routing::node_task_key(chosen),
+                    TaskMessage::new(format!("task-{}", i), "relu", vec![input()]),
+                    Duration::from_secs(5),
+                )
+                .await
+                .unwrap();
+            assert_eq!(reply.sender, chosen);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_tasks_all_answered() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let routing_key = routing::ki_task_key("batch");
+        routing::declare_routed_queue(broker.as_ref(), &routing::capability_queue("batch"), std::slice::from_ref(&routing_key))
+            .await
+            .unwrap();
+        tokio::spawn(run_node(Uuid::new_v4(), config(&["batch"]), broker.clone()));
+
+        let client = RpcClient::new(Uuid::new_v4(), broker).await.unwrap();
+        let calls = (0..16).map(|i| {
+            let client = client.clone();
+            let routing_key = routing_key.clone();
+            async move {
+                let reply: Envelope<ResultMessage> = client
+                    .call(
+                        &routing_key,
+                        TaskMessage::new(format!("task-{}", i), "relu", vec![Tensor::scalar(i as f32)]),
+                        Duration::from_secs(5),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(reply.payload.outputs, vec![Tensor::scalar(i as f32)]);
+            }
+        });
+        join_all(calls).await;
+    }
+
+    #[tokio::test]
+    async fn test_ki_node_advertises_capacity() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let load_balancer = LoadBalancer::new();
+        load_balancer::spawn_registration_listener(
+            broker.clone(),
+            load_balancer.clone(),
+            NodeRegistry::new(),
+            Uuid::new_v4(),
+            "ki",
+        )
+            .await
+            .unwrap();
+
+        let node_id = Uuid::new_v4();
+        tokio::spawn(run_node(node_id, config(&[]), broker));
+
+        for _ in 0..50 {
+            if load_balancer.nodes.read().unwrap().contains_key(&node_id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let nodes = load_balancer.nodes.read().unwrap();
+        assert_eq!(nodes[&node_id].capacity, 4);
+        assert!(nodes[&node_id].ops.contains("matmul"));
+    }
+
+    #[tokio::test]
+    async fn test_kernel_failure_is_returned_to_caller() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let node_id = Uuid::new_v4();
+        routing::declare_routed_queue(broker.as_ref(), &routing::node_task_queue(node_id), &[routing::node_task_key(node_id)])
+            .await
+            .unwrap();
+        tokio::spawn(run_node(node_id, config(&[]), broker.clone()));
+
+        let client = RpcClient::new(Uuid::new_v4(), broker).await.unwrap();
+        let matrix = Tensor::new(vec![2, 3], vec![1.0f32; 6]).unwrap();
+        for (op, inputs) in [("conv9d", vec![input()]), ("matmul", vec![matrix.clone(), matrix])] {
+            let reply: Envelope<ResultMessage> = client
+                .call(
+                    &routing::node_task_key(node_id),
+                    TaskMessage::new("task-1", op, inputs),
+                    Duration::from_secs(5),
+                )
+                .await
+                .unwrap();
+            assert!(reply.payload.outputs.is_empty());
+            assert!(reply.payload.error.is_some(), "{} should fail", op);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_task_reuses_stored_result() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        let dead_letters = routing::declare_routed_queue(broker.as_ref(), "redelivery_queue", &[]).await.unwrap();
+        routing::declare_routed_queue(broker.as_ref(), &routing::an_result_queue(), &[routing::an_result_key()])
+            .await
+            .unwrap();
+
+        let task = TaskMessage::new("task-1", "relu", vec![input()]);
+        let completed = DedupStore::new(DEFAULT_DEDUP_CAPACITY);
+        assert!(matches!(completed.claim(task.dedup_key()), Claim::Claimed));
+        let stored = vec![Tensor::scalar(42.0f64)];
+        completed.complete(task.dedup_key(), ResultMessage::for_task(&task, stored.clone()));
+
+        Envelope::new(Uuid::new_v4(), task)
+            .publish_with(broker.as_ref(), "redelivery_queue", CodecConfig::global())
+            .await
+            .unwrap();
+        let delivery = broker.get("redelivery_queue").await.unwrap().unwrap();
+        let node = KiNode {
+            node_id: Uuid::new_v4(),
+            transport: broker.clone(),
+            completed,
+            kernels: KernelRegistry::builtin(),
+            mailbox: Mailbox::new(),
+        };
+        handle_delivery(&node, &dead_letters, delivery).await;
+
+        let delivery = broker.get(&routing::an_result_queue()).await.unwrap().unwrap();
+        let result = Envelope::<ResultMessage>::from_delivery(&delivery).unwrap();
+        assert_eq!(result.payload.outputs, stored);
+        assert!(broker.get("redelivery_queue").await.unwrap().is_none());
+    }
+}
@@ -1,19 +1,32 @@
 // load_balancer.rs: Implements load balancing for An nodes to effectively distribute tasks.
 
-use std::collections::HashMap;
+use crate::messages::{Envelope, NodeRegistration};
+use crate::messaging::{Transport, TransportError};
+use crate::node_registry::NodeRegistry;
+use crate::routing;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
 use tracing::{info, error};
 use rand::seq::IteratorRandom;
 
+// How often nodes re-announce themselves, so listeners started later still learn about them
+pub const REGISTRATION_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Clone, Debug)]
 pub struct NodeLoadInfo {
     pub node_id: Uuid,
     pub task_count: usize,
+    // Number of tasks the node can run at once
+    pub capacity: usize,
+    // Kernels the node has registered
+    pub ops: HashSet<String>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct LoadBalancer {
     pub nodes: Arc<RwLock<HashMap<Uuid, NodeLoadInfo>>>,
 }
@@ -26,9 +39,36 @@ impl LoadBalancer {
     }
 
     pub fn add_node(&self, node_id: Uuid) {
+        self.add_node_with_capacity(node_id, 1);
+    }
+
+    // Adds a node, or updates the capacity of a known node without resetting its task count.
+    pub fn add_node_with_capacity(&self, node_id: Uuid, capacity: usize) {
+        let capacity = capacity.max(1);
         let mut nodes = self.nodes.write().unwrap();
-        nodes.insert(node_id, NodeLoadInfo { node_id, task_count: 0 });
-        info!("Added node to load balancer: {}", node_id);
+        match nodes.get_mut(&node_id) {
+            Some(node_info) => node_info.capacity = capacity,
+            None => {
+                nodes.insert(
+                    node_id,
+                    NodeLoadInfo {
+                        node_id,
+                        task_count: 0,
+                        capacity,
+                        ops: HashSet::new(),
+                    },
+                );
+                info!("Added node to load balancer: {} (capacity {})", node_id, capacity);
+            }
+        }
+    }
+
+    // Like `add_node_with_capacity`, and also replaces the ops the node can run.
+    pub fn register_node(&self, node_id: Uuid, capacity: usize, ops: impl IntoIterator<Item = String>) {
+        self.add_node_with_capacity(node_id, capacity);
+        if let Some(node_info) = self.nodes.write().unwrap().get_mut(&node_id) {
+            node_info.ops = ops.into_iter().collect();
+        }
     }
 
     pub fn remove_node(&self, node_id: &Uuid) {
@@ -41,22 +81,51 @@ impl LoadBalancer {
     }
 
     pub fn assign_task(&self) -> Option<Uuid> {
+        self.assign_where(|_| true)
+    }
+
+    // Assigns a task to the least loaded node that has registered a kernel for `op`.
+    pub fn assign_task_for(&self, op: &str) -> Option<Uuid> {
+        self.assign_where(|node_info| node_info.ops.contains(op))
+    }
+
+    fn assign_where(&self, eligible: impl Fn(&NodeLoadInfo) -> bool) -> Option<Uuid> {
         let mut nodes = self.nodes.write().unwrap();
         if nodes.is_empty() {
             error!("No nodes available to assign task.");
             return None;
         }
 
-        // Find the node with the least tasks
-        if let Some((node_id, node_info)) = nodes.values_mut().min_by_key(|n| n.task_count) {
+        // Find the eligible node with the lowest load relative to its capacity
+        if let Some(node_info) = nodes
+            .values_mut()
+            .filter(|node_info| eligible(node_info))
+            .min_by(|a, b| (a.task_count * b.capacity).cmp(&(b.task_count * a.capacity)))
+        {
             node_info.task_count += 1;
-            info!("Assigned task to node: {}. Task count: {}", node_id, node_info.task_count);
-            Some(*node_id)
+            info!("Assigned task to node: {}. Task count: {}", node_info.node_id, node_info.task_count);
+            Some(node_info.node_id)
         } else {
             None
         }
     }
 
+    // Number of nodes that have registered a kernel for `op`
+    pub fn nodes_for(&self, op: &str) -> usize {
+        let nodes = self.nodes.read().unwrap();
+        nodes.values().filter(|node_info| node_info.ops.contains(op)).count()
+    }
+
+    // Id and capacity of each node that has registered a kernel for `op`
+    pub fn capacities_for(&self, op: &str) -> Vec<(Uuid, usize)> {
+        let nodes = self.nodes.read().unwrap();
+        nodes
+            .values()
+            .filter(|node_info| node_info.ops.contains(op))
+            .map(|node_info| (node_info.node_id, node_info.capacity))
+            .collect()
+    }
+
     pub fn complete_task(&self, node_id: &Uuid) {
         let mut nodes = self.nodes.write().unwrap();
         if let Some(node_info) = nodes.get_mut(node_id) {
@@ -86,3 +155,126 @@ pub async fn monitor_node_load(mut rx: broadcast::Receiver<NodeLoadInfo>, load_b
         }
     }
 }
+
+// Periodically advertises a node to the registration listeners for its role.
+pub async fn announce(transport: Arc<dyn Transport>, node_id: Uuid, registration: NodeRegistration) {
+    let routing_key = routing::node_registration_key(&registration.role);
+    let mut ticker = interval(REGISTRATION_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = Envelope::new(node_id, registration.clone())
+            .route(transport.as_ref(), &routing_key)
+            .await
+        {
+            error!("Failed to announce {} node {}: {:?}", registration.role, node_id, e);
+        }
+    }
+}
+
+// Keeps the load balancer up to date with the capacity nodes announce for `role`, and the registry with
+// when each was last heard from.
+pub async fn spawn_registration_listener(
+    transport: Arc<dyn Transport>,
+    load_balancer: LoadBalancer,
+    registry: NodeRegistry,
+    listener_id: Uuid,
+    role: &str,
+) -> Result<(), TransportError> {
+    let queue_name = routing::registration_queue(listener_id);
+    let dead_letters = routing::declare_node_queue(
+        transport.as_ref(),
+        &queue_name,
+        &[routing::node_registration_key(role)],
+    )
+    .await?;
+    let mut consumer = transport
+        .consume(&queue_name, &format!("registration_listener_{}", listener_id))
+        .await?;
+
+    let role = role.to_string();
+    tokio::spawn(async move {
+        while let Some(delivery) = consumer.next().await {
+            let registration = Envelope::<NodeRegistration>::from_delivery(&delivery)
+                .map_err(|e| e.to_string())
+                .and_then(|envelope| {
+                    let node_id = Uuid::parse_str(&envelope.payload.node_id).map_err(|e| e.to_string())?;
+                    let address = match envelope.payload.address {
+                        Some(address) => Some(address.parse::<SocketAddr>().map_err(|e| e.to_string())?),
+                        None => None,
+                    };
+                    Ok((node_id, envelope.payload.capacity as usize, envelope.payload.ops, address))
+                });
+            match registration {
+                Ok((node_id, capacity, ops, address)) => {
+                    load_balancer.register_node(node_id, capacity, ops);
+                    registry.heartbeat(node_id, &role);
+                    // Tasks addressed to the node go straight to it when the transport takes peer connections
+                    if let Some(address) = address {
+                        registry.set_address(&node_id, address);
+                        transport.add_peer_route(&routing::node_task_key(node_id), address);
+                    }
+                    if let Err(e) = delivery.ack().await {
+                        error!("Failed to acknowledge message: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("Rejecting node registration: {}", e);
+                    if let Err(e) = dead_letters.dead_letter(transport.as_ref(), &delivery, &e).await {
+                        error!("Failed to dead-letter node registration: {:?}", e);
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign_task_weighs_capacity() {
+        let load_balancer = LoadBalancer::new();
+        let small = Uuid::new_v4();
+        let large = Uuid::new_v4();
+        load_balancer.add_node_with_capacity(small, 1);
+        load_balancer.add_node_with_capacity(large, 3);
+
+        let mut assigned = HashMap::new();
+        for _ in 0..8 {
+            *assigned.entry(load_balancer.assign_task().unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(assigned[&small], 2);
+        assert_eq!(assigned[&large], 6);
+    }
+
+    #[test]
+    fn test_assign_task_for_op() {
+        let load_balancer = LoadBalancer::new();
+        let dense = Uuid::new_v4();
+        let conv = Uuid::new_v4();
+        load_balancer.register_node(dense, 1, vec!["dense_forward".to_string(), "relu".to_string()]);
+        load_balancer.register_node(conv, 8, vec!["conv2d".to_string(), "relu".to_string()]);
+
+        for _ in 0..3 {
+            assert_eq!(load_balancer.assign_task_for("dense_forward"), Some(dense));
+        }
+        assert_eq!(load_balancer.assign_task_for("relu"), Some(conv));
+        assert_eq!(load_balancer.assign_task_for("attention"), None);
+        assert_eq!(load_balancer.nodes_for("relu"), 2);
+    }
+
+    #[test]
+    fn test_reregistration_keeps_task_count() {
+        let load_balancer = LoadBalancer::new();
+        let node_id = Uuid::new_v4();
+        load_balancer.add_node_with_capacity(node_id, 2);
+        load_balancer.assign_task();
+        load_balancer.add_node_with_capacity(node_id, 4);
+
+        let nodes = load_balancer.nodes.read().unwrap();
+        assert_eq!(nodes[&node_id].task_count, 1);
+        assert_eq!(nodes[&node_id].capacity, 4);
+    }
+}
@@ -1,9 +1,13 @@
 // logging_metrics.rs: Implements logging and metrics collection for monitoring node health and performance.
 
-use tracing::{info, error, warn, debug, Level};
-use tracing_subscriber::{fmt, EnvFilter};
-use std::time::{Instant, Duration};
-use prometheus::{Encoder, TextEncoder, Counter, Histogram, register_counter, register_histogram};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_counter, register_counter_vec, register_gauge_vec, register_histogram,
+    register_histogram_vec, Counter, CounterVec, Encoder, GaugeVec, Histogram, HistogramVec, TextEncoder,
+};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+use tracing_subscriber::EnvFilter;
 use warp::Filter;
 
 // Metrics definitions
@@ -16,6 +20,28 @@ lazy_static! {
         "task_processing_seconds",
         "Histogram of task processing times"
     ).unwrap();
+    static ref TRAINING_STEPS: CounterVec = register_counter_vec!(
+        "training_steps_total",
+        "Total number of training steps applied, by model",
+        &["model"]
+    ).unwrap();
+    static ref TRAINING_LOSS: GaugeVec = register_gauge_vec!(
+        "training_loss",
+        "Loss of the latest training step, by model",
+        &["model"]
+    ).unwrap();
+    static ref INFERENCE_QUEUE_LATENCY: HistogramVec = register_histogram_vec!(
+        "inference_queue_seconds",
+        "Time inference requests wait for their batch to be dispatched, by model and version",
+        &["model", "version"],
+        exponential_buckets(0.0005, 2.0, 14).unwrap()
+    ).unwrap();
+    static ref INFERENCE_BATCH_SIZE: HistogramVec = register_histogram_vec!(
+        "inference_batch_rows",
+        "Rows in each dispatched inference batch, by model and version",
+        &["model", "version"],
+        exponential_buckets(1.0, 2.0, 11).unwrap()
+    ).unwrap();
 }
 
 pub fn init_logging() {
@@ -33,6 +59,22 @@ pub fn log_task_processing(start_time: Instant) {
     debug!("Task processed in {:?} seconds.", elapsed);
 }
 
+pub fn log_training_step(model: &str, step: u64, loss: f32) {
+    TRAINING_STEPS.with_label_values(&[model]).inc();
+    TRAINING_LOSS.with_label_values(&[model]).set(loss as f64);
+    info!("Model {} step {}: loss {:.6}", model, step, loss);
+}
+
+pub fn log_inference_batch(model: &str, version: u64, rows: usize, queue_latencies: &[Duration]) {
+    let version = version.to_string();
+    let labels = [model, version.as_str()];
+    INFERENCE_BATCH_SIZE.with_label_values(&labels).observe(rows as f64);
+    for latency in queue_latencies {
+        INFERENCE_QUEUE_LATENCY.with_label_values(&labels).observe(latency.as_secs_f64());
+    }
+    debug!("Dispatching {} rows of model {} v{} from {} requests", rows, model, version, queue_latencies.len());
+}
+
 pub async fn metrics_endpoint() -> impl warp::Reply {
     let encoder = TextEncoder::new();
     let metric_families = prometheus::gather();
@@ -42,7 +84,7 @@ pub async fn metrics_endpoint() -> impl warp::Reply {
 }
 
 pub async fn run_metrics_server() {
-    let metrics_route = warp::path("metrics").map(metrics_endpoint);
+    let metrics_route = warp::path("metrics").then(metrics_endpoint);
     warp::serve(metrics_route).run(([127, 0, 0, 1], 9090)).await;
 }
 
@@ -50,6 +92,7 @@ pub async fn run_metrics_server() {
 mod tests {
     use super::*;
     use std::time::Duration;
+    use warp::Reply;
 
     #[test]
     fn test_logging_initialization() {
@@ -69,4 +112,20 @@ mod tests {
         std::thread::sleep(Duration::from_millis(100));
         log_task_processing(start_time);
     }
+
+    #[test]
+    fn test_log_training_step() {
+        log_training_step("metrics-test", 1, 0.5);
+        log_training_step("metrics-test", 2, 0.25);
+        assert_eq!(TRAINING_STEPS.with_label_values(&["metrics-test"]).get(), 2.0);
+        assert_eq!(TRAINING_LOSS.with_label_values(&["metrics-test"]).get(), 0.25);
+    }
+
+    #[test]
+    fn test_log_inference_batch() {
+        log_inference_batch("metrics-test", 2, 6, &[Duration::from_millis(1), Duration::from_millis(3)]);
+        let batch_size = INFERENCE_BATCH_SIZE.with_label_values(&["metrics-test", "2"]);
+        assert_eq!((batch_size.get_sample_count(), batch_size.get_sample_sum()), (1, 6.0));
+        assert_eq!(INFERENCE_QUEUE_LATENCY.with_label_values(&["metrics-test", "2"]).get_sample_count(), 2);
+    }
 }
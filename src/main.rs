@@ -1,6 +1,7 @@
 // main.rs: entry-point
 
 use std::env;
+use std::sync::Arc;
 use tracing::error;
 
 mod an_node;
@@ -11,6 +12,37 @@ mod node_registry; // Added node registry module
 mod backup; // Added backup module
 mod api; // Added API module
 mod task_recovery; // Added task recovery module
+mod messaging; // Added messaging transport module
+mod messages; // Added message envelope module
+mod rpc; // Added request/reply RPC module
+mod dead_letter; // Added dead-letter queue module
+mod backoff; // Added retry backoff module
+mod codec; // Added payload codec module
+mod routing; // Added topic routing module
+mod scheduler; // Added task scheduler module
+mod load_balancer; // Added load balancer module
+mod dedup; // Added idempotency store module
+mod network; // Added direct node-to-node transport module
+mod tensor; // Added tensor module
+mod kernels; // Added compute kernel registry module
+mod mlp; // Added multi-layer perceptron module
+mod model; // Added model spec and weights loader module
+mod pipeline; // Added pipeline parallelism module
+mod tensor_parallel; // Added tensor parallelism module
+mod logging_metrics; // Added logging and metrics module
+mod training; // Added data-parallel training module
+mod autodiff; // Added reverse-mode autodiff module
+mod optimizer; // Added optimizer and learning-rate schedule module
+mod allreduce; // Added ring all-reduce module
+mod checkpoint; // Added training checkpoint module
+mod serving; // Added online inference serving module
+mod dataset; // Added dataset sharding module
+mod conv; // Added convolution and pooling kernel module
+mod attention; // Added transformer attention kernel module
+#[cfg(test)]
+mod test_support; // Added shared kernel test helpers module
+
+use messaging::{InMemoryBroker, Transport};
 
 #[tokio::main]
 async fn main() {
@@ -20,27 +52,72 @@ async fn main() {
     // Determine the node type based on an environment variable or command-line argument
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        error!("Usage: distributed_neural_network [node_type]");
+        error!("Usage: distributed_neural_network [principal|an|ki|local|dlq|checkpoint]");
         std::process::exit(1);
     }
 
     let node_type = &args[1];
 
+    // "local" runs one node of each type in this process on an in-memory broker
+    if node_type == "local" {
+        let transport: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
+        if let Err(e) = tokio::try_join!(
+            principal::run(transport.clone()),
+            an_node::run(transport.clone()),
+            ki_node::run(transport.clone()),
+        ) {
+            error!("Failed to run local cluster: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let transport = match messaging::transport_from_env().await {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("Failed to set up message transport: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
     match node_type.as_str() {
+        "dlq" => {
+            if let Err(e) = run_dead_letter_command(&args[2..], transport.as_ref()).await {
+                error!("Dead-letter command failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        "checkpoint" => {
+            if let Err(e) = run_checkpoint_command(&args[2..], transport).await {
+                error!("Checkpoint command failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
         "principal" => {
-            if let Err(e) = principal::run().await {
+            if let Err(e) = principal::run(transport).await {
                 error!("Failed to run principal node: {:?}", e);
                 std::process::exit(1);
             }
         }
         "an" => {
-            if let Err(e) = an_node::run().await {
+            // Serves training loss and task timings for Prometheus
+            tokio::spawn(logging_metrics::run_metrics_server());
+            // Serves the task API and online inference over HTTP, on API_PORT (3030 by default)
+            match serving::InferenceServer::connect(transport.clone(), serving::BatchingConfig::from_env()).await {
+                Ok(inference) => {
+                    let port = env::var("API_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(3030);
+                    let task_manager = Arc::new(task_recovery::TaskRecoveryManager::new("tasks.json"));
+                    tokio::spawn(api::serve(api::Api::new(task_manager).with_inference(inference), port));
+                }
+                Err(e) => error!("Failed to start the inference server: {:?}", e),
+            }
+            if let Err(e) = an_node::run(transport).await {
                 error!("Failed to run an node: {:?}", e);
                 std::process::exit(1);
             }
         }
         "ki" => {
-            if let Err(e) = ki_node::run().await {
+            if let Err(e) = ki_node::run(transport).await {
                 error!("Failed to run ki node: {:?}", e);
                 std::process::exit(1);
             }
@@ -51,3 +128,49 @@ async fn main() {
         }
     }
 }
+
+// Handles `checkpoint <an node id> <model>`: asks the An node to checkpoint the model and waits for the result.
+async fn run_checkpoint_command(args: &[String], transport: Arc<dyn Transport>) -> Result<(), messaging::TransportError> {
+    let (node_id, model) = match args {
+        [node_id, model, ..] => (uuid::Uuid::parse_str(node_id)?, model.as_str()),
+        _ => return Err("Usage: distributed_neural_network checkpoint <an node id> <model>".into()),
+    };
+    let rpc_client = rpc::RpcClient::new(uuid::Uuid::new_v4(), transport).await?;
+    let ack = principal::request_checkpoint(&rpc_client, node_id, model, rpc::DEFAULT_RPC_TIMEOUT).await?;
+    match ack.error {
+        None => println!("Saved checkpoint {} of model {} at step {}", ack.version, ack.model, ack.step),
+        Some(e) => return Err(format!("Node {} could not checkpoint model {}: {}", node_id, ack.model, e).into()),
+    }
+    Ok(())
+}
+
+// Handles `dlq inspect <queue> [limit]` and `dlq replay <queue> [limit]`.
+async fn run_dead_letter_command(args: &[String], transport: &dyn Transport) -> Result<(), messaging::TransportError> {
+    let (command, queue_name) = match args {
+        [command, queue_name, ..] => (command.as_str(), queue_name.as_str()),
+        _ => return Err("Usage: distributed_neural_network dlq [inspect|replay] <queue> [limit]".into()),
+    };
+    let limit = match args.get(2) {
+        Some(limit) => limit.parse()?,
+        None => 100,
+    };
+    let policy = dead_letter::DeadLetterPolicy::new(queue_name, dead_letter::DEFAULT_MAX_ATTEMPTS);
+
+    match command {
+        "inspect" => {
+            for letter in policy.inspect(transport, limit).await? {
+                println!(
+                    "{}\t{}",
+                    letter.reason().unwrap_or("unknown"),
+                    String::from_utf8_lossy(&letter.data)
+                );
+            }
+        }
+        "replay" => {
+            let replayed = policy.replay(transport, limit).await?;
+            println!("Replayed {} messages onto {}", replayed, queue_name);
+        }
+        _ => return Err(format!("Unknown dlq command: {}", command).into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,502 @@
+// messages.rs: Defines the versioned envelope and the payload types shared by every node on the message bus.
+
+use crate::codec::{self, Codec, CodecConfig};
+use crate::kernels::{Attr, Attrs};
+use crate::messaging::{Delivery, Headers, Transport, TransportError};
+use crate::routing;
+use crate::tensor::Tensor;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use uuid::Uuid;
+
+// Bump the major version for changes older nodes cannot read; minor versions must stay compatible with
+// the self-describing codecs. Bincode is read by position, so it only works between nodes on the same
+// version, and replies to a node on another version fall back to JSON.
+// 2.0 replaced the string task data and results with tensors.
+pub const SCHEMA_VERSION: SchemaVersion = SchemaVersion { major: 2, minor: 5 };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Task,
+    Result,
+    RoleAssignment,
+    RoleAck,
+    UpdateRequest,
+    // Added in 1.2
+    NodeRegistration,
+    // Added in 2.2
+    ModelUpdate,
+    ModelAck,
+    // Added in 2.3
+    RingChunk,
+    // Added in 2.4
+    CheckpointRequest,
+    CheckpointAck,
+}
+
+// Implemented by every type that can travel inside an envelope.
+pub trait Payload: Serialize + DeserializeOwned {
+    const KIND: MessageKind;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskMessage {
+    pub task_id: String,
+    // Stays the same across redeliveries and retries of the same work
+    #[serde(default)]
+    pub idempotency_key: String,
+    // Name of the kernel that computes the task (added in 2.1)
+    #[serde(default)]
+    pub op: String,
+    pub inputs: Vec<Tensor>,
+    #[serde(default)]
+    pub attrs: Attrs,
+}
+
+impl TaskMessage {
+    pub fn new(task_id: impl Into<String>, op: impl Into<String>, inputs: Vec<Tensor>) -> Self {
+        TaskMessage {
+            task_id: task_id.into(),
+            idempotency_key: Uuid::new_v4().to_string(),
+            op: op.into(),
+            inputs,
+            attrs: Attrs::new(),
+        }
+    }
+
+    pub fn with_attr(mut self, name: &str, value: impl Into<Attr>) -> Self {
+        self.attrs.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn dedup_key(&self) -> &str {
+        if self.idempotency_key.is_empty() {
+            &self.task_id
+        } else {
+            &self.idempotency_key
+        }
+    }
+}
+
+impl Payload for TaskMessage {
+    const KIND: MessageKind = MessageKind::Task;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResultMessage {
+    pub task_id: String,
+    // Copied from the task, so duplicate results can be recognised
+    #[serde(default)]
+    pub idempotency_key: String,
+    pub outputs: Vec<Tensor>,
+    // Why the task could not be computed; `outputs` is empty when set (added in 2.1)
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ResultMessage {
+    pub fn for_task(task: &TaskMessage, outputs: Vec<Tensor>) -> Self {
+        ResultMessage {
+            task_id: task.task_id.clone(),
+            idempotency_key: task.dedup_key().to_string(),
+            outputs,
+            error: None,
+        }
+    }
+
+    pub fn failed(task: &TaskMessage, error: impl Into<String>) -> Self {
+        ResultMessage {
+            task_id: task.task_id.clone(),
+            idempotency_key: task.dedup_key().to_string(),
+            outputs: Vec::new(),
+            error: Some(error.into()),
+        }
+    }
+}
+
+impl Payload for ResultMessage {
+    const KIND: MessageKind = MessageKind::Result;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoleAssignment {
+    pub node_id: String,
+    pub role: String,
+}
+
+impl Payload for RoleAssignment {
+    const KIND: MessageKind = MessageKind::RoleAssignment;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoleAck {
+    pub node_id: String,
+    pub role: String,
+    pub accepted: bool,
+}
+
+impl Payload for RoleAck {
+    const KIND: MessageKind = MessageKind::RoleAck;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateRequest {
+    pub update_id: String,
+    pub content: String,
+}
+
+impl Payload for UpdateRequest {
+    const KIND: MessageKind = MessageKind::UpdateRequest;
+}
+
+// Announces a node, the ops it can run and how many tasks it can run at once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeRegistration {
+    pub node_id: String,
+    pub role: String,
+    pub capabilities: Vec<String>,
+    pub capacity: u32,
+    // Kernels registered on the node (added in 2.1)
+    #[serde(default)]
+    pub ops: Vec<String>,
+    // Where the node takes direct peer connections, when it uses the TCP transport (added in 2.5)
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+impl Payload for NodeRegistration {
+    const KIND: MessageKind = MessageKind::NodeRegistration;
+}
+
+// Ships a model to An nodes: its spec file (JSON) and every parameter.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelUpdate {
+    pub name: String,
+    pub version: u64,
+    pub spec: String,
+    pub weights: BTreeMap<String, Tensor>,
+}
+
+impl Payload for ModelUpdate {
+    const KIND: MessageKind = MessageKind::ModelUpdate;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelAck {
+    pub name: String,
+    pub version: u64,
+    pub accepted: bool,
+    // Why the model was rejected
+    pub error: Option<String>,
+}
+
+impl Payload for ModelAck {
+    const KIND: MessageKind = MessageKind::ModelAck;
+}
+
+// One chunk of a ring all-reduce, sent by a Ki node to the next node in the ring.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingChunk {
+    // Names the collective, so chunks of different reductions never mix
+    pub collective_id: String,
+    // Reduce-scatter steps come first, then all-gather steps
+    pub step: u32,
+    pub values: Tensor,
+}
+
+impl Payload for RingChunk {
+    const KIND: MessageKind = MessageKind::RingChunk;
+}
+
+// Asks an An node to checkpoint a model now rather than wait for the next periodic checkpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckpointRequest {
+    pub model: String,
+}
+
+impl Payload for CheckpointRequest {
+    const KIND: MessageKind = MessageKind::CheckpointRequest;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckpointAck {
+    pub model: String,
+    // The checkpoint's version and the training steps it covers
+    pub version: u64,
+    pub step: u64,
+    // Why no checkpoint was taken
+    pub error: Option<String>,
+}
+
+impl CheckpointAck {
+    pub fn failed(model: &str, error: impl Into<String>) -> Self {
+        CheckpointAck {
+            model: model.to_string(),
+            version: 0,
+            step: 0,
+            error: Some(error.into()),
+        }
+    }
+}
+
+impl Payload for CheckpointAck {
+    const KIND: MessageKind = MessageKind::CheckpointAck;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope<T> {
+    pub kind: MessageKind,
+    pub version: SchemaVersion,
+    pub sender: Uuid,
+    pub correlation_id: Uuid,
+    pub reply_to: Option<String>,
+    pub sent_at: DateTime<Utc>,
+    // Codecs the sender can decode, used to pick the encoding of replies
+    #[serde(default)]
+    pub accept: Vec<Codec>,
+    pub payload: T,
+}
+
+// The leading envelope fields, readable without knowing the payload type. Field order must match
+// `Envelope` so that non-self-describing codecs can decode it as a prefix.
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnvelopeHeader {
+    pub kind: MessageKind,
+    pub version: SchemaVersion,
+    pub sender: Uuid,
+    pub correlation_id: Uuid,
+}
+
+impl EnvelopeHeader {
+    pub fn peek(bytes: &[u8], headers: &Headers) -> Result<Self, EnvelopeError> {
+        let header: EnvelopeHeader =
+            codec::decode(bytes, headers).map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+        if header.version.major != SCHEMA_VERSION.major {
+            return Err(EnvelopeError::UnsupportedVersion(header.version));
+        }
+        Ok(header)
+    }
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    Malformed(String),
+    UnsupportedVersion(SchemaVersion),
+    UnexpectedKind { expected: MessageKind, found: MessageKind },
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "malformed envelope: {}", e),
+            EnvelopeError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported schema version {} (this node speaks {})",
+                version, SCHEMA_VERSION
+            ),
+            EnvelopeError::UnexpectedKind { expected, found } => {
+                write!(f, "expected a {:?} message, got {:?}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl<T: Payload> Envelope<T> {
+    pub fn new(sender: Uuid, payload: T) -> Self {
+        Envelope {
+            kind: T::KIND,
+            version: SCHEMA_VERSION,
+            sender,
+            correlation_id: Uuid::new_v4(),
+            reply_to: None,
+            sent_at: Utc::now(),
+            accept: Codec::available(),
+            payload,
+        }
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = correlation_id;
+        self
+    }
+
+    pub fn with_reply_to(mut self, reply_to: &str) -> Self {
+        self.reply_to = Some(reply_to.to_string());
+        self
+    }
+
+    // Builds a response that carries this message's correlation id.
+    pub fn reply<R: Payload>(&self, sender: Uuid, payload: R) -> Envelope<R> {
+        Envelope::new(sender, payload).with_correlation_id(self.correlation_id)
+    }
+
+    // The codec configuration to use when answering this message.
+    pub fn reply_codec(&self) -> CodecConfig {
+        let config = CodecConfig::global();
+        config.with_codec(Codec::negotiate(config.codec, &self.accept, self.version == SCHEMA_VERSION))
+    }
+
+    pub fn encode(&self, config: &CodecConfig) -> Result<(Vec<u8>, Headers), EnvelopeError> {
+        config.encode(self).map_err(|e| EnvelopeError::Malformed(e.to_string()))
+    }
+
+    pub fn decode(bytes: &[u8], headers: &Headers) -> Result<Self, EnvelopeError> {
+        let header = EnvelopeHeader::peek(bytes, headers)?;
+        if header.kind != T::KIND {
+            return Err(EnvelopeError::UnexpectedKind {
+                expected: T::KIND,
+                found: header.kind,
+            });
+        }
+        codec::decode(bytes, headers).map_err(|e| EnvelopeError::Malformed(e.to_string()))
+    }
+
+    pub fn from_delivery(delivery: &Delivery) -> Result<Self, EnvelopeError> {
+        Self::decode(&delivery.data, &delivery.headers)
+    }
+
+    pub async fn publish_with(&self, transport: &dyn Transport, queue_name: &str, config: &CodecConfig) -> Result<(), TransportError> {
+        let (payload, headers) = self.encode(config)?;
+        transport.publish_with_headers(queue_name, &payload, &headers).await
+    }
+
+    // Publishes through the topic exchange, to whichever queues are bound to the routing key.
+    pub async fn route(&self, transport: &dyn Transport, routing_key: &str) -> Result<(), TransportError> {
+        let (payload, headers) = self.encode(CodecConfig::global())?;
+        transport
+            .publish_routed(routing::EXCHANGE, routing_key, &payload, &headers)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Payload>(envelope: &Envelope<impl Payload>) -> Result<Envelope<T>, EnvelopeError> {
+        let (bytes, headers) = envelope.encode(&CodecConfig::default()).unwrap();
+        Envelope::decode(&bytes, &headers)
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let sender = Uuid::new_v4();
+        let input = Tensor::new(vec![2], vec![1.0f32, 2.0]).unwrap();
+        let task = TaskMessage::new("task-1", "relu", vec![input.clone()]).with_attr("axis", 1i64);
+        let envelope = Envelope::new(sender, task).with_reply_to("reply_queue");
+
+        let decoded = round_trip::<TaskMessage>(&envelope).unwrap();
+        assert_eq!(decoded.kind, MessageKind::Task);
+        assert_eq!(decoded.sender, sender);
+        assert_eq!(decoded.correlation_id, envelope.correlation_id);
+        assert_eq!(decoded.reply_to.as_deref(), Some("reply_queue"));
+        assert_eq!(decoded.payload.task_id, "task-1");
+        assert_eq!(decoded.payload.idempotency_key, envelope.payload.idempotency_key);
+        assert_eq!(decoded.payload.op, "relu");
+        assert_eq!(decoded.payload.inputs, vec![input]);
+        assert_eq!(decoded.payload.attrs["axis"], Attr::Int(1));
+    }
+
+    #[test]
+    fn test_reply_keeps_correlation_id() {
+        let request = Envelope::new(Uuid::new_v4(), TaskMessage::new("task-2", "relu", Vec::new()));
+        let reply = request.reply(Uuid::new_v4(), ResultMessage::for_task(&request.payload, Vec::new()));
+        assert_eq!(reply.correlation_id, request.correlation_id);
+        assert_eq!(reply.kind, MessageKind::Result);
+    }
+
+    #[test]
+    fn test_unknown_major_version_is_rejected() {
+        let mut envelope = Envelope::new(
+            Uuid::new_v4(),
+            UpdateRequest {
+                update_id: "update-1".to_string(),
+                content: String::new(),
+            },
+        );
+        envelope.version = SchemaVersion {
+            major: SCHEMA_VERSION.major + 1,
+            minor: 0,
+        };
+
+        let result = round_trip::<UpdateRequest>(&envelope);
+        assert!(matches!(result, Err(EnvelopeError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn test_newer_minor_version_is_accepted() {
+        let mut envelope = Envelope::new(
+            Uuid::new_v4(),
+            RoleAssignment {
+                node_id: "node-1".to_string(),
+                role: "ki".to_string(),
+            },
+        );
+        envelope.version.minor += 1;
+
+        assert!(round_trip::<RoleAssignment>(&envelope).is_ok());
+    }
+
+    #[test]
+    fn test_wrong_kind_is_rejected() {
+        let envelope = Envelope::new(
+            Uuid::new_v4(),
+            ResultMessage {
+                task_id: "task-3".to_string(),
+                idempotency_key: String::new(),
+                outputs: Vec::new(),
+                error: None,
+            },
+        );
+
+        let result = round_trip::<TaskMessage>(&envelope);
+        assert!(matches!(result, Err(EnvelopeError::UnexpectedKind { .. })));
+    }
+
+    #[test]
+    fn test_envelope_round_trip_with_every_codec() {
+        for codec in Codec::available() {
+            let envelope = Envelope::new(
+                Uuid::new_v4(),
+                ResultMessage {
+                    task_id: "task-4".to_string(),
+                    idempotency_key: String::new(),
+                    outputs: vec![Tensor::new(vec![3], vec![1i32, 2, 3]).unwrap()],
+                    error: None,
+                },
+            );
+            let (bytes, headers) = envelope.encode(&CodecConfig::default().with_codec(codec)).unwrap();
+
+            let header = EnvelopeHeader::peek(&bytes, &headers).unwrap();
+            assert_eq!(header.correlation_id, envelope.correlation_id);
+            let decoded = Envelope::<ResultMessage>::decode(&bytes, &headers).unwrap();
+            assert_eq!(decoded.payload.outputs[0].to_vec::<i32>().unwrap(), vec![1, 2, 3]);
+            assert_eq!(decoded.accept, Codec::available());
+        }
+    }
+
+    #[test]
+    fn test_reply_codec_respects_accept_list() {
+        let mut request = Envelope::new(Uuid::new_v4(), TaskMessage::new("task-5", "relu", Vec::new()));
+        request.accept = vec![Codec::Json];
+        assert_eq!(request.reply_codec().codec, Codec::Json);
+    }
+}
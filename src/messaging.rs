@@ -1,51 +1,941 @@
 // messaging.rs: Implements RabbitMQ messaging logic, including sending and receiving messages across the network.
 
-use lapin::{options::*, types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties};
+use crate::backoff::Backoff;
+use async_trait::async_trait;
+use futures_util::stream::StreamExt;
+use lapin::{
+    options::*,
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind,
+};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use tracing::info;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::{mpsc, Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Duration;
+use tracing::{error, info, warn};
 
-pub async fn establish_connection(amqp_addr: &str) -> Result<Channel, Box<dyn Error>> {
+pub type TransportError = Box<dyn Error + Send + Sync>;
+pub type Headers = BTreeMap<String, String>;
+
+pub const DEAD_LETTER_EXCHANGE: &str = "dead_letter_exchange";
+// How long the dead-letter queue of an exclusive queue outlives its last use, so that the dead letters of
+// a node that is gone can still be inspected for a while
+pub const ORPHANED_DEAD_LETTER_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// A broker-agnostic view of the operations the nodes need from the message bus.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn declare_queue(&self, queue_name: &str) -> Result<(), TransportError>;
+    // Declares a queue that belongs to this process: the broker deletes it when the process's connection
+    // closes, so that per-process queues do not pile up across restarts.
+    async fn declare_exclusive_queue(&self, queue_name: &str) -> Result<(), TransportError>;
+    // Declares a queue whose rejected messages are routed to `dead_letter_queue`.
+    async fn declare_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError>;
+    // An exclusive queue with a dead-letter queue. The dead-letter queue stays readable by other processes
+    // and expires once unused for ORPHANED_DEAD_LETTER_EXPIRY.
+    async fn declare_exclusive_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError>;
+    async fn declare_topic_exchange(&self, exchange: &str) -> Result<(), TransportError>;
+    // Binds a queue to a topic exchange; `*` matches one word of the routing key and `#` zero or more.
+    async fn bind_queue(&self, queue_name: &str, exchange: &str, pattern: &str) -> Result<(), TransportError>;
+    // Publishes through an exchange. The empty exchange routes directly to the queue named by the key.
+    async fn publish_routed(&self, exchange: &str, routing_key: &str, payload: &[u8], headers: &Headers) -> Result<(), TransportError>;
+    // Starts a consumer that holds at most `prefetch` unacknowledged deliveries (0 means unlimited).
+    async fn consume_with_prefetch(&self, queue_name: &str, consumer_tag: &str, prefetch: u16) -> Result<Consumer, TransportError>;
+    // Pulls a single message without a consumer, for inspecting queues.
+    async fn get(&self, queue_name: &str) -> Result<Option<Delivery>, TransportError>;
+
+    // Where other nodes can connect to this one directly, for transports that take peer connections.
+    fn peer_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    // Sends publishes whose routing key matches `pattern` straight to `peer`. Transports without peer
+    // connections route everything through the broker and ignore this.
+    fn add_peer_route(&self, _pattern: &str, _peer: SocketAddr) {}
+
+    async fn publish_with_headers(&self, queue_name: &str, payload: &[u8], headers: &Headers) -> Result<(), TransportError> {
+        self.publish_routed("", queue_name, payload, headers).await
+    }
+
+    async fn consume(&self, queue_name: &str, consumer_tag: &str) -> Result<Consumer, TransportError> {
+        self.consume_with_prefetch(queue_name, consumer_tag, 0).await
+    }
+}
+
+// Matches an AMQP topic routing key against a binding pattern.
+pub fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches(pattern: &[&str], key: &[&str]) -> bool {
+        match (pattern.first(), key.first()) {
+            (None, None) => true,
+            (Some(&"#"), _) => matches(&pattern[1..], key) || (!key.is_empty() && matches(pattern, &key[1..])),
+            (Some(&"*"), Some(_)) => matches(&pattern[1..], &key[1..]),
+            (Some(word), Some(key_word)) if word == key_word => matches(&pattern[1..], &key[1..]),
+            _ => false,
+        }
+    }
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let key: Vec<&str> = routing_key.split('.').collect();
+    matches(&pattern, &key)
+}
+
+#[async_trait]
+trait Acker: Send + Sync {
+    async fn ack(&self) -> Result<(), TransportError>;
+    async fn nack(&self, requeue: bool) -> Result<(), TransportError>;
+}
+
+pub struct Delivery {
+    pub data: Vec<u8>,
+    pub headers: Headers,
+    acker: Box<dyn Acker>,
+}
+
+impl Delivery {
+    pub async fn ack(&self) -> Result<(), TransportError> {
+        self.acker.ack().await
+    }
+
+    pub async fn nack(&self, requeue: bool) -> Result<(), TransportError> {
+        self.acker.nack(requeue).await
+    }
+}
+
+pub struct Consumer {
+    rx: mpsc::Receiver<Delivery>,
+}
+
+impl Consumer {
+    pub async fn next(&mut self) -> Option<Delivery> {
+        self.rx.recv().await
+    }
+}
+
+// Picks the transport from the TRANSPORT environment variable ("amqp" by default, "memory" or "tcp").
+pub async fn transport_from_env() -> Result<Arc<dyn Transport>, TransportError> {
+    let kind = std::env::var("TRANSPORT").unwrap_or_else(|_| "amqp".into());
+    match kind.as_str() {
+        "memory" => Ok(Arc::new(InMemoryBroker::new())),
+        "tcp" => Ok(Arc::new(crate::network::TcpTransport::from_env().await?)),
+        "amqp" => {
+            let amqp_addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
+            Ok(Arc::new(AmqpTransport::connect(&amqp_addr).await?))
+        }
+        other => Err(format!("Unknown transport: {}", other).into()),
+    }
+}
+
+// RabbitMQ implementation. Publishes wait for a broker confirm and are retried with backoff, and a
+// supervisor task re-establishes the connection, re-declares queues and restarts consumers after a drop.
+#[derive(Clone)]
+pub struct AmqpTransport {
+    amqp_addr: String,
+    session: Arc<RwLock<Arc<AmqpSession>>>,
+    topology: Arc<RwLock<Topology>>,
+    reconnect: Arc<Notify>,
+    publish_backoff: Backoff,
+}
+
+struct AmqpSession {
+    connection: Connection,
+    channel: Channel,
+}
+
+// Everything that has to be recreated on a fresh connection.
+#[derive(Default)]
+struct Topology {
+    declarations: Vec<Declaration>,
+    consumers: Vec<ConsumerRegistration>,
+}
+
+#[derive(Clone, PartialEq)]
+enum Declaration {
+    Queue(String),
+    ExclusiveQueue(String),
+    DeadLetterQueue { queue_name: String, dead_letter_queue: String, exclusive: bool },
+    TopicExchange(String),
+    Binding { queue_name: String, exchange: String, pattern: String },
+}
+
+#[derive(Clone)]
+struct ConsumerRegistration {
+    queue_name: String,
+    consumer_tag: String,
+    prefetch: u16,
+    tx: mpsc::Sender<Delivery>,
+}
+
+impl AmqpTransport {
+    pub async fn connect(amqp_addr: &str) -> Result<Self, TransportError> {
+        let reconnect = Arc::new(Notify::new());
+        let session = open_session(amqp_addr, reconnect.clone()).await?;
+        let transport = AmqpTransport {
+            amqp_addr: amqp_addr.to_string(),
+            session: Arc::new(RwLock::new(Arc::new(session))),
+            topology: Arc::new(RwLock::new(Topology::default())),
+            reconnect,
+            publish_backoff: Backoff::default(),
+        };
+        transport.spawn_supervisor();
+        Ok(transport)
+    }
+
+    fn channel(&self) -> Channel {
+        self.session.read().unwrap().channel.clone()
+    }
+
+    fn record(&self, declaration: Declaration) {
+        let mut topology = self.topology.write().unwrap();
+        if !topology.declarations.contains(&declaration) {
+            topology.declarations.push(declaration);
+        }
+    }
+
+    fn spawn_supervisor(&self) {
+        let transport = self.clone();
+        tokio::spawn(async move {
+            let backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30), None);
+            loop {
+                transport.reconnect.notified().await;
+                if transport.session.read().unwrap().connection.status().connected() {
+                    continue;
+                }
+                warn!("Lost connection to RabbitMQ at {}, reconnecting...", transport.amqp_addr);
+                if let Ok(session) = backoff.retry("Reconnecting to RabbitMQ", || transport.restore_session()).await {
+                    *transport.session.write().unwrap() = Arc::new(session);
+                    info!("Reconnected to RabbitMQ at {}", transport.amqp_addr);
+                }
+            }
+        });
+    }
+
+    async fn restore_session(&self) -> Result<AmqpSession, TransportError> {
+        let session = open_session(&self.amqp_addr, self.reconnect.clone()).await?;
+        let (declarations, consumers) = {
+            let mut topology = self.topology.write().unwrap();
+            topology.consumers.retain(|consumer| !consumer.tx.is_closed());
+            (topology.declarations.clone(), topology.consumers.clone())
+        };
+        for declaration in &declarations {
+            declare_on(&session.channel, declaration).await?;
+        }
+        for consumer in &consumers {
+            start_consumer(&session.channel, consumer).await?;
+        }
+        Ok(session)
+    }
+}
+
+async fn open_session(amqp_addr: &str, reconnect: Arc<Notify>) -> Result<AmqpSession, TransportError> {
     let connection = Connection::connect(amqp_addr, ConnectionProperties::default()).await?;
+    connection.on_error(move |e| {
+        error!("RabbitMQ connection error: {:?}", e);
+        reconnect.notify_one();
+    });
     let channel = connection.create_channel().await?;
+    // Every publish on this channel is confirmed by the broker
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
     info!("Established connection to RabbitMQ at: {}", amqp_addr);
-    Ok(channel)
+    Ok(AmqpSession { connection, channel })
 }
 
-pub async fn declare_queue(channel: &Channel, queue_name: &str) -> Result<(), Box<dyn Error>> {
-    channel
-        .queue_declare(
+async fn declare_on(channel: &Channel, declaration: &Declaration) -> Result<(), TransportError> {
+    match declaration {
+        Declaration::Queue(queue_name) => {
+            channel
+                .queue_declare(
+                    queue_name,
+                    QueueDeclareOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            info!("Declared queue: {}", queue_name);
+        }
+        Declaration::ExclusiveQueue(queue_name) => {
+            channel
+                .queue_declare(queue_name, exclusive_queue_options(), FieldTable::default())
+                .await?;
+            info!("Declared exclusive queue: {}", queue_name);
+        }
+        Declaration::DeadLetterQueue {
             queue_name,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-    info!("Declared queue: {}", queue_name);
+            dead_letter_queue,
+            exclusive,
+        } => {
+            channel
+                .exchange_declare(
+                    DEAD_LETTER_EXCHANGE,
+                    ExchangeKind::Direct,
+                    ExchangeDeclareOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            let mut dead_letter_arguments = FieldTable::default();
+            if *exclusive {
+                dead_letter_arguments.insert(
+                    "x-expires".into(),
+                    AMQPValue::LongLongInt(ORPHANED_DEAD_LETTER_EXPIRY.as_millis() as i64),
+                );
+            }
+            channel
+                .queue_declare(dead_letter_queue, QueueDeclareOptions::default(), dead_letter_arguments)
+                .await?;
+            channel
+                .queue_bind(
+                    dead_letter_queue,
+                    DEAD_LETTER_EXCHANGE,
+                    dead_letter_queue,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+
+            let mut arguments = FieldTable::default();
+            arguments.insert(
+                "x-dead-letter-exchange".into(),
+                AMQPValue::LongString(DEAD_LETTER_EXCHANGE.into()),
+            );
+            arguments.insert(
+                "x-dead-letter-routing-key".into(),
+                AMQPValue::LongString(dead_letter_queue.as_str().into()),
+            );
+            let options = match exclusive {
+                true => exclusive_queue_options(),
+                false => QueueDeclareOptions::default(),
+            };
+            channel.queue_declare(queue_name, options, arguments).await?;
+            info!("Declared queue: {} (dead letters to {})", queue_name, dead_letter_queue);
+        }
+        Declaration::TopicExchange(exchange) => {
+            channel
+                .exchange_declare(
+                    exchange,
+                    ExchangeKind::Topic,
+                    ExchangeDeclareOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            info!("Declared topic exchange: {}", exchange);
+        }
+        Declaration::Binding { queue_name, exchange, pattern } => {
+            channel
+                .queue_bind(
+                    queue_name,
+                    exchange,
+                    pattern,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            info!("Bound queue {} to {} with pattern {}", queue_name, exchange, pattern);
+        }
+    }
     Ok(())
 }
 
-pub async fn publish_message(channel: &Channel, queue_name: &str, payload: &[u8]) -> Result<(), Box<dyn Error>> {
-    channel
-        .basic_publish(
-            "",
-            queue_name,
-            BasicPublishOptions::default(),
-            payload,
-            BasicProperties::default(),
-        )
-        .await?;
-    info!("Published message to queue: {}", queue_name);
-    Ok(())
+fn exclusive_queue_options() -> QueueDeclareOptions {
+    QueueDeclareOptions {
+        exclusive: true,
+        auto_delete: true,
+        ..QueueDeclareOptions::default()
+    }
 }
 
-pub async fn consume_messages(channel: &Channel, queue_name: &str, consumer_tag: &str) -> Result<lapin::Consumer, Box<dyn Error>> {
-    let consumer = channel
+// Forwards deliveries from a lapin consumer until the channel goes away.
+async fn start_consumer(channel: &Channel, registration: &ConsumerRegistration) -> Result<(), TransportError> {
+    // A per-consumer QoS applies to the consumers started after it on the channel.
+    channel
+        .basic_qos(registration.prefetch, BasicQosOptions::default())
+        .await?;
+    let mut consumer = channel
         .basic_consume(
-            queue_name,
-            consumer_tag,
+            &registration.queue_name,
+            &registration.consumer_tag,
             BasicConsumeOptions::default(),
             FieldTable::default(),
         )
         .await?;
-    info!("Started consuming messages from queue: {}", queue_name);
-    Ok(consumer)
+    info!("Started consuming messages from queue: {}", registration.queue_name);
+
+    let tx = registration.tx.clone();
+    let queue_name = registration.queue_name.clone();
+    tokio::spawn(async move {
+        while let Some(delivery) = consumer.next().await {
+            match delivery {
+                Ok(delivery) => {
+                    if tx.send(from_lapin_delivery(delivery)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Error in consumer for queue {}: {:?}", queue_name, e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn to_field_table(headers: &Headers) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (key, value) in headers {
+        table.insert(key.clone().into(), AMQPValue::LongString(value.clone().into()));
+    }
+    table
+}
+
+fn from_field_table(table: Option<&FieldTable>) -> Headers {
+    let mut headers = Headers::new();
+    if let Some(table) = table {
+        for (key, value) in table.inner() {
+            let value = match value {
+                AMQPValue::LongString(s) => s.to_string(),
+                AMQPValue::ShortString(s) => s.to_string(),
+                AMQPValue::LongInt(n) => n.to_string(),
+                AMQPValue::LongUInt(n) => n.to_string(),
+                AMQPValue::LongLongInt(n) => n.to_string(),
+                AMQPValue::ShortInt(n) => n.to_string(),
+                AMQPValue::ShortUInt(n) => n.to_string(),
+                AMQPValue::Boolean(b) => b.to_string(),
+                // Nested tables such as x-death are not needed by the nodes.
+                _ => continue,
+            };
+            headers.insert(key.to_string(), value);
+        }
+    }
+    headers
+}
+
+fn from_lapin_delivery(delivery: lapin::message::Delivery) -> Delivery {
+    Delivery {
+        headers: from_field_table(delivery.properties.headers().as_ref()),
+        data: delivery.data,
+        acker: Box::new(AmqpAcker(delivery.acker)),
+    }
+}
+
+struct AmqpAcker(lapin::acker::Acker);
+
+#[async_trait]
+impl Acker for AmqpAcker {
+    async fn ack(&self) -> Result<(), TransportError> {
+        self.0.ack(BasicAckOptions::default()).await?;
+        Ok(())
+    }
+
+    async fn nack(&self, requeue: bool) -> Result<(), TransportError> {
+        self.0
+            .nack(BasicNackOptions {
+                requeue,
+                ..BasicNackOptions::default()
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for AmqpTransport {
+    async fn declare_queue(&self, queue_name: &str) -> Result<(), TransportError> {
+        let declaration = Declaration::Queue(queue_name.to_string());
+        declare_on(&self.channel(), &declaration).await?;
+        self.record(declaration);
+        Ok(())
+    }
+
+    async fn declare_exclusive_queue(&self, queue_name: &str) -> Result<(), TransportError> {
+        let declaration = Declaration::ExclusiveQueue(queue_name.to_string());
+        declare_on(&self.channel(), &declaration).await?;
+        self.record(declaration);
+        Ok(())
+    }
+
+    async fn declare_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError> {
+        let declaration = Declaration::DeadLetterQueue {
+            queue_name: queue_name.to_string(),
+            dead_letter_queue: dead_letter_queue.to_string(),
+            exclusive: false,
+        };
+        declare_on(&self.channel(), &declaration).await?;
+        self.record(declaration);
+        Ok(())
+    }
+
+    async fn declare_exclusive_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError> {
+        let declaration = Declaration::DeadLetterQueue {
+            queue_name: queue_name.to_string(),
+            dead_letter_queue: dead_letter_queue.to_string(),
+            exclusive: true,
+        };
+        declare_on(&self.channel(), &declaration).await?;
+        self.record(declaration);
+        Ok(())
+    }
+
+    async fn declare_topic_exchange(&self, exchange: &str) -> Result<(), TransportError> {
+        let declaration = Declaration::TopicExchange(exchange.to_string());
+        declare_on(&self.channel(), &declaration).await?;
+        self.record(declaration);
+        Ok(())
+    }
+
+    async fn bind_queue(&self, queue_name: &str, exchange: &str, pattern: &str) -> Result<(), TransportError> {
+        let declaration = Declaration::Binding {
+            queue_name: queue_name.to_string(),
+            exchange: exchange.to_string(),
+            pattern: pattern.to_string(),
+        };
+        declare_on(&self.channel(), &declaration).await?;
+        self.record(declaration);
+        Ok(())
+    }
+
+    async fn publish_routed(&self, exchange: &str, routing_key: &str, payload: &[u8], headers: &Headers) -> Result<(), TransportError> {
+        let properties = BasicProperties::default().with_headers(to_field_table(headers));
+        self.publish_backoff
+            .retry(&format!("Publishing to {}", routing_key), || async {
+                let channel = self.channel();
+                let result: Result<(), TransportError> = async {
+                    let confirmation = channel
+                        .basic_publish(
+                            exchange,
+                            routing_key,
+                            BasicPublishOptions::default(),
+                            payload,
+                            properties.clone(),
+                        )
+                        .await?
+                        .await?;
+                    if confirmation.is_nack() {
+                        return Err(format!("Broker rejected message for {}", routing_key).into());
+                    }
+                    Ok(())
+                }
+                .await;
+                if result.is_err() && !channel.status().connected() {
+                    self.reconnect.notify_one();
+                }
+                result
+            })
+            .await?;
+        info!("Published message to {} with routing key: {}", exchange, routing_key);
+        Ok(())
+    }
+
+    async fn consume_with_prefetch(&self, queue_name: &str, consumer_tag: &str, prefetch: u16) -> Result<Consumer, TransportError> {
+        let (tx, rx) = mpsc::channel(1);
+        let registration = ConsumerRegistration {
+            queue_name: queue_name.to_string(),
+            consumer_tag: consumer_tag.to_string(),
+            prefetch,
+            tx,
+        };
+        start_consumer(&self.channel(), &registration).await?;
+        self.topology.write().unwrap().consumers.push(registration);
+        Ok(Consumer { rx })
+    }
+
+    async fn get(&self, queue_name: &str) -> Result<Option<Delivery>, TransportError> {
+        let message = self
+            .channel()
+            .basic_get(queue_name, BasicGetOptions::default())
+            .await?;
+        Ok(message.map(|message| from_lapin_delivery(message.delivery)))
+    }
+}
+
+type MemoryMessage = (Vec<u8>, Headers);
+
+struct MemoryQueue {
+    tx: mpsc::UnboundedSender<MemoryMessage>,
+    rx: Mutex<mpsc::UnboundedReceiver<MemoryMessage>>,
+    dead_letter: RwLock<Option<Arc<MemoryQueue>>>,
+}
+
+impl MemoryQueue {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        MemoryQueue {
+            tx,
+            rx: Mutex::new(rx),
+            dead_letter: RwLock::new(None),
+        }
+    }
+
+    fn delivery(self: &Arc<Self>, (data, headers): MemoryMessage, permit: Option<OwnedSemaphorePermit>) -> Delivery {
+        Delivery {
+            data: data.clone(),
+            headers: headers.clone(),
+            acker: Box::new(MemoryAcker {
+                queue: self.clone(),
+                message: std::sync::Mutex::new(Some((data, headers))),
+                permit: std::sync::Mutex::new(permit),
+            }),
+        }
+    }
+}
+
+// In-process broker so a whole cluster can run inside one process without RabbitMQ.
+// Consumers on the same queue compete for messages, as they do on a RabbitMQ queue.
+#[derive(Clone, Default)]
+pub struct InMemoryBroker {
+    queues: Arc<RwLock<HashMap<String, Arc<MemoryQueue>>>>,
+    exchanges: Arc<RwLock<HashMap<String, Vec<Binding>>>>,
+}
+
+#[derive(Clone, PartialEq)]
+struct Binding {
+    pattern: String,
+    queue_name: String,
+}
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        InMemoryBroker {
+            queues: Arc::new(RwLock::new(HashMap::new())),
+            exchanges: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn queue(&self, queue_name: &str) -> Option<Arc<MemoryQueue>> {
+        self.queues.read().unwrap().get(queue_name).cloned()
+    }
+}
+
+struct MemoryAcker {
+    queue: Arc<MemoryQueue>,
+    // Taken once the delivery is settled
+    message: std::sync::Mutex<Option<MemoryMessage>>,
+    // Frees a prefetch slot on the consumer once the delivery is settled
+    permit: std::sync::Mutex<Option<OwnedSemaphorePermit>>,
+}
+
+impl MemoryAcker {
+    fn settle(&self) -> Option<MemoryMessage> {
+        self.permit.lock().unwrap().take();
+        self.message.lock().unwrap().take()
+    }
+}
+
+#[async_trait]
+impl Acker for MemoryAcker {
+    async fn ack(&self) -> Result<(), TransportError> {
+        self.settle();
+        Ok(())
+    }
+
+    async fn nack(&self, requeue: bool) -> Result<(), TransportError> {
+        let Some((data, mut headers)) = self.settle() else {
+            return Ok(());
+        };
+        if requeue {
+            self.queue.tx.send((data, headers))?;
+        } else if let Some(dead_letter) = self.queue.dead_letter.read().unwrap().as_ref() {
+            headers.insert("x-death-reason".to_string(), "rejected".to_string());
+            dead_letter.tx.send((data, headers))?;
+        }
+        Ok(())
+    }
+}
+
+// Like RabbitMQ when a channel closes, a delivery dropped without an ack or nack goes back on its queue.
+impl Drop for MemoryAcker {
+    fn drop(&mut self) {
+        if let Some(message) = self.settle() {
+            let _ = self.queue.tx.send(message);
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for InMemoryBroker {
+    async fn declare_queue(&self, queue_name: &str) -> Result<(), TransportError> {
+        let mut queues = self.queues.write().unwrap();
+        queues
+            .entry(queue_name.to_string())
+            .or_insert_with(|| Arc::new(MemoryQueue::new()));
+        info!("Declared in-memory queue: {}", queue_name);
+        Ok(())
+    }
+
+    // Every in-memory queue goes away with the process
+    async fn declare_exclusive_queue(&self, queue_name: &str) -> Result<(), TransportError> {
+        self.declare_queue(queue_name).await
+    }
+
+    async fn declare_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError> {
+        self.declare_queue(dead_letter_queue).await?;
+        self.declare_queue(queue_name).await?;
+        let queues = self.queues.read().unwrap();
+        *queues[queue_name].dead_letter.write().unwrap() = queues.get(dead_letter_queue).cloned();
+        Ok(())
+    }
+
+    async fn declare_exclusive_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError> {
+        self.declare_queue_with_dead_letter(queue_name, dead_letter_queue).await
+    }
+
+    async fn declare_topic_exchange(&self, exchange: &str) -> Result<(), TransportError> {
+        self.exchanges
+            .write()
+            .unwrap()
+            .entry(exchange.to_string())
+            .or_default();
+        info!("Declared in-memory topic exchange: {}", exchange);
+        Ok(())
+    }
+
+    async fn bind_queue(&self, queue_name: &str, exchange: &str, pattern: &str) -> Result<(), TransportError> {
+        if self.queue(queue_name).is_none() {
+            return Err(format!("Queue not declared: {}", queue_name).into());
+        }
+        let mut exchanges = self.exchanges.write().unwrap();
+        let bindings = exchanges
+            .get_mut(exchange)
+            .ok_or_else(|| format!("Exchange not declared: {}", exchange))?;
+        let binding = Binding {
+            pattern: pattern.to_string(),
+            queue_name: queue_name.to_string(),
+        };
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+        Ok(())
+    }
+
+    async fn publish_routed(&self, exchange: &str, routing_key: &str, payload: &[u8], headers: &Headers) -> Result<(), TransportError> {
+        let queue_names: Vec<String> = if exchange.is_empty() {
+            vec![routing_key.to_string()]
+        } else {
+            let exchanges = self.exchanges.read().unwrap();
+            let bindings = exchanges
+                .get(exchange)
+                .ok_or_else(|| format!("Exchange not declared: {}", exchange))?;
+            let mut matched: Vec<String> = bindings
+                .iter()
+                .filter(|binding| topic_matches(&binding.pattern, routing_key))
+                .map(|binding| binding.queue_name.clone())
+                .collect();
+            matched.sort();
+            matched.dedup();
+            matched
+        };
+
+        if queue_names.is_empty() {
+            warn!("Dropping unroutable message for {} on {}", routing_key, exchange);
+        }
+        for queue_name in queue_names {
+            match self.queue(&queue_name) {
+                Some(queue) => {
+                    queue.tx.send((payload.to_vec(), headers.clone()))?;
+                    info!("Published message to in-memory queue: {}", queue_name);
+                }
+                // Like the AMQP default exchange, messages for unknown queues are dropped.
+                None => warn!("Dropping message for undeclared queue: {}", queue_name),
+            }
+        }
+        Ok(())
+    }
+
+    async fn consume_with_prefetch(&self, queue_name: &str, consumer_tag: &str, prefetch: u16) -> Result<Consumer, TransportError> {
+        let queue = self
+            .queue(queue_name)
+            .ok_or_else(|| format!("Queue not declared: {}", queue_name))?;
+        info!("Consumer {} started on in-memory queue: {}", consumer_tag, queue_name);
+
+        let unacked = (prefetch > 0).then(|| Arc::new(Semaphore::new(prefetch as usize)));
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                // Only take a message off the queue once the consumer has room for it.
+                let permit = match tx.reserve().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                let prefetch_permit = match &unacked {
+                    Some(unacked) => match unacked.clone().acquire_owned().await {
+                        Ok(prefetch_permit) => Some(prefetch_permit),
+                        Err(_) => break,
+                    },
+                    None => None,
+                };
+                let message = {
+                    let mut queue_rx = queue.rx.lock().await;
+                    queue_rx.recv().await
+                };
+                let Some(message) = message else { break };
+                if tx.is_closed() {
+                    let _ = queue.tx.send(message);
+                    break;
+                }
+                permit.send(queue.delivery(message, prefetch_permit));
+            }
+        });
+
+        Ok(Consumer { rx })
+    }
+
+    async fn get(&self, queue_name: &str) -> Result<Option<Delivery>, TransportError> {
+        let queue = self
+            .queue(queue_name)
+            .ok_or_else(|| format!("Queue not declared: {}", queue_name))?;
+        let message = queue.rx.lock().await.try_recv().ok();
+        Ok(message.map(|message| queue.delivery(message, None)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn take(broker: &InMemoryBroker, queue_name: &str) -> Vec<u8> {
+        let delivery = broker.get(queue_name).await.unwrap().unwrap();
+        delivery.ack().await.unwrap();
+        delivery.data.clone()
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_publish_and_consume() {
+        let broker = InMemoryBroker::new();
+        broker.declare_queue("test_queue").await.unwrap();
+        broker.publish_with_headers("test_queue", b"hello", &Headers::new()).await.unwrap();
+
+        let mut consumer = broker.consume("test_queue", "test_consumer").await.unwrap();
+        let delivery = consumer.next().await.unwrap();
+        assert_eq!(delivery.data, b"hello");
+        delivery.ack().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_nack_requeues() {
+        let broker = InMemoryBroker::new();
+        broker.declare_queue("requeue_queue").await.unwrap();
+        broker.publish_with_headers("requeue_queue", b"retry me", &Headers::new()).await.unwrap();
+
+        let mut consumer = broker.consume("requeue_queue", "test_consumer").await.unwrap();
+        let delivery = consumer.next().await.unwrap();
+        delivery.nack(true).await.unwrap();
+
+        let redelivered = consumer.next().await.unwrap();
+        assert_eq!(redelivered.data, b"retry me");
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_unsettled_delivery_is_requeued_when_dropped() {
+        let broker = InMemoryBroker::new();
+        broker.declare_queue("dropped_queue").await.unwrap();
+        broker.publish_with_headers("dropped_queue", b"not lost", &Headers::new()).await.unwrap();
+
+        drop(broker.get("dropped_queue").await.unwrap().unwrap());
+        let redelivered = broker.get("dropped_queue").await.unwrap().unwrap();
+        assert_eq!(redelivered.data, b"not lost");
+        redelivered.ack().await.unwrap();
+        drop(redelivered);
+        assert!(broker.get("dropped_queue").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_competing_consumers() {
+        let broker = InMemoryBroker::new();
+        broker.declare_queue("shared_queue").await.unwrap();
+        let mut first = broker.consume("shared_queue", "first").await.unwrap();
+        let mut second = broker.consume("shared_queue", "second").await.unwrap();
+
+        broker.publish_with_headers("shared_queue", b"one", &Headers::new()).await.unwrap();
+        broker.publish_with_headers("shared_queue", b"two", &Headers::new()).await.unwrap();
+
+        let a = first.next().await.unwrap();
+        let b = second.next().await.unwrap();
+        let mut received = vec![a.data, b.data];
+        received.sort();
+        assert_eq!(received, vec![b"one".to_vec(), b"two".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_rejected_message_is_dead_lettered() {
+        let broker = InMemoryBroker::new();
+        broker
+            .declare_queue_with_dead_letter("work_queue", "work_queue.dlq")
+            .await
+            .unwrap();
+        let mut headers = Headers::new();
+        headers.insert("x-test".to_string(), "1".to_string());
+        broker.publish_with_headers("work_queue", b"poison", &headers).await.unwrap();
+
+        let delivery = broker.get("work_queue").await.unwrap().unwrap();
+        assert_eq!(delivery.headers.get("x-test").map(String::as_str), Some("1"));
+        delivery.nack(false).await.unwrap();
+
+        let dead = broker.get("work_queue.dlq").await.unwrap().unwrap();
+        assert_eq!(dead.data, b"poison");
+        assert_eq!(dead.headers.get("x-death-reason").map(String::as_str), Some("rejected"));
+        assert!(broker.get("work_queue").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("task.ki.matmul", "task.ki.matmul"));
+        assert!(topic_matches("task.ki.*", "task.ki.matmul"));
+        assert!(!topic_matches("task.ki.*", "task.ki"));
+        assert!(topic_matches("node.#", "node.abc.control"));
+        assert!(topic_matches("node.abc.#", "node.abc"));
+        assert!(topic_matches("#", "anything.at.all"));
+        assert!(!topic_matches("task.an", "task.ki.matmul"));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_topic_routing() {
+        let broker = InMemoryBroker::new();
+        broker.declare_topic_exchange("test_exchange").await.unwrap();
+        broker.declare_queue("matmul_queue").await.unwrap();
+        broker.declare_queue("all_tasks_queue").await.unwrap();
+        broker.bind_queue("matmul_queue", "test_exchange", "task.ki.matmul").await.unwrap();
+        broker.bind_queue("all_tasks_queue", "test_exchange", "task.#").await.unwrap();
+
+        broker
+            .publish_routed("test_exchange", "task.ki.matmul", b"multiply", &Headers::new())
+            .await
+            .unwrap();
+        broker
+            .publish_routed("test_exchange", "task.ki.relu", b"activate", &Headers::new())
+            .await
+            .unwrap();
+
+        assert_eq!(take(&broker, "matmul_queue").await, b"multiply");
+        assert!(broker.get("matmul_queue").await.unwrap().is_none());
+        assert_eq!(take(&broker, "all_tasks_queue").await, b"multiply");
+        assert_eq!(take(&broker, "all_tasks_queue").await, b"activate");
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_prefetch_limits_unacked_deliveries() {
+        let broker = InMemoryBroker::new();
+        broker.declare_queue("prefetch_queue").await.unwrap();
+        for i in 0..3u8 {
+            broker.publish_with_headers("prefetch_queue", &[i], &Headers::new()).await.unwrap();
+        }
+
+        let mut consumer = broker
+            .consume_with_prefetch("prefetch_queue", "prefetch_consumer", 2)
+            .await
+            .unwrap();
+        let first = consumer.next().await.unwrap();
+        let second = consumer.next().await.unwrap();
+        let blocked = tokio::time::timeout(Duration::from_millis(50), consumer.next()).await;
+        assert!(blocked.is_err());
+
+        first.ack().await.unwrap();
+        let third = consumer.next().await.unwrap();
+        assert_eq!(third.data, [2]);
+        second.ack().await.unwrap();
+        third.ack().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_consume_undeclared_queue_fails() {
+        let broker = InMemoryBroker::new();
+        assert!(broker.consume("missing_queue", "test_consumer").await.is_err());
+    }
 }
@@ -0,0 +1,199 @@
+// mlp.rs: Defines multi-layer perceptrons, their single-process forward pass and the kernel Ki nodes run them with.
+
+use crate::kernels::{self, Activation, Attr, Attrs, KernelError};
+use crate::messages::TaskMessage;
+use crate::tensor::Tensor;
+
+#[derive(Debug, Clone)]
+pub struct DenseLayer {
+    // [in, out]
+    pub weights: Tensor,
+    // [out]
+    pub bias: Tensor,
+    pub activation: Activation,
+}
+
+impl DenseLayer {
+    pub fn new(weights: Tensor, bias: Tensor, activation: Activation) -> Result<Self, KernelError> {
+        let (_, out_features) = kernels::matrix_shape("dense layer", &weights)?;
+        bias.expect_shape(&[out_features])?;
+        Ok(DenseLayer {
+            weights,
+            bias,
+            activation,
+        })
+    }
+
+    pub fn in_features(&self) -> usize {
+        self.weights.shape()[0]
+    }
+
+    pub fn out_features(&self) -> usize {
+        self.weights.shape()[1]
+    }
+
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor, KernelError> {
+        let (batch, in_features) = kernels::matrix_shape("dense layer", x)?;
+        if in_features != self.in_features() {
+            return Err(KernelError::InvalidInput(format!(
+                "dense layer expects {} features, got {}",
+                self.in_features(),
+                in_features
+            )));
+        }
+        let mut values = kernels::matmul_values(
+            &x.values::<f32>()?,
+            &self.weights.values::<f32>()?,
+            batch,
+            in_features,
+            self.out_features(),
+        );
+        let bias = self.bias.values::<f32>()?;
+        for row in values.chunks_exact_mut(self.out_features().max(1)) {
+            for (value, bias) in row.iter_mut().zip(bias.iter()) {
+                *value += bias;
+            }
+        }
+        self.activation.apply(&Tensor::new(vec![batch, self.out_features()], values)?)
+    }
+}
+
+// A stack of dense layers, each feeding the next.
+#[derive(Debug, Clone)]
+pub struct Mlp {
+    pub layers: Vec<DenseLayer>,
+}
+
+impl Mlp {
+    pub fn new(layers: Vec<DenseLayer>) -> Result<Self, KernelError> {
+        for (index, pair) in layers.windows(2).enumerate() {
+            if pair[0].out_features() != pair[1].in_features() {
+                return Err(KernelError::InvalidInput(format!(
+                    "layer {} outputs {} features but layer {} expects {}",
+                    index,
+                    pair[0].out_features(),
+                    index + 1,
+                    pair[1].in_features()
+                )));
+            }
+        }
+        Ok(Mlp { layers })
+    }
+
+    // Runs every layer in this process; the reference distributed runs are checked against.
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor, KernelError> {
+        let mut activations = x.clone();
+        for layer in &self.layers {
+            activations = layer.forward(&activations)?;
+        }
+        Ok(activations)
+    }
+
+    // A task running the whole network on the batch `x`. An nodes split it by rows across Ki nodes.
+    pub fn task(&self, task_id: impl Into<String>, x: Tensor) -> TaskMessage {
+        let mut inputs = vec![x];
+        for layer in &self.layers {
+            inputs.push(layer.weights.clone());
+            inputs.push(layer.bias.clone());
+        }
+        let activations: Vec<String> = self
+            .layers
+            .iter()
+            .map(|layer| layer.activation.name().to_string())
+            .collect();
+        TaskMessage::new(task_id, "mlp_forward", inputs).with_attr("activations", activations)
+    }
+}
+
+// inputs: x [batch, in], then weights and bias for each layer; attrs: activations, one per layer;
+// outputs: the last layer's activations [batch, out]
+pub fn mlp_forward(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("mlp_forward", inputs, 0)?;
+    Ok(vec![network(&inputs[1..], attrs)?.forward(x)?])
+}
+
+// Rebuilds a network from the weights and bias of each layer and the `activations` attribute, as
+// `Mlp::task` lays them out.
+pub fn network(parameters: &[Tensor], attrs: &Attrs) -> Result<Mlp, KernelError> {
+    let activations = match attrs.get("activations") {
+        Some(Attr::Strs(names)) => names.clone(),
+        None => Vec::new(),
+        Some(other) => {
+            return Err(KernelError::InvalidInput(format!(
+                "attribute activations should be a list of names, got {:?}",
+                other
+            )))
+        }
+    };
+    if parameters.len() != 2 * activations.len() {
+        return Err(KernelError::InvalidInput(format!(
+            "network got {} parameter tensors for {} activations",
+            parameters.len(),
+            activations.len()
+        )));
+    }
+
+    let layers = parameters
+        .chunks_exact(2)
+        .zip(&activations)
+        .map(|(pair, activation)| DenseLayer::new(pair[0].clone(), pair[1].clone(), Activation::parse(activation)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    Mlp::new(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::KernelRegistry;
+
+    #[test]
+    fn test_mlp_kernel_matches_reference() {
+        let mlp = Mlp::new(vec![
+            DenseLayer::new(
+                Tensor::new(vec![2, 3], vec![1.0f32, -1.0, 0.5, 0.0, 2.0, -0.5]).unwrap(),
+                Tensor::new(vec![3], vec![0.1f32, 0.2, 0.3]).unwrap(),
+                Activation::Tanh,
+            )
+            .unwrap(),
+            DenseLayer::new(
+                Tensor::new(vec![3, 2], vec![1.0f32, 0.0, 0.0, 1.0, 1.0, 1.0]).unwrap(),
+                Tensor::new(vec![2], vec![0.0f32, 0.0]).unwrap(),
+                Activation::Softmax,
+            )
+            .unwrap(),
+        ])
+        .unwrap();
+        let x = Tensor::new(vec![2, 2], vec![1.0f32, 2.0, -3.0, 0.5]).unwrap();
+
+        let task = mlp.task("task-1", x.clone());
+        let outputs = KernelRegistry::builtin().run(&task.op, &task.inputs, &task.attrs).unwrap();
+        let reference = mlp.forward(&x).unwrap();
+        assert_eq!(outputs, vec![reference.clone()]);
+        assert_eq!(reference.shape(), &[2, 2]);
+
+        // First row by hand: tanh([1, 2]·W1 + b1), then softmax of its product with W2
+        let hidden: Vec<f32> = [1.1f32, 3.2, -0.2].iter().map(|value| value.tanh()).collect();
+        let logits = [hidden[0] + hidden[2], hidden[1] + hidden[2]];
+        let expected = 1.0 / (1.0 + (logits[1] - logits[0]).exp());
+        assert!((reference.to_vec::<f32>().unwrap()[0] - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rejects_mismatched_layers() {
+        let layer = |inputs: usize, outputs: usize| {
+            DenseLayer::new(
+                Tensor::zeros(crate::tensor::DType::F32, vec![inputs, outputs]),
+                Tensor::zeros(crate::tensor::DType::F32, vec![outputs]),
+                Activation::Relu,
+            )
+            .unwrap()
+        };
+        assert!(Mlp::new(vec![layer(4, 3), layer(2, 1)]).is_err());
+        assert!(DenseLayer::new(
+            Tensor::zeros(crate::tensor::DType::F32, vec![4, 3]),
+            Tensor::zeros(crate::tensor::DType::F32, vec![4]),
+            Activation::Relu
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,1059 @@
+// model.rs: Defines the model spec and weights file formats, and loads them into an executable graph.
+
+use crate::attention::{self, Attention, AttentionWeights};
+use crate::conv::{self, Pool, Window2d};
+use crate::kernels::{self, Activation, Attrs, KernelError};
+use crate::messages::{ModelUpdate, TaskMessage};
+use crate::mlp::{DenseLayer, Mlp};
+use crate::tensor::{Tensor, TensorError};
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const WEIGHTS_MAGIC: &[u8; 8] = b"ANKIWTS\0";
+const WEIGHTS_VERSION: u32 = 1;
+
+// Named parameter tensors, e.g. "hidden.weights" and "hidden.bias"
+pub type Weights = BTreeMap<String, Tensor>;
+
+// A model as written in a spec file (JSON).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelSpec {
+    pub name: String,
+    #[serde(default)]
+    pub version: u64,
+    // Features per input row
+    pub inputs: usize,
+    // The shape each input row is read as, e.g. [channels, height, width] for images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_shape: Option<Vec<usize>>,
+    // Seeds the initializers of parameters the weights file does not provide
+    #[serde(default)]
+    pub seed: u64,
+    pub layers: Vec<LayerSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayerSpec {
+    Dense {
+        name: String,
+        units: usize,
+        #[serde(default = "default_activation")]
+        activation: Activation,
+        #[serde(default = "default_weight_initializer")]
+        initializer: Initializer,
+        #[serde(default = "default_bias_initializer")]
+        bias_initializer: Initializer,
+    },
+    // Over [channels, height, width]; each pair is (height, width)
+    Conv2d {
+        name: String,
+        filters: usize,
+        kernel_size: [usize; 2],
+        #[serde(default = "default_step")]
+        stride: [usize; 2],
+        #[serde(default)]
+        padding: [usize; 2],
+        #[serde(default = "default_step")]
+        dilation: [usize; 2],
+        #[serde(default = "default_activation")]
+        activation: Activation,
+        #[serde(default = "default_weight_initializer")]
+        initializer: Initializer,
+        #[serde(default = "default_bias_initializer")]
+        bias_initializer: Initializer,
+    },
+    // The stride defaults to the kernel size
+    MaxPool2d {
+        kernel_size: [usize; 2],
+        #[serde(default)]
+        stride: Option<[usize; 2]>,
+        #[serde(default)]
+        padding: [usize; 2],
+    },
+    AvgPool2d {
+        kernel_size: [usize; 2],
+        #[serde(default)]
+        stride: Option<[usize; 2]>,
+        #[serde(default)]
+        padding: [usize; 2],
+    },
+    // Per channel, with running statistics as parameters
+    BatchNorm {
+        name: String,
+        #[serde(default = "default_epsilon")]
+        epsilon: f32,
+    },
+    Flatten,
+    // Token ids [seq] to [seq, dim], plus a learned embedding of each position unless `positional` is false
+    Embedding {
+        name: String,
+        vocab: usize,
+        dim: usize,
+        #[serde(default = "default_true")]
+        positional: bool,
+        #[serde(default = "default_embedding_initializer")]
+        initializer: Initializer,
+    },
+    // Multi-head self-attention over [seq, dim], added to its input unless `residual` is false
+    Attention {
+        name: String,
+        heads: usize,
+        #[serde(default)]
+        causal: bool,
+        #[serde(default = "default_true")]
+        residual: bool,
+        #[serde(default = "default_weight_initializer")]
+        initializer: Initializer,
+    },
+    // Over the last axis
+    LayerNorm {
+        name: String,
+        #[serde(default = "default_epsilon")]
+        epsilon: f32,
+    },
+    Gelu,
+}
+
+impl LayerSpec {
+    fn name(&self) -> Option<&str> {
+        match self {
+            LayerSpec::Dense { name, .. }
+            | LayerSpec::Conv2d { name, .. }
+            | LayerSpec::BatchNorm { name, .. }
+            | LayerSpec::Embedding { name, .. }
+            | LayerSpec::Attention { name, .. }
+            | LayerSpec::LayerNorm { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    // The window of a convolution or pooling layer
+    fn window(&self) -> Option<Window2d> {
+        match *self {
+            LayerSpec::Conv2d {
+                kernel_size,
+                stride,
+                padding,
+                dilation,
+                ..
+            } => Some(Window2d {
+                kernel: kernel_size,
+                stride,
+                padding,
+                dilation,
+            }),
+            LayerSpec::MaxPool2d {
+                kernel_size,
+                stride,
+                padding,
+            }
+            | LayerSpec::AvgPool2d {
+                kernel_size,
+                stride,
+                padding,
+            } => Some(Window2d {
+                kernel: kernel_size,
+                stride: stride.unwrap_or(kernel_size),
+                padding,
+                dilation: [1, 1],
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn default_activation() -> Activation {
+    Activation::Identity
+}
+
+fn default_weight_initializer() -> Initializer {
+    Initializer::GlorotUniform
+}
+
+fn default_bias_initializer() -> Initializer {
+    Initializer::Zeros
+}
+
+fn default_step() -> [usize; 2] {
+    [1, 1]
+}
+
+fn default_epsilon() -> f32 {
+    conv::DEFAULT_EPSILON as f32
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_embedding_initializer() -> Initializer {
+    Initializer::Normal { mean: 0.0, std: 0.02 }
+}
+
+const ATTENTION_PROJECTIONS: [&str; 4] = ["query", "key", "value", "output"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Initializer {
+    Zeros,
+    Constant { value: f32 },
+    Uniform { low: f32, high: f32 },
+    Normal { mean: f32, std: f32 },
+    // Uniform in ±sqrt(6 / (fan_in + fan_out))
+    GlorotUniform,
+    // Normal with std sqrt(2 / fan_in)
+    HeNormal,
+}
+
+impl Initializer {
+    pub fn initialize(&self, shape: Vec<usize>, fan_in: usize, fan_out: usize, rng: &mut StdRng) -> Tensor {
+        let len: usize = shape.iter().product();
+        let values: Vec<f32> = match *self {
+            Initializer::Zeros => vec![0.0; len],
+            Initializer::Constant { value } => vec![value; len],
+            Initializer::Uniform { low, high } => (0..len).map(|_| rng.gen_range(low..=high)).collect(),
+            Initializer::Normal { mean, std } => (0..len).map(|_| mean + std * standard_normal(rng)).collect(),
+            Initializer::GlorotUniform => {
+                let limit = (6.0 / (fan_in + fan_out).max(1) as f32).sqrt();
+                (0..len).map(|_| rng.gen_range(-limit..=limit)).collect()
+            }
+            Initializer::HeNormal => {
+                let std = (2.0 / fan_in.max(1) as f32).sqrt();
+                (0..len).map(|_| std * standard_normal(rng)).collect()
+            }
+        };
+        Tensor::new(shape, values).unwrap()
+    }
+}
+
+// Box-Muller transform
+fn standard_normal(rng: &mut StdRng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+#[derive(Debug)]
+pub enum ModelError {
+    InvalidSpec(String),
+    MissingWeight(String),
+    UnexpectedWeight(String),
+    Shape { parameter: String, expected: Vec<usize>, found: Vec<usize> },
+    MalformedWeights(String),
+    Kernel(KernelError),
+    Io(io::Error),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::InvalidSpec(reason) => write!(f, "invalid model spec: {}", reason),
+            ModelError::MissingWeight(name) => write!(f, "weights file has no parameter {}", name),
+            ModelError::UnexpectedWeight(name) => write!(f, "weights file has unknown parameter {}", name),
+            ModelError::Shape { parameter, expected, found } => {
+                write!(f, "parameter {} should have shape {:?}, found {:?}", parameter, expected, found)
+            }
+            ModelError::MalformedWeights(reason) => write!(f, "malformed weights file: {}", reason),
+            ModelError::Kernel(e) => write!(f, "{}", e),
+            ModelError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl From<io::Error> for ModelError {
+    fn from(e: io::Error) -> Self {
+        ModelError::Io(e)
+    }
+}
+
+impl From<KernelError> for ModelError {
+    fn from(e: KernelError) -> Self {
+        ModelError::Kernel(e)
+    }
+}
+
+impl From<TensorError> for ModelError {
+    fn from(e: TensorError) -> Self {
+        ModelError::MalformedWeights(e.to_string())
+    }
+}
+
+impl ModelSpec {
+    pub fn from_json(json: &str) -> Result<Self, ModelError> {
+        serde_json::from_str(json).map_err(|e| ModelError::InvalidSpec(e.to_string()))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Shape of every parameter, in layer order.
+    pub fn parameter_shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let mut shapes = Vec::new();
+        // Only a spec that does not validate has layers whose input shapes cannot be traced
+        let inputs = self.layer_inputs().unwrap_or_default();
+        for (layer, input) in self.layers.iter().zip(inputs) {
+            match layer {
+                LayerSpec::Dense { name, units, .. } => {
+                    shapes.push((format!("{}.weights", name), vec![input[input.len() - 1], *units]));
+                    shapes.push((format!("{}.bias", name), vec![*units]));
+                }
+                LayerSpec::Conv2d {
+                    name,
+                    filters,
+                    kernel_size: [kh, kw],
+                    ..
+                } => {
+                    shapes.push((format!("{}.weights", name), vec![*filters, input[0], *kh, *kw]));
+                    shapes.push((format!("{}.bias", name), vec![*filters]));
+                }
+                LayerSpec::BatchNorm { name, .. } => {
+                    for parameter in ["gamma", "beta", "running_mean", "running_var"] {
+                        shapes.push((format!("{}.{}", name, parameter), vec![input[0]]));
+                    }
+                }
+                LayerSpec::Embedding {
+                    name,
+                    vocab,
+                    dim,
+                    positional,
+                    ..
+                } => {
+                    shapes.push((format!("{}.table", name), vec![*vocab, *dim]));
+                    if *positional {
+                        shapes.push((format!("{}.positions", name), vec![input[0], *dim]));
+                    }
+                }
+                LayerSpec::Attention { name, .. } => {
+                    let dim = input[1];
+                    for projection in ATTENTION_PROJECTIONS {
+                        shapes.push((format!("{}.{}.weights", name, projection), vec![dim, dim]));
+                        shapes.push((format!("{}.{}.bias", name, projection), vec![dim]));
+                    }
+                }
+                LayerSpec::LayerNorm { name, .. } => {
+                    for parameter in ["gamma", "beta"] {
+                        shapes.push((format!("{}.{}", name, parameter), vec![input[input.len() - 1]]));
+                    }
+                }
+                LayerSpec::MaxPool2d { .. } | LayerSpec::AvgPool2d { .. } | LayerSpec::Flatten | LayerSpec::Gelu => {}
+            }
+        }
+        shapes
+    }
+
+    // The shape of one example as each layer receives it. Convolution and pooling layers take
+    // [channels, height, width], embeddings token ids [seq] and attention [seq, dim]. Dense layers take
+    // flat features, or [seq, features] to apply to each position; batch-norm, layer-norm and GELU take any.
+    fn layer_inputs(&self) -> Result<Vec<Vec<usize>>, ModelError> {
+        let mut shape = self.input_shape.clone().unwrap_or_else(|| vec![self.inputs]);
+        if shape.iter().product::<usize>() != self.inputs || shape.is_empty() {
+            return Err(ModelError::InvalidSpec(format!(
+                "input shape {:?} does not hold {} inputs",
+                shape, self.inputs
+            )));
+        }
+        let mut inputs = Vec::with_capacity(self.layers.len());
+        let mut dense = false;
+        for (index, layer) in self.layers.iter().enumerate() {
+            let invalid = |reason: String| ModelError::InvalidSpec(format!("layer {}: {}", index, reason));
+            inputs.push(shape.clone());
+            if dense && !matches!(layer, LayerSpec::Dense { .. }) {
+                return Err(invalid("only dense layers can follow a dense layer on flat inputs".to_string()));
+            }
+            shape = match layer {
+                LayerSpec::Dense { units, .. } => match shape.as_slice() {
+                    [_] => {
+                        dense = true;
+                        vec![*units]
+                    }
+                    &[seq, _] => vec![seq, *units],
+                    _ => {
+                        let reason = format!("a dense layer needs flat or [seq, features] inputs, got shape {:?}", shape);
+                        return Err(invalid(reason));
+                    }
+                },
+                LayerSpec::Conv2d { .. } | LayerSpec::MaxPool2d { .. } | LayerSpec::AvgPool2d { .. } => {
+                    let &[channels, height, width] = shape.as_slice() else {
+                        return Err(invalid(format!("needs [channels, height, width] inputs, got shape {:?}", shape)));
+                    };
+                    let window = layer.window().unwrap();
+                    window.validate().map_err(|e| invalid(e.to_string()))?;
+                    let [height, width] = window.output_size([height, width]).map_err(|e| invalid(e.to_string()))?;
+                    match layer {
+                        LayerSpec::Conv2d { filters, .. } => vec![*filters, height, width],
+                        _ => vec![channels, height, width],
+                    }
+                }
+                LayerSpec::BatchNorm { .. } | LayerSpec::LayerNorm { .. } | LayerSpec::Gelu => shape,
+                LayerSpec::Flatten => vec![shape.iter().product()],
+                LayerSpec::Embedding { dim, .. } => {
+                    let &[seq] = shape.as_slice() else {
+                        return Err(invalid(format!("an embedding needs token ids [seq], got shape {:?}", shape)));
+                    };
+                    vec![seq, *dim]
+                }
+                LayerSpec::Attention { heads, .. } => {
+                    let &[_, dim] = shape.as_slice() else {
+                        return Err(invalid(format!("attention needs [seq, dim] inputs, got shape {:?}", shape)));
+                    };
+                    if *heads == 0 || dim % heads != 0 {
+                        return Err(invalid(format!("{} heads do not divide dim {}", heads, dim)));
+                    }
+                    shape
+                }
+            };
+        }
+        Ok(inputs)
+    }
+
+    pub fn validate(&self) -> Result<(), ModelError> {
+        if self.name.is_empty() {
+            return Err(ModelError::InvalidSpec("the model has no name".to_string()));
+        }
+        if self.inputs == 0 {
+            return Err(ModelError::InvalidSpec("the model takes no inputs".to_string()));
+        }
+        if self.layers.is_empty() {
+            return Err(ModelError::InvalidSpec("the model has no layers".to_string()));
+        }
+        let mut names = HashSet::new();
+        for layer in &self.layers {
+            if let Some(name) = layer.name() {
+                if !names.insert(name) {
+                    return Err(ModelError::InvalidSpec(format!("layer name {} is used twice", name)));
+                }
+            }
+            match layer {
+                LayerSpec::Dense { name, units: 0, .. } => {
+                    return Err(ModelError::InvalidSpec(format!("layer {} has no units", name)))
+                }
+                LayerSpec::Conv2d { name, filters: 0, .. } => {
+                    return Err(ModelError::InvalidSpec(format!("layer {} has no filters", name)))
+                }
+                LayerSpec::Embedding { name, vocab: 0, .. } | LayerSpec::Embedding { name, dim: 0, .. } => {
+                    return Err(ModelError::InvalidSpec(format!("layer {} has an empty table", name)))
+                }
+                LayerSpec::BatchNorm { name, epsilon, .. } | LayerSpec::LayerNorm { name, epsilon } if *epsilon <= 0.0 => {
+                    return Err(ModelError::InvalidSpec(format!("layer {} needs a positive epsilon", name)))
+                }
+                _ => {}
+            }
+        }
+        self.layer_inputs()?;
+        Ok(())
+    }
+}
+
+// A layer ahead of a model's dense layers on flat inputs, over a batch of examples.
+#[derive(Debug, Clone)]
+pub enum FeatureLayer {
+    Conv2d {
+        weights: Tensor,
+        bias: Tensor,
+        window: Window2d,
+        activation: Activation,
+    },
+    Pool(Pool, Window2d),
+    // Normalizes with the statistics kept from training
+    BatchNorm {
+        gamma: Tensor,
+        beta: Tensor,
+        mean: Tensor,
+        variance: Tensor,
+        epsilon: f32,
+    },
+    Flatten,
+    Embedding {
+        table: Tensor,
+        positions: Option<Tensor>,
+    },
+    Attention {
+        attention: Attention,
+        weights: Box<AttentionWeights>,
+        residual: bool,
+    },
+    LayerNorm {
+        gamma: Tensor,
+        beta: Tensor,
+        epsilon: f32,
+    },
+    Gelu,
+    // A dense layer applied to each position of [batch, seq, features]
+    Dense(DenseLayer),
+}
+
+impl FeatureLayer {
+    // In `ModelSpec::parameter_shapes` order
+    fn parameters(&self) -> Vec<&Tensor> {
+        match self {
+            FeatureLayer::Conv2d { weights, bias, .. } => vec![weights, bias],
+            FeatureLayer::BatchNorm {
+                gamma,
+                beta,
+                mean,
+                variance,
+                ..
+            } => vec![gamma, beta, mean, variance],
+            FeatureLayer::Embedding { table, positions } => [table].into_iter().chain(positions).collect(),
+            FeatureLayer::Attention { weights, .. } => weights.tensors().to_vec(),
+            FeatureLayer::LayerNorm { gamma, beta, .. } => vec![gamma, beta],
+            FeatureLayer::Dense(layer) => vec![&layer.weights, &layer.bias],
+            FeatureLayer::Pool(..) | FeatureLayer::Flatten | FeatureLayer::Gelu => Vec::new(),
+        }
+    }
+
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor, KernelError> {
+        match self {
+            FeatureLayer::Conv2d {
+                weights,
+                bias,
+                window,
+                activation,
+            } => activation.apply(&conv::conv2d(x, weights, bias, window)?),
+            FeatureLayer::Pool(pool, window) => pool.forward(x, window),
+            FeatureLayer::BatchNorm {
+                gamma,
+                beta,
+                mean,
+                variance,
+                epsilon,
+            } => conv::batch_norm_inference(x, gamma, beta, mean, variance, *epsilon),
+            FeatureLayer::Flatten => conv::flatten(x),
+            FeatureLayer::Embedding { table, positions } => {
+                let y = attention::embedding(x, table)?;
+                let Some(positions) = positions else {
+                    return Ok(y);
+                };
+                let positions = positions.values::<f32>()?;
+                let mut values = y.to_vec::<f32>()?;
+                for sequence in values.chunks_exact_mut(positions.len()) {
+                    sequence.iter_mut().zip(positions.iter()).for_each(|(value, position)| *value += position);
+                }
+                Ok(Tensor::new(y.shape().to_vec(), values)?)
+            }
+            FeatureLayer::Attention {
+                attention,
+                weights,
+                residual,
+            } => {
+                let y = attention.forward(x, weights, None)?;
+                if !*residual {
+                    return Ok(y);
+                }
+                let values = y.values::<f32>()?.iter().zip(x.values::<f32>()?.iter()).map(|(y, x)| y + x).collect();
+                Ok(Tensor::new(y.shape().to_vec(), values)?)
+            }
+            FeatureLayer::LayerNorm { gamma, beta, epsilon } => attention::layer_norm(x, gamma, beta, *epsilon),
+            FeatureLayer::Gelu => attention::gelu(x),
+            FeatureLayer::Dense(layer) => {
+                let &[batch, seq, features] = x.shape() else {
+                    let reason = format!("expected [batch, seq, features], got shape {:?}", x.shape());
+                    return Err(KernelError::InvalidInput(reason));
+                };
+                let y = layer.forward(&x.reshape(vec![batch * seq, features])?)?;
+                Ok(y.reshape(vec![batch, seq, layer.out_features()])?)
+            }
+        }
+    }
+}
+
+// A validated model, ready to run.
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub spec: ModelSpec,
+    // The layers ahead of the dense layers on flat inputs; empty for an MLP
+    pub features: Vec<FeatureLayer>,
+    // The dense layers
+    pub graph: Mlp,
+}
+
+impl Model {
+    // Builds the model from its spec. Parameters come from `weights` when given, which must then hold
+    // exactly the parameters of the spec; otherwise they are initialized as the spec says.
+    pub fn load(spec: ModelSpec, weights: Option<Weights>) -> Result<Self, ModelError> {
+        spec.validate()?;
+        let shapes = spec.parameter_shapes();
+        let mut parameters = match weights {
+            Some(mut weights) => {
+                let mut parameters = Weights::new();
+                for (name, shape) in &shapes {
+                    let tensor = weights.remove(name).ok_or_else(|| ModelError::MissingWeight(name.clone()))?;
+                    if tensor.shape() != shape.as_slice() {
+                        return Err(ModelError::Shape {
+                            parameter: name.clone(),
+                            expected: shape.clone(),
+                            found: tensor.shape().to_vec(),
+                        });
+                    }
+                    parameters.insert(name.clone(), tensor);
+                }
+                if let Some(name) = weights.into_keys().next() {
+                    return Err(ModelError::UnexpectedWeight(name));
+                }
+                parameters
+            }
+            None => initialize(&spec),
+        };
+
+        let mut take = |name: &str, parameter: &str| parameters.remove(&format!("{}.{}", name, parameter)).unwrap();
+        let mut features = Vec::new();
+        let mut layers = Vec::with_capacity(spec.layers.len());
+        for (layer, input) in spec.layers.iter().zip(spec.layer_inputs()?) {
+            match layer {
+                LayerSpec::Dense { name, activation, .. } => {
+                    let dense = DenseLayer::new(take(name, "weights"), take(name, "bias"), *activation)?;
+                    match input.len() {
+                        1 => layers.push(dense),
+                        _ => features.push(FeatureLayer::Dense(dense)),
+                    }
+                }
+                LayerSpec::Conv2d { name, activation, .. } => features.push(FeatureLayer::Conv2d {
+                    weights: take(name, "weights"),
+                    bias: take(name, "bias"),
+                    window: layer.window().unwrap(),
+                    activation: *activation,
+                }),
+                LayerSpec::MaxPool2d { .. } => features.push(FeatureLayer::Pool(Pool::Max, layer.window().unwrap())),
+                LayerSpec::AvgPool2d { .. } => features.push(FeatureLayer::Pool(Pool::Avg, layer.window().unwrap())),
+                LayerSpec::BatchNorm { name, epsilon } => features.push(FeatureLayer::BatchNorm {
+                    gamma: take(name, "gamma"),
+                    beta: take(name, "beta"),
+                    mean: take(name, "running_mean"),
+                    variance: take(name, "running_var"),
+                    epsilon: *epsilon,
+                }),
+                LayerSpec::Flatten => features.push(FeatureLayer::Flatten),
+                LayerSpec::Embedding { name, positional, .. } => features.push(FeatureLayer::Embedding {
+                    table: take(name, "table"),
+                    positions: positional.then(|| take(name, "positions")),
+                }),
+                LayerSpec::Attention {
+                    name,
+                    heads,
+                    causal,
+                    residual,
+                    ..
+                } => {
+                    let mut tensors = Vec::with_capacity(8);
+                    for projection in ATTENTION_PROJECTIONS {
+                        tensors.push(take(name, &format!("{}.weights", projection)));
+                        tensors.push(take(name, &format!("{}.bias", projection)));
+                    }
+                    features.push(FeatureLayer::Attention {
+                        attention: Attention {
+                            heads: *heads,
+                            causal: *causal,
+                        },
+                        weights: Box::new(AttentionWeights::from_slice(&tensors)?),
+                        residual: *residual,
+                    })
+                }
+                LayerSpec::LayerNorm { name, epsilon } => features.push(FeatureLayer::LayerNorm {
+                    gamma: take(name, "gamma"),
+                    beta: take(name, "beta"),
+                    epsilon: *epsilon,
+                }),
+                LayerSpec::Gelu => features.push(FeatureLayer::Gelu),
+            }
+        }
+        Ok(Model {
+            features,
+            graph: Mlp::new(layers)?,
+            spec,
+        })
+    }
+
+    // Reads a spec file and, if given, a weights file.
+    pub fn from_files(spec_path: &Path, weights_path: Option<&Path>) -> Result<Self, ModelError> {
+        let spec = ModelSpec::from_json(&fs::read_to_string(spec_path)?)?;
+        let weights = weights_path.map(load_weights).transpose()?;
+        Model::load(spec, weights)
+    }
+
+    // Validates and builds a model shipped by the principal.
+    pub fn from_update(update: ModelUpdate) -> Result<Self, ModelError> {
+        let spec = ModelSpec::from_json(&update.spec)?;
+        if spec.name != update.name || spec.version != update.version {
+            return Err(ModelError::InvalidSpec(format!(
+                "update for {} v{} carries the spec of {} v{}",
+                update.name, update.version, spec.name, spec.version
+            )));
+        }
+        Model::load(spec, Some(update.weights))
+    }
+
+    pub fn to_update(&self) -> ModelUpdate {
+        ModelUpdate {
+            name: self.spec.name.clone(),
+            version: self.spec.version,
+            spec: self.spec.to_json(),
+            weights: self.weights(),
+        }
+    }
+
+    pub fn weights(&self) -> Weights {
+        let (mut features, mut dense) = (self.features.iter(), self.graph.layers.iter());
+        let mut parameters = Vec::new();
+        for (spec, input) in self.spec.layers.iter().zip(self.spec.layer_inputs().unwrap()) {
+            match spec {
+                LayerSpec::Dense { .. } if input.len() == 1 => {
+                    let layer = dense.next().unwrap();
+                    parameters.extend([&layer.weights, &layer.bias]);
+                }
+                _ => parameters.extend(features.next().unwrap().parameters()),
+            }
+        }
+        let names = self.spec.parameter_shapes().into_iter().map(|(name, _)| name);
+        names.zip(parameters.into_iter().cloned()).collect()
+    }
+
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor, KernelError> {
+        if self.features.is_empty() {
+            return self.graph.forward(x);
+        }
+        let mut activations = match &self.spec.input_shape {
+            Some(shape) => {
+                let rows = x.shape().first().copied().unwrap_or(0);
+                x.reshape([&[rows][..], shape].concat())?
+            }
+            None => x.clone(),
+        };
+        for layer in &self.features {
+            activations = layer.forward(&activations)?;
+        }
+        self.graph.forward(&activations)
+    }
+
+    // A task running the model on the batch `x`: `mlp_forward` for an MLP, otherwise `model_forward` with
+    // the spec and every parameter.
+    pub fn task(&self, task_id: impl Into<String>, x: Tensor) -> TaskMessage {
+        if self.features.is_empty() {
+            return self.graph.task(task_id, x);
+        }
+        let mut weights = self.weights();
+        let mut inputs = vec![x];
+        for (name, _) in self.spec.parameter_shapes() {
+            inputs.push(weights.remove(&name).unwrap());
+        }
+        TaskMessage::new(task_id, "model_forward", inputs).with_attr("spec", self.spec.to_json())
+    }
+}
+
+// inputs: a batch [rows, inputs], then each parameter of the model in `parameter_shapes` order; attrs:
+// spec, the model spec as JSON; outputs: the model's output
+pub fn model_forward(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let invalid = |e: ModelError| KernelError::InvalidInput(e.to_string());
+    let spec = kernels::str_attr(attrs, "spec")?
+        .ok_or_else(|| KernelError::InvalidInput("model_forward needs a spec attribute".to_string()))?;
+    let spec = ModelSpec::from_json(spec).map_err(invalid)?;
+    let x = kernels::input("model_forward", inputs, 0)?;
+    let weights: Weights = spec
+        .parameter_shapes()
+        .into_iter()
+        .map(|(name, _)| name)
+        .zip(inputs[1..].iter().cloned())
+        .collect();
+    let model = Model::load(spec, Some(weights)).map_err(invalid)?;
+    Ok(vec![model.forward(x)?])
+}
+
+fn initialize(spec: &ModelSpec) -> Weights {
+    let mut rng = StdRng::seed_from_u64(spec.seed);
+    let mut weights = Weights::new();
+    let shapes: BTreeMap<String, Vec<usize>> = spec.parameter_shapes().into_iter().collect();
+    for layer in &spec.layers {
+        match layer {
+            LayerSpec::Dense {
+                name,
+                units,
+                initializer,
+                bias_initializer,
+                ..
+            } => {
+                let features = shapes[&format!("{}.weights", name)][0];
+                weights.insert(
+                    format!("{}.weights", name),
+                    initializer.initialize(vec![features, *units], features, *units, &mut rng),
+                );
+                weights.insert(
+                    format!("{}.bias", name),
+                    bias_initializer.initialize(vec![*units], features, *units, &mut rng),
+                );
+            }
+            LayerSpec::Conv2d {
+                name,
+                initializer,
+                bias_initializer,
+                ..
+            } => {
+                let shape = shapes[&format!("{}.weights", name)].clone();
+                let area = shape[2] * shape[3];
+                let (fan_in, fan_out) = (shape[1] * area, shape[0] * area);
+                let filters = shape[0];
+                weights.insert(format!("{}.weights", name), initializer.initialize(shape, fan_in, fan_out, &mut rng));
+                weights.insert(
+                    format!("{}.bias", name),
+                    bias_initializer.initialize(vec![filters], fan_in, fan_out, &mut rng),
+                );
+            }
+            LayerSpec::BatchNorm { name, .. } => {
+                let channels = shapes[&format!("{}.gamma", name)].clone();
+                for (parameter, value) in [("gamma", 1.0f32), ("beta", 0.0), ("running_mean", 0.0), ("running_var", 1.0)] {
+                    let values = vec![value; channels[0]];
+                    weights.insert(format!("{}.{}", name, parameter), Tensor::new(channels.clone(), values).unwrap());
+                }
+            }
+            LayerSpec::Embedding {
+                name,
+                vocab,
+                dim,
+                initializer,
+                ..
+            } => {
+                let table = initializer.initialize(vec![*vocab, *dim], *vocab, *dim, &mut rng);
+                weights.insert(format!("{}.table", name), table);
+                if let Some(shape) = shapes.get(&format!("{}.positions", name)) {
+                    let positions = initializer.initialize(shape.clone(), shape[0], *dim, &mut rng);
+                    weights.insert(format!("{}.positions", name), positions);
+                }
+            }
+            LayerSpec::Attention { name, initializer, .. } => {
+                let shape = shapes[&format!("{}.query.weights", name)].clone();
+                let dim = shape[0];
+                for projection in ATTENTION_PROJECTIONS {
+                    let projection_weights = initializer.initialize(shape.clone(), dim, dim, &mut rng);
+                    weights.insert(format!("{}.{}.weights", name, projection), projection_weights);
+                    let bias = Tensor::new(vec![dim], vec![0.0f32; dim]).unwrap();
+                    weights.insert(format!("{}.{}.bias", name, projection), bias);
+                }
+            }
+            LayerSpec::LayerNorm { name, .. } => {
+                let shape = shapes[&format!("{}.gamma", name)].clone();
+                for (parameter, value) in [("gamma", 1.0f32), ("beta", 0.0)] {
+                    let values = vec![value; shape[0]];
+                    weights.insert(format!("{}.{}", name, parameter), Tensor::new(shape.clone(), values).unwrap());
+                }
+            }
+            LayerSpec::MaxPool2d { .. } | LayerSpec::AvgPool2d { .. } | LayerSpec::Flatten | LayerSpec::Gelu => {}
+        }
+    }
+    weights
+}
+
+// Weights file layout, integers little-endian:
+//   8 bytes magic, u32 version, u32 parameter count
+//   per parameter: u32 name length, name (UTF-8), zero padding to an 8-byte boundary,
+//   u64 tensor length, tensor in its wire format
+// The padding keeps tensor data 8-byte aligned so decoding can borrow it.
+pub fn encode_weights(weights: &Weights) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(WEIGHTS_MAGIC);
+    bytes.extend_from_slice(&WEIGHTS_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(weights.len() as u32).to_le_bytes());
+    for (name, tensor) in weights {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(8), 0);
+        bytes.extend_from_slice(&(tensor.encoded_len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&tensor.encode());
+    }
+    bytes
+}
+
+// Decodes without copying tensor data; the tensors keep a reference to `bytes`.
+pub fn decode_weights(bytes: Bytes) -> Result<Weights, ModelError> {
+    let truncated = || ModelError::MalformedWeights("truncated file".to_string());
+    if bytes.len() < 16 || &bytes[..8] != WEIGHTS_MAGIC {
+        return Err(ModelError::MalformedWeights("not a weights file".to_string()));
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != WEIGHTS_VERSION {
+        return Err(ModelError::MalformedWeights(format!("unsupported version {}", version)));
+    }
+    let count = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+
+    let mut weights = Weights::new();
+    let mut offset = 16;
+    for _ in 0..count {
+        let name_len = u32::from_le_bytes(bytes.get(offset..offset + 4).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+        offset += 4;
+        let name = std::str::from_utf8(bytes.get(offset..offset + name_len).ok_or_else(truncated)?)
+            .map_err(|e| ModelError::MalformedWeights(e.to_string()))?
+            .to_string();
+        offset = (offset + name_len).next_multiple_of(8);
+        let tensor_len = u64::from_le_bytes(bytes.get(offset..offset + 8).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+        offset += 8;
+        let end = offset.checked_add(tensor_len).filter(|&end| end <= bytes.len()).ok_or_else(truncated)?;
+        let tensor = Tensor::decode(bytes.slice(offset..end))?;
+        if weights.insert(name.clone(), tensor).is_some() {
+            return Err(ModelError::MalformedWeights(format!("parameter {} appears twice", name)));
+        }
+        offset = end;
+    }
+    if offset != bytes.len() {
+        return Err(ModelError::MalformedWeights("trailing bytes".to_string()));
+    }
+    Ok(weights)
+}
+
+pub fn load_weights(path: &Path) -> Result<Weights, ModelError> {
+    decode_weights(Bytes::from(fs::read(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"{
+        "name": "iris",
+        "version": 3,
+        "inputs": 4,
+        "seed": 7,
+        "layers": [
+            {"type": "dense", "name": "hidden", "units": 8, "activation": "relu", "initializer": {"kind": "he_normal"}},
+            {"type": "dense", "name": "output", "units": 3, "activation": "softmax",
+             "bias_initializer": {"kind": "constant", "value": 0.1}}
+        ]
+    }"#;
+
+    #[test]
+    fn test_load_spec_and_initialize() {
+        let spec = ModelSpec::from_json(SPEC).unwrap();
+        let model = Model::load(spec.clone(), None).unwrap();
+        assert_eq!(model.graph.layers.len(), 2);
+        assert_eq!(model.graph.layers[1].bias.to_vec::<f32>().unwrap(), vec![0.1; 3]);
+
+        // Initialization is deterministic for a given seed
+        assert_eq!(Model::load(spec.clone(), None).unwrap().weights(), model.weights());
+
+        let batch = Tensor::new(vec![2, 4], vec![0.5f32; 8]).unwrap();
+        assert_eq!(model.forward(&batch).unwrap().shape(), &[2, 3]);
+        assert_eq!(ModelSpec::from_json(&spec.to_json()).unwrap(), spec);
+    }
+
+    #[test]
+    fn test_weights_file_round_trip() {
+        let model = Model::load(ModelSpec::from_json(SPEC).unwrap(), None).unwrap();
+        let dir = std::env::temp_dir().join(format!("model_test_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (spec_path, weights_path) = (dir.join("iris.json"), dir.join("iris.weights"));
+        fs::write(&spec_path, model.spec.to_json()).unwrap();
+        fs::write(&weights_path, encode_weights(&model.weights())).unwrap();
+
+        let reloaded = Model::from_files(&spec_path, Some(&weights_path)).unwrap();
+        assert_eq!(reloaded.weights(), model.weights());
+        fs::remove_dir_all(dir).unwrap();
+
+        let encoded = encode_weights(&model.weights());
+        assert!(decode_weights(Bytes::from(encoded[..encoded.len() - 1].to_vec())).is_err());
+        assert!(decode_weights(Bytes::from_static(b"not weights at all")).is_err());
+    }
+
+    #[test]
+    fn test_cnn_spec_runs_through_its_feature_layers() {
+        let spec = ModelSpec::from_json(
+            r#"{"name": "digits", "inputs": 36, "input_shape": [1, 6, 6], "seed": 2, "layers": [
+                {"type": "conv2d", "name": "conv", "filters": 2, "kernel_size": [3, 3], "padding": [1, 1], "activation": "relu"},
+                {"type": "batch_norm", "name": "norm"},
+                {"type": "max_pool2d", "kernel_size": [2, 2]},
+                {"type": "flatten"},
+                {"type": "dense", "name": "out", "units": 3, "activation": "softmax"}]}"#,
+        )
+        .unwrap();
+        let model = Model::load(spec.clone(), None).unwrap();
+        assert_eq!(model.features.len(), 4);
+        assert_eq!(model.weights()["conv.weights"].shape(), &[2, 1, 3, 3]);
+        assert_eq!(model.weights()["out.weights"].shape(), &[18, 3]);
+
+        let x = Tensor::new(vec![2, 36], (0..72).map(|i| (i as f32 * 0.37).sin()).collect::<Vec<_>>()).unwrap();
+        let y = model.forward(&x).unwrap();
+        assert_eq!(y.shape(), &[2, 3]);
+
+        // A Ki node rebuilds the same model from the task, and a shipped model keeps its parameters
+        let task = model.task("cnn-1", x.clone());
+        assert_eq!(task.op, "model_forward");
+        let outputs = crate::kernels::KernelRegistry::builtin().run(&task.op, &task.inputs, &task.attrs).unwrap();
+        assert_eq!(outputs, vec![y.clone()]);
+        assert_eq!(Model::from_update(model.to_update()).unwrap().forward(&x).unwrap(), y);
+
+        let mut dense_first = spec;
+        dense_first.layers.swap(0, 4);
+        assert!(matches!(Model::load(dense_first, None), Err(ModelError::InvalidSpec(_))));
+    }
+
+    #[test]
+    fn test_transformer_spec_runs_through_its_feature_layers() {
+        let spec = ModelSpec::from_json(
+            r#"{"name": "tokens", "inputs": 5, "seed": 4, "layers": [
+                {"type": "embedding", "name": "embed", "vocab": 7, "dim": 8},
+                {"type": "layer_norm", "name": "norm"},
+                {"type": "attention", "name": "attend", "heads": 2, "causal": true},
+                {"type": "dense", "name": "expand", "units": 16},
+                {"type": "gelu"},
+                {"type": "dense", "name": "project", "units": 8},
+                {"type": "flatten"},
+                {"type": "dense", "name": "out", "units": 7, "activation": "softmax"}]}"#,
+        )
+        .unwrap();
+        let model = Model::load(spec.clone(), None).unwrap();
+        assert_eq!(model.features.len(), 7);
+        assert_eq!(model.graph.layers.len(), 1);
+        assert_eq!(model.weights()["embed.positions"].shape(), &[5, 8]);
+        assert_eq!(model.weights()["attend.value.weights"].shape(), &[8, 8]);
+        assert_eq!(model.weights()["out.weights"].shape(), &[40, 7]);
+
+        let x = Tensor::new(vec![2, 5], vec![0.0f32, 3.0, 6.0, 1.0, 1.0, 2.0, 2.0, 5.0, 4.0, 0.0]).unwrap();
+        let y = model.forward(&x).unwrap();
+        assert_eq!(y.shape(), &[2, 7]);
+        let task = model.task("transformer-1", x.clone());
+        let outputs = crate::kernels::KernelRegistry::builtin().run(&task.op, &task.inputs, &task.attrs).unwrap();
+        assert_eq!(outputs, vec![y.clone()]);
+        assert_eq!(Model::from_update(model.to_update()).unwrap().forward(&x).unwrap(), y);
+
+        let mut uneven_heads = spec;
+        uneven_heads.layers[2] = LayerSpec::Attention {
+            name: "attend".to_string(),
+            heads: 3,
+            causal: false,
+            residual: true,
+            initializer: Initializer::GlorotUniform,
+        };
+        assert!(matches!(Model::load(uneven_heads, None), Err(ModelError::InvalidSpec(_))));
+    }
+
+    #[test]
+    fn test_loader_validates_shapes() {
+        let spec = ModelSpec::from_json(SPEC).unwrap();
+        let weights = Model::load(spec.clone(), None).unwrap().weights();
+
+        let mut wrong_shape = weights.clone();
+        wrong_shape.insert("hidden.bias".to_string(), Tensor::new(vec![4], vec![0.0f32; 4]).unwrap());
+        assert!(matches!(
+            Model::load(spec.clone(), Some(wrong_shape)),
+            Err(ModelError::Shape { ref parameter, .. }) if parameter == "hidden.bias"
+        ));
+
+        let mut missing = weights.clone();
+        missing.remove("output.weights");
+        assert!(matches!(Model::load(spec.clone(), Some(missing)), Err(ModelError::MissingWeight(_))));
+
+        let mut extra = weights;
+        extra.insert("stray".to_string(), Tensor::scalar(1.0f32));
+        assert!(matches!(Model::load(spec.clone(), Some(extra)), Err(ModelError::UnexpectedWeight(_))));
+
+        let mut duplicate = spec;
+        duplicate.layers.push(duplicate.layers[0].clone());
+        assert!(matches!(Model::load(duplicate, None), Err(ModelError::InvalidSpec(_))));
+    }
+}
@@ -1,31 +1,135 @@
 // network.rs: Abstracts network operations such as connecting nodes and handling retries.
 
-use tokio::net::TcpStream;
+use crate::messaging::{topic_matches, Consumer, Delivery, Headers, InMemoryBroker, Transport, TransportError};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{timeout, Duration};
-use tracing::{info, error};
-use std::error::Error;
+use tracing::{info, error, warn};
+use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+// Large enough for a sizeable tensor; larger frames are rejected rather than buffered
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+const FRAME_VERSION: u8 = 1;
+const CONNECT_RETRIES: u32 = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const LISTEN_ADDR_HEADER: &str = "x-listen-addr";
+const DEFAULT_TCP_LISTEN_ADDR: &str = "0.0.0.0:7878";
+
+// One message on a peer connection, addressed the same way as a broker publish.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub exchange: String,
+    pub routing_key: String,
+    pub headers: Headers,
+    pub payload: Vec<u8>,
+}
 
 #[derive(Clone, Debug)]
 pub struct NetworkManager {
     pub connected_nodes: Arc<RwLock<HashSet<SocketAddr>>>,
+    // Write halves of the persistent connections, keyed by peer address. A connection a peer opened is also
+    // listed under the address it listens on, once the peer has said what that is.
+    peers: Arc<RwLock<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>>,
+    // Held while connecting to an address, so that concurrent sends share one connection
+    connect_locks: Arc<RwLock<HashMap<SocketAddr, Arc<Mutex<()>>>>>,
+    // Where this node accepts connections, announced to every peer it connects to
+    listen_addr: Arc<RwLock<Option<SocketAddr>>>,
+    // Where frames read from any connection are delivered
+    inbox: Arc<RwLock<Option<mpsc::UnboundedSender<Frame>>>>,
+    max_frame_size: usize,
 }
 
 impl NetworkManager {
     pub fn new() -> Self {
         NetworkManager {
             connected_nodes: Arc::new(RwLock::new(HashSet::new())),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            connect_locks: Arc::new(RwLock::new(HashMap::new())),
+            listen_addr: Arc::new(RwLock::new(None)),
+            inbox: Arc::new(RwLock::new(None)),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
         }
     }
 
-    pub async fn connect_to_node(&self, address: SocketAddr, retry_count: u32, timeout_duration: Duration) -> Result<(), Box<dyn Error>> {
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    // Where peers should connect to reach this node: the listen address unless `advertise` replaced it.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        *self.listen_addr.read().unwrap()
+    }
+
+    // Sets the address announced to peers, e.g. when listening on 0.0.0.0.
+    pub fn advertise(&self, address: SocketAddr) {
+        *self.listen_addr.write().unwrap() = Some(address);
+    }
+
+    // Returns the stream of frames received from peers. Only the latest receiver gets frames.
+    pub fn inbox(&self) -> mpsc::UnboundedReceiver<Frame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.inbox.write().unwrap() = Some(tx);
+        rx
+    }
+
+    // Accepts peer connections on `address` and returns the bound address.
+    pub async fn listen(&self, address: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Listening for node connections on: {}", local_addr);
+        *self.listen_addr.write().unwrap() = Some(local_addr);
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        info!("Accepted connection from node at: {}", peer);
+                        let _ = stream.set_nodelay(true);
+                        let (reader, writer) = stream.into_split();
+                        // Keep the write half so that replies to this peer go back over the same socket
+                        let writer = Arc::new(Mutex::new(writer));
+                        manager.add_peer(peer, writer.clone());
+                        tokio::spawn(manager.clone().read_frames(reader, peer, writer));
+                    }
+                    Err(e) => {
+                        error!("Failed to accept node connection: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    // Opens a persistent connection to `address` unless there already is one.
+    pub async fn connect_to_node(&self, address: SocketAddr, retry_count: u32, timeout_duration: Duration) -> Result<(), TransportError> {
+        let lock = self.connect_locks.write().unwrap().entry(address).or_default().clone();
+        let _connecting = lock.lock().await;
+        if self.peers.read().unwrap().contains_key(&address) {
+            return Ok(());
+        }
         for attempt in 0..retry_count {
             match timeout(timeout_duration, TcpStream::connect(address)).await {
-                Ok(Ok(_stream)) => {
+                Ok(Ok(stream)) => {
                     info!("Successfully connected to node at: {}", address);
-                    self.connected_nodes.write().unwrap().insert(address);
+                    stream.set_nodelay(true)?;
+                    let (reader, writer) = stream.into_split();
+                    let writer = Arc::new(Mutex::new(writer));
+                    let listen_addr = *self.listen_addr.read().unwrap();
+                    if let Some(listen_addr) = listen_addr {
+                        write_frame(&mut *writer.lock().await, &hello(listen_addr), self.max_frame_size).await?;
+                    }
+                    self.add_peer(address, writer.clone());
+                    tokio::spawn(self.clone().read_frames(reader, address, writer));
                     return Ok(());
                 }
                 Ok(Err(e)) => {
@@ -39,7 +143,33 @@ impl NetworkManager {
         Err("Failed to connect after retries".into())
     }
 
+    // Sends a frame over the persistent connection to `address`, connecting first if needed.
+    pub async fn send(&self, address: SocketAddr, frame: &Frame) -> Result<(), TransportError> {
+        let bytes = encode_frame(frame, self.max_frame_size)?;
+        let existing = self.peers.read().unwrap().get(&address).cloned();
+        let writer = match existing {
+            Some(writer) => writer,
+            None => {
+                self.connect_to_node(address, CONNECT_RETRIES, CONNECT_TIMEOUT).await?;
+                self.peers
+                    .read()
+                    .unwrap()
+                    .get(&address)
+                    .cloned()
+                    .ok_or_else(|| format!("Connection to {} closed", address))?
+            }
+        };
+
+        let result = writer.lock().await.write_all(&bytes).await;
+        if let Err(e) = result {
+            self.evict(address, &writer);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
     pub fn disconnect_node(&self, address: &SocketAddr) {
+        self.peers.write().unwrap().remove(address);
         let mut nodes = self.connected_nodes.write().unwrap();
         if nodes.remove(address) {
             info!("Disconnected from node at: {}", address);
@@ -52,6 +182,328 @@ impl NetworkManager {
         let nodes = self.connected_nodes.read().unwrap();
         nodes.iter().cloned().collect()
     }
+
+    fn add_peer(&self, address: SocketAddr, writer: Arc<Mutex<OwnedWriteHalf>>) {
+        self.peers.write().unwrap().insert(address, writer);
+        self.connected_nodes.write().unwrap().insert(address);
+    }
+
+    // Forgets a connection that failed or closed, under every address it is listed under.
+    fn evict(&self, peer: SocketAddr, writer: &Arc<Mutex<OwnedWriteHalf>>) {
+        self.peers.write().unwrap().retain(|_, listed| !Arc::ptr_eq(listed, writer));
+        if self.connected_nodes.write().unwrap().remove(&peer) {
+            info!("Disconnected from node at: {}", peer);
+        }
+    }
+
+    async fn read_frames<R: AsyncRead + Unpin>(self, mut reader: R, peer: SocketAddr, writer: Arc<Mutex<OwnedWriteHalf>>) {
+        loop {
+            match read_frame(&mut reader, self.max_frame_size).await {
+                Ok(Some(frame)) if frame.exchange.is_empty() && frame.routing_key.is_empty() => {
+                    // A peer that connected to us says where it listens; a connection we opened to it wins
+                    match frame.headers.get(LISTEN_ADDR_HEADER).map(|address| address.parse::<SocketAddr>()) {
+                        Some(Ok(address)) => {
+                            self.peers.write().unwrap().entry(address).or_insert_with(|| writer.clone());
+                        }
+                        _ => warn!("Ignoring control frame from {}: {:?}", peer, frame.headers),
+                    }
+                }
+                Ok(Some(frame)) => {
+                    let inbox = self.inbox.read().unwrap().clone();
+                    match inbox {
+                        Some(inbox) if inbox.send(frame).is_ok() => {}
+                        _ => warn!("Dropping frame from {}: nobody is receiving", peer),
+                    }
+                }
+                Ok(None) => {
+                    info!("Connection from {} closed", peer);
+                    break;
+                }
+                Err(e) => {
+                    error!("Dropping connection to {}: {}", peer, e);
+                    break;
+                }
+            }
+        }
+        // The next send to this peer connects afresh instead of writing into a dead socket
+        self.evict(peer, &writer);
+    }
+}
+
+impl Default for NetworkManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The first frame on a connection we open, telling the peer where to reach us.
+fn hello(listen_addr: SocketAddr) -> Frame {
+    let mut headers = Headers::new();
+    headers.insert(LISTEN_ADDR_HEADER.to_string(), listen_addr.to_string());
+    Frame {
+        exchange: String::new(),
+        routing_key: String::new(),
+        headers,
+        payload: Vec::new(),
+    }
+}
+
+// Frame layout, all integers big-endian:
+//   u32 length of everything that follows
+//   u8  frame version
+//   u16 exchange length, exchange
+//   u16 routing key length, routing key
+//   u16 header count, then per header: u16 key length, key, u32 value length, value
+//   payload (the rest of the frame)
+pub fn encode_frame(frame: &Frame, max_frame_size: usize) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(frame.payload.len() + 64);
+    body.push(FRAME_VERSION);
+    put_short_string(&mut body, &frame.exchange)?;
+    put_short_string(&mut body, &frame.routing_key)?;
+    body.extend_from_slice(&u16::try_from(frame.headers.len()).map_err(|_| invalid("too many headers"))?.to_be_bytes());
+    for (key, value) in &frame.headers {
+        put_short_string(&mut body, key)?;
+        body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        body.extend_from_slice(value.as_bytes());
+    }
+    body.extend_from_slice(&frame.payload);
+
+    if body.len() > max_frame_size {
+        return Err(invalid(&format!("frame of {} bytes exceeds the {} byte limit", body.len(), max_frame_size)));
+    }
+    let mut bytes = Vec::with_capacity(body.len() + 4);
+    bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+// Reads one frame. Returns None when the peer closed the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_frame_size: usize) -> io::Result<Option<Frame>> {
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if length > max_frame_size {
+        return Err(invalid(&format!("frame of {} bytes exceeds the {} byte limit", length, max_frame_size)));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    decode_frame_body(body).map(Some)
+}
+
+fn decode_frame_body(body: Vec<u8>) -> io::Result<Frame> {
+    let mut cursor = FrameCursor { body: &body, position: 0 };
+    let version = cursor.take(1)?[0];
+    if version != FRAME_VERSION {
+        return Err(invalid(&format!("unsupported frame version {}", version)));
+    }
+    let exchange = cursor.short_string()?;
+    let routing_key = cursor.short_string()?;
+    let header_count = cursor.u16()?;
+    let mut headers = Headers::new();
+    for _ in 0..header_count {
+        let key = cursor.short_string()?;
+        let value_length = cursor.u32()? as usize;
+        let value = String::from_utf8(cursor.take(value_length)?.to_vec()).map_err(|e| invalid(&e.to_string()))?;
+        headers.insert(key, value);
+    }
+    let payload_start = cursor.position;
+    let mut payload = body;
+    payload.drain(..payload_start);
+    Ok(Frame {
+        exchange,
+        routing_key,
+        headers,
+        payload,
+    })
+}
+
+struct FrameCursor<'a> {
+    body: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FrameCursor<'a> {
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.body.len())
+            .ok_or_else(|| invalid("truncated frame"))?;
+        let bytes = &self.body[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn short_string(&mut self) -> io::Result<String> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|e| invalid(&e.to_string()))
+    }
+}
+
+fn put_short_string(buffer: &mut Vec<u8>, value: &str) -> io::Result<()> {
+    let length = u16::try_from(value.len()).map_err(|_| invalid("string field too long"))?;
+    buffer.extend_from_slice(&length.to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Writes a frame to any stream.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame, max_frame_size: usize) -> io::Result<()> {
+    writer.write_all(&encode_frame(frame, max_frame_size)?).await
+}
+
+// A broker-less transport: queues live in a local in-memory broker, and publishes whose routing key
+// matches a peer route are sent to that peer over a direct TCP connection instead.
+#[derive(Clone)]
+pub struct TcpTransport {
+    local: InMemoryBroker,
+    network: NetworkManager,
+    // Binding-style pattern -> peer that owns matching queues
+    routes: Arc<RwLock<Vec<(String, SocketAddr)>>>,
+}
+
+impl TcpTransport {
+    // Listens on `address` through `network`, which the caller configures, e.g. with another frame size limit.
+    pub async fn bind(address: SocketAddr, network: NetworkManager) -> Result<Self, TransportError> {
+        let mut inbox = network.inbox();
+        network.listen(address).await?;
+        let local = InMemoryBroker::new();
+
+        // Frames from peers are published locally, exactly as if they had been published here
+        let broker = local.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = inbox.recv().await {
+                if let Err(e) = broker
+                    .publish_routed(&frame.exchange, &frame.routing_key, &frame.payload, &frame.headers)
+                    .await
+                {
+                    error!("Failed to deliver frame for {}: {:?}", frame.routing_key, e);
+                }
+            }
+        });
+
+        Ok(TcpTransport {
+            local,
+            network,
+            routes: Arc::new(RwLock::new(Vec::new())),
+        })
+    }
+
+    // Reads TCP_LISTEN_ADDR (default 0.0.0.0:7878), TCP_ADVERTISE_ADDR (the address peers connect to,
+    // default the listen address), TCP_MAX_FRAME_SIZE and TCP_PEERS, a comma-separated list of
+    // `pattern=host:port` routes.
+    pub async fn from_env() -> Result<Self, TransportError> {
+        let listen_addr = std::env::var("TCP_LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_TCP_LISTEN_ADDR.into());
+        let max_frame_size = std::env::var("TCP_MAX_FRAME_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+        let network = NetworkManager::new().with_max_frame_size(max_frame_size);
+        let transport = TcpTransport::bind(resolve(&listen_addr).await?, network).await?;
+        if let Ok(address) = std::env::var("TCP_ADVERTISE_ADDR") {
+            transport.network.advertise(resolve(&address).await?);
+        }
+        let peers = std::env::var("TCP_PEERS").unwrap_or_default();
+        for route in peers.split(',').map(str::trim).filter(|route| !route.is_empty()) {
+            let (pattern, peer) = route
+                .split_once('=')
+                .ok_or_else(|| format!("TCP_PEERS entry {} is not pattern=host:port", route))?;
+            transport.add_peer_route(pattern.trim(), resolve(peer.trim()).await?);
+        }
+        Ok(transport)
+    }
+
+    fn peer_for(&self, routing_key: &str) -> Option<SocketAddr> {
+        self.routes
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(pattern, _)| topic_matches(pattern, routing_key))
+            .map(|(_, peer)| *peer)
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn declare_queue(&self, queue_name: &str) -> Result<(), TransportError> {
+        self.local.declare_queue(queue_name).await
+    }
+
+    async fn declare_exclusive_queue(&self, queue_name: &str) -> Result<(), TransportError> {
+        self.local.declare_exclusive_queue(queue_name).await
+    }
+
+    async fn declare_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError> {
+        self.local.declare_queue_with_dead_letter(queue_name, dead_letter_queue).await
+    }
+
+    async fn declare_exclusive_queue_with_dead_letter(&self, queue_name: &str, dead_letter_queue: &str) -> Result<(), TransportError> {
+        self.local.declare_exclusive_queue_with_dead_letter(queue_name, dead_letter_queue).await
+    }
+
+    async fn declare_topic_exchange(&self, exchange: &str) -> Result<(), TransportError> {
+        self.local.declare_topic_exchange(exchange).await
+    }
+
+    async fn bind_queue(&self, queue_name: &str, exchange: &str, pattern: &str) -> Result<(), TransportError> {
+        self.local.bind_queue(queue_name, exchange, pattern).await
+    }
+
+    async fn publish_routed(&self, exchange: &str, routing_key: &str, payload: &[u8], headers: &Headers) -> Result<(), TransportError> {
+        let Some(peer) = self.peer_for(routing_key) else {
+            return self.local.publish_routed(exchange, routing_key, payload, headers).await;
+        };
+        let frame = Frame {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            headers: headers.clone(),
+            payload: payload.to_vec(),
+        };
+        self.network.send(peer, &frame).await
+    }
+
+    async fn consume_with_prefetch(&self, queue_name: &str, consumer_tag: &str, prefetch: u16) -> Result<Consumer, TransportError> {
+        self.local.consume_with_prefetch(queue_name, consumer_tag, prefetch).await
+    }
+
+    async fn get(&self, queue_name: &str) -> Result<Option<Delivery>, TransportError> {
+        self.local.get(queue_name).await
+    }
+
+    fn peer_address(&self) -> Option<SocketAddr> {
+        self.network.listen_addr()
+    }
+
+    // Matches routing keys, or queue names for direct publishes. A later route for the same pattern
+    // replaces the earlier one, so re-announced peers do not pile up.
+    fn add_peer_route(&self, pattern: &str, peer: SocketAddr) {
+        let mut routes = self.routes.write().unwrap();
+        match routes.iter_mut().find(|(existing, _)| existing == pattern) {
+            Some(route) => route.1 = peer,
+            None => routes.push((pattern.to_string(), peer)),
+        }
+    }
+}
+
+async fn resolve(address: &str) -> Result<SocketAddr, TransportError> {
+    tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to an address", address).into())
 }
 
 #[cfg(test)]
@@ -89,4 +541,164 @@ mod tests {
         assert_eq!(nodes.len(), 1);
         assert_eq!(nodes[0], test_address);
     }
+
+    #[tokio::test]
+    async fn test_frame_round_trip() {
+        let mut headers = Headers::new();
+        headers.insert("content-type".to_string(), "application/json".to_string());
+        let frame = Frame {
+            exchange: "an_ki".to_string(),
+            routing_key: "task.ki.matmul".to_string(),
+            headers,
+            payload: vec![7; 1000],
+        };
+
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &frame, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+        write_frame(&mut bytes, &frame, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+        let mut reader = bytes.as_slice();
+        assert_eq!(read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).await.unwrap(), Some(frame.clone()));
+        assert_eq!(read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).await.unwrap(), Some(frame.clone()));
+        assert_eq!(read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).await.unwrap(), None);
+        assert!(encode_frame(&frame, 100).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_large_frames_over_persistent_connection() {
+        let server = NetworkManager::new();
+        let mut inbox = server.inbox();
+        let address = server.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let client = NetworkManager::new();
+        for i in 0..3u8 {
+            let frame = Frame {
+                exchange: String::new(),
+                routing_key: "tensors".to_string(),
+                headers: Headers::new(),
+                payload: vec![i; 8 * 1024 * 1024],
+            };
+            client.send(address, &frame).await.unwrap();
+            assert_eq!(inbox.recv().await.unwrap(), frame);
+        }
+        assert_eq!(client.list_connected_nodes(), vec![address]);
+    }
+
+    #[tokio::test]
+    async fn test_accepting_side_replies_over_the_same_connection() {
+        let server = NetworkManager::new();
+        let mut server_inbox = server.inbox();
+        let server_address = server.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client = NetworkManager::new();
+        let mut client_inbox = client.inbox();
+        let client_address = client.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let frame = |routing_key: &str| Frame {
+            exchange: String::new(),
+            routing_key: routing_key.to_string(),
+            headers: Headers::new(),
+            payload: vec![1, 2, 3],
+        };
+        client.send(server_address, &frame("request")).await.unwrap();
+        assert_eq!(server_inbox.recv().await.unwrap(), frame("request"));
+        server.send(client_address, &frame("reply")).await.unwrap();
+        assert_eq!(client_inbox.recv().await.unwrap(), frame("reply"));
+
+        // The client never accepted a connection of its own
+        assert_eq!(client.list_connected_nodes(), vec![server_address]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_sends_share_a_connection_and_closed_peers_are_evicted() {
+        let server = NetworkManager::new();
+        let mut inbox = server.inbox();
+        let address = server.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client = NetworkManager::new();
+        let frame = Frame {
+            exchange: String::new(),
+            routing_key: "chunks".to_string(),
+            headers: Headers::new(),
+            payload: vec![0; 1024],
+        };
+        let sends = futures_util::future::join_all((0..8).map(|_| client.send(address, &frame))).await;
+        assert!(sends.into_iter().all(|sent| sent.is_ok()));
+        for _ in 0..8 {
+            inbox.recv().await.unwrap();
+        }
+        assert_eq!(server.list_connected_nodes().len(), 1);
+
+        // A peer that accepts and then hangs up is forgotten, so the next send reconnects
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closing = listener.local_addr().unwrap();
+        tokio::spawn(async move { drop(listener.accept().await) });
+        client.send(closing, &frame).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while client.list_connected_nodes().contains(&closing) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ring_chunks_travel_over_peer_connections() {
+        use crate::allreduce::{self, Mailbox};
+        use crate::messages::RingChunk;
+        use crate::routing;
+        use crate::tensor::Tensor;
+
+        let sender = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), NetworkManager::new()).await.unwrap();
+        let receiver = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), NetworkManager::new()).await.unwrap();
+        let receiver_address = receiver.peer_address().unwrap();
+        let receiver_id = uuid::Uuid::new_v4();
+        let mailbox = Mailbox::new();
+        allreduce::spawn_listener(receiver_id, Arc::new(receiver), mailbox.clone()).await.unwrap();
+
+        sender.add_peer_route(&routing::node_collective_key(receiver_id), receiver_address);
+        let values = Tensor::new(vec![2], vec![1.0f32, 2.0]).unwrap();
+        let chunk = RingChunk {
+            collective_id: "collective-1".to_string(),
+            step: 0,
+            values: values.clone(),
+        };
+        allreduce::send_chunk(&sender, uuid::Uuid::new_v4(), receiver_id, chunk).await.unwrap();
+        assert_eq!(mailbox.take("collective-1", 0, Duration::from_secs(5)).await, Some(values));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_transport_rpc_between_nodes() {
+        use crate::ki_node::{self, KiConfig};
+        use crate::messages::{Envelope, ResultMessage, TaskMessage};
+        use crate::routing;
+        use crate::rpc::RpcClient;
+        use crate::kernels::KernelRegistry;
+        use crate::tensor::Tensor;
+
+        let an_side = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), NetworkManager::new()).await.unwrap();
+        let ki_side = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), NetworkManager::new()).await.unwrap();
+        an_side.add_peer_route("task.ki.#", ki_side.peer_address().unwrap());
+        ki_side.add_peer_route("rpc_reply_queue.*", an_side.peer_address().unwrap());
+
+        let routing_key = routing::ki_task_key("tcp");
+        routing::declare_routed_queue(&ki_side, &routing::capability_queue("tcp"), std::slice::from_ref(&routing_key))
+            .await
+            .unwrap();
+        let config = KiConfig {
+            capabilities: vec!["tcp".to_string()],
+            concurrency: 2,
+            prefetch: 2,
+            dedup_capacity: 100,
+            dedup_path: None,
+            kernels: KernelRegistry::builtin(),
+        };
+        tokio::spawn(ki_node::run_node(uuid::Uuid::new_v4(), config, Arc::new(ki_side)));
+
+        let client = RpcClient::new(uuid::Uuid::new_v4(), Arc::new(an_side)).await.unwrap();
+        let input = Tensor::new(vec![256, 256], vec![0.5f32; 256 * 256]).unwrap();
+        let reply: Envelope<ResultMessage> = client
+            .call(&routing_key, TaskMessage::new("task-1", "relu", vec![input.clone()]), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(reply.payload.outputs, vec![input]);
+    }
 }
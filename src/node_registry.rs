@@ -1,17 +1,20 @@
 // node_registry.rs: Implements a node registry for keeping track of active nodes in the distributed neural network system.
 
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 use chrono::{Utc, DateTime};
 use tracing::{info, error};
-use std::error::Error;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct NodeInfo {
     pub node_id: Uuid,
     pub last_seen: DateTime<Utc>,
     pub role: String,
+    // Where the node takes direct peer connections, if it said
+    pub address: Option<SocketAddr>,
 }
 
 #[derive(Clone)]
@@ -31,11 +34,40 @@ impl NodeRegistry {
             node_id,
             last_seen: Utc::now(),
             role,
+            address: None,
         };
         self.nodes.write().unwrap().insert(node_id, node_info.clone());
         info!("Registered new node: {:?}", node_info);
     }
 
+    // Records a periodic announcement: registers a node the first time it is heard from, and refreshes
+    // its last-seen time afterwards.
+    pub fn heartbeat(&self, node_id: Uuid, role: &str) {
+        let mut nodes = self.nodes.write().unwrap();
+        match nodes.get_mut(&node_id) {
+            Some(node_info) => {
+                node_info.last_seen = Utc::now();
+                node_info.role = role.to_string();
+            }
+            None => {
+                let node_info = NodeInfo {
+                    node_id,
+                    last_seen: Utc::now(),
+                    role: role.to_string(),
+                    address: None,
+                };
+                info!("Registered new node: {:?}", node_info);
+                nodes.insert(node_id, node_info);
+            }
+        }
+    }
+
+    pub fn set_address(&self, node_id: &Uuid, address: SocketAddr) {
+        if let Some(node_info) = self.nodes.write().unwrap().get_mut(node_id) {
+            node_info.address = Some(address);
+        }
+    }
+
     pub fn update_last_seen(&self, node_id: &Uuid) {
         let mut nodes = self.nodes.write().unwrap();
         if let Some(node_info) = nodes.get_mut(node_id) {
@@ -64,6 +96,28 @@ impl NodeRegistry {
         let nodes = self.nodes.read().unwrap();
         nodes.get(node_id).cloned()
     }
+
+    // Nodes with `role` heard from within `max_age`, ordered by id so every caller sees the same order.
+    pub fn active_nodes_with_role(&self, role: &str, max_age: Duration) -> Vec<Uuid> {
+        let cutoff = chrono::Duration::from_std(max_age)
+            .ok()
+            .and_then(|max_age| Utc::now().checked_sub_signed(max_age))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let nodes = self.nodes.read().unwrap();
+        let mut active: Vec<Uuid> = nodes
+            .values()
+            .filter(|node_info| node_info.role == role && node_info.last_seen >= cutoff)
+            .map(|node_info| node_info.node_id)
+            .collect();
+        active.sort();
+        active
+    }
+}
+
+impl Default for NodeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
@@ -109,4 +163,22 @@ mod tests {
         let active_nodes = registry.list_active_nodes();
         assert_eq!(active_nodes.len(), 2);
     }
+
+    #[test]
+    fn test_active_nodes_with_role() {
+        let registry = NodeRegistry::new();
+        let ki_1 = Uuid::new_v4();
+        let ki_2 = Uuid::new_v4();
+        registry.heartbeat(ki_1, "ki");
+        registry.heartbeat(ki_2, "ki");
+        registry.heartbeat(Uuid::new_v4(), "an");
+
+        let mut expected = vec![ki_1, ki_2];
+        expected.sort();
+        assert_eq!(registry.active_nodes_with_role("ki", Duration::from_secs(60)), expected);
+
+        // A node that stops announcing drops out
+        registry.nodes.write().unwrap().get_mut(&ki_1).unwrap().last_seen = Utc::now() - chrono::Duration::seconds(120);
+        assert_eq!(registry.active_nodes_with_role("ki", Duration::from_secs(60)), vec![ki_2]);
+    }
 }
@@ -0,0 +1,342 @@
+// optimizer.rs: Update rules and learning-rate schedules the parameter server applies gradients with, and the per-parameter state they keep.
+
+use crate::kernels::{self, Attrs, KernelError};
+use crate::model::Weights;
+use crate::tensor::{DType, Tensor};
+use std::f64::consts::PI;
+
+// Name of the step counter among the tensors of a saved optimizer state
+const STEP_KEY: &str = "step";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Optimizer {
+    // w -= lr * g
+    Sgd,
+    // v = momentum * v + g; w -= lr * v
+    Momentum { momentum: f64 },
+    // s = rho * s + (1 - rho) * g^2; w -= lr * g / (sqrt(s) + epsilon)
+    RmsProp { rho: f64, epsilon: f64 },
+    // Bias-corrected moving averages of g and g^2, as in Kingma & Ba
+    Adam { beta1: f64, beta2: f64, epsilon: f64 },
+}
+
+impl Optimizer {
+    // From the training job's attributes: `optimizer` names the rule ("sgd" by default, "momentum",
+    // "rmsprop" or "adam"), and `momentum`, `rho`, `beta1`, `beta2` and `epsilon` override its defaults.
+    pub fn from_attrs(attrs: &Attrs) -> Result<Self, KernelError> {
+        let number = |name: &str, default: f64| -> Result<f64, KernelError> {
+            Ok(kernels::float_attr(attrs, name)?.unwrap_or(default))
+        };
+        match kernels::str_attr(attrs, "optimizer")?.unwrap_or("sgd") {
+            "sgd" => Ok(Optimizer::Sgd),
+            "momentum" => Ok(Optimizer::Momentum {
+                momentum: number("momentum", 0.9)?,
+            }),
+            "rmsprop" => Ok(Optimizer::RmsProp {
+                rho: number("rho", 0.9)?,
+                epsilon: number("epsilon", 1e-8)?,
+            }),
+            "adam" => Ok(Optimizer::Adam {
+                beta1: number("beta1", 0.9)?,
+                beta2: number("beta2", 0.999)?,
+                epsilon: number("epsilon", 1e-8)?,
+            }),
+            other => Err(KernelError::InvalidInput(format!("unknown optimizer {:?}", other))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Optimizer::Sgd => "sgd",
+            Optimizer::Momentum { .. } => "momentum",
+            Optimizer::RmsProp { .. } => "rmsprop",
+            Optimizer::Adam { .. } => "adam",
+        }
+    }
+
+    // The state the rule keeps for every parameter
+    fn slots(&self) -> &'static [&'static str] {
+        match self {
+            Optimizer::Sgd => &[],
+            Optimizer::Momentum { .. } => &["velocity"],
+            Optimizer::RmsProp { .. } => &["square"],
+            Optimizer::Adam { .. } => &["m", "v"],
+        }
+    }
+
+    // The parameter `name` after one update with `gradient`. Reads and replaces the parameter's slots in
+    // `state`; the caller advances `state.step` once every parameter has been updated.
+    pub fn update(
+        &self,
+        name: &str,
+        parameter: &Tensor,
+        gradient: &[f32],
+        learning_rate: f64,
+        state: &mut OptimizerState,
+    ) -> Result<Tensor, KernelError> {
+        if gradient.len() != parameter.len() {
+            return Err(KernelError::InvalidInput(format!(
+                "gradient for {} has {} values, expected {}",
+                name,
+                gradient.len(),
+                parameter.len()
+            )));
+        }
+        let mut slots = self
+            .slots()
+            .iter()
+            .map(|slot| state.slot(name, slot, parameter))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut values = parameter.to_vec::<f32>()?;
+        let lr = learning_rate as f32;
+        match *self {
+            Optimizer::Sgd => {
+                for (value, g) in values.iter_mut().zip(gradient) {
+                    *value -= lr * g;
+                }
+            }
+            Optimizer::Momentum { momentum } => {
+                for ((value, g), velocity) in values.iter_mut().zip(gradient).zip(slots[0].iter_mut()) {
+                    *velocity = momentum as f32 * *velocity + g;
+                    *value -= lr * *velocity;
+                }
+            }
+            Optimizer::RmsProp { rho, epsilon } => {
+                for ((value, g), square) in values.iter_mut().zip(gradient).zip(slots[0].iter_mut()) {
+                    *square = rho as f32 * *square + (1.0 - rho as f32) * g * g;
+                    *value -= lr * g / (square.sqrt() + epsilon as f32);
+                }
+            }
+            Optimizer::Adam { beta1, beta2, epsilon } => {
+                let t = (state.step + 1) as i32;
+                let correction1 = 1.0 - beta1.powi(t) as f32;
+                let correction2 = 1.0 - beta2.powi(t) as f32;
+                let (m, v) = slots.split_at_mut(1);
+                for (((value, g), m), v) in values.iter_mut().zip(gradient).zip(m[0].iter_mut()).zip(v[0].iter_mut()) {
+                    *m = beta1 as f32 * *m + (1.0 - beta1 as f32) * g;
+                    *v = beta2 as f32 * *v + (1.0 - beta2 as f32) * g * g;
+                    *value -= lr * (*m / correction1) / ((*v / correction2).sqrt() + epsilon as f32);
+                }
+            }
+        }
+        for (slot, values) in self.slots().iter().zip(slots) {
+            state
+                .slots
+                .insert(slot_key(name, slot), Tensor::new(parameter.shape().to_vec(), values)?);
+        }
+        Ok(Tensor::new(parameter.shape().to_vec(), values)?)
+    }
+}
+
+fn slot_key(parameter: &str, slot: &str) -> String {
+    format!("{}:{}", parameter, slot)
+}
+
+// Everything an optimizer carries from one step to the next.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OptimizerState {
+    // Updates applied so far
+    pub step: u64,
+    // Keyed "<parameter>:<slot>"
+    pub slots: Weights,
+}
+
+impl OptimizerState {
+    // The values of one slot, zero before the first update.
+    fn slot(&self, parameter: &str, slot: &str, like: &Tensor) -> Result<Vec<f32>, KernelError> {
+        match self.slots.get(&slot_key(parameter, slot)) {
+            Some(values) => {
+                values.expect_shape(like.shape())?;
+                Ok(values.to_vec::<f32>()?)
+            }
+            None => Ok(vec![0.0; like.len()]),
+        }
+    }
+
+    // The state as named tensors, for saving alongside the weights.
+    pub fn to_weights(&self) -> Weights {
+        let mut weights = self.slots.clone();
+        weights.insert(STEP_KEY.to_string(), Tensor::scalar(self.step as f64));
+        weights
+    }
+
+    pub fn from_weights(mut weights: Weights) -> Result<Self, KernelError> {
+        let step = match weights.remove(STEP_KEY) {
+            Some(step) => {
+                step.expect_dtype(DType::F64)?;
+                step.to_vec::<f64>()?.first().copied().unwrap_or(0.0) as u64
+            }
+            None => 0,
+        };
+        Ok(OptimizerState { step, slots: weights })
+    }
+}
+
+// How the learning rate falls after warm-up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decay {
+    Constant,
+    // Multiplied by `gamma` every `every` steps
+    Step { every: usize, gamma: f64 },
+    // Half a cosine from the base rate down to `min_learning_rate` over the remaining steps
+    Cosine { min_learning_rate: f64 },
+}
+
+// The learning rate at each step: a linear ramp over the first `warmup_steps`, then `decay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    pub learning_rate: f64,
+    pub warmup_steps: usize,
+    pub decay: Decay,
+    // Length of the run, which the cosine decay spans
+    pub steps: usize,
+}
+
+impl Schedule {
+    // From the training job's attributes: `schedule` is "constant" (the default), "step", "cosine" or
+    // "warmup" (a constant rate after warm-up). `warmup_steps` adds a warm-up to any of them, and
+    // `step_size`, `gamma` and `min_learning_rate` tune the decay.
+    pub fn from_attrs(attrs: &Attrs, learning_rate: f64, steps: usize) -> Result<Self, KernelError> {
+        let count = |name: &str, default: usize| -> Result<usize, KernelError> {
+            match kernels::int_attr(attrs, name)? {
+                None => Ok(default),
+                Some(value) if value >= 0 => Ok(value as usize),
+                Some(value) => Err(KernelError::InvalidInput(format!("{} must not be negative, got {}", name, value))),
+            }
+        };
+        let name = kernels::str_attr(attrs, "schedule")?.unwrap_or("constant");
+        let default_warmup = if name == "warmup" { (steps / 10).max(1) } else { 0 };
+        let warmup_steps = count("warmup_steps", default_warmup)?;
+        let decay = match name {
+            "constant" | "warmup" => Decay::Constant,
+            "step" => Decay::Step {
+                every: count("step_size", (steps.saturating_sub(warmup_steps) / 3).max(1))?.max(1),
+                gamma: kernels::float_attr(attrs, "gamma")?.unwrap_or(0.1),
+            },
+            "cosine" => Decay::Cosine {
+                min_learning_rate: kernels::float_attr(attrs, "min_learning_rate")?.unwrap_or(0.0),
+            },
+            other => return Err(KernelError::InvalidInput(format!("unknown schedule {:?}", other))),
+        };
+        Ok(Schedule {
+            learning_rate,
+            warmup_steps,
+            decay,
+            steps,
+        })
+    }
+
+    // The rate for the update after `step` updates.
+    pub fn rate(&self, step: u64) -> f64 {
+        let step = step as usize;
+        if step < self.warmup_steps {
+            return self.learning_rate * (step + 1) as f64 / self.warmup_steps as f64;
+        }
+        let step = step - self.warmup_steps;
+        match self.decay {
+            Decay::Constant => self.learning_rate,
+            Decay::Step { every, gamma } => self.learning_rate * gamma.powi((step / every.max(1)) as i32),
+            Decay::Cosine { min_learning_rate } => {
+                let span = self.steps.saturating_sub(self.warmup_steps).max(1);
+                let progress = (step as f64 / span as f64).min(1.0);
+                min_learning_rate + (self.learning_rate - min_learning_rate) * (1.0 + (PI * progress).cos()) / 2.0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optimizers_minimise_a_quadratic() {
+        let optimizers = [
+            (Optimizer::Sgd, 0.1),
+            (Optimizer::Momentum { momentum: 0.9 }, 0.02),
+            (Optimizer::RmsProp { rho: 0.9, epsilon: 1e-8 }, 0.05),
+            (
+                Optimizer::Adam {
+                    beta1: 0.9,
+                    beta2: 0.999,
+                    epsilon: 1e-8,
+                },
+                0.1,
+            ),
+        ];
+        for (optimizer, learning_rate) in optimizers {
+            // f(w) = sum((w - target)^2), with gradient 2 (w - target)
+            let target = [3.0f32, -2.0];
+            let mut w = Tensor::new(vec![2], vec![0.0f32, 0.0]).unwrap();
+            let mut state = OptimizerState::default();
+            for _ in 0..300 {
+                let gradient: Vec<f32> = w
+                    .to_vec::<f32>()
+                    .unwrap()
+                    .iter()
+                    .zip(target)
+                    .map(|(w, target)| 2.0 * (w - target))
+                    .collect();
+                w = optimizer.update("w", &w, &gradient, learning_rate, &mut state).unwrap();
+                state.step += 1;
+            }
+            for (w, target) in w.to_vec::<f32>().unwrap().iter().zip(target) {
+                assert!((w - target).abs() < 0.05, "{} ended at {}", optimizer.name(), w);
+            }
+            assert_eq!(state.slots.len(), optimizer.slots().len());
+        }
+    }
+
+    #[test]
+    fn test_adam_state_round_trips() {
+        let adam = Optimizer::Adam {
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+        };
+        let w = Tensor::new(vec![2], vec![1.0f32, 1.0]).unwrap();
+        let mut state = OptimizerState::default();
+        // Bias correction makes Adam's first step the learning rate in size, whatever the gradient's scale
+        let first = adam.update("w", &w, &[100.0, -0.001], 0.1, &mut state).unwrap();
+        let first = first.to_vec::<f32>().unwrap();
+        assert!((first[0] - 0.9).abs() < 1e-4 && (first[1] - 1.1).abs() < 1e-4, "{:?}", first);
+        state.step += 1;
+
+        let restored = OptimizerState::from_weights(state.to_weights()).unwrap();
+        assert_eq!(restored, state);
+        let mut resumed = restored.clone();
+        let a = adam.update("w", &w, &[1.0, 1.0], 0.1, &mut state).unwrap();
+        let b = adam.update("w", &w, &[1.0, 1.0], 0.1, &mut resumed).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_schedules() {
+        let mut attrs = Attrs::new();
+        attrs.insert("schedule".to_string(), "step".into());
+        attrs.insert("step_size".to_string(), 10i64.into());
+        let step = Schedule::from_attrs(&attrs, 1.0, 100).unwrap();
+        assert_eq!(step.rate(9), 1.0);
+        assert!((step.rate(10) - 0.1).abs() < 1e-12);
+        assert!((step.rate(25) - 0.01).abs() < 1e-12);
+
+        attrs.insert("schedule".to_string(), "cosine".into());
+        attrs.insert("warmup_steps".to_string(), 10i64.into());
+        let cosine = Schedule::from_attrs(&attrs, 1.0, 110).unwrap();
+        assert!((cosine.rate(0) - 0.1).abs() < 1e-12);
+        assert_eq!(cosine.rate(9), 1.0);
+        assert_eq!(cosine.rate(10), 1.0);
+        assert!((cosine.rate(60) - 0.5).abs() < 1e-12);
+        assert!(cosine.rate(110).abs() < 1e-12);
+
+        attrs.clear();
+        attrs.insert("schedule".to_string(), "warmup".into());
+        let warmup = Schedule::from_attrs(&attrs, 0.5, 40).unwrap();
+        assert_eq!(warmup.warmup_steps, 4);
+        assert_eq!(warmup.rate(1), 0.25);
+        assert_eq!(warmup.rate(30), 0.5);
+
+        attrs.insert("schedule".to_string(), "exponential".into());
+        assert!(Schedule::from_attrs(&attrs, 0.5, 40).is_err());
+    }
+}
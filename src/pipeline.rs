@@ -0,0 +1,248 @@
+// pipeline.rs: Partitions a model into contiguous layer ranges across Ki nodes and runs micro-batches through the stages.
+
+use crate::mlp::Mlp;
+use crate::tensor::Tensor;
+use futures_util::future::join_all;
+use std::future::Future;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::info;
+use uuid::Uuid;
+
+pub const DEFAULT_MICRO_BATCHES: usize = 4;
+
+// A contiguous range of layers run by one Ki node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub node_id: Uuid,
+    pub layers: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StageReport {
+    pub node_id: Uuid,
+    pub layers: Range<usize>,
+    pub micro_batches: usize,
+    // Time spent waiting for the stage's results
+    pub busy: Duration,
+    // Busy time as a fraction of the whole run
+    pub utilisation: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineReport {
+    pub elapsed: Duration,
+    pub stages: Vec<StageReport>,
+}
+
+impl PipelineReport {
+    pub fn log(&self, task_id: &str) {
+        info!("Pipeline for task {} took {:?}", task_id, self.elapsed);
+        for (index, stage) in self.stages.iter().enumerate() {
+            info!(
+                "Stage {} on node {} (layers {:?}): {} micro-batches, busy {:?}, utilisation {:.0}%",
+                index,
+                stage.node_id,
+                stage.layers,
+                stage.micro_batches,
+                stage.busy,
+                stage.utilisation * 100.0
+            );
+        }
+    }
+}
+
+// Relative cost of each layer of a dense network, in multiply-adds per input row.
+pub fn layer_costs(mlp: &Mlp) -> Vec<u64> {
+    mlp.layers
+        .iter()
+        .map(|layer| (layer.in_features() * layer.out_features()) as u64)
+        .collect()
+}
+
+// Splits the layers into contiguous stages, one per node, so that each node's share of the total cost
+// follows its share of the total capacity. Larger nodes take the earlier stages. Each stage gets at
+// least one layer, so with more nodes than layers the smallest nodes are left out.
+pub fn partition(costs: &[u64], nodes: &[(Uuid, usize)]) -> Vec<Stage> {
+    let mut nodes = nodes.to_vec();
+    nodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    nodes.truncate(costs.len());
+    if nodes.is_empty() {
+        return Vec::new();
+    }
+
+    let total_cost: u64 = costs.iter().sum();
+    let total_capacity: usize = nodes.iter().map(|(_, capacity)| (*capacity).max(1)).sum();
+    let mut stages = Vec::with_capacity(nodes.len());
+    let (mut start, mut cost, mut capacity) = (0, 0, 0);
+    for (index, &(node_id, node_capacity)) in nodes.iter().enumerate() {
+        let later_stages = nodes.len() - index - 1;
+        capacity += node_capacity.max(1);
+        let target = total_cost as f64 * capacity as f64 / total_capacity as f64;
+
+        let mut end = start + 1;
+        cost += costs[start];
+        if later_stages == 0 {
+            end = costs.len();
+        } else {
+            // Take further layers while that brings the running cost closer to the target, leaving one
+            // layer for each later stage
+            while end < costs.len() - later_stages {
+                let extended = cost + costs[end];
+                if (extended as f64 - target).abs() > (cost as f64 - target).abs() {
+                    break;
+                }
+                cost = extended;
+                end += 1;
+            }
+        }
+        stages.push(Stage {
+            node_id,
+            layers: start..end,
+        });
+        start = end;
+    }
+    stages
+}
+
+// The layers of one stage as a network of their own.
+pub fn stage_network(mlp: &Mlp, stage: &Stage) -> Mlp {
+    Mlp {
+        layers: mlp.layers[stage.layers.clone()].to_vec(),
+    }
+}
+
+// Streams micro-batches through the stages GPipe-style: every stage runs concurrently, taking the next
+// micro-batch as soon as the previous stage hands it over, so stage `s` works on micro-batch `m` while
+// stage `s + 1` works on `m - 1`. `run_stage(stage, micro_batch, activations)` computes one stage.
+// Returns the last stage's outputs in micro-batch order.
+pub async fn run<F, Fut, E>(
+    stages: &[Stage],
+    micro_batches: Vec<Tensor>,
+    run_stage: F,
+) -> Result<(Vec<Tensor>, PipelineReport), E>
+where
+    F: Fn(usize, usize, Tensor) -> Fut,
+    Fut: Future<Output = Result<Tensor, E>>,
+{
+    let started = Instant::now();
+    let count = micro_batches.len();
+    let (first_sender, mut receiver) = mpsc::unbounded_channel();
+    for (index, micro_batch) in micro_batches.into_iter().enumerate() {
+        let _ = first_sender.send((index, micro_batch));
+    }
+    drop(first_sender);
+
+    let mut workers = Vec::with_capacity(stages.len());
+    for stage in 0..stages.len() {
+        let (sender, next_receiver) = mpsc::unbounded_channel();
+        let mut inbound = std::mem::replace(&mut receiver, next_receiver);
+        let run_stage = &run_stage;
+        workers.push(async move {
+            let mut busy = Duration::ZERO;
+            while let Some((index, activations)) = inbound.recv().await {
+                let computing = Instant::now();
+                let outputs = run_stage(stage, index, activations).await;
+                busy += computing.elapsed();
+                // Dropping the sender on failure ends the later stages
+                let _ = sender.send((index, outputs?));
+            }
+            Ok::<Duration, E>(busy)
+        });
+    }
+
+    let (busy, outputs) = tokio::join!(join_all(workers), async move {
+        let mut outputs = Vec::with_capacity(count);
+        while let Some((_, activations)) = receiver.recv().await {
+            outputs.push(activations);
+        }
+        outputs
+    });
+    let elapsed = started.elapsed();
+
+    let mut reports = Vec::with_capacity(stages.len());
+    for (stage, busy) in stages.iter().zip(busy) {
+        let busy = busy?;
+        reports.push(StageReport {
+            node_id: stage.node_id,
+            layers: stage.layers.clone(),
+            micro_batches: count,
+            busy,
+            utilisation: busy.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON),
+        });
+    }
+    Ok((outputs, PipelineReport { elapsed, stages: reports }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(capacities: &[usize]) -> Vec<(Uuid, usize)> {
+        capacities.iter().map(|&capacity| (Uuid::new_v4(), capacity)).collect()
+    }
+
+    #[test]
+    fn test_partition_follows_capacity() {
+        let layers = |stages: &[Stage]| stages.iter().map(|stage| stage.layers.clone()).collect::<Vec<_>>();
+
+        assert_eq!(layers(&partition(&[1; 6], &nodes(&[1, 1, 1]))), vec![0..2, 2..4, 4..6]);
+        assert_eq!(layers(&partition(&[1; 6], &nodes(&[1, 2]))), vec![0..4, 4..6]);
+        // One expensive layer gets a stage to itself
+        assert_eq!(layers(&partition(&[1, 1, 8, 1, 1], &nodes(&[1, 1, 1]))), vec![0..2, 2..3, 3..5]);
+        // More nodes than layers: every layer is a stage
+        assert_eq!(partition(&[5, 5], &nodes(&[4, 2, 1])).len(), 2);
+        assert!(partition(&[1, 2], &[]).is_empty());
+
+        let large = nodes(&[1, 4]);
+        assert_eq!(partition(&[1; 3], &large)[0].node_id, large[1].0);
+    }
+
+    #[tokio::test]
+    async fn test_micro_batches_overlap_across_stages() {
+        let stages: Vec<Stage> = (0..3)
+            .map(|index| Stage {
+                node_id: Uuid::new_v4(),
+                layers: index..index + 1,
+            })
+            .collect();
+        let batch = Tensor::new(vec![8, 1], (0..8).map(|i| i as f32).collect()).unwrap();
+        let micro_batches = batch.split_rows(4).unwrap();
+
+        let (outputs, report) = run(&stages, micro_batches, |stage, _, x: Tensor| async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let values = x.values::<f32>()?.iter().map(|value| value * 10.0 + stage as f32).collect();
+            Tensor::new(x.shape().to_vec(), values)
+        })
+        .await
+        .unwrap();
+
+        let expected: Vec<f32> = (0..8).map(|i| i as f32 * 1000.0 + 12.0).collect();
+        assert_eq!(Tensor::concat_rows(&outputs).unwrap().to_vec::<f32>().unwrap(), expected);
+        // 3 stages x 4 micro-batches take 6 steps when overlapped, and 12 when run one after another
+        assert!(report.elapsed < Duration::from_millis(12 * 20), "{:?}", report.elapsed);
+        for stage in &report.stages {
+            assert_eq!(stage.micro_batches, 4);
+            assert!(stage.utilisation > 0.3 && stage.utilisation <= 1.0, "{:?}", stage);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stage_failure_stops_the_pipeline() {
+        let stages = vec![
+            Stage { node_id: Uuid::new_v4(), layers: 0..1 },
+            Stage { node_id: Uuid::new_v4(), layers: 1..2 },
+        ];
+        let micro_batches = Tensor::zeros(crate::tensor::DType::F32, vec![4, 2]).split_rows(4).unwrap();
+        let result = run(&stages, micro_batches, |stage, index, x| async move {
+            if stage == 1 && index == 2 {
+                Err("stage 1 failed")
+            } else {
+                Ok(x)
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap_err(), "stage 1 failed");
+    }
+}
@@ -1,68 +1,76 @@
 // principal.rs: Implements the specific responsibilities of the Principal, including role management and global coordination.
-use lapin::{options::*, types::FieldTable, BasicProperties, Connection, ConnectionProperties};
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use crate::messages::{CheckpointAck, CheckpointRequest, Envelope, ModelAck, ModelUpdate, NodeRegistration, UpdateRequest};
+use crate::messaging::{Transport, TransportError};
+use crate::model::Model;
+use crate::routing;
+use crate::rpc::{RpcClient, RpcError, DEFAULT_RPC_TIMEOUT};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
 use tracing::{error, info};
-
-#[derive(Serialize, Deserialize, Debug)]
-struct RoleAssignment {
-    node_id: String,
-    role: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct UpdateRequest {
-    update_id: String,
-    content: String,
-}
-
-pub async fn run() -> Result<(), Box<dyn Error>> {
-    // Establish connection to RabbitMQ
-    let amqp_addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-    let connection = Connection::connect(&amqp_addr, ConnectionProperties::default()).await?;
-    let channel = connection.create_channel().await?;
+use uuid::Uuid;
+
+pub async fn run(transport: Arc<dyn Transport>) -> Result<(), TransportError> {
+    let node_id = Uuid::new_v4();
+
+    // The canonical model, if configured, is broadcast to the An nodes that are already running.
+    // Nodes that start later receive it through `ship_model` when they announce themselves.
+    if let Some(spec_path) = std::env::var_os("PRINCIPAL_MODEL_SPEC").map(PathBuf::from) {
+        let weights_path = std::env::var_os("PRINCIPAL_MODEL_WEIGHTS").map(PathBuf::from);
+        let model = Model::from_files(&spec_path, weights_path.as_deref())?;
+        info!("Loaded model {} v{} from {}", model.spec.name, model.spec.version, spec_path.display());
+        publish_model(transport.as_ref(), node_id, &model).await?;
+        let rpc_client = RpcClient::new(node_id, transport.clone()).await?;
+        spawn_model_shipper(transport.clone(), rpc_client, Arc::new(model), node_id).await?;
+    }
 
     // Declare the queue for receiving update requests from An nodes
-    let queue_name = "principal_update_queue";
-    channel
-        .queue_declare(
-            queue_name,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let queue_name = routing::principal_update_queue();
+    let dead_letters =
+        routing::declare_routed_queue(transport.as_ref(), &queue_name, &[routing::principal_update_key()]).await?;
 
     // Start consuming update requests from the queue
-    let mut consumer = channel
-        .basic_consume(
-            queue_name,
-            "principal_consumer",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let mut consumer = transport.consume(&queue_name, "principal_consumer").await?;
 
-    info!("Principal node is running and waiting for update requests...");
+    info!("Principal node {} is running and waiting for update requests...", node_id);
 
     while let Some(delivery) = consumer.next().await {
-        let (_, delivery) = delivery.expect("error in consumer");
-        let update_request: UpdateRequest = serde_json::from_slice(&delivery.data)?;
-
-        info!("Received update request: {:?}", update_request);
+        let envelope = match Envelope::<UpdateRequest>::from_delivery(&delivery) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!("Rejecting update request: {}", e);
+                if let Err(e) = dead_letters.dead_letter(transport.as_ref(), &delivery, &e.to_string()).await {
+                    error!("Failed to dead-letter update request: {:?}", e);
+                }
+                continue;
+            }
+        };
+
+        info!("Received update request from {}: {:?}", envelope.sender, envelope.payload);
 
         // Approve or reject the update request
-        if let Err(e) = process_update_request(update_request).await {
+        if let Err(e) = process_update_request(envelope.payload).await {
             error!("Failed to process update request: {:?}", e);
+            if let Err(e) = dead_letters
+                .retry_or_dead_letter(transport.as_ref(), &delivery, &e.to_string())
+                .await
+            {
+                error!("Failed to requeue update request: {:?}", e);
+            }
+            continue;
         }
 
         // Acknowledge the message
-        delivery.ack(BasicAckOptions::default()).await?;
+        if let Err(e) = delivery.ack().await {
+            error!("Failed to acknowledge message: {:?}", e);
+        }
     }
 
     Ok(())
 }
 
-async fn process_update_request(update: UpdateRequest) -> Result<(), Box<dyn Error>> {
+async fn process_update_request(update: UpdateRequest) -> Result<(), TransportError> {
     // Placeholder for update request approval logic
     // Validate the update and apply it to the master database if approved
     info!("Processing update request with ID: {}", update.update_id);
@@ -71,27 +79,96 @@ async fn process_update_request(update: UpdateRequest) -> Result<(), Box<dyn Err
     Ok(())
 }
 
-pub async fn assign_role(node_id: &str, role: &str, channel: &lapin::Channel) -> Result<(), Box<dyn Error>> {
-    let role_assignment = RoleAssignment {
-        node_id: node_id.to_string(),
-        role: role.to_string(),
-    };
-
-    // Serialize the role assignment
-    let payload = serde_json::to_vec(&role_assignment)?;
-
-    // Publish the role assignment to the role management queue
-    let role_queue = "role_assignment_queue";
-    channel
-        .basic_publish(
-            "",
-            role_queue,
-            BasicPublishOptions::default(),
-            &payload,
-            BasicProperties::default(),
-        )
+// Broadcasts a model to every running An node without waiting for acknowledgements.
+pub async fn publish_model(transport: &dyn Transport, sender: Uuid, model: &Model) -> Result<(), TransportError> {
+    Envelope::new(sender, model.to_update())
+        .route(transport, &routing::model_update_key())
         .await?;
+    info!("Published model {} v{}", model.spec.name, model.spec.version);
+    Ok(())
+}
 
-    info!("Assigned role '{}' to node '{}'", role, node_id);
+// Asks one An node to checkpoint a model: one it is training at the end of the current step, any other
+// it has installed right away.
+pub async fn request_checkpoint(
+    rpc_client: &RpcClient,
+    node_id: Uuid,
+    model: &str,
+    call_timeout: Duration,
+) -> Result<CheckpointAck, RpcError> {
+    let request = CheckpointRequest { model: model.to_string() };
+    let ack: Envelope<CheckpointAck> = rpc_client
+        .call(&routing::node_control_key(node_id), request, call_timeout)
+        .await?;
+
+    match &ack.payload.error {
+        None => info!(
+            "Node '{}' saved checkpoint {} of model {} at step {}",
+            node_id, ack.payload.version, ack.payload.model, ack.payload.step
+        ),
+        Some(e) => error!("Node '{}' could not checkpoint model {}: {}", node_id, ack.payload.model, e),
+    }
+    Ok(ack.payload)
+}
+
+// Ships `model` to each An node the first time it announces itself. A node that does not answer gets it
+// again on its next announcement; one that refuses it, e.g. because it already has that version, does not.
+pub async fn spawn_model_shipper(
+    transport: Arc<dyn Transport>,
+    rpc_client: RpcClient,
+    model: Arc<Model>,
+    listener_id: Uuid,
+) -> Result<(), TransportError> {
+    let queue_name = routing::registration_queue(listener_id);
+    let dead_letters =
+        routing::declare_node_queue(transport.as_ref(), &queue_name, &[routing::node_registration_key("an")]).await?;
+    let mut consumer = transport
+        .consume(&queue_name, &format!("model_shipper_{}", listener_id))
+        .await?;
+
+    let shipped = Arc::new(Mutex::new(HashSet::new()));
+    tokio::spawn(async move {
+        while let Some(delivery) = consumer.next().await {
+            let an_node = Envelope::<NodeRegistration>::from_delivery(&delivery)
+                .map_err(|e| e.to_string())
+                .and_then(|envelope| Uuid::parse_str(&envelope.payload.node_id).map_err(|e| e.to_string()));
+            match an_node {
+                Ok(an_node) => {
+                    if shipped.lock().unwrap().insert(an_node) {
+                        let (rpc_client, model, shipped) = (rpc_client.clone(), model.clone(), shipped.clone());
+                        tokio::spawn(async move {
+                            if let Err(e) = ship_model(&rpc_client, an_node, &model, DEFAULT_RPC_TIMEOUT).await {
+                                error!("Failed to ship model {} to node '{}': {:?}", model.spec.name, an_node, e);
+                                shipped.lock().unwrap().remove(&an_node);
+                            }
+                        });
+                    }
+                    if let Err(e) = delivery.ack().await {
+                        error!("Failed to acknowledge message: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("Rejecting node registration: {}", e);
+                    if let Err(e) = dead_letters.dead_letter(transport.as_ref(), &delivery, &e).await {
+                        error!("Failed to dead-letter node registration: {:?}", e);
+                    }
+                }
+            }
+        }
+    });
     Ok(())
 }
+
+// Sends a model to one An node and waits for it to confirm the model validated and was installed.
+pub async fn ship_model(rpc_client: &RpcClient, node_id: Uuid, model: &Model, call_timeout: Duration) -> Result<ModelAck, RpcError> {
+    let update: ModelUpdate = model.to_update();
+    let ack: Envelope<ModelAck> = rpc_client
+        .call(&routing::node_control_key(node_id), update, call_timeout)
+        .await?;
+
+    match &ack.payload.error {
+        None => info!("Node '{}' installed model {} v{}", node_id, ack.payload.name, ack.payload.version),
+        Some(e) => error!("Node '{}' rejected model {} v{}: {}", node_id, ack.payload.name, ack.payload.version, e),
+    }
+    Ok(ack.payload)
+}
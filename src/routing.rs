@@ -0,0 +1,177 @@
+// routing.rs: Defines the topic exchange, routing keys and queue names used to address roles, capabilities and individual nodes.
+
+use crate::dead_letter::{DeadLetterPolicy, DEFAULT_MAX_ATTEMPTS};
+use crate::messaging::{Transport, TransportError};
+use tracing::info;
+use uuid::Uuid;
+
+pub const EXCHANGE: &str = "an_ki";
+
+// Capability every Ki node serves unless configured otherwise.
+pub const DEFAULT_CAPABILITY: &str = "compute";
+
+// Tasks sent by the principal to any An node
+pub fn an_task_key() -> String {
+    "task.an".to_string()
+}
+
+// Tasks for any Ki node that offers a capability
+pub fn ki_task_key(capability: &str) -> String {
+    format!("task.ki.{}", capability)
+}
+
+// Tasks addressed to one specific node, e.g. after the scheduler picked it
+pub fn node_task_key(node_id: Uuid) -> String {
+    format!("node.{}.task", node_id)
+}
+
+// Control traffic (role assignments, shutdown, ...) addressed to one node
+pub fn node_control_key(node_id: Uuid) -> String {
+    format!("node.{}.control", node_id)
+}
+
+pub fn principal_update_key() -> String {
+    "principal.update".to_string()
+}
+
+// Results that were not requested through RPC
+pub fn an_result_key() -> String {
+    "result.an".to_string()
+}
+
+// Node announcements, e.g. a Ki node advertising its capacity
+pub fn node_registration_key(role: &str) -> String {
+    format!("registry.{}", role)
+}
+
+// Collective traffic, such as all-reduce chunks, addressed to one node
+pub fn node_collective_key(node_id: Uuid) -> String {
+    format!("node.{}.collective", node_id)
+}
+
+// Models the principal broadcasts to every An node
+pub fn model_update_key() -> String {
+    "model.update".to_string()
+}
+
+pub fn an_task_queue() -> String {
+    "an.tasks".to_string()
+}
+
+// Shared by every Ki node with the capability, so they compete for its tasks
+pub fn capability_queue(capability: &str) -> String {
+    format!("ki.capability.{}", capability)
+}
+
+pub fn node_task_queue(node_id: Uuid) -> String {
+    format!("node.{}.tasks", node_id)
+}
+
+pub fn node_control_queue(node_id: Uuid) -> String {
+    format!("node.{}.control", node_id)
+}
+
+pub fn node_collective_queue(node_id: Uuid) -> String {
+    format!("node.{}.collective", node_id)
+}
+
+pub fn principal_update_queue() -> String {
+    "principal.updates".to_string()
+}
+
+// Each listener gets its own queue so that every listener sees every announcement
+pub fn registration_queue(listener_id: Uuid) -> String {
+    format!("registry.listener.{}", listener_id)
+}
+
+pub fn an_result_queue() -> String {
+    "an.results".to_string()
+}
+
+// Reads the capabilities a Ki node serves from KI_CAPABILITIES (comma separated).
+pub fn capabilities_from_env() -> Vec<String> {
+    let capabilities: Vec<String> = std::env::var("KI_CAPABILITIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|capability| !capability.is_empty())
+        .map(str::to_string)
+        .collect();
+    if capabilities.is_empty() {
+        vec![DEFAULT_CAPABILITY.to_string()]
+    } else {
+        capabilities
+    }
+}
+
+// Declares a queue with its dead-letter queue and binds it to the exchange under each routing key.
+pub async fn declare_routed_queue(
+    transport: &dyn Transport,
+    queue_name: &str,
+    routing_keys: &[String],
+) -> Result<DeadLetterPolicy, TransportError> {
+    transport.declare_topic_exchange(EXCHANGE).await?;
+    let dead_letters = DeadLetterPolicy::new(queue_name, DEFAULT_MAX_ATTEMPTS);
+    dead_letters.declare(transport).await?;
+    bind(transport, queue_name, routing_keys).await?;
+    Ok(dead_letters)
+}
+
+// Like `declare_routed_queue`, for a queue that belongs to one process, such as a node's own task, control
+// or collective queue. The broker deletes it when the process goes away, so restarts leave nothing behind.
+pub async fn declare_node_queue(
+    transport: &dyn Transport,
+    queue_name: &str,
+    routing_keys: &[String],
+) -> Result<DeadLetterPolicy, TransportError> {
+    transport.declare_topic_exchange(EXCHANGE).await?;
+    let dead_letters = DeadLetterPolicy::new(queue_name, DEFAULT_MAX_ATTEMPTS);
+    dead_letters.declare_exclusive(transport).await?;
+    bind(transport, queue_name, routing_keys).await?;
+    Ok(dead_letters)
+}
+
+async fn bind(transport: &dyn Transport, queue_name: &str, routing_keys: &[String]) -> Result<(), TransportError> {
+    for routing_key in routing_keys {
+        transport.bind_queue(queue_name, EXCHANGE, routing_key).await?;
+    }
+    info!("Queue {} receives {:?}", queue_name, routing_keys);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::{Headers, InMemoryBroker};
+
+    #[tokio::test]
+    async fn test_node_and_capability_routing() {
+        let broker = InMemoryBroker::new();
+        let node_a = Uuid::new_v4();
+        let node_b = Uuid::new_v4();
+        for node_id in [node_a, node_b] {
+            declare_routed_queue(&broker, &node_task_queue(node_id), &[node_task_key(node_id)])
+                .await
+                .unwrap();
+        }
+        declare_routed_queue(&broker, &capability_queue("matmul"), &[ki_task_key("matmul")])
+            .await
+            .unwrap();
+
+        broker
+            .publish_routed(EXCHANGE, &node_task_key(node_b), b"for b", &Headers::new())
+            .await
+            .unwrap();
+        broker
+            .publish_routed(EXCHANGE, &ki_task_key("matmul"), b"any matmul", &Headers::new())
+            .await
+            .unwrap();
+
+        assert!(broker.get(&node_task_queue(node_a)).await.unwrap().is_none());
+        assert_eq!(broker.get(&node_task_queue(node_b)).await.unwrap().unwrap().data, b"for b");
+        assert_eq!(
+            broker.get(&capability_queue("matmul")).await.unwrap().unwrap().data,
+            b"any matmul"
+        );
+    }
+}
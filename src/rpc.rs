@@ -0,0 +1,210 @@
+// rpc.rs: Implements request/reply calls over the message bus using correlation ids, per-caller reply queues and timeouts.
+
+use crate::messages::{Envelope, EnvelopeError, EnvelopeHeader, Payload};
+use crate::messaging::{Headers, Transport, TransportError};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+type PendingCalls = Arc<Mutex<HashMap<Uuid, oneshot::Sender<(Vec<u8>, Headers)>>>>;
+
+#[derive(Debug)]
+pub enum RpcError {
+    Transport(TransportError),
+    Envelope(EnvelopeError),
+    Timeout { routing_key: String, after: Duration },
+    Closed,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Transport(e) => write!(f, "transport error: {}", e),
+            RpcError::Envelope(e) => write!(f, "invalid reply: {}", e),
+            RpcError::Timeout { routing_key, after } => {
+                write!(f, "no reply for {} within {:?}", routing_key, after)
+            }
+            RpcError::Closed => write!(f, "reply listener stopped"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<TransportError> for RpcError {
+    fn from(e: TransportError) -> Self {
+        RpcError::Transport(e)
+    }
+}
+
+impl From<EnvelopeError> for RpcError {
+    fn from(e: EnvelopeError) -> Self {
+        RpcError::Envelope(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct RpcClient {
+    node_id: Uuid,
+    transport: Arc<dyn Transport>,
+    reply_queue: String,
+    pending: PendingCalls,
+}
+
+impl RpcClient {
+    pub async fn new(node_id: Uuid, transport: Arc<dyn Transport>) -> Result<Self, TransportError> {
+        let reply_queue = format!("rpc_reply_queue.{}", node_id);
+        // Every client has its own reply queue, which goes away with the process
+        transport.declare_exclusive_queue(&reply_queue).await?;
+        let mut consumer = transport
+            .consume(&reply_queue, &format!("rpc_client_{}", node_id))
+            .await?;
+
+        let pending: PendingCalls = Arc::new(Mutex::new(HashMap::new()));
+        let listener_pending = pending.clone();
+        tokio::spawn(async move {
+            while let Some(delivery) = consumer.next().await {
+                match EnvelopeHeader::peek(&delivery.data, &delivery.headers) {
+                    Ok(header) => {
+                        let waiter = listener_pending.lock().unwrap().remove(&header.correlation_id);
+                        match waiter {
+                            Some(waiter) => {
+                                let _ = waiter.send((delivery.data.clone(), delivery.headers.clone()));
+                            }
+                            None => warn!(
+                                "Discarding late or unknown reply {} from {}",
+                                header.correlation_id, header.sender
+                            ),
+                        }
+                    }
+                    Err(e) => error!("Discarding unreadable reply: {:?}", e),
+                }
+                if let Err(e) = delivery.ack().await {
+                    error!("Failed to acknowledge reply: {:?}", e);
+                }
+            }
+        });
+
+        info!("RPC client ready, replies on queue: {}", reply_queue);
+        Ok(RpcClient {
+            node_id,
+            transport,
+            reply_queue,
+            pending,
+        })
+    }
+
+    pub async fn call<Req: Payload, Resp: Payload>(
+        &self,
+        routing_key: &str,
+        request: Req,
+        call_timeout: Duration,
+    ) -> Result<Envelope<Resp>, RpcError> {
+        let envelope = Envelope::new(self.node_id, request).with_reply_to(&self.reply_queue);
+        let correlation_id = envelope.correlation_id;
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(correlation_id, tx);
+
+        if let Err(e) = envelope.route(self.transport.as_ref(), routing_key).await {
+            self.pending.lock().unwrap().remove(&correlation_id);
+            return Err(e.into());
+        }
+
+        match timeout(call_timeout, rx).await {
+            Ok(Ok((reply, headers))) => Ok(Envelope::decode(&reply, &headers)?),
+            Ok(Err(_)) => Err(RpcError::Closed),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&correlation_id);
+                Err(RpcError::Timeout {
+                    routing_key: routing_key.to_string(),
+                    after: call_timeout,
+                })
+            }
+        }
+    }
+}
+
+// Sends a reply to the caller's reply queue. Returns false if the request did not ask for one.
+pub async fn respond<Req: Payload, Resp: Payload>(
+    transport: &dyn Transport,
+    node_id: Uuid,
+    request: &Envelope<Req>,
+    payload: Resp,
+) -> Result<bool, TransportError> {
+    match &request.reply_to {
+        Some(reply_to) => {
+            request
+                .reply(node_id, payload)
+                .publish_with(transport, reply_to, &request.reply_codec())
+                .await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{ResultMessage, TaskMessage};
+    use crate::messaging::InMemoryBroker;
+    use crate::routing;
+    use crate::tensor::Tensor;
+
+    #[tokio::test]
+    async fn test_call_receives_matching_reply() {
+        let broker = Arc::new(InMemoryBroker::new());
+        routing::declare_routed_queue(broker.as_ref(), "echo_queue", &["task.echo".to_string()])
+            .await
+            .unwrap();
+
+        let server_transport = broker.clone();
+        let mut consumer = broker.consume("echo_queue", "echo_server").await.unwrap();
+        tokio::spawn(async move {
+            while let Some(delivery) = consumer.next().await {
+                let request = Envelope::<TaskMessage>::from_delivery(&delivery).unwrap();
+                let reply = ResultMessage::for_task(&request.payload, request.payload.inputs.clone());
+                respond(server_transport.as_ref(), Uuid::new_v4(), &request, reply).await.unwrap();
+                delivery.ack().await.unwrap();
+            }
+        });
+
+        let client = RpcClient::new(Uuid::new_v4(), broker.clone()).await.unwrap();
+        let reply: Envelope<ResultMessage> = client
+            .call(
+                "task.echo",
+                TaskMessage::new("task-1", "echo", vec![Tensor::scalar(7u8)]),
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.payload.task_id, "task-1");
+        assert_eq!(reply.payload.outputs, vec![Tensor::scalar(7u8)]);
+    }
+
+    #[tokio::test]
+    async fn test_call_times_out_without_responder() {
+        let broker = Arc::new(InMemoryBroker::new());
+        routing::declare_routed_queue(broker.as_ref(), "silent_queue", &["task.silent".to_string()])
+            .await
+            .unwrap();
+
+        let client = RpcClient::new(Uuid::new_v4(), broker).await.unwrap();
+        let result: Result<Envelope<ResultMessage>, RpcError> = client
+            .call(
+                "task.silent",
+                TaskMessage::new("task-2", "echo", Vec::new()),
+                Duration::from_millis(50),
+            )
+            .await;
+        assert!(matches!(result, Err(RpcError::Timeout { .. })));
+        assert!(client.pending.lock().unwrap().is_empty());
+    }
+}
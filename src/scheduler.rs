@@ -1,40 +1,54 @@
 // scheduler.rs: Implements a task scheduler that assigns tasks to Ki nodes based on load and capacity.
 
 use crate::load_balancer::LoadBalancer;
-use tokio::sync::mpsc;
+use crate::messages::{Envelope, TaskMessage};
+use crate::messaging::{Transport, TransportError};
+use crate::routing;
+use crate::tensor::Tensor;
+use std::sync::Arc;
 use uuid::Uuid;
 use tracing::{info, error};
 use std::time::Duration;
 use tokio::time;
-use std::error::Error;
 
 #[derive(Clone, Debug)]
 pub struct Task {
     pub task_id: Uuid,
-    pub data: String,
+    pub op: String,
+    pub inputs: Vec<Tensor>,
 }
 
 pub struct Scheduler {
+    node_id: Uuid,
     load_balancer: LoadBalancer,
-    task_tx: mpsc::Sender<Task>,
+    transport: Arc<dyn Transport>,
 }
 
 impl Scheduler {
-    pub fn new(load_balancer: LoadBalancer, task_tx: mpsc::Sender<Task>) -> Self {
+    pub fn new(node_id: Uuid, load_balancer: LoadBalancer, transport: Arc<dyn Transport>) -> Self {
         Scheduler {
+            node_id,
             load_balancer,
-            task_tx,
+            transport,
         }
     }
 
-    pub async fn schedule_task(&self, task: Task) -> Result<(), Box<dyn Error>> {
-        if let Some(node_id) = self.load_balancer.assign_task() {
-            info!("Scheduling task {} to node {}", task.task_id, node_id);
-            self.task_tx.send(task).await?;
-            Ok(())
+    // Sends the task to a node that runs its op, picked by the load balancer, and returns that node's id.
+    pub async fn schedule_task(&self, task: Task) -> Result<Uuid, TransportError> {
+        if let Some(node_id) = self.load_balancer.assign_task_for(&task.op) {
+            info!("Scheduling {} task {} to node {}", task.op, task.task_id, node_id);
+            let message = TaskMessage::new(task.task_id.to_string(), task.op, task.inputs);
+            if let Err(e) = Envelope::new(self.node_id, message)
+                .route(self.transport.as_ref(), &routing::node_task_key(node_id))
+                .await
+            {
+                self.load_balancer.complete_task(&node_id);
+                return Err(e);
+            }
+            Ok(node_id)
         } else {
-            error!("No available nodes to schedule task {}");
-            Err("No available nodes".into())
+            error!("No available nodes to schedule {} task {}", task.op, task.task_id);
+            Err(format!("No available nodes run {}", task.op).into())
         }
     }
 
@@ -45,7 +59,8 @@ impl Scheduler {
             // Placeholder for actual task generation or retrieval logic
             let task = Task {
                 task_id: Uuid::new_v4(),
-                data: "Sample task data".to_string(),
+                op: "relu".to_string(),
+                inputs: Vec::new(),
             };
 
             if let Err(e) = self.schedule_task(task).await {
@@ -59,22 +74,37 @@ impl Scheduler {
 mod tests {
     use super::*;
     use crate::load_balancer::LoadBalancer;
-    use tokio::sync::mpsc;
+    use crate::messaging::InMemoryBroker;
 
     #[tokio::test]
     async fn test_schedule_task() {
+        let broker: Arc<dyn Transport> = Arc::new(InMemoryBroker::new());
         let load_balancer = LoadBalancer::new();
-        let (task_tx, mut task_rx) = mpsc::channel(10);
-        let scheduler = Scheduler::new(load_balancer.clone(), task_tx);
+        let scheduler = Scheduler::new(Uuid::new_v4(), load_balancer.clone(), broker.clone());
 
-        load_balancer.add_node(Uuid::new_v4());
+        let node_id = Uuid::new_v4();
+        let node_queue = routing::node_task_queue(node_id);
+        routing::declare_routed_queue(broker.as_ref(), &node_queue, &[routing::node_task_key(node_id)])
+            .await
+            .unwrap();
+        load_balancer.register_node(node_id, 1, vec!["matmul".to_string()]);
+        load_balancer.register_node(Uuid::new_v4(), 4, vec!["relu".to_string()]);
         let task = Task {
             task_id: Uuid::new_v4(),
-            data: "Test data".to_string(),
+            op: "matmul".to_string(),
+            inputs: Vec::new(),
         };
 
-        scheduler.schedule_task(task.clone()).await.unwrap();
-        let received_task = task_rx.recv().await.unwrap();
-        assert_eq!(received_task.task_id, task.task_id);
+        assert_eq!(scheduler.schedule_task(task.clone()).await.unwrap(), node_id);
+        let delivery = broker.get(&node_queue).await.unwrap().unwrap();
+        let received_task = Envelope::<TaskMessage>::from_delivery(&delivery).unwrap();
+        assert_eq!(received_task.payload.task_id, task.task_id.to_string());
+        assert_eq!(received_task.payload.op, "matmul");
+
+        let unknown = Task {
+            op: "conv2d".to_string(),
+            ..task
+        };
+        assert!(scheduler.schedule_task(unknown).await.is_err());
     }
 }
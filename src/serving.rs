@@ -0,0 +1,369 @@
+// serving.rs: Online inference: queues requests for a model version, runs them through the An and Ki nodes in batches, and hands each caller its rows of the result.
+
+use crate::logging_metrics;
+use crate::messages::{Envelope, ResultMessage, TaskMessage};
+use crate::messaging::{Transport, TransportError};
+use crate::routing;
+use crate::rpc::{RpcClient, DEFAULT_RPC_TIMEOUT};
+use crate::tensor::{Tensor, TensorError};
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{error, info};
+use uuid::Uuid;
+
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 32;
+pub const DEFAULT_MAX_WAIT: Duration = Duration::from_millis(5);
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How requests are grouped: a batch is dispatched once it holds `max_batch_size` rows, or once its
+// first request has waited `max_wait`. Each model version queues at most `queue_capacity` requests,
+// and its queue and batching task go away after `idle_timeout` without requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchingConfig {
+    pub max_batch_size: usize,
+    pub max_wait: Duration,
+    pub queue_capacity: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        BatchingConfig {
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_wait: DEFAULT_MAX_WAIT,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl BatchingConfig {
+    // Reads SERVE_MAX_BATCH_SIZE, SERVE_MAX_WAIT_MS, SERVE_QUEUE_CAPACITY and SERVE_IDLE_TIMEOUT_MS.
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<u64>().ok());
+        let defaults = BatchingConfig::default();
+        BatchingConfig {
+            max_batch_size: var("SERVE_MAX_BATCH_SIZE").map_or(defaults.max_batch_size, |size| size.max(1) as usize),
+            max_wait: var("SERVE_MAX_WAIT_MS").map_or(defaults.max_wait, Duration::from_millis),
+            queue_capacity: var("SERVE_QUEUE_CAPACITY").map_or(defaults.queue_capacity, |capacity| capacity.max(1) as usize),
+            idle_timeout: var("SERVE_IDLE_TIMEOUT_MS").map_or(defaults.idle_timeout, Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InferenceError {
+    InvalidInput(String),
+    // The model version's queue is full
+    Overloaded,
+    // The batch could not be run
+    Failed(String),
+}
+
+impl fmt::Display for InferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InferenceError::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            InferenceError::Overloaded => write!(f, "too many requests are queued"),
+            InferenceError::Failed(reason) => write!(f, "inference failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for InferenceError {}
+
+impl From<TensorError> for InferenceError {
+    fn from(e: TensorError) -> Self {
+        InferenceError::InvalidInput(e.to_string())
+    }
+}
+
+// Runs one batch of a model version and returns its outputs, one row per input row.
+type Runner = Arc<dyn Fn(String, u64, Tensor) -> BoxFuture<'static, Result<Tensor, String>> + Send + Sync>;
+
+// The queue of each model version being served, removed by its batching task once it goes idle
+type Queues = Arc<Mutex<HashMap<(String, u64), mpsc::Sender<Request>>>>;
+
+struct Request {
+    inputs: Tensor,
+    enqueued: Instant,
+    reply: oneshot::Sender<Result<Tensor, InferenceError>>,
+}
+
+impl Request {
+    fn rows(&self) -> usize {
+        self.inputs.shape()[0]
+    }
+
+    // Requests go in one batch only if their rows can be stacked
+    fn fits_with(&self, other: &Request) -> bool {
+        self.inputs.dtype() == other.inputs.dtype() && self.inputs.shape()[1..] == other.inputs.shape()[1..]
+    }
+}
+
+// Queues inference requests per model version, with one batching task for each version served.
+#[derive(Clone)]
+pub struct InferenceServer {
+    config: BatchingConfig,
+    run: Runner,
+    queues: Queues,
+}
+
+impl InferenceServer {
+    pub fn new<F, Fut>(config: BatchingConfig, run: F) -> Self
+    where
+        F: Fn(String, u64, Tensor) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Tensor, String>> + Send + 'static,
+    {
+        InferenceServer {
+            config,
+            run: Arc::new(move |model, version, inputs| Box::pin(run(model, version, inputs))),
+            queues: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Runs batches as `model_forward` tasks on the An nodes, which spread them across the Ki nodes.
+    pub async fn connect(transport: Arc<dyn Transport>, config: BatchingConfig) -> Result<Self, TransportError> {
+        let rpc_client = RpcClient::new(Uuid::new_v4(), transport).await?;
+        Ok(InferenceServer::new(config, move |model: String, version, inputs| {
+            let rpc_client = rpc_client.clone();
+            async move {
+                let task = TaskMessage::new(Uuid::new_v4().to_string(), "model_forward", vec![inputs])
+                    .with_attr("model", model)
+                    .with_attr("version", version as i64);
+                let result: Envelope<ResultMessage> = rpc_client
+                    .call(&routing::an_task_key(), task, DEFAULT_RPC_TIMEOUT)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                match result.payload.error {
+                    Some(e) => Err(e),
+                    None => result.payload.outputs.into_iter().next().ok_or_else(|| "no outputs".to_string()),
+                }
+            }
+        }))
+    }
+
+    // Runs `inputs` (one example per row) through the model version, batched with other requests for it.
+    pub async fn infer(&self, model: &str, version: u64, inputs: Tensor) -> Result<Tensor, InferenceError> {
+        if inputs.rank() < 2 || inputs.shape()[0] == 0 {
+            return Err(InferenceError::InvalidInput(format!(
+                "expected a batch of at least one row, got shape {:?}",
+                inputs.shape()
+            )));
+        }
+        let (reply, result) = oneshot::channel();
+        let request = Request {
+            inputs,
+            enqueued: Instant::now(),
+            reply,
+        };
+        // A queue closed for going idle after it was looked up is replaced by a new one
+        match self.queue(model, version).try_send(request) {
+            Err(mpsc::error::TrySendError::Closed(request)) => self.queue(model, version).try_send(request),
+            sent => sent,
+        }
+        .map_err(|_| InferenceError::Overloaded)?;
+        result
+            .await
+            .unwrap_or_else(|_| Err(InferenceError::Failed("the batch was dropped".to_string())))
+    }
+
+    fn queue(&self, model: &str, version: u64) -> mpsc::Sender<Request> {
+        let mut queues = self.queues.lock().unwrap();
+        queues
+            .entry((model.to_string(), version))
+            .or_insert_with(|| {
+                info!("Serving model {} v{}", model, version);
+                let (sender, receiver) = mpsc::channel(self.config.queue_capacity);
+                let served = Served {
+                    model: model.to_string(),
+                    version,
+                    queues: self.queues.clone(),
+                };
+                tokio::spawn(form_batches(served, self.config, self.run.clone(), receiver));
+                sender
+            })
+            .clone()
+    }
+}
+
+// The model version a batching task serves, and where its queue is listed.
+struct Served {
+    model: String,
+    version: u64,
+    queues: Queues,
+}
+
+impl Served {
+    // Unlists the queue and closes it, so that no request is queued after the batching task stops reading.
+    fn retire(&self, queue: &mut mpsc::Receiver<Request>) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.remove(&(self.model.clone(), self.version));
+        queue.close();
+        info!("Stopped serving idle model {} v{}", self.model, self.version);
+    }
+}
+
+// Groups queued requests into batches and dispatches each without waiting for the one before.
+// After `idle_timeout` without requests the queue is retired; requests already in it are still run.
+async fn form_batches(served: Served, config: BatchingConfig, run: Runner, mut queue: mpsc::Receiver<Request>) {
+    let (model, version) = (served.model.clone(), served.version);
+    // A request that did not fit in the previous batch starts the next one
+    let mut carried: Option<Request> = None;
+    loop {
+        let first = match carried.take() {
+            Some(request) => request,
+            None => match timeout(config.idle_timeout, queue.recv()).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(_) => {
+                    served.retire(&mut queue);
+                    continue;
+                }
+            },
+        };
+        let deadline = first.enqueued + config.max_wait;
+        let mut rows = first.rows();
+        let mut batch = vec![first];
+        while rows < config.max_batch_size {
+            match timeout_at(deadline, queue.recv()).await {
+                Ok(Some(request)) if rows + request.rows() <= config.max_batch_size && request.fits_with(&batch[0]) => {
+                    rows += request.rows();
+                    batch.push(request);
+                }
+                Ok(Some(request)) => {
+                    carried = Some(request);
+                    break;
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+        tokio::spawn(run_batch(model.clone(), version, run.clone(), batch));
+    }
+}
+
+async fn run_batch(model: String, version: u64, run: Runner, batch: Vec<Request>) {
+    let dispatched = Instant::now();
+    let latencies: Vec<Duration> = batch.iter().map(|request| dispatched - request.enqueued).collect();
+    let rows: Vec<usize> = batch.iter().map(Request::rows).collect();
+    logging_metrics::log_inference_batch(&model, version, rows.iter().sum(), &latencies);
+
+    let inputs: Vec<Tensor> = batch.iter().map(|request| request.inputs.clone()).collect();
+    let outputs = match Tensor::concat_rows(&inputs) {
+        Ok(inputs) => run(model.clone(), version, inputs).await,
+        Err(e) => Err(e.to_string()),
+    };
+    let total: usize = rows.iter().sum();
+    let outputs = outputs.and_then(|outputs| match outputs.shape().first() {
+        Some(&found) if found == total => Ok(outputs),
+        _ => Err(format!("expected {} output rows, got shape {:?}", total, outputs.shape())),
+    });
+    if let Err(e) = &outputs {
+        error!("Inference batch of model {} v{} failed: {}", model, version, e);
+    }
+
+    let mut start = 0;
+    for (request, rows) in batch.into_iter().zip(rows) {
+        let result = match &outputs {
+            Ok(outputs) => outputs.slice_rows(start..start + rows).map_err(InferenceError::from),
+            Err(e) => Err(InferenceError::Failed(e.clone())),
+        };
+        start += rows;
+        // The caller may have given up
+        let _ = request.reply.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join_all;
+
+    // Doubles every value and records the rows of each batch
+    fn doubling(config: BatchingConfig) -> (InferenceServer, Arc<Mutex<Vec<usize>>>) {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let recorded = batches.clone();
+        let server = InferenceServer::new(config, move |_, _, inputs: Tensor| {
+            recorded.lock().unwrap().push(inputs.shape()[0]);
+            async move {
+                let doubled = inputs.to_vec::<f32>().unwrap().iter().map(|value| value * 2.0).collect();
+                Tensor::new(inputs.shape().to_vec(), doubled).map_err(|e| e.to_string())
+            }
+        });
+        (server, batches)
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_batched_and_split_back() {
+        let config = BatchingConfig {
+            max_batch_size: 4,
+            max_wait: Duration::from_millis(50),
+            queue_capacity: 16,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        };
+        let (server, batches) = doubling(config);
+        let requests = (0..5).map(|i| {
+            let server = server.clone();
+            async move {
+                let rows = if i == 0 { 2 } else { 1 };
+                let inputs = Tensor::new(vec![rows, 3], vec![i as f32; rows * 3]).unwrap();
+                server.infer("double", 1, inputs).await.unwrap()
+            }
+        });
+        let outputs = join_all(requests).await;
+        for (i, output) in outputs.iter().enumerate() {
+            let rows = if i == 0 { 2 } else { 1 };
+            assert_eq!(output.shape(), &[rows, 3]);
+            assert_eq!(output.to_vec::<f32>().unwrap(), vec![i as f32 * 2.0; rows * 3]);
+        }
+        // Six rows in batches of at most four
+        let mut batches = batches.lock().unwrap().clone();
+        batches.sort();
+        assert_eq!(batches, vec![2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_a_lone_request_waits_at_most_max_wait() {
+        let config = BatchingConfig {
+            max_batch_size: 64,
+            max_wait: Duration::from_millis(20),
+            queue_capacity: 16,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        };
+        let (server, batches) = doubling(config);
+        let started = Instant::now();
+        let output = server.infer("double", 1, Tensor::new(vec![1, 2], vec![1.0f32, 2.0]).unwrap()).await.unwrap();
+        assert_eq!(output.to_vec::<f32>().unwrap(), vec![2.0, 4.0]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(*batches.lock().unwrap(), vec![1]);
+
+        let invalid = server.infer("double", 1, Tensor::new(vec![3], vec![1.0f32; 3]).unwrap()).await;
+        assert!(matches!(invalid, Err(InferenceError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn test_idle_queues_are_retired_and_made_again_on_demand() {
+        let config = BatchingConfig {
+            max_wait: Duration::from_millis(1),
+            idle_timeout: Duration::from_millis(20),
+            ..BatchingConfig::default()
+        };
+        let (server, batches) = doubling(config);
+        let inputs = Tensor::new(vec![1, 2], vec![1.0f32, 2.0]).unwrap();
+        server.infer("double", 1, inputs.clone()).await.unwrap();
+        server.infer("double", 2, inputs.clone()).await.unwrap();
+        assert_eq!(server.queues.lock().unwrap().len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.queues.lock().unwrap().is_empty());
+        assert_eq!(server.infer("double", 1, inputs).await.unwrap().to_vec::<f32>().unwrap(), vec![2.0, 4.0]);
+        assert_eq!(batches.lock().unwrap().len(), 3);
+    }
+}
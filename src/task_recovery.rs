@@ -2,7 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::{self, Read, Write};
 use std::sync::{Arc, RwLock};
 use tracing::{error, info};
@@ -30,8 +30,8 @@ impl TaskRecoveryManager {
     }
 
     pub fn add_task(&self, task: Task) {
-        let mut tasks = self.tasks.write().unwrap();
-        tasks.insert(task.task_id, task.clone());
+        // Released before persisting, which reads the tasks
+        self.tasks.write().unwrap().insert(task.task_id, task.clone());
         info!("Added task to recovery manager: {:?}", task);
         if let Err(e) = self.persist_tasks() {
             error!("Failed to persist tasks: {:?}", e);
@@ -39,8 +39,8 @@ impl TaskRecoveryManager {
     }
 
     pub fn remove_task(&self, task_id: &Uuid) {
-        let mut tasks = self.tasks.write().unwrap();
-        if tasks.remove(task_id).is_some() {
+        let removed = self.tasks.write().unwrap().remove(task_id);
+        if removed.is_some() {
             info!("Removed task from recovery manager: {}", task_id);
             if let Err(e) = self.persist_tasks() {
                 error!("Failed to persist tasks: {:?}", e);
@@ -67,7 +67,7 @@ impl TaskRecoveryManager {
     fn persist_tasks(&self) -> Result<(), io::Error> {
         let tasks = self.tasks.read().unwrap();
         let content = serde_json::to_string(&*tasks)?;
-        let mut file = OpenOptions::new().write(true).truncate(true).open(&self.storage_file)?;
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&self.storage_file)?;
         file.write_all(content.as_bytes())?;
         Ok(())
     }
@@ -80,7 +80,8 @@ mod tests {
 
     #[test]
     fn test_task_recovery() {
-        let storage_file = "test_tasks.json";
+        let storage_file = std::env::temp_dir().join(format!("recovery_tasks_{}.json", Uuid::new_v4()));
+        let storage_file = storage_file.to_str().unwrap();
         let recovery_manager = TaskRecoveryManager::new(storage_file);
 
         let task = Task {
@@ -0,0 +1,572 @@
+// tensor.rs: Defines the Tensor type carried by tasks and results, and its compact wire format.
+
+use bytes::Bytes;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+const WIRE_VERSION: u8 = 1;
+// Version, dtype, rank and padding, so the dims and data that follow stay 8-byte aligned
+const WIRE_PREFIX_LEN: usize = 8;
+// The wire format stores the rank in one byte
+pub const MAX_RANK: usize = u8::MAX as usize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DType {
+    F32,
+    F64,
+    I32,
+    U8,
+}
+
+impl DType {
+    pub fn size(self) -> usize {
+        match self {
+            DType::F32 | DType::I32 => 4,
+            DType::F64 => 8,
+            DType::U8 => 1,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            DType::F32 => 0,
+            DType::F64 => 1,
+            DType::I32 => 2,
+            DType::U8 => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, TensorError> {
+        match code {
+            0 => Ok(DType::F32),
+            1 => Ok(DType::F64),
+            2 => Ok(DType::I32),
+            3 => Ok(DType::U8),
+            other => Err(TensorError::Malformed(format!("unknown dtype code {}", other))),
+        }
+    }
+}
+
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DType::F32 => "f32",
+            DType::F64 => "f64",
+            DType::I32 => "i32",
+            DType::U8 => "u8",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Rust element types a tensor can hold.
+pub trait Element: bytemuck::Pod {
+    const DTYPE: DType;
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn extend_le(self, out: &mut Vec<u8>);
+}
+
+macro_rules! element {
+    ($type:ty, $dtype:expr) => {
+        impl Element for $type {
+            const DTYPE: DType = $dtype;
+
+            fn from_le_slice(bytes: &[u8]) -> Self {
+                <$type>::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            fn extend_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    };
+}
+
+element!(f32, DType::F32);
+element!(f64, DType::F64);
+element!(i32, DType::I32);
+element!(u8, DType::U8);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TensorError {
+    ShapeMismatch { expected: Vec<usize>, found: Vec<usize> },
+    DTypeMismatch { expected: DType, found: DType },
+    Malformed(String),
+}
+
+impl fmt::Display for TensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TensorError::ShapeMismatch { expected, found } => {
+                write!(f, "expected shape {:?}, found {:?}", expected, found)
+            }
+            TensorError::DTypeMismatch { expected, found } => {
+                write!(f, "expected dtype {}, found {}", expected, found)
+            }
+            TensorError::Malformed(reason) => write!(f, "malformed tensor: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TensorError {}
+
+// A dense, row-major tensor. The buffer holds the elements little-endian and is shared, not copied,
+// by clones, reshapes and decoding.
+#[derive(Clone, PartialEq)]
+pub struct Tensor {
+    shape: Vec<usize>,
+    dtype: DType,
+    data: Bytes,
+}
+
+impl Tensor {
+    pub fn new<T: Element>(shape: Vec<usize>, values: Vec<T>) -> Result<Self, TensorError> {
+        check_rank(&shape)?;
+        let expected: usize = shape.iter().product();
+        if values.len() != expected {
+            return Err(TensorError::Malformed(format!(
+                "{} values do not fill shape {:?}",
+                values.len(),
+                shape
+            )));
+        }
+        let data = if cfg!(target_endian = "little") {
+            Bytes::copy_from_slice(bytemuck::cast_slice(&values))
+        } else {
+            let mut data = Vec::with_capacity(values.len() * T::DTYPE.size());
+            for value in values {
+                value.extend_le(&mut data);
+            }
+            Bytes::from(data)
+        };
+        Ok(Tensor {
+            shape,
+            dtype: T::DTYPE,
+            data,
+        })
+    }
+
+    // Panics if `shape` has more than MAX_RANK dimensions, which `new` would reject.
+    pub fn zeros(dtype: DType, shape: Vec<usize>) -> Self {
+        assert!(shape.len() <= MAX_RANK, "rank {} exceeds the maximum of {}", shape.len(), MAX_RANK);
+        let len: usize = shape.iter().product();
+        Tensor {
+            shape,
+            dtype,
+            data: Bytes::from(vec![0; len * dtype.size()]),
+        }
+    }
+
+    pub fn scalar<T: Element>(value: T) -> Self {
+        Tensor::new(Vec::new(), vec![value]).unwrap()
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
+    // Number of elements
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    // Borrows the elements when the buffer is suitably aligned, and copies them otherwise.
+    pub fn values<T: Element>(&self) -> Result<Cow<'_, [T]>, TensorError> {
+        self.expect_dtype(T::DTYPE)?;
+        if cfg!(target_endian = "little") {
+            if let Ok(values) = bytemuck::try_cast_slice::<u8, T>(&self.data) {
+                return Ok(Cow::Borrowed(values));
+            }
+        }
+        Ok(Cow::Owned(
+            self.data
+                .chunks_exact(T::DTYPE.size())
+                .map(T::from_le_slice)
+                .collect(),
+        ))
+    }
+
+    pub fn to_vec<T: Element>(&self) -> Result<Vec<T>, TensorError> {
+        Ok(self.values::<T>()?.into_owned())
+    }
+
+    // Same elements under a new shape, sharing the buffer.
+    pub fn reshape(&self, shape: Vec<usize>) -> Result<Tensor, TensorError> {
+        check_rank(&shape)?;
+        if shape.iter().product::<usize>() != self.len() {
+            return Err(TensorError::ShapeMismatch {
+                expected: self.shape.clone(),
+                found: shape,
+            });
+        }
+        Ok(Tensor {
+            shape,
+            dtype: self.dtype,
+            data: self.data.clone(),
+        })
+    }
+
+    // Splits along the first axis into at most `parts` contiguous, nearly equal tensors that share
+    // this tensor's buffer.
+    pub fn split_rows(&self, parts: usize) -> Result<Vec<Tensor>, TensorError> {
+        let rows = *self
+            .shape
+            .first()
+            .ok_or_else(|| TensorError::Malformed("cannot split a scalar".to_string()))?;
+        let parts = parts.clamp(1, rows.max(1));
+        let row_bytes = self.shape[1..].iter().product::<usize>() * self.dtype.size();
+        let mut tensors = Vec::with_capacity(parts);
+        let mut start = 0;
+        for part in 0..parts {
+            let count = rows / parts + usize::from(part < rows % parts);
+            let mut shape = self.shape.clone();
+            shape[0] = count;
+            tensors.push(Tensor {
+                shape,
+                dtype: self.dtype,
+                data: self.data.slice(start * row_bytes..(start + count) * row_bytes),
+            });
+            start += count;
+        }
+        Ok(tensors)
+    }
+
+    // The rows in `rows`, sharing this tensor's buffer.
+    pub fn slice_rows(&self, rows: Range<usize>) -> Result<Tensor, TensorError> {
+        let count = *self
+            .shape
+            .first()
+            .ok_or_else(|| TensorError::Malformed("cannot slice a scalar".to_string()))?;
+        if rows.start > rows.end || rows.end > count {
+            return Err(TensorError::Malformed(format!("rows {:?} out of range for {} rows", rows, count)));
+        }
+        let row_bytes = self.shape[1..].iter().product::<usize>() * self.dtype.size();
+        let mut shape = self.shape.clone();
+        shape[0] = rows.len();
+        Ok(Tensor {
+            shape,
+            dtype: self.dtype,
+            data: self.data.slice(rows.start * row_bytes..rows.end * row_bytes),
+        })
+    }
+
+    // Joins tensors along the first axis; the inverse of `split_rows`.
+    pub fn concat_rows(tensors: &[Tensor]) -> Result<Tensor, TensorError> {
+        let first = tensors
+            .first()
+            .ok_or_else(|| TensorError::Malformed("nothing to concatenate".to_string()))?;
+        if first.rank() == 0 {
+            return Err(TensorError::Malformed("cannot concatenate scalars".to_string()));
+        }
+        let mut shape = first.shape.clone();
+        shape[0] = 0;
+        let mut data = Vec::with_capacity(tensors.iter().map(|tensor| tensor.data.len()).sum());
+        for tensor in tensors {
+            tensor.expect_dtype(first.dtype)?;
+            if tensor.rank() != first.rank() || tensor.shape[1..] != first.shape[1..] {
+                return Err(TensorError::ShapeMismatch {
+                    expected: first.shape.clone(),
+                    found: tensor.shape.clone(),
+                });
+            }
+            shape[0] += tensor.shape[0];
+            data.extend_from_slice(&tensor.data);
+        }
+        Ok(Tensor {
+            shape,
+            dtype: first.dtype,
+            data: Bytes::from(data),
+        })
+    }
+
+    // Splits a matrix into at most `parts` nearly equal groups of contiguous columns. Unlike
+    // `split_rows` this copies, since the columns of a row-major matrix are not contiguous.
+    pub fn split_columns(&self, parts: usize) -> Result<Vec<Tensor>, TensorError> {
+        let &[rows, columns] = self.shape.as_slice() else {
+            return Err(TensorError::Malformed(format!("cannot split the columns of shape {:?}", self.shape)));
+        };
+        let parts = parts.clamp(1, columns.max(1));
+        let size = self.dtype.size();
+        let mut tensors = Vec::with_capacity(parts);
+        let mut start = 0;
+        for part in 0..parts {
+            let count = columns / parts + usize::from(part < columns % parts);
+            let mut data = Vec::with_capacity(rows * count * size);
+            for row in 0..rows {
+                let offset = (row * columns + start) * size;
+                data.extend_from_slice(&self.data[offset..offset + count * size]);
+            }
+            tensors.push(Tensor {
+                shape: vec![rows, count],
+                dtype: self.dtype,
+                data: Bytes::from(data),
+            });
+            start += count;
+        }
+        Ok(tensors)
+    }
+
+    // Joins matrices with the same number of rows side by side; the inverse of `split_columns`.
+    pub fn concat_columns(tensors: &[Tensor]) -> Result<Tensor, TensorError> {
+        let first = tensors
+            .first()
+            .ok_or_else(|| TensorError::Malformed("nothing to concatenate".to_string()))?;
+        let rows = match first.shape.as_slice() {
+            &[rows, _] => rows,
+            shape => return Err(TensorError::Malformed(format!("cannot join the columns of shape {:?}", shape))),
+        };
+        let mut columns = 0;
+        for tensor in tensors {
+            tensor.expect_dtype(first.dtype)?;
+            match tensor.shape.as_slice() {
+                &[tensor_rows, tensor_columns] if tensor_rows == rows => columns += tensor_columns,
+                shape => {
+                    return Err(TensorError::ShapeMismatch {
+                        expected: first.shape.clone(),
+                        found: shape.to_vec(),
+                    })
+                }
+            }
+        }
+        let size = first.dtype.size();
+        let mut data = Vec::with_capacity(rows * columns * size);
+        for row in 0..rows {
+            for tensor in tensors {
+                let row_bytes = tensor.shape[1] * size;
+                data.extend_from_slice(&tensor.data[row * row_bytes..(row + 1) * row_bytes]);
+            }
+        }
+        Ok(Tensor {
+            shape: vec![rows, columns],
+            dtype: first.dtype,
+            data: Bytes::from(data),
+        })
+    }
+
+    pub fn expect_dtype(&self, dtype: DType) -> Result<(), TensorError> {
+        if self.dtype != dtype {
+            return Err(TensorError::DTypeMismatch {
+                expected: dtype,
+                found: self.dtype,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn expect_shape(&self, shape: &[usize]) -> Result<(), TensorError> {
+        if self.shape != shape {
+            return Err(TensorError::ShapeMismatch {
+                expected: shape.to_vec(),
+                found: self.shape.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        WIRE_PREFIX_LEN + 8 * self.shape.len() + self.data.len()
+    }
+
+    // Wire format, integers little-endian:
+    //   u8 version, u8 dtype, u8 rank, 5 bytes padding
+    //   u64 per dimension
+    //   element data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(&[WIRE_VERSION, self.dtype.code(), self.shape.len() as u8, 0, 0, 0, 0, 0]);
+        for &dim in &self.shape {
+            bytes.extend_from_slice(&(dim as u64).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    // Decodes without copying the element data; the tensor keeps a reference to `bytes`.
+    pub fn decode(bytes: Bytes) -> Result<Tensor, TensorError> {
+        if bytes.len() < WIRE_PREFIX_LEN {
+            return Err(TensorError::Malformed("truncated header".to_string()));
+        }
+        if bytes[0] != WIRE_VERSION {
+            return Err(TensorError::Malformed(format!("unsupported wire version {}", bytes[0])));
+        }
+        let dtype = DType::from_code(bytes[1])?;
+        let rank = bytes[2] as usize;
+        let data_start = WIRE_PREFIX_LEN + 8 * rank;
+        if bytes.len() < data_start {
+            return Err(TensorError::Malformed("truncated shape".to_string()));
+        }
+
+        let mut shape = Vec::with_capacity(rank);
+        let mut len: usize = 1;
+        for dim_bytes in bytes[WIRE_PREFIX_LEN..data_start].chunks_exact(8) {
+            let dim = usize::try_from(u64::from_le_bytes(dim_bytes.try_into().unwrap()))
+                .map_err(|_| TensorError::Malformed("dimension too large".to_string()))?;
+            len = len
+                .checked_mul(dim)
+                .ok_or_else(|| TensorError::Malformed("shape too large".to_string()))?;
+            shape.push(dim);
+        }
+
+        let data_len = len
+            .checked_mul(dtype.size())
+            .ok_or_else(|| TensorError::Malformed("shape too large".to_string()))?;
+        if bytes.len() - data_start != data_len {
+            return Err(TensorError::Malformed(format!(
+                "expected {} data bytes for shape {:?}, found {}",
+                data_len,
+                shape,
+                bytes.len() - data_start
+            )));
+        }
+        Ok(Tensor {
+            shape,
+            dtype,
+            data: bytes.slice(data_start..),
+        })
+    }
+}
+
+fn check_rank(shape: &[usize]) -> Result<(), TensorError> {
+    if shape.len() > MAX_RANK {
+        return Err(TensorError::Malformed(format!("rank {} exceeds the maximum of {}", shape.len(), MAX_RANK)));
+    }
+    Ok(())
+}
+
+impl fmt::Debug for Tensor {
+    // The shape and dtype only; element dumps of large tensors drown the logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tensor({}, {:?})", self.dtype, self.shape)
+    }
+}
+
+// Tensors serialize as one byte string in the wire format, which binary codecs store as-is.
+impl Serialize for Tensor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.encode())
+    }
+}
+
+impl<'de> Deserialize<'de> for Tensor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(TensorVisitor)
+    }
+}
+
+struct TensorVisitor;
+
+impl<'de> Visitor<'de> for TensorVisitor {
+    type Value = Tensor;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an encoded tensor")
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Tensor, E> {
+        Tensor::decode(Bytes::from(bytes)).map_err(E::custom)
+    }
+
+    // The tensor outlives the message buffer, so borrowed bytes are copied once
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Tensor, E> {
+        self.visit_byte_buf(bytes.to_vec())
+    }
+
+    // Self-describing text formats such as JSON write byte strings as arrays of numbers
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Tensor, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        self.visit_byte_buf(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+
+    #[test]
+    fn test_wire_round_trip_for_every_dtype() {
+        let tensors = [
+            Tensor::new(vec![2, 3], vec![1.5f32, -2.0, 0.0, 4.25, 5.0, 6.0]).unwrap(),
+            Tensor::new(vec![3], vec![1.0f64, f64::MAX, -0.5]).unwrap(),
+            Tensor::new(vec![2, 2], vec![i32::MIN, -1, 0, i32::MAX]).unwrap(),
+            Tensor::new(vec![4], vec![0u8, 1, 254, 255]).unwrap(),
+            Tensor::scalar(3.0f32),
+            Tensor::zeros(DType::F32, vec![0, 5]),
+        ];
+        for tensor in tensors {
+            let encoded = tensor.encode();
+            assert_eq!(encoded.len(), tensor.encoded_len());
+            assert_eq!(Tensor::decode(Bytes::from(encoded)).unwrap(), tensor);
+        }
+    }
+
+    #[test]
+    fn test_decode_shares_the_buffer() {
+        let tensor = Tensor::new(vec![1024], (0..1024).map(|i| i as f32).collect()).unwrap();
+        let encoded = Bytes::from(tensor.encode());
+        let decoded = Tensor::decode(encoded.clone()).unwrap();
+
+        let data_start = encoded.len() - decoded.data.len();
+        assert_eq!(decoded.data.as_ptr(), encoded[data_start..].as_ptr());
+        assert!(matches!(decoded.values::<f32>().unwrap(), Cow::Borrowed(_)));
+        assert_eq!(decoded.values::<f32>().unwrap()[1023], 1023.0);
+    }
+
+    #[test]
+    fn test_rejects_malformed_input() {
+        let tensor = Tensor::new(vec![2, 2], vec![1i32, 2, 3, 4]).unwrap();
+        let encoded = tensor.encode();
+        assert!(Tensor::decode(Bytes::from(encoded[..encoded.len() - 1].to_vec())).is_err());
+        assert!(Tensor::decode(Bytes::from_static(&[9, 0, 0, 0, 0, 0, 0, 0])).is_err());
+        assert!(Tensor::new(vec![3], vec![1.0f32]).is_err());
+        assert!(matches!(tensor.values::<f32>(), Err(TensorError::DTypeMismatch { .. })));
+        assert!(tensor.reshape(vec![3]).is_err());
+        assert!(tensor.reshape(vec![1; MAX_RANK + 1]).is_err());
+        assert!(Tensor::new(vec![1; MAX_RANK + 1], vec![1i32]).is_err());
+        assert_eq!(tensor.reshape(vec![4]).unwrap().to_vec::<i32>().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_split_and_concat_rows() {
+        let tensor = Tensor::new(vec![5, 2], (0..10).collect::<Vec<i32>>()).unwrap();
+        let parts = tensor.split_rows(3).unwrap();
+        let rows: Vec<usize> = parts.iter().map(|part| part.shape()[0]).collect();
+        assert_eq!(rows, vec![2, 2, 1]);
+        assert_eq!(parts[2].to_vec::<i32>().unwrap(), vec![8, 9]);
+        assert_eq!(Tensor::concat_rows(&parts).unwrap(), tensor);
+
+        assert_eq!(tensor.split_rows(8).unwrap().len(), 5);
+        assert_eq!(tensor.slice_rows(1..3).unwrap().to_vec::<i32>().unwrap(), vec![2, 3, 4, 5]);
+        assert!(tensor.slice_rows(4..6).is_err());
+        let columns = tensor.split_columns(2).unwrap();
+        assert_eq!(columns[0].to_vec::<i32>().unwrap(), vec![0, 2, 4, 6, 8]);
+        assert_eq!(Tensor::concat_columns(&columns).unwrap(), tensor);
+        let other = Tensor::new(vec![1, 3], vec![0i32; 3]).unwrap();
+        assert!(Tensor::concat_rows(&[tensor, other]).is_err());
+    }
+
+    #[test]
+    fn test_serde_round_trip_with_every_codec() {
+        let tensor = Tensor::new(vec![2, 2], vec![0.5f32, 1.5, -2.5, 3.5]).unwrap();
+        for codec in Codec::available() {
+            let bytes = codec.encode(&tensor).unwrap();
+            let decoded: Tensor = codec.decode(&bytes).unwrap();
+            assert_eq!(decoded, tensor, "codec {:?}", codec);
+        }
+    }
+}
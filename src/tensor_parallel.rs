@@ -0,0 +1,171 @@
+// tensor_parallel.rs: Splits one matrix product across nodes by sharding the weight matrix, and combines the partial results.
+
+use crate::kernels::{self, KernelError};
+use crate::tensor::Tensor;
+use futures_util::future::join_all;
+use std::future::Future;
+
+// How the weight matrix W [in, out] of x·W is cut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardAxis {
+    // Each shard holds some of W's columns and multiplies the whole input; the products sit side by side.
+    Columns,
+    // Each shard holds some of W's rows and multiplies the matching columns of the input; the products add up.
+    Rows,
+}
+
+impl ShardAxis {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShardAxis::Columns => "columns",
+            ShardAxis::Rows => "rows",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, KernelError> {
+        [ShardAxis::Columns, ShardAxis::Rows]
+            .into_iter()
+            .find(|axis| axis.name() == name)
+            .ok_or_else(|| KernelError::InvalidInput(format!("unknown shard axis {:?}", name)))
+    }
+}
+
+// A weight matrix cut into shards, one for each node taking part in the product.
+#[derive(Debug, Clone)]
+pub struct ShardedMatmul {
+    pub axis: ShardAxis,
+    pub shards: Vec<Tensor>,
+    in_features: usize,
+    out_features: usize,
+}
+
+impl ShardedMatmul {
+    // Cuts `weights` into at most `parts` nearly equal shards; fewer when it has fewer columns (or rows).
+    pub fn new(weights: &Tensor, axis: ShardAxis, parts: usize) -> Result<Self, KernelError> {
+        let (in_features, out_features) = kernels::matrix_shape("sharded matmul", weights)?;
+        let shards = match axis {
+            ShardAxis::Columns => weights.split_columns(parts)?,
+            ShardAxis::Rows => weights.split_rows(parts)?,
+        };
+        Ok(ShardedMatmul {
+            axis,
+            shards,
+            in_features,
+            out_features,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    // The inputs of the `matmul` each shard runs: the whole of `x` with a column shard, and the columns of
+    // `x` that meet its rows with a row shard.
+    pub fn shard_inputs(&self, x: &Tensor) -> Result<Vec<Vec<Tensor>>, KernelError> {
+        let (_, in_features) = kernels::matrix_shape("sharded matmul", x)?;
+        if in_features != self.in_features {
+            return Err(KernelError::InvalidInput(format!(
+                "sharded matmul expects {} features, got {}",
+                self.in_features, in_features
+            )));
+        }
+        let inputs = match self.axis {
+            ShardAxis::Columns => self.shards.iter().map(|shard| vec![x.clone(), shard.clone()]).collect(),
+            // split_columns divides the columns of x exactly as split_rows divided the rows of W
+            ShardAxis::Rows => x
+                .split_columns(self.len())?
+                .into_iter()
+                .zip(&self.shards)
+                .map(|(columns, shard)| vec![columns, shard.clone()])
+                .collect(),
+        };
+        Ok(inputs)
+    }
+
+    // Gathers (column shards) or sums (row shards) the partial products, given in shard order.
+    pub fn combine(&self, partials: &[Tensor]) -> Result<Tensor, KernelError> {
+        if partials.len() != self.len() {
+            return Err(KernelError::InvalidInput(format!(
+                "sharded matmul has {} shards but got {} partial products",
+                self.len(),
+                partials.len()
+            )));
+        }
+        match self.axis {
+            ShardAxis::Columns => Ok(Tensor::concat_columns(partials)?),
+            ShardAxis::Rows => {
+                let (batch, _) = kernels::matrix_shape("sharded matmul", &partials[0])?;
+                let mut sum = vec![0.0f32; batch * self.out_features];
+                for partial in partials {
+                    partial.expect_shape(&[batch, self.out_features])?;
+                    for (total, value) in sum.iter_mut().zip(partial.values::<f32>()?.iter()) {
+                        *total += value;
+                    }
+                }
+                Ok(Tensor::new(vec![batch, self.out_features], sum)?)
+            }
+        }
+    }
+
+    // Computes x·W with every shard's product running concurrently. `matmul(shard, inputs)` runs the
+    // `matmul` kernel for one shard, wherever that shard lives.
+    pub async fn run<F, Fut, E>(&self, x: &Tensor, matmul: F) -> Result<Tensor, E>
+    where
+        F: Fn(usize, Vec<Tensor>) -> Fut,
+        Fut: Future<Output = Result<Tensor, E>>,
+        E: From<KernelError>,
+    {
+        let inputs = self.shard_inputs(x)?;
+        let partials = join_all(inputs.into_iter().enumerate().map(|(shard, inputs)| matmul(shard, inputs))).await;
+        let partials = partials.into_iter().collect::<Result<Vec<_>, _>>()?;
+        Ok(self.combine(&partials)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::{Attrs, KernelRegistry};
+    use crate::test_support::tensor;
+
+    #[tokio::test]
+    async fn test_sharded_matmul_matches_unsharded() {
+        let registry = KernelRegistry::builtin();
+        let x = tensor(vec![5, 7], 1.0);
+        let weights = tensor(vec![7, 9], 2.0);
+        let expected = registry
+            .run("matmul", &[x.clone(), weights.clone()], &Attrs::new())
+            .unwrap()
+            .remove(0)
+            .to_vec::<f32>()
+            .unwrap();
+
+        for axis in [ShardAxis::Columns, ShardAxis::Rows] {
+            // Uneven shards, and more parts than one axis of an input has
+            for parts in [1, 2, 3, 4, 12] {
+                let sharded = ShardedMatmul::new(&weights, axis, parts).unwrap();
+                let product = sharded
+                    .run(&x, |_, inputs| {
+                        let registry = &registry;
+                        async move { registry.run("matmul", &inputs, &Attrs::new()).map(|mut outputs| outputs.remove(0)) }
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(product.shape(), &[5, 9]);
+                for (found, expected) in product.to_vec::<f32>().unwrap().iter().zip(&expected) {
+                    assert!((found - expected).abs() < 1e-5, "{:?} with {} parts", axis, parts);
+                }
+            }
+        }
+        assert_eq!(ShardedMatmul::new(&weights, ShardAxis::Columns, 3).unwrap().shards[0].shape(), &[7, 3]);
+        assert_eq!(ShardedMatmul::new(&weights, ShardAxis::Rows, 3).unwrap().shards[0].shape(), &[3, 9]);
+    }
+
+    #[test]
+    fn test_rejects_mismatched_inputs() {
+        let sharded = ShardedMatmul::new(&tensor(vec![4, 2], 0.0), ShardAxis::Rows, 2).unwrap();
+        assert!(sharded.shard_inputs(&tensor(vec![3, 3], 0.0)).is_err());
+        assert!(sharded.combine(&[tensor(vec![3, 2], 0.0)]).is_err());
+        assert!(ShardAxis::parse("diagonal").is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// test_support.rs: Helpers shared by the kernel tests: deterministic inputs, tolerant comparisons and numeric gradients.
+
+use crate::tensor::Tensor;
+
+// A tensor of `shape` filled with distinct values in [-1, 1]; different seeds give different values.
+pub fn tensor(shape: Vec<usize>, seed: f32) -> Tensor {
+    let len = shape.iter().product();
+    Tensor::new(shape, (0..len).map(|i| ((i as f32 + seed) * 0.37).sin()).collect::<Vec<f32>>()).unwrap()
+}
+
+// Asserts every value is within `absolute`, or within `relative` of the expected value if that is more.
+pub fn assert_close(found: &Tensor, expected: &[f32], absolute: f32, relative: f32) {
+    let found = found.to_vec::<f32>().unwrap();
+    assert_eq!(found.len(), expected.len());
+    for (found, expected) in found.iter().zip(expected) {
+        let tolerance = absolute.max(relative * expected.abs());
+        assert!((found - expected).abs() < tolerance, "{:?} vs {:?}", found, expected);
+    }
+}
+
+// d(sum(probe * f(x))) / dx by central differences
+pub fn numeric_gradient(x: &Tensor, probe: &Tensor, f: impl Fn(&Tensor) -> Tensor) -> Vec<f32> {
+    let values = x.to_vec::<f32>().unwrap();
+    let probe = probe.to_vec::<f32>().unwrap();
+    let loss = |values: Vec<f32>| -> f32 {
+        let y = f(&Tensor::new(x.shape().to_vec(), values).unwrap()).to_vec::<f32>().unwrap();
+        y.iter().zip(&probe).map(|(y, p)| y * p).sum()
+    };
+    (0..values.len())
+        .map(|i| {
+            let (mut plus, mut minus) = (values.clone(), values.clone());
+            plus[i] += 1e-2;
+            minus[i] -= 1e-2;
+            (loss(plus) - loss(minus)) / 2e-2
+        })
+        .collect()
+}
@@ -0,0 +1,586 @@
+// training.rs: Data-parallel training: Ki nodes compute gradients for shards of each mini-batch, and a parameter server on the An node averages them and updates the weights.
+
+use crate::autodiff::{Tape, Var};
+use crate::dataset::{self, DatasetSpec};
+use crate::kernels::{self, Attrs, KernelError};
+use crate::messages::TaskMessage;
+use crate::mlp::{self, Mlp};
+use crate::model::Weights;
+use crate::optimizer::{Optimizer, OptimizerState, Schedule};
+use crate::tensor::Tensor;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
+use std::time::Duration;
+
+pub const DEFAULT_STEPS: usize = 100;
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+pub const DEFAULT_LEARNING_RATE: f64 = 0.01;
+pub const DEFAULT_BARRIER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loss {
+    // Mean squared error over every output
+    Mse,
+    // Mean over rows of the cross-entropy between the outputs, as probabilities, and the targets
+    CrossEntropy,
+}
+
+impl Loss {
+    pub fn name(self) -> &'static str {
+        match self {
+            Loss::Mse => "mse",
+            Loss::CrossEntropy => "cross_entropy",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, KernelError> {
+        match name {
+            "mse" => Ok(Loss::Mse),
+            "cross_entropy" => Ok(Loss::CrossEntropy),
+            _ => Err(KernelError::InvalidInput(format!("unknown loss {:?}", name))),
+        }
+    }
+
+    // Records the loss of `predictions` against `targets` on `tape`.
+    pub fn record(self, tape: &mut Tape, predictions: Var, targets: &Tensor) -> Result<Var, KernelError> {
+        match self {
+            Loss::Mse => tape.mse(predictions, targets),
+            Loss::CrossEntropy => tape.cross_entropy(predictions, targets),
+        }
+    }
+}
+
+// How the gradients of a step's shards are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradientSync {
+    // Every Ki node pushes its gradients to the An node, which averages them
+    ParameterServer,
+    // The Ki nodes sum their gradients around a ring, and only one of them reports the result
+    AllReduce,
+}
+
+impl GradientSync {
+    pub fn name(self) -> &'static str {
+        match self {
+            GradientSync::ParameterServer => "parameter_server",
+            GradientSync::AllReduce => "allreduce",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, KernelError> {
+        match name {
+            "parameter_server" => Ok(GradientSync::ParameterServer),
+            "allreduce" => Ok(GradientSync::AllReduce),
+            _ => Err(KernelError::InvalidInput(format!("unknown gradient sync {:?}", name))),
+        }
+    }
+}
+
+// How long a round waits for gradients. The round closes as soon as `quorum` workers (all of them when
+// unset) have pushed theirs; later pushes are dropped. If the quorum is not met within `timeout` the
+// round fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Barrier {
+    pub quorum: Option<usize>,
+    pub timeout: Duration,
+}
+
+impl Barrier {
+    pub fn required(&self, workers: usize) -> usize {
+        self.quorum.map_or(workers, |quorum| quorum.clamp(1, workers.max(1)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BarrierTimeout {
+    pub arrived: usize,
+    pub required: usize,
+}
+
+impl fmt::Display for BarrierTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "only {} of {} gradient pushes arrived before the barrier timed out", self.arrived, self.required)
+    }
+}
+
+impl std::error::Error for BarrierTimeout {}
+
+// A training job, read from the attributes of a `model_train` task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingConfig {
+    pub steps: usize,
+    pub batch_size: usize,
+    pub loss: Loss,
+    pub optimizer: Optimizer,
+    // Starts from the `learning_rate` attribute
+    pub schedule: Schedule,
+    pub barrier: Barrier,
+    pub sync: GradientSync,
+    // Steps between checkpoints, when the An node keeps them
+    pub checkpoint_every: Option<usize>,
+}
+
+impl TrainingConfig {
+    pub fn from_attrs(attrs: &Attrs) -> Result<Self, KernelError> {
+        let positive = |name: &str, default: usize| -> Result<usize, KernelError> {
+            match kernels::int_attr(attrs, name)? {
+                None => Ok(default),
+                Some(value) if value > 0 => Ok(value as usize),
+                Some(value) => Err(KernelError::InvalidInput(format!("{} must be positive, got {}", name, value))),
+            }
+        };
+        let learning_rate = kernels::float_attr(attrs, "learning_rate")?.unwrap_or(DEFAULT_LEARNING_RATE);
+        if learning_rate <= 0.0 || !learning_rate.is_finite() {
+            return Err(KernelError::InvalidInput(format!("learning_rate must be positive, got {}", learning_rate)));
+        }
+        let quorum = match kernels::int_attr(attrs, "barrier_quorum")? {
+            None => None,
+            Some(_) => Some(positive("barrier_quorum", 0)?),
+        };
+        let timeout = match kernels::int_attr(attrs, "barrier_timeout_ms")? {
+            None => DEFAULT_BARRIER_TIMEOUT,
+            Some(_) => Duration::from_millis(positive("barrier_timeout_ms", 0)? as u64),
+        };
+        let steps = positive("steps", DEFAULT_STEPS)?;
+        Ok(TrainingConfig {
+            steps,
+            batch_size: positive("batch_size", DEFAULT_BATCH_SIZE)?,
+            loss: Loss::parse(kernels::str_attr(attrs, "loss")?.unwrap_or("mse"))?,
+            optimizer: Optimizer::from_attrs(attrs)?,
+            schedule: Schedule::from_attrs(attrs, learning_rate, steps)?,
+            barrier: Barrier { quorum, timeout },
+            sync: GradientSync::parse(kernels::str_attr(attrs, "sync")?.unwrap_or("parameter_server"))?,
+            checkpoint_every: match kernels::int_attr(attrs, "checkpoint_every")? {
+                None => None,
+                Some(_) => Some(positive("checkpoint_every", 0)?),
+            },
+        })
+    }
+
+    // The inputs and targets of the mini-batch for `step`. Steps walk through the data in order,
+    // starting over after the last (possibly shorter) mini-batch.
+    pub fn mini_batch(&self, step: usize, x: &Tensor, y: &Tensor) -> Result<(Tensor, Tensor), KernelError> {
+        let rows = x.shape().first().copied().unwrap_or(0);
+        if y.shape().first() != Some(&rows) || rows == 0 {
+            return Err(KernelError::InvalidInput(format!(
+                "training needs as many target rows as input rows, got {:?} and {:?}",
+                x.shape(),
+                y.shape()
+            )));
+        }
+        let start = step % rows.div_ceil(self.batch_size) * self.batch_size;
+        let end = (start + self.batch_size).min(rows);
+        Ok((x.slice_rows(start..end)?, y.slice_rows(start..end)?))
+    }
+}
+
+// Where a training job's examples come from.
+#[derive(Debug, Clone)]
+pub enum TrainingData {
+    // Shipped with the task; the An node cuts each mini-batch into shards
+    Tensors { x: Tensor, y: Tensor },
+    // On the Ki nodes' disks; each Ki node reads the mini-batches of its own shard
+    Dataset { spec: DatasetSpec, records: usize },
+}
+
+impl TrainingData {
+    // From a `model_train` task: its `dataset` attribute, a dataset descriptor, or else its inputs and targets.
+    pub fn from_task(task: &TaskMessage) -> Result<Self, KernelError> {
+        if let Some(json) = kernels::str_attr(&task.attrs, "dataset")? {
+            let spec = DatasetSpec::from_json(json)?;
+            let records = spec.records()?;
+            if records == 0 {
+                return Err(KernelError::InvalidInput(format!("dataset {} has no records", spec.name)));
+            }
+            return Ok(TrainingData::Dataset { spec, records });
+        }
+        match (task.inputs.first(), task.inputs.get(1)) {
+            (Some(x), Some(y)) => Ok(TrainingData::Tensors { x: x.clone(), y: y.clone() }),
+            _ => Err(KernelError::InvalidInput(
+                "model_train tasks need inputs and targets, or a dataset attribute".to_string(),
+            )),
+        }
+    }
+
+    // The op Ki nodes run to compute gradients on this data
+    pub fn op(&self) -> &'static str {
+        match self {
+            TrainingData::Tensors { .. } => "mlp_gradients",
+            TrainingData::Dataset { .. } => "shard_gradients",
+        }
+    }
+
+    // The gradient tasks of `step` for `workers` workers, each with the rows it covers. There are fewer
+    // tasks than workers when there are fewer rows than workers. Shards of a dataset follow the number of
+    // workers, so they are re-balanced whenever it changes.
+    pub fn shard_tasks(
+        &self,
+        config: &TrainingConfig,
+        step: usize,
+        mlp: &Mlp,
+        workers: usize,
+        task_id: impl Fn(usize) -> String,
+    ) -> Result<Vec<(TaskMessage, usize)>, KernelError> {
+        match self {
+            TrainingData::Tensors { x, y } => {
+                let (x, y) = config.mini_batch(step, x, y)?;
+                let inputs = x.split_rows(workers)?;
+                let targets = y.split_rows(workers)?;
+                Ok(inputs
+                    .into_iter()
+                    .zip(targets)
+                    .enumerate()
+                    .map(|(shard, (x, y))| {
+                        let rows = x.shape()[0];
+                        (gradient_task(task_id(shard), mlp, config.loss, x, y), rows)
+                    })
+                    .collect())
+            }
+            TrainingData::Dataset { spec, records } => Ok(dataset::shards(*records, workers)
+                .into_iter()
+                .enumerate()
+                .map(|(index, shard)| {
+                    let rows = dataset::batch_rows(&shard, config.batch_size);
+                    (shard_gradient_task(task_id(index), mlp, config, spec, shard, step), rows)
+                })
+                .collect()),
+        }
+    }
+}
+
+// What one worker pushes for one round: the loss and gradients over the rows of its shard.
+#[derive(Debug, Clone)]
+pub struct GradientPush {
+    pub rows: usize,
+    pub loss: f32,
+    pub gradients: Weights,
+}
+
+impl GradientPush {
+    // From the outputs of `mlp_gradients`: the loss, then one gradient per parameter in `names` order.
+    pub fn from_outputs(rows: usize, names: &[String], outputs: Vec<Tensor>) -> Result<Self, KernelError> {
+        if outputs.len() != names.len() + 1 {
+            return Err(KernelError::InvalidInput(format!(
+                "expected a loss and {} gradients, got {} outputs",
+                names.len(),
+                outputs.len()
+            )));
+        }
+        let mut outputs = outputs.into_iter();
+        let loss = match outputs.next().map(|loss| loss.to_vec::<f32>()) {
+            Some(Ok(values)) if values.len() == 1 => values[0],
+            _ => return Err(KernelError::InvalidInput("the loss should be a scalar".to_string())),
+        };
+        Ok(GradientPush {
+            rows,
+            loss,
+            gradients: names.iter().cloned().zip(outputs).collect(),
+        })
+    }
+}
+
+// Holds the weights being trained and the optimizer's state, and applies each round's gradients to them.
+#[derive(Debug, Clone)]
+pub struct ParameterServer {
+    parameters: Weights,
+    optimizer: Optimizer,
+    schedule: Schedule,
+    state: OptimizerState,
+}
+
+impl ParameterServer {
+    pub fn new(parameters: Weights, optimizer: Optimizer, schedule: Schedule) -> Self {
+        ParameterServer {
+            parameters,
+            optimizer,
+            schedule,
+            state: OptimizerState::default(),
+        }
+    }
+
+    // Continues from a saved optimizer state instead of a fresh one.
+    pub fn with_state(mut self, state: OptimizerState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn parameters(&self) -> &Weights {
+        &self.parameters
+    }
+
+    pub fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    // Rounds applied so far
+    pub fn step(&self) -> u64 {
+        self.state.step
+    }
+
+    // Averages the pushes of one round, weighting each by the rows it covered, updates every parameter
+    // with the optimizer and returns the round's loss.
+    pub fn apply(&mut self, pushes: &[GradientPush]) -> Result<f32, KernelError> {
+        let rows: usize = pushes.iter().map(|push| push.rows).sum();
+        if rows == 0 {
+            return Err(KernelError::InvalidInput("no gradients to apply".to_string()));
+        }
+        let mut updated = Weights::new();
+        for (name, parameter) in &self.parameters {
+            let mut gradient = vec![0.0f32; parameter.len()];
+            for push in pushes {
+                let pushed = push
+                    .gradients
+                    .get(name)
+                    .ok_or_else(|| KernelError::InvalidInput(format!("no gradient for {}", name)))?;
+                pushed.expect_shape(parameter.shape())?;
+                let weight = push.rows as f32 / rows as f32;
+                for (total, value) in gradient.iter_mut().zip(pushed.values::<f32>()?.iter()) {
+                    *total += weight * value;
+                }
+            }
+            let learning_rate = self.schedule.rate(self.state.step);
+            let parameter = self
+                .optimizer
+                .update(name, parameter, &gradient, learning_rate, &mut self.state)?;
+            updated.insert(name.clone(), parameter);
+        }
+        self.parameters = updated;
+        self.state.step += 1;
+        Ok(pushes.iter().map(|push| push.loss * push.rows as f32).sum::<f32>() / rows as f32)
+    }
+}
+
+// Runs one synchronous round: asks each of `workers` for its push concurrently and waits at the barrier.
+// Returns the pushes that made it, in arrival order.
+pub async fn round<F, Fut, T, E>(barrier: &Barrier, workers: usize, push: F) -> Result<Vec<T>, E>
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: From<BarrierTimeout>,
+{
+    let required = barrier.required(workers);
+    let mut pending: FuturesUnordered<Fut> = (0..workers).map(push).collect();
+    let deadline = tokio::time::Instant::now() + barrier.timeout;
+    let mut pushed = Vec::with_capacity(workers);
+    while pushed.len() < required {
+        match tokio::time::timeout_at(deadline, pending.next()).await {
+            Ok(Some(result)) => pushed.push(result?),
+            Ok(None) => break,
+            Err(_) => {
+                return Err(BarrierTimeout {
+                    arrived: pushed.len(),
+                    required,
+                }
+                .into())
+            }
+        }
+    }
+    Ok(pushed)
+}
+
+// A task computing the loss and gradients of `mlp` on the inputs `x` and targets `y`.
+pub fn gradient_task(task_id: impl Into<String>, mlp: &Mlp, loss: Loss, x: Tensor, y: Tensor) -> TaskMessage {
+    let mut task = mlp.task(task_id, x);
+    task.op = "mlp_gradients".to_string();
+    task.inputs.insert(1, y);
+    task.with_attr("loss", loss.name())
+}
+
+// A task computing the loss and gradients of `mlp` on the mini-batch `step` reads from `shard` of a dataset.
+// Each worker reads its share of the rows of a mini-batch, `batch_size` at most.
+pub fn shard_gradient_task(
+    task_id: impl Into<String>,
+    mlp: &Mlp,
+    config: &TrainingConfig,
+    spec: &DatasetSpec,
+    shard: Range<usize>,
+    step: usize,
+) -> TaskMessage {
+    let mut task = mlp.task(task_id, Tensor::scalar(0.0f32));
+    task.op = "shard_gradients".to_string();
+    task.inputs.remove(0);
+    task.with_attr("loss", config.loss.name())
+        .with_attr("dataset", spec.to_json())
+        .with_attr("shard_start", shard.start as i64)
+        .with_attr("shard_end", shard.end as i64)
+        .with_attr("step", step as i64)
+        .with_attr("batch_size", config.batch_size as i64)
+}
+
+// The loss of `mlp` on `x` against `y`, and its gradient with respect to each weight and bias, in
+// layer order.
+pub fn gradients(mlp: &Mlp, loss: Loss, x: &Tensor, y: &Tensor) -> Result<(f32, Vec<Tensor>), KernelError> {
+    let mut tape = Tape::new();
+    let mut activations = tape.leaf(x.clone());
+    let mut parameters = Vec::with_capacity(2 * mlp.layers.len());
+    for layer in &mlp.layers {
+        let weights = tape.leaf(layer.weights.clone());
+        let bias = tape.leaf(layer.bias.clone());
+        activations = tape.dense(activations, weights, bias, layer.activation)?;
+        parameters.extend([weights, bias]);
+    }
+    let output = loss.record(&mut tape, activations, y)?;
+    let value = tape.value(output).to_vec::<f32>()?[0];
+
+    let grads = tape.backward(output)?;
+    let gradients = parameters
+        .into_iter()
+        .map(|parameter| {
+            grads
+                .get(parameter)
+                .cloned()
+                .unwrap_or_else(|| Tensor::zeros(tape.value(parameter).dtype(), tape.value(parameter).shape().to_vec()))
+        })
+        .collect();
+    Ok((value, gradients))
+}
+
+// inputs: x [batch, in], targets y [batch, out], then weights and bias for each layer; attrs:
+// activations, one per layer, and loss ("mse" by default, or "cross_entropy"); outputs: the loss as a scalar, then the
+// gradient of each weight and bias
+pub fn mlp_gradients(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let x = kernels::input("mlp_gradients", inputs, 0)?;
+    let y = kernels::input("mlp_gradients", inputs, 1)?;
+    let loss = Loss::parse(kernels::str_attr(attrs, "loss")?.unwrap_or("mse"))?;
+    let network = mlp::network(&inputs[2..], attrs)?;
+    let (value, gradients) = gradients(&network, loss, x, y)?;
+    let mut outputs = Vec::with_capacity(gradients.len() + 1);
+    outputs.push(Tensor::scalar(value));
+    outputs.extend(gradients);
+    Ok(outputs)
+}
+
+// inputs: weights and bias for each layer; attrs: activations and loss as for mlp_gradients, dataset (a
+// descriptor), shard_start and shard_end (the shard's records), step and batch_size; outputs: as for
+// mlp_gradients, over the examples the step reads from the shard on this node's disk
+pub fn shard_gradients(inputs: &[Tensor], attrs: &Attrs) -> Result<Vec<Tensor>, KernelError> {
+    let required = |name: &str| -> Result<usize, KernelError> {
+        match kernels::int_attr(attrs, name)? {
+            Some(value) if value >= 0 => Ok(value as usize),
+            _ => Err(KernelError::InvalidInput(format!("shard_gradients needs a non-negative {} attribute", name))),
+        }
+    };
+    let spec = DatasetSpec::from_json(
+        kernels::str_attr(attrs, "dataset")?
+            .ok_or_else(|| KernelError::InvalidInput("shard_gradients needs a dataset attribute".to_string()))?,
+    )?;
+    let shard = required("shard_start")?..required("shard_end")?;
+    let batch_size = required("batch_size")?;
+    if shard.is_empty() || batch_size == 0 {
+        return Err(KernelError::InvalidInput(format!("empty shard {:?} or batch", shard)));
+    }
+    let (x, y) = spec.read(&spec.batch_indices(&shard, required("step")?, batch_size))?;
+    let loss = Loss::parse(kernels::str_attr(attrs, "loss")?.unwrap_or("mse"))?;
+    let network = mlp::network(inputs, attrs)?;
+    let (value, gradients) = gradients(&network, loss, &x, &y)?;
+    let mut outputs = Vec::with_capacity(gradients.len() + 1);
+    outputs.push(Tensor::scalar(value));
+    outputs.extend(gradients);
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::{Activation, KernelRegistry};
+    use crate::mlp::DenseLayer;
+    use crate::test_support::tensor;
+
+    fn network() -> Mlp {
+        Mlp::new(vec![
+            DenseLayer::new(tensor(vec![3, 4], 1.0), tensor(vec![4], 2.0), Activation::Tanh).unwrap(),
+            DenseLayer::new(tensor(vec![4, 2], 3.0), tensor(vec![2], 4.0), Activation::Sigmoid).unwrap(),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_gradient_kernel_reduces_loss() {
+        let mlp = network();
+        let x = Tensor::new(vec![4, 3], vec![0.5f32, -1.0, 0.2, 1.0, 0.3, -0.4, -0.7, 0.8, 0.1, 0.0, 0.2, 0.9]).unwrap();
+        let y = Tensor::new(vec![4, 2], vec![1.0f32, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0]).unwrap();
+        let task = gradient_task("grad-1", &mlp, Loss::Mse, x.clone(), y.clone());
+        let outputs = KernelRegistry::builtin().run(&task.op, &task.inputs, &task.attrs).unwrap();
+        assert_eq!(outputs.len(), 5);
+        assert_eq!(outputs[1].shape(), &[3, 4]);
+        assert_eq!(outputs[4].shape(), &[2]);
+
+        let names: Vec<String> = ["w0", "b0", "w1", "b1"].iter().map(|name| name.to_string()).collect();
+        let parameters: Weights = names
+            .iter()
+            .cloned()
+            .zip(mlp.layers.iter().flat_map(|layer| [layer.weights.clone(), layer.bias.clone()]))
+            .collect();
+        let mut server = ParameterServer::new(parameters, Optimizer::Sgd, Schedule::from_attrs(&Attrs::new(), 0.5, 1).unwrap());
+        let push = GradientPush::from_outputs(4, &names, outputs).unwrap();
+        let before = server.apply(&[push]).unwrap();
+
+        let p = server.parameters();
+        let trained = Mlp::new(vec![
+            DenseLayer::new(p["w0"].clone(), p["b0"].clone(), Activation::Tanh).unwrap(),
+            DenseLayer::new(p["w1"].clone(), p["b1"].clone(), Activation::Sigmoid).unwrap(),
+        ])
+        .unwrap();
+        let (after, _) = gradients(&trained, Loss::Mse, &x, &y).unwrap();
+        assert!(after < before, "{} then {}", before, after);
+        assert_eq!(server.step(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_round_closes_at_the_quorum() {
+        let barrier = Barrier {
+            quorum: Some(2),
+            timeout: Duration::from_millis(500),
+        };
+        let mut pushed = round(&barrier, 3, |worker| async move {
+            // Worker 2 straggles past the barrier
+            tokio::time::sleep(Duration::from_millis(if worker == 2 { 2000 } else { 10 })).await;
+            Ok::<usize, BarrierTimeout>(worker)
+        })
+        .await
+        .unwrap();
+        pushed.sort();
+        assert_eq!(pushed, vec![0, 1]);
+
+        let strict = Barrier {
+            quorum: None,
+            timeout: Duration::from_millis(50),
+        };
+        let timed_out = round(&strict, 3, |worker| async move {
+            tokio::time::sleep(Duration::from_millis(if worker == 2 { 2000 } else { 10 })).await;
+            Ok::<usize, BarrierTimeout>(worker)
+        })
+        .await;
+        assert_eq!(timed_out.unwrap_err(), BarrierTimeout { arrived: 2, required: 3 });
+    }
+
+    #[test]
+    fn test_config_from_attrs() {
+        let mut attrs = Attrs::new();
+        attrs.insert("steps".to_string(), 5i64.into());
+        attrs.insert("batch_size".to_string(), 3i64.into());
+        attrs.insert("barrier_quorum".to_string(), 2i64.into());
+        let config = TrainingConfig::from_attrs(&attrs).unwrap();
+        assert_eq!(config.steps, 5);
+        assert_eq!(config.barrier.quorum, Some(2));
+        assert_eq!(config.optimizer, Optimizer::Sgd);
+        assert_eq!(config.schedule.learning_rate, DEFAULT_LEARNING_RATE);
+        assert_eq!(config.schedule.decay, crate::optimizer::Decay::Constant);
+        assert_eq!(config.sync, GradientSync::ParameterServer);
+
+        // 7 rows in batches of 3: rows 0..3, 3..6, 6..7, then 0..3 again
+        let x = Tensor::new(vec![7, 1], (0..7).map(|i| i as f32).collect()).unwrap();
+        let (batch, _) = config.mini_batch(2, &x, &x).unwrap();
+        assert_eq!(batch.to_vec::<f32>().unwrap(), vec![6.0]);
+        let (batch, _) = config.mini_batch(3, &x, &x).unwrap();
+        assert_eq!(batch.shape(), &[3, 1]);
+
+        attrs.insert("sync".to_string(), "allreduce".into());
+        assert_eq!(TrainingConfig::from_attrs(&attrs).unwrap().sync, GradientSync::AllReduce);
+
+        attrs.insert("learning_rate".to_string(), (-1.0).into());
+        assert!(TrainingConfig::from_attrs(&attrs).is_err());
+    }
+}